@@ -0,0 +1,62 @@
+// Times programs that spend their time calling functions and building
+// values, to compare changes to the virtual machine. Run with `cargo bench`.
+use plover::vm::{Timings, Value};
+use plover::Interpreter;
+use std::time::Duration;
+
+const RUNS: u32 = 5;
+
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "fib",
+        "fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end
+        fib (25)",
+    ),
+    (
+        "count",
+        "fn count (n, acc) -> if n == 0 then acc else count (n - 1, acc + n) end end
+        count (1000000, 0)",
+    ),
+    (
+        "list",
+        "type List := Nil | Cons (integer, List) end
+        fn build (n, list) -> if n == 0 then list else build (n - 1, Cons (n, list)) end end
+        fn sum (list, acc) ->
+            match list with
+                Nil -> acc
+                | Cons (head, tail) -> sum (tail, acc + head)
+            end
+        end
+        fn go (n, acc) -> if n == 0 then acc else go (n - 1, acc + sum (build (1000, Nil), 0)) end end
+        go (50, 0)",
+    ),
+    (
+        "records",
+        "fn step (p) -> {a = p.b, b = (p.a + p.b) % 1000} end
+        fn iterate (n, p) -> if n == 0 then p.a else iterate (n - 1, step (p)) end end
+        iterate (500000, {a = 0, b = 1})",
+    ),
+];
+
+fn run(src: &str) -> (Value, Duration) {
+    let mut interpreter = Interpreter::new();
+    interpreter.vm.timings = Some(Timings::default());
+    match interpreter.eval_str(src) {
+        Ok(value) => (value, interpreter.vm.timings.unwrap().run),
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+fn main() {
+    println!("size of a value: {} bytes", std::mem::size_of::<Value>());
+    for (name, src) in PROGRAMS {
+        let mut best = Duration::MAX;
+        let mut result = String::new();
+        for _ in 0..RUNS {
+            let (value, time) = run(src);
+            best = best.min(time);
+            result = value.to_string();
+        }
+        println!("{:8} {:>10.2?} ({})", name, best, result);
+    }
+}
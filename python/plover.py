@@ -0,0 +1,122 @@
+# Python bindings for Plover, for scripting from Python and notebooks. They
+# load the shared library built with `cargo build --release --features python`
+# through ctypes, from PLOVER_LIBRARY if it is set and from target/release
+# otherwise.
+import ctypes
+import json
+import os
+import sys
+from collections import namedtuple
+
+# A value of a datatype, such as Some (1), with the names of its type and
+# variant. Variants without a value hold None.
+Variant = namedtuple("Variant", ["type", "variant", "value"])
+
+
+class Opaque:
+    # Values that only exist within the interpreter, such as functions, which
+    # are kept as the REPL prints them.
+    def __init__(self, text):
+        self.text = text
+
+    def __repr__(self):
+        return self.text
+
+
+class PloverError(Exception):
+    pass
+
+
+_library = None
+
+
+def _load():
+    global _library
+    if _library is not None:
+        return _library
+    name = {"darwin": "libplover.dylib", "win32": "plover.dll"}.get(
+        sys.platform, "libplover.so"
+    )
+    root = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+    path = os.environ.get("PLOVER_LIBRARY") or os.path.join(
+        root, "target", "release", name
+    )
+    library = ctypes.CDLL(path)
+    library.plover_new.restype = ctypes.c_void_p
+    library.plover_free.argtypes = [ctypes.c_void_p]
+    library.plover_eval.restype = ctypes.c_void_p
+    library.plover_eval.argtypes = [ctypes.c_void_p, ctypes.c_char_p]
+    library.plover_last_error.restype = ctypes.c_char_p
+    library.plover_value_free.argtypes = [ctypes.c_void_p]
+    # Strings from the library are kept as pointers so they can be freed.
+    library.plover_value_json.restype = ctypes.c_void_p
+    library.plover_value_json.argtypes = [ctypes.c_void_p]
+    library.plover_value_string.restype = ctypes.c_void_p
+    library.plover_value_string.argtypes = [ctypes.c_void_p]
+    library.plover_string_free.argtypes = [ctypes.c_void_p]
+    _library = library
+    return library
+
+
+def _take_string(library, s):
+    try:
+        return ctypes.string_at(s).decode("utf-8")
+    finally:
+        library.plover_string_free(s)
+
+
+# Values are converted from the JSON the library writes for them. Tuples
+# become tuples, records become dictionaries and unit becomes None.
+def _convert(value):
+    kind = value["kind"]
+    if kind in ("Boolean", "Char", "Integer", "String"):
+        return value["value"]
+    if kind == "Datatype":
+        return Variant(value["type"], value["variant"], _convert(value["value"]))
+    if kind == "Record":
+        return {field["name"]: _convert(field["value"]) for field in value["fields"]}
+    if kind == "Tuple":
+        return tuple(_convert(element) for element in value["elements"])
+    return None
+
+
+class Interpreter:
+    # Each interpreter keeps what is defined in it, as a REPL session does.
+    def __init__(self):
+        self._library = _load()
+        self._interpreter = self._library.plover_new()
+
+    def close(self):
+        if self._interpreter:
+            self._library.plover_free(self._interpreter)
+            self._interpreter = None
+
+    def __del__(self):
+        self.close()
+
+    def eval(self, src):
+        if not self._interpreter:
+            raise PloverError("The interpreter has been closed.")
+        value = self._library.plover_eval(self._interpreter, src.encode("utf-8"))
+        if not value:
+            raise PloverError(self._library.plover_last_error().decode("utf-8"))
+        try:
+            text = self._library.plover_value_json(value)
+            if text:
+                return _convert(json.loads(_take_string(self._library, text)))
+            text = self._library.plover_value_string(value)
+            return Opaque(_take_string(self._library, text))
+        finally:
+            self._library.plover_value_free(value)
+
+
+_default = None
+
+
+# Evaluates a program in an interpreter shared by every call, so that later
+# programs can use what earlier ones define.
+def eval(src):
+    global _default
+    if _default is None:
+        _default = Interpreter()
+    return _default.eval(src)
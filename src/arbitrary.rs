@@ -0,0 +1,674 @@
+// Generators for well typed programs, driven by arbitrary bytes, and a
+// shrinker for finding small counterexamples to properties.
+use std::panic;
+
+use crate::parser::{Operator, Span, AST};
+use crate::pretty;
+use std::sync::Arc;
+
+use crate::typeinfer::Type;
+
+const MAX_DEPTH: usize = 5;
+
+// Reads choices from fuzzer input. Once the input is used up every choice is
+// zero, which always picks a leaf, so generation terminates.
+pub struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    pub fn new(data: &'a [u8]) -> Input<'a> {
+        Input { data, pos: 0 }
+    }
+
+    pub fn choose(&mut self, n: usize) -> usize {
+        match self.data.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                *b as usize % n
+            }
+            None => 0,
+        }
+    }
+}
+
+struct Generator<'a, 'b> {
+    input: &'b mut Input<'a>,
+    scope: Vec<(String, Type)>,
+    next_id: usize,
+}
+
+impl<'a, 'b> Generator<'a, 'b> {
+    fn fresh(&mut self) -> String {
+        let id = format!("v{}", self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn scalar_type(&mut self) -> Type {
+        match self.input.choose(2) {
+            0 => Type::Integer,
+            _ => Type::Boolean,
+        }
+    }
+
+    fn value_type(&mut self) -> Type {
+        match self.input.choose(3) {
+            0 => Type::Integer,
+            1 => Type::Boolean,
+            _ => {
+                let len = 2 + self.input.choose(2);
+                Type::Tuple((0..len).map(|_| self.element_type()).collect())
+            }
+        }
+    }
+
+    // Tuples nest, but only one level deep to keep programs small.
+    fn element_type(&mut self) -> Type {
+        match self.input.choose(4) {
+            0 => Type::Tuple((0..2).map(|_| self.scalar_type()).collect()),
+            _ => self.scalar_type(),
+        }
+    }
+
+    fn literal(&mut self, typ: &Type) -> AST {
+        let span = Span::unknown();
+        match typ {
+            Type::Boolean => AST::Boolean(self.input.choose(2) == 1, span),
+            Type::Tuple(elements) => {
+                AST::Tuple(elements.iter().map(|t| self.literal(t)).collect(), span)
+            }
+            _ => AST::Integer(self.input.choose(100) as i64, span),
+        }
+    }
+
+    fn identifier(&mut self, typ: &Type) -> AST {
+        let candidates: Vec<&String> = self
+            .scope
+            .iter()
+            .filter(|(_, t)| t == typ)
+            .map(|(id, _)| id)
+            .collect();
+        if candidates.is_empty() {
+            self.literal(typ)
+        } else {
+            let id = candidates[self.input.choose(candidates.len())].to_string();
+            AST::Identifier(id, Span::unknown())
+        }
+    }
+
+    fn operator(&mut self, typ: &Type, depth: usize) -> AST {
+        let span = Span::unknown();
+        match typ {
+            Type::Boolean => match self.input.choose(4) {
+                0 => AST::UnaryOp(Operator::Not, Box::new(self.operand(typ, depth + 1)), span),
+                1 => {
+                    let op = [Operator::And, Operator::Or][self.input.choose(2)].clone();
+                    self.binary(op, typ, depth)
+                }
+                2 => {
+                    let op = [
+                        Operator::Greater,
+                        Operator::GreaterEqual,
+                        Operator::Less,
+                        Operator::LessEqual,
+                    ][self.input.choose(4)]
+                    .clone();
+                    self.binary(op, &Type::Integer, depth)
+                }
+                _ => {
+                    let op = [Operator::Equal, Operator::NotEqual][self.input.choose(2)].clone();
+                    let operand = self.value_type();
+                    self.binary(op, &operand, depth)
+                }
+            },
+            Type::Tuple(elements) => AST::Tuple(
+                elements
+                    .iter()
+                    .map(|t| self.expression(t, depth + 1))
+                    .collect(),
+                span,
+            ),
+            _ => match self.input.choose(6) {
+                0 => AST::UnaryOp(
+                    Operator::Minus,
+                    Box::new(self.operand(typ, depth + 1)),
+                    span,
+                ),
+                n => {
+                    let op = [
+                        Operator::Plus,
+                        Operator::Minus,
+                        Operator::Multiply,
+                        Operator::Divide,
+                        Operator::Mod,
+                    ][n - 1]
+                        .clone();
+                    self.binary(op, typ, depth)
+                }
+            },
+        }
+    }
+
+    fn binary(&mut self, op: Operator, operand: &Type, depth: usize) -> AST {
+        let lhs = self.operand(operand, depth + 1);
+        let rhs = self.operand(operand, depth + 1);
+        AST::BinaryOp(op, Box::new(lhs), Box::new(rhs), Span::unknown())
+    }
+
+    // A named function that can be called at any type, which exercises
+    // giving each call its own type. Its type names the variables the
+    // calls fill in, a for the result and b for anything else.
+    fn function(&mut self) -> (AST, Type) {
+        let span = Span::unknown();
+        let var = |name: &str| Type::Polymorphic(name.to_string());
+        let id = |name: &str| AST::Identifier(name.to_string(), span);
+        let (param, body, param_type) = match self.input.choose(3) {
+            0 => (id("x"), id("x"), var("a")),
+            1 => (
+                AST::Tuple(vec![id("x"), id("y")], span),
+                id("x"),
+                Type::Tuple(Arc::new([var("a"), var("b")])),
+            ),
+            _ => (
+                AST::Tuple(vec![id("c"), id("x"), id("y")], span),
+                AST::If(vec![(id("c"), id("x"))], Box::new(id("y")), span),
+                Type::Tuple(Arc::new([Type::Boolean, var("a"), var("a")])),
+            ),
+        };
+        let name = self.fresh();
+        let fun = AST::Function(
+            Some(name),
+            Box::new(param),
+            Box::new(AST::Program(vec![body], span)),
+            span,
+        );
+        (
+            fun,
+            Type::Function(Arc::new(param_type), Arc::new(var("a"))),
+        )
+    }
+
+    // The type of an argument to a function from above, called for a
+    // result of type typ.
+    fn argument_type(&mut self, param: &Type, typ: &Type, other: &mut Option<Type>) -> Type {
+        match param {
+            Type::Polymorphic(name) if name == "a" => typ.clone(),
+            Type::Polymorphic(_) => other.get_or_insert_with(|| self.value_type()).clone(),
+            Type::Tuple(elements) => Type::Tuple(
+                elements
+                    .iter()
+                    .map(|element| self.argument_type(element, typ, other))
+                    .collect(),
+            ),
+            _ => param.clone(),
+        }
+    }
+
+    // A call to a named function in scope, or else a lambda that is called
+    // immediately, which exercises parameter passing and upvalues.
+    fn call(&mut self, typ: &Type, depth: usize) -> AST {
+        let span = Span::unknown();
+        let functions: Vec<(String, Type)> = self
+            .scope
+            .iter()
+            .filter(|(_, t)| matches!(t, Type::Function(_, _)))
+            .cloned()
+            .collect();
+        if !functions.is_empty() && self.input.choose(2) == 0 {
+            let (name, fun_type) = functions[self.input.choose(functions.len())].clone();
+            if let Type::Function(param, _) = fun_type {
+                let arg_type = self.argument_type(&param, typ, &mut None);
+                let arg = self.expression(&arg_type, depth + 1);
+                return AST::Call(Box::new(AST::Identifier(name, span)), Box::new(arg), span);
+            }
+        }
+        let param_type = self.value_type();
+        let arg = self.expression(&param_type, depth + 1);
+        let len = self.scope.len();
+        let param = match &param_type {
+            Type::Tuple(elements) => {
+                let mut ids = Vec::new();
+                for element in elements.iter() {
+                    let id = self.fresh();
+                    self.scope.push((id.to_string(), element.clone()));
+                    ids.push(AST::Identifier(id, span));
+                }
+                AST::Tuple(ids, span)
+            }
+            _ => {
+                let id = self.fresh();
+                self.scope.push((id.to_string(), param_type.clone()));
+                AST::Identifier(id, span)
+            }
+        };
+        let body = self.body(typ, depth + 1);
+        self.scope.truncate(len);
+        AST::Call(
+            Box::new(AST::Function(None, Box::new(param), Box::new(body), span)),
+            Box::new(arg),
+            span,
+        )
+    }
+
+    // Conditionals can't be used as operands without parentheses, so they
+    // are left out here to keep the program the same when printed.
+    fn operand(&mut self, typ: &Type, depth: usize) -> AST {
+        let choices = if depth >= MAX_DEPTH { 2 } else { 4 };
+        match self.input.choose(choices) {
+            0 => self.literal(typ),
+            1 => self.identifier(typ),
+            2 => self.call(typ, depth),
+            _ => self.operator(typ, depth),
+        }
+    }
+
+    fn expression(&mut self, typ: &Type, depth: usize) -> AST {
+        if depth >= MAX_DEPTH || self.input.choose(4) != 0 {
+            return self.operand(typ, depth);
+        }
+        AST::If(
+            vec![(
+                self.operand(&Type::Boolean, depth + 1),
+                self.expression(typ, depth + 1),
+            )],
+            Box::new(self.expression(typ, depth + 1)),
+            Span::unknown(),
+        )
+    }
+
+    // Some definitions followed by an expression of the given type.
+    fn body(&mut self, typ: &Type, depth: usize) -> AST {
+        let len = self.scope.len();
+        let mut statements = Vec::new();
+        for _ in 0..self.input.choose(3) {
+            if self.input.choose(3) == 0 {
+                let (fun, fun_type) = self.function();
+                if let AST::Function(Some(name), _, _, _) = &fun {
+                    self.scope.push((name.to_string(), fun_type));
+                }
+                statements.push(fun);
+                continue;
+            }
+            let value_type = self.value_type();
+            let value = self.expression(&value_type, depth + 1);
+            let id = self.fresh();
+            statements.push(AST::Define(
+                Box::new(AST::Identifier(id.to_string(), Span::unknown())),
+                Box::new(value),
+                Span::unknown(),
+            ));
+            self.scope.push((id, value_type));
+        }
+        statements.push(self.expression(typ, depth + 1));
+        self.scope.truncate(len);
+        AST::Program(statements, Span::unknown())
+    }
+}
+
+// Builds a well typed program from arbitrary bytes.
+pub fn program(input: &mut Input) -> AST {
+    let mut generator = Generator {
+        input,
+        scope: Vec::new(),
+        next_id: 0,
+    };
+    let typ = generator.value_type();
+    generator.body(&typ, 0)
+}
+
+// A small xorshift generator, so that runs are repeatable.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new() -> Random {
+        Random {
+            state: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 7;
+                self.state ^= self.state << 17;
+                (self.state >> 32) as u8
+            })
+            .collect()
+    }
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::new()
+    }
+}
+
+fn shrink_into<F: Fn(AST) -> AST>(ast: &AST, rebuild: F, candidates: &mut Vec<AST>) {
+    for candidate in shrink(ast) {
+        candidates.push(rebuild(candidate));
+    }
+}
+
+// Smaller variations of a program, simplest first. They are not necessarily
+// well typed, so properties that depend on types should hold for programs
+// that fail to type check.
+pub fn shrink(ast: &AST) -> Vec<AST> {
+    let mut candidates = Vec::new();
+    match ast {
+        AST::Ascription(expr, typ, span) => {
+            candidates.push((**expr).clone());
+            shrink_into(
+                expr,
+                |expr| AST::Ascription(Box::new(expr), typ.clone(), *span),
+                &mut candidates,
+            );
+        }
+        AST::BinaryOp(op, lhs, rhs, span) => {
+            candidates.push((**lhs).clone());
+            candidates.push((**rhs).clone());
+            shrink_into(
+                lhs,
+                |lhs| AST::BinaryOp(op.clone(), Box::new(lhs), rhs.clone(), *span),
+                &mut candidates,
+            );
+            shrink_into(
+                rhs,
+                |rhs| AST::BinaryOp(op.clone(), lhs.clone(), Box::new(rhs), *span),
+                &mut candidates,
+            );
+        }
+        AST::Boolean(true, span) => candidates.push(AST::Boolean(false, *span)),
+        AST::Call(fun, arg, span) => {
+            if let AST::Function(_, _, body, _) = &**fun {
+                if let AST::Program(expressions, _) = &**body {
+                    if expressions.len() == 1 {
+                        candidates.push(expressions[0].clone());
+                    }
+                }
+            }
+            shrink_into(
+                fun,
+                |fun| AST::Call(Box::new(fun), arg.clone(), *span),
+                &mut candidates,
+            );
+            shrink_into(
+                arg,
+                |arg| AST::Call(fun.clone(), Box::new(arg), *span),
+                &mut candidates,
+            );
+        }
+        AST::Define(id, value, span) => {
+            shrink_into(
+                value,
+                |value| AST::Define(id.clone(), Box::new(value), *span),
+                &mut candidates,
+            );
+        }
+        AST::Field(value, field, span) => {
+            shrink_into(
+                value,
+                |value| AST::Field(Box::new(value), field.clone(), *span),
+                &mut candidates,
+            );
+        }
+        AST::For(pattern, iter, body, span) => {
+            candidates.push((**iter).clone());
+            shrink_into(
+                body,
+                |body| AST::For(pattern.clone(), iter.clone(), Box::new(body), *span),
+                &mut candidates,
+            );
+        }
+        AST::Function(id, param, body, span) => {
+            shrink_into(
+                body,
+                |body| AST::Function(id.clone(), param.clone(), Box::new(body), *span),
+                &mut candidates,
+            );
+        }
+        AST::If(conds, els, span) => {
+            candidates.push((**els).clone());
+            for cond in conds {
+                candidates.push(cond.1.clone());
+            }
+            for i in 0..conds.len() {
+                if conds.len() > 1 {
+                    let mut fewer = conds.clone();
+                    fewer.remove(i);
+                    candidates.push(AST::If(fewer, els.clone(), *span));
+                }
+                shrink_into(
+                    &conds[i].0,
+                    |cond| {
+                        let mut conds = conds.clone();
+                        conds[i].0 = cond;
+                        AST::If(conds, els.clone(), *span)
+                    },
+                    &mut candidates,
+                );
+                shrink_into(
+                    &conds[i].1,
+                    |then| {
+                        let mut conds = conds.clone();
+                        conds[i].1 = then;
+                        AST::If(conds, els.clone(), *span)
+                    },
+                    &mut candidates,
+                );
+            }
+            shrink_into(
+                els,
+                |els| AST::If(conds.clone(), Box::new(els), *span),
+                &mut candidates,
+            );
+        }
+        AST::Index(value, index, span) => {
+            shrink_into(
+                value,
+                |value| AST::Index(Box::new(value), index.clone(), *span),
+                &mut candidates,
+            );
+            shrink_into(
+                index,
+                |index| AST::Index(value.clone(), Box::new(index), *span),
+                &mut candidates,
+            );
+        }
+        AST::Integer(i, span) => {
+            if *i != 0 {
+                candidates.push(AST::Integer(0, *span));
+            }
+            if *i / 2 != 0 {
+                candidates.push(AST::Integer(*i / 2, *span));
+            }
+        }
+        AST::Match(cond, cases, span) => {
+            for i in 0..cases.len() {
+                shrink_into(
+                    &cases[i].1,
+                    |body| {
+                        let mut cases = cases.clone();
+                        cases[i].1 = body;
+                        AST::Match(cond.clone(), cases, *span)
+                    },
+                    &mut candidates,
+                );
+            }
+        }
+        AST::Program(expressions, span) => {
+            for i in 0..expressions.len().saturating_sub(1) {
+                let mut fewer = expressions.clone();
+                fewer.remove(i);
+                candidates.push(AST::Program(fewer, *span));
+            }
+            for i in 0..expressions.len() {
+                shrink_into(
+                    &expressions[i],
+                    |expression| {
+                        let mut expressions = expressions.clone();
+                        expressions[i] = expression;
+                        AST::Program(expressions, *span)
+                    },
+                    &mut candidates,
+                );
+            }
+        }
+        AST::Record(fields, span) => {
+            for i in 0..fields.len() {
+                shrink_into(
+                    &fields[i].1,
+                    |value| {
+                        let mut fields = fields.clone();
+                        fields[i].1 = value;
+                        AST::Record(fields, *span)
+                    },
+                    &mut candidates,
+                );
+            }
+        }
+        AST::Slice(value, start, end, span) => {
+            shrink_into(
+                value,
+                |value| AST::Slice(Box::new(value), start.clone(), end.clone(), *span),
+                &mut candidates,
+            );
+            shrink_into(
+                start,
+                |start| AST::Slice(value.clone(), Box::new(start), end.clone(), *span),
+                &mut candidates,
+            );
+            shrink_into(
+                end,
+                |end| AST::Slice(value.clone(), start.clone(), Box::new(end), *span),
+                &mut candidates,
+            );
+        }
+        AST::Spread(ast, span) => {
+            shrink_into(
+                ast,
+                |ast| AST::Spread(Box::new(ast), *span),
+                &mut candidates,
+            );
+        }
+        AST::Tuple(elements, span) => {
+            for i in 0..elements.len() {
+                shrink_into(
+                    &elements[i],
+                    |element| {
+                        let mut elements = elements.clone();
+                        elements[i] = element;
+                        AST::Tuple(elements, *span)
+                    },
+                    &mut candidates,
+                );
+            }
+        }
+        AST::UnaryOp(op, operand, span) => {
+            candidates.push((**operand).clone());
+            shrink_into(
+                operand,
+                |operand| AST::UnaryOp(op.clone(), Box::new(operand), *span),
+                &mut candidates,
+            );
+        }
+        AST::Boolean(false, _)
+        | AST::Char(_, _)
+        | AST::Datatype(_, _, _)
+        | AST::Identifier(_, _)
+        | AST::String(_, _)
+        | AST::Unit(_) => {}
+    }
+    candidates
+}
+
+// Checks a property against generated programs. On failure the program is
+// shrunk for as long as the property keeps failing, and the smallest one
+// found is reported.
+pub fn check<F: Fn(&AST) -> bool>(cases: usize, property: F) {
+    let holds =
+        |ast: &AST| panic::catch_unwind(panic::AssertUnwindSafe(|| property(ast))).unwrap_or(false);
+    let mut random = Random::new();
+    for _ in 0..cases {
+        let mut ast = program(&mut Input::new(&random.bytes(64)));
+        if holds(&ast) {
+            continue;
+        }
+        while let Some(smaller) = shrink(&ast).into_iter().find(|c| !holds(c)) {
+            ast = smaller;
+        }
+        panic!("Property does not hold for:\n{}", pretty::print(&ast));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::arbitrary;
+    use crate::codegen;
+    use crate::parser;
+    use crate::parser::{Span, AST};
+    use crate::pretty;
+    use crate::typeinfer;
+    use crate::vm;
+
+    #[test]
+    fn shrinks() {
+        match parser::parse("if true then 1 + 20 else 3 end") {
+            Ok(ast) => {
+                let candidates: Vec<String> =
+                    arbitrary::shrink(&ast).iter().map(pretty::print).collect();
+                assert_eq!(
+                    candidates,
+                    vec![
+                        "3",
+                        "1 + 20",
+                        "if false then\n    1 + 20\nelse\n    3\nend",
+                        "if true then\n    1\nelse\n    3\nend",
+                        "if true then\n    20\nelse\n    3\nend",
+                        "if true then\n    0 + 20\nelse\n    3\nend",
+                        "if true then\n    1 + 0\nelse\n    3\nend",
+                        "if true then\n    1 + 10\nelse\n    3\nend",
+                        "if true then\n    1 + 20\nelse\n    0\nend",
+                        "if true then\n    1 + 20\nelse\n    1\nend",
+                    ]
+                );
+            }
+            Err(_) => {
+                unreachable!();
+            }
+        }
+        assert!(arbitrary::shrink(&AST::Program(Vec::new(), Span::unknown())).is_empty());
+    }
+
+    #[test]
+    fn roundtrips() {
+        arbitrary::check(500, |ast| {
+            let printed = pretty::print(ast);
+            match parser::parse(&printed) {
+                Ok(reparsed) => reparsed.to_string() == ast.to_string(),
+                Err(_) => false,
+            }
+        });
+    }
+
+    #[test]
+    fn evaluates() {
+        arbitrary::check(500, |ast| {
+            let mut ids = HashMap::new();
+            if typeinfer::infer(ast, &mut ids).is_err() {
+                return true;
+            }
+            let mut vm = vm::VirtualMachine::new();
+            match codegen::eval(&mut vm, ast) {
+                Ok(_) => true,
+                Err(err) => err.err == "Division by zero." || err.err == "Integer overflow.",
+            }
+        });
+    }
+}
@@ -0,0 +1,799 @@
+// Native functions that every virtual machine starts with.
+use std::convert::TryFrom;
+use std::fs;
+use std::sync::Arc;
+
+use crate::parser::Span;
+use crate::typeinfer::Type;
+use crate::vm::{Channel, Chunk, Coroutine, Environment, Opcode, Value, VirtualMachine};
+
+fn function(param: Type, body: Type) -> Type {
+    Type::Function(Arc::new(param), Arc::new(body))
+}
+
+fn overflow() -> String {
+    "Integer overflow.".to_string()
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    Ok(i64::try_from(value.clone())?)
+}
+
+fn pow(x: i64, y: i64) -> Result<i64, String> {
+    if y < 0 {
+        return Err("pow: negative exponent.".to_string());
+    }
+    // Only 0, 1 and -1 can be raised to exponents this large.
+    let result = match u32::try_from(y) {
+        Ok(y) => x.checked_pow(y),
+        Err(_) => match x {
+            0 | 1 => Some(x),
+            -1 => Some(if y % 2 == 0 { 1 } else { -1 }),
+            _ => None,
+        },
+    };
+    result.ok_or_else(overflow)
+}
+
+// Remainders fail like % does, when dividing by zero or when the quotient
+// overflows.
+fn remainder(x: i64, y: i64, rem: fn(i64, i64) -> Option<i64>) -> Result<i64, String> {
+    if y == 0 {
+        return Err("Division by zero.".to_string());
+    }
+    rem(x, y).ok_or_else(overflow)
+}
+
+fn gcd(x: i64, y: i64) -> Result<i64, String> {
+    let (mut a, mut b) = (x.unsigned_abs(), y.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    i64::try_from(a).map_err(|_| overflow())
+}
+
+// Parsing results in the variant with a parameter, or the other one if the
+// string is not valid.
+fn parsed<T: Into<Value>>(
+    datatype: &str,
+    some: &str,
+    none: &str,
+    result: Option<T>,
+) -> Result<Value, String> {
+    Ok(match result {
+        Some(value) => Value::Datatype(
+            datatype.to_string(),
+            some.to_string(),
+            Arc::new(value.into()),
+        ),
+        None => Value::Datatype(
+            datatype.to_string(),
+            none.to_string(),
+            Arc::new(Value::Unit),
+        ),
+    })
+}
+
+fn string(value: &Value) -> Result<String, String> {
+    Ok(String::try_from(value.clone())?)
+}
+
+fn character(value: &Value) -> Result<char, String> {
+    Ok(char::try_from(value.clone())?)
+}
+
+// Characters whose other case takes more than one character, such as ß, are
+// left as they are.
+fn single(mut chars: impl Iterator<Item = char>, c: char) -> char {
+    match (chars.next(), chars.next()) {
+        (Some(converted), None) => converted,
+        _ => c,
+    }
+}
+
+// Makes a builtin of a single instruction, for those that need the virtual
+// machine rather than just their arguments.
+fn register_code(vm: &mut VirtualMachine, name: &str, typ: Type, op: Opcode) {
+    let chunk = Chunk {
+        code: vec![op, Opcode::Ret(1)],
+        signature: Some((typ.clone(), Span::unknown())),
+    };
+    let fun = Value::Function(Arc::new(chunk), Arc::new(Environment::new()));
+    vm.env.values.insert(name.to_string(), fun.clone());
+    vm.env.types.insert(name.to_string(), typ.clone());
+    vm.values.insert(name.to_string(), (typ, fun));
+}
+
+pub fn register(vm: &mut VirtualMachine) {
+    let unary = function(Type::Integer, Type::Integer);
+    let binary = function(
+        Type::Tuple(Arc::new([Type::Integer, Type::Integer])),
+        Type::Integer,
+    );
+    vm.register_fn("abs", unary, |args| {
+        integer(&args[0])?
+            .checked_abs()
+            .map(Value::Integer)
+            .ok_or_else(overflow)
+    });
+    vm.register_fn("min", binary.clone(), |args| {
+        Ok(Value::Integer(integer(&args[0])?.min(integer(&args[1])?)))
+    });
+    vm.register_fn("max", binary.clone(), |args| {
+        Ok(Value::Integer(integer(&args[0])?.max(integer(&args[1])?)))
+    });
+    vm.register_fn("pow", binary.clone(), |args| {
+        pow(integer(&args[0])?, integer(&args[1])?).map(Value::Integer)
+    });
+    vm.register_fn("mod", binary.clone(), |args| {
+        remainder(
+            integer(&args[0])?,
+            integer(&args[1])?,
+            i64::checked_rem_euclid,
+        )
+        .map(Value::Integer)
+    });
+    vm.register_fn("rem", binary.clone(), |args| {
+        remainder(integer(&args[0])?, integer(&args[1])?, i64::checked_rem).map(Value::Integer)
+    });
+    vm.register_fn("gcd", binary.clone(), |args| {
+        gcd(integer(&args[0])?, integer(&args[1])?).map(Value::Integer)
+    });
+    // Arithmetic that can't overflow, for programs that want results to wrap
+    // around or stop at the largest and smallest integers.
+    let never_overflow = [
+        ("wrapping_add", i64::wrapping_add as fn(i64, i64) -> i64),
+        ("wrapping_sub", i64::wrapping_sub),
+        ("wrapping_mul", i64::wrapping_mul),
+        ("saturating_add", i64::saturating_add),
+        ("saturating_sub", i64::saturating_sub),
+        ("saturating_mul", i64::saturating_mul),
+    ];
+    for (name, op) in never_overflow.iter().copied() {
+        vm.register_fn(name, binary.clone(), move |args| {
+            Ok(Value::Integer(op(integer(&args[0])?, integer(&args[1])?)))
+        });
+    }
+
+    vm.register_datatype(
+        "IntegerOption",
+        &[("SomeInteger", Some(Type::Integer)), ("NoInteger", None)],
+    );
+    vm.register_datatype(
+        "BooleanOption",
+        &[("SomeBoolean", Some(Type::Boolean)), ("NoBoolean", None)],
+    );
+    vm.register_fn(
+        "integer_to_string",
+        function(Type::Integer, Type::String),
+        |args| Ok(Value::String(integer(&args[0])?.to_string())),
+    );
+    vm.register_fn(
+        "boolean_to_string",
+        function(Type::Boolean, Type::String),
+        |args| Ok(Value::String(bool::try_from(args[0].clone())?.to_string())),
+    );
+    vm.register_fn(
+        "parse_integer",
+        function(Type::String, Type::Datatype("IntegerOption".to_string())),
+        |args| {
+            let result = string(&args[0])?.parse::<i64>().ok();
+            parsed("IntegerOption", "SomeInteger", "NoInteger", result)
+        },
+    );
+    vm.register_fn(
+        "parse_boolean",
+        function(Type::String, Type::Datatype("BooleanOption".to_string())),
+        |args| {
+            let result = match string(&args[0])?.as_str() {
+                "true" => Some(true),
+                "false" => Some(false),
+                _ => None,
+            };
+            parsed("BooleanOption", "SomeBoolean", "NoBoolean", result)
+        },
+    );
+
+    vm.register_datatype(
+        "CharOption",
+        &[("SomeChar", Some(Type::Char)), ("NoChar", None)],
+    );
+    let classify = function(Type::Char, Type::Boolean);
+    vm.register_fn("is_digit", classify.clone(), |args| {
+        Ok(Value::Boolean(character(&args[0])?.is_ascii_digit()))
+    });
+    vm.register_fn("is_alpha", classify.clone(), |args| {
+        Ok(Value::Boolean(character(&args[0])?.is_alphabetic()))
+    });
+    vm.register_fn("is_space", classify, |args| {
+        Ok(Value::Boolean(character(&args[0])?.is_whitespace()))
+    });
+    let convert = function(Type::Char, Type::Char);
+    vm.register_fn("to_upper", convert.clone(), |args| {
+        let c = character(&args[0])?;
+        Ok(Value::Char(single(c.to_uppercase(), c)))
+    });
+    vm.register_fn("to_lower", convert, |args| {
+        let c = character(&args[0])?;
+        Ok(Value::Char(single(c.to_lowercase(), c)))
+    });
+    vm.register_fn("ord", function(Type::Char, Type::Integer), |args| {
+        Ok(Value::Integer(character(&args[0])? as i64))
+    });
+    vm.register_fn(
+        "chr",
+        function(Type::Integer, Type::Datatype("CharOption".to_string())),
+        |args| {
+            let result = u32::try_from(integer(&args[0])?)
+                .ok()
+                .and_then(char::from_u32);
+            parsed("CharOption", "SomeChar", "NoChar", result)
+        },
+    );
+    vm.register_fn(
+        "char_to_string",
+        function(Type::Char, Type::String),
+        |args| Ok(Value::String(character(&args[0])?.to_string())),
+    );
+    vm.register_fn(
+        "string_length",
+        function(Type::String, Type::Integer),
+        |args| Ok(Value::Integer(string(&args[0])?.chars().count() as i64)),
+    );
+    vm.register_fn(
+        "char_at",
+        function(
+            Type::Tuple(Arc::new([Type::String, Type::Integer])),
+            Type::Datatype("CharOption".to_string()),
+        ),
+        |args| {
+            let s = string(&args[0])?;
+            let result = usize::try_from(integer(&args[1])?)
+                .ok()
+                .and_then(|i| s.chars().nth(i));
+            parsed("CharOption", "SomeChar", "NoChar", result)
+        },
+    );
+
+    // A range is an iterator, whose state is the next integer and the end.
+    let bounds = Type::Tuple(Arc::new([Type::Integer, Type::Integer]));
+    let more = function(bounds.clone(), Type::Boolean);
+    let next = function(
+        bounds.clone(),
+        Type::Tuple(Arc::new([Type::Integer, bounds.clone()])),
+    );
+    vm.register_fn("range_more", more.clone(), |args| {
+        Ok(Value::Boolean(integer(&args[0])? < integer(&args[1])?))
+    });
+    vm.register_fn("range_next", next.clone(), |args| {
+        let i = integer(&args[0])?;
+        let state = vec![i.checked_add(1).ok_or_else(overflow)?, integer(&args[1])?];
+        Ok(Value::Tuple(vec![Value::Integer(i), Value::from(state)]))
+    });
+    vm.register_fn(
+        "range",
+        function(bounds.clone(), Type::Tuple(Arc::new([bounds, more, next]))),
+        |args| {
+            Ok(Value::Tuple(vec![
+                Value::Tuple(args.to_vec()),
+                Value::Native("range_more".to_string()),
+                Value::Native("range_next".to_string()),
+            ]))
+        },
+    );
+
+    // Native functions can't stop the program, so exit is code for the
+    // virtual machine instead.
+    register_code(
+        vm,
+        "exit",
+        function(Type::Integer, Type::Never),
+        Opcode::Exit,
+    );
+
+    // Functions passed to pmap run on other threads, which needs the virtual
+    // machine, so pmap is code for it as well.
+    let param = Type::Polymorphic("a".to_string());
+    let result = Type::Polymorphic("b".to_string());
+    let typ = function(
+        Type::Tuple(Arc::new([
+            function(param.clone(), result.clone()),
+            Type::Repeated(Arc::new(param)),
+        ])),
+        Type::Repeated(Arc::new(result)),
+    );
+    register_code(vm, "pmap", typ, Opcode::Pmap);
+
+    // Channels carry values of whatever type is first sent on them. Receiving
+    // checks the limits while it waits, and spawn runs a function on a thread
+    // of its own, which both need the virtual machine, so they are code as
+    // well.
+    let element = Type::Polymorphic("a".to_string());
+    let channel = Type::Channel(Arc::new(element.clone()));
+    vm.register_fn("channel", function(Type::Unit, channel.clone()), |_| {
+        Ok(Value::Channel(Arc::new(Channel::new())))
+    });
+    vm.register_fn(
+        "send",
+        function(
+            Type::Tuple(Arc::new([channel.clone(), element.clone()])),
+            Type::Unit,
+        ),
+        |args| match &args[0] {
+            Value::Channel(channel) => {
+                channel.send(args[1].clone());
+                Ok(Value::Unit)
+            }
+            _ => Err("send: expected a channel.".to_string()),
+        },
+    );
+    register_code(vm, "receive", function(channel, element), Opcode::Receive);
+    let typ = function(function(Type::Unit, Type::Unit), Type::Unit);
+    register_code(vm, "spawn", typ, Opcode::Spawn);
+
+    // A coroutine is resumed by calling the function that coroutine gives,
+    // which switches to the coroutine's stack until the function it runs
+    // calls the yield function it is given, or returns. Switching needs the
+    // virtual machine, so resuming and yielding are code for it.
+    let element = Type::Polymorphic("a".to_string());
+    let yields = function(element.clone(), Type::Unit);
+    let step = Type::Tuple(Arc::new([Type::Boolean, element.clone()]));
+    let resume = Arc::new(Chunk {
+        code: vec![Opcode::Resume, Opcode::Ret(1)],
+        signature: Some((function(Type::Unit, step.clone()), Span::unknown())),
+    });
+    let yielder = Arc::new(Chunk {
+        code: vec![Opcode::Yield, Opcode::Ret(1)],
+        signature: Some((yields.clone(), Span::unknown())),
+    });
+    vm.register_fn(
+        "coroutine",
+        function(function(yields, element), function(Type::Unit, step)),
+        move |args| Ok(Coroutine::start(args[0].clone(), &resume, &yielder)),
+    );
+}
+
+pub const FILE_FUNCTIONS: [&str; 2] = ["read_file", "write_file"];
+
+// Registered separately, since programs that can't be trusted shouldn't have
+// them.
+pub fn register_files(vm: &mut VirtualMachine) {
+    vm.register_fn("read_file", function(Type::String, Type::String), |args| {
+        let path = string(&args[0])?;
+        fs::read_to_string(&path)
+            .map(Value::String)
+            .map_err(|err| format!("Could not read {}: {}.", path, err))
+    });
+    vm.register_fn(
+        "write_file",
+        function(
+            Type::Tuple(Arc::new([Type::String, Type::String])),
+            Type::Unit,
+        ),
+        |args| {
+            let path = string(&args[0])?;
+            fs::write(&path, string(&args[1])?)
+                .map(|_| Value::Unit)
+                .map_err(|err| format!("Could not write {}: {}.", path, err))
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::diagnostics::Code;
+    use crate::parser;
+    use crate::vm::{CoroutineRef, Value};
+    use crate::{Error, Interpreter};
+
+    macro_rules! eval {
+        ($input:expr, $value:expr) => {{
+            match Interpreter::new().eval_str($input) {
+                Ok(value) => assert_eq!(value, Value::Integer($value)),
+                Err(_) => unreachable!(),
+            }
+        }};
+    }
+
+    macro_rules! evalstr {
+        ($input:expr, $value:expr) => {{
+            match Interpreter::new().eval_str($input) {
+                Ok(value) => assert_eq!(value.to_string(), $value),
+                Err(_) => unreachable!(),
+            }
+        }};
+    }
+
+    macro_rules! evalfails {
+        ($input:expr, $err:expr) => {{
+            match Interpreter::new().eval_str($input) {
+                Err(Error::Interpreter(err)) => assert_eq!(err.err, $err),
+                _ => unreachable!(),
+            }
+        }};
+    }
+
+    #[test]
+    fn math() {
+        eval!("abs (-3)", 3);
+        eval!("abs (3)", 3);
+        eval!("min (2, -7)", -7);
+        eval!("max (2, -7)", 2);
+        eval!("pow (2, 10)", 1024);
+        eval!("pow (7, 0)", 1);
+        eval!("pow (-1, 9223372036854775807)", -1);
+        eval!("gcd (12, 18)", 6);
+        eval!("gcd (-4, 0)", 4);
+        eval!(
+            "fn lcm (a, b) -> abs (a * b) / gcd (a, b) end lcm (4, 6)",
+            12
+        );
+        evalfails!("abs (-9223372036854775807 - 1)", "Integer overflow.");
+        evalfails!("pow (2, 63)", "Integer overflow.");
+        evalfails!("pow (2, -1)", "pow: negative exponent.");
+        evalfails!("gcd (-9223372036854775807 - 1, 0)", "Integer overflow.");
+        eval!("mod (7, 3)", 1);
+        eval!("mod (-7, 3)", 2);
+        eval!("mod (7, -3)", 1);
+        eval!("mod (-7, -3)", 2);
+        eval!("rem (7, 3)", 1);
+        eval!("rem (-7, 3)", -1);
+        eval!("rem (7, -3)", 1);
+        eval!("rem (-7, -3)", -1);
+        eval!("rem (-7, 3) - (-7) % 3", 0);
+        evalfails!("mod (1, 0)", "Division by zero.");
+        evalfails!("rem (1, 0)", "Division by zero.");
+        evalfails!("mod (-9223372036854775807 - 1, -1)", "Integer overflow.");
+        eval!(
+            "wrapping_add (9223372036854775807, 1)",
+            -9223372036854775807 - 1
+        );
+        eval!(
+            "wrapping_sub (-9223372036854775807, 3)",
+            9223372036854775806
+        );
+        eval!(
+            "wrapping_mul (4611686018427387904, 2)",
+            -9223372036854775807 - 1
+        );
+        eval!("wrapping_mul (6, 7)", 42);
+        eval!(
+            "saturating_add (9223372036854775807, 1)",
+            9223372036854775807
+        );
+        eval!(
+            "saturating_sub (-9223372036854775807, 3)",
+            -9223372036854775807 - 1
+        );
+        eval!(
+            "saturating_mul (-4611686018427387904, 4)",
+            -9223372036854775807 - 1
+        );
+        eval!("saturating_mul (6, -7)", -42);
+    }
+
+    #[test]
+    fn conversions() {
+        evalstr!("integer_to_string (-42)", "\"-42\"");
+        evalstr!("boolean_to_string (1 < 2)", "\"true\"");
+        evalstr!("parse_integer (\"12\")", "IntegerOption 12");
+        evalstr!("parse_integer (\"twelve\")", "IntegerOption");
+        evalstr!("parse_boolean (\"false\")", "BooleanOption false");
+        evalstr!("parse_boolean (\"no\")", "BooleanOption");
+        eval!(
+            "fn read (s) ->
+                 match parse_integer (s) with
+                     SomeInteger (n) -> n
+                     | NoInteger -> 0
+                 end
+             end
+             read (integer_to_string (7)) + read (\"x\")",
+            7
+        );
+    }
+
+    #[test]
+    fn characters() {
+        evalstr!("(is_digit ('7'), is_digit ('x'))", "(true, false)");
+        evalstr!("(is_alpha ('é'), is_alpha ('_'))", "(true, false)");
+        evalstr!(
+            "(is_space (' '), is_space ('\\t'), is_space ('.'))",
+            "(true, true, false)"
+        );
+        evalstr!(
+            "(to_upper ('q'), to_lower ('Q'), to_upper ('ß'))",
+            "('Q', 'q', 'ß')"
+        );
+        evalstr!(
+            "(ord ('A'), chr (955), chr (-1))",
+            "(65, CharOption 'λ', CharOption)"
+        );
+        evalstr!("char_to_string ('\\'')", "\"'\"");
+        evalstr!("string_length (\"héllo\")", "5");
+        evalstr!(
+            "(char_at (\"héllo\", 1), char_at (\"hi\", 2))",
+            "(CharOption 'é', CharOption)"
+        );
+        eval!(
+            "fn digit (c) -> if is_digit (c) then 1 else 0 end end
+             fn count (s, i) ->
+                 match char_at (s, i) with
+                     SomeChar (c) -> digit (c) + count (s, i + 1)
+                     | NoChar -> 0
+                 end
+             end
+             count (\"a1b22\", 0)",
+            3
+        );
+    }
+
+    #[test]
+    fn files() {
+        let path = env::temp_dir().join(format!("plover-files-{}.txt", std::process::id()));
+        let path = parser::escape(&path.to_string_lossy());
+        let mut interpreter = Interpreter::new();
+        let src = format!(
+            "write_file ({}, \"a\\nb\")
+             read_file ({})",
+            path, path
+        );
+        match interpreter.eval_str(&src) {
+            Ok(value) => assert_eq!(value, Value::String("a\nb".to_string())),
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("read_file (\"/nonexistent/plover\")") {
+            Err(Error::Interpreter(err)) => {
+                assert!(err.err.starts_with("Could not read /nonexistent/plover: "))
+            }
+            _ => unreachable!(),
+        }
+
+        interpreter.vm.set_file_access(false);
+        match interpreter.eval_str(&format!("read_file ({})", path)) {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Unknown identifier: read_file."),
+            _ => unreachable!(),
+        }
+        interpreter.vm.reset();
+        assert!(!interpreter.vm.env.types.contains_key("write_file"));
+        interpreter.vm.set_file_access(true);
+        match interpreter.eval_str(&format!("read_file ({})", path)) {
+            Ok(value) => assert_eq!(value, Value::String("a\nb".to_string())),
+            Err(_) => unreachable!(),
+        }
+        let _ = fs::remove_file(
+            env::temp_dir().join(format!("plover-files-{}.txt", std::process::id())),
+        );
+    }
+
+    #[test]
+    fn exits() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("def x := 1\nif x == 1 then exit (4) else () end\nx + 1") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.code, Code::Exit);
+                assert_eq!(err.err, "Exited with status 4.");
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(interpreter.vm.exit_status, Some(4));
+        match interpreter.eval_str("x") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Unknown identifier: x."),
+            _ => unreachable!(),
+        }
+        // Calls to exit fit in any branch, since they never produce a value.
+        eval!(
+            "fn check (n) -> if n < 0 then exit (1) else n * 2 end end check (4)",
+            8
+        );
+        evalstr!(
+            "fn name (n) -> if n == 0 then \"none\" elsif n < 0 then exit (1) else \"some\" end end
+             (name (0), name (2))",
+            "(\"none\", \"some\")"
+        );
+        // Parameters in other branches take the type the rest give them.
+        evalstr!(
+            "fn pick (x, c) -> if c then x elsif x == 0 then exit (1) else 2 end end
+             pick",
+            "fn (integer, boolean) -> integer @ 1:1"
+        );
+        evalstr!(
+            "fn first (p) -> match p with (x, true) -> x | _ -> exit (1) end end
+             first",
+            "fn (t3, boolean) -> t3 @ 1:1"
+        );
+        evalfails!(
+            "if true then exit (1) elsif true then 1 else 'a' end",
+            "Type error: expected integer but found char."
+        );
+        evalfails!("exit (256)", "exit: status 256 is not from 0 to 255.");
+        evalfails!("exit (-1)", "exit: status -1 is not from 0 to 255.");
+        interpreter.vm.reset();
+        assert_eq!(interpreter.vm.exit_status, None);
+        match interpreter.eval_str("exit (0)") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.code, Code::Exit),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn maps_in_parallel() {
+        evalstr!(
+            "fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end
+             pmap (fib, (10, 15, 20, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14))",
+            "(55, 610, 6765, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 377)"
+        );
+        evalstr!(
+            "def k := 3 pmap (fn (x) -> integer_to_string (k * x) end, (1, 2))",
+            "(\"3\", \"6\")"
+        );
+        evalstr!("pmap", "fn (a -> b, (a, ...)) -> (b, ...)");
+        evalstr!(
+            "def p := pmap p (fn (x) -> x == 1 end, (1, 2, 3))",
+            "(true, false, false)"
+        );
+        evalfails!(
+            "pmap (fn (x) -> x end, 5)",
+            "Type error: pmap takes a tuple of known length."
+        );
+        evalfails!(
+            "pmap (fn (x) -> x + 1 end, (1, true))",
+            "Type error: expected (t1 -> t2, (t1, t1)) but found (integer -> integer, (integer, boolean))."
+        );
+        // The first element to fail, in order, gives the error.
+        evalfails!(
+            "pmap (fn (x) -> 10 / (x - 2) end, (1, 2, 3, 4, 2))",
+            "Division by zero."
+        );
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("pmap (fn (x) -> exit (x) end, (3, 4))") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Exited with status 3."),
+            _ => unreachable!(),
+        }
+        assert_eq!(interpreter.vm.exit_status, Some(3));
+        // Work done on other threads counts towards the limits.
+        interpreter.vm.max_instructions = Some(1000);
+        match interpreter.eval_str("fn loop (n) -> loop (n + 1) end pmap (loop, (1, 2))") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.code, Code::EvaluationLimit),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn sends_on_channels() {
+        eval!(
+            "def numbers := channel ()
+             def squares := channel ()
+             fn square () ->
+               def n := receive (numbers)
+               send (squares, n * n)
+               if n > 0 then square () else () end
+             end
+             fn total (sum) -> def n := receive (squares) if n > 0 then total (sum + n) else sum end end
+             spawn (fn () -> for n in range (1, 11) do send (numbers, n) end send (numbers, 0) end)
+             spawn (square)
+             total (0)",
+            385
+        );
+        evalstr!(
+            "def words := channel () def counts := channel ()
+             send (words, \"a\") send (counts, 1)
+             (receive (words), receive (counts))",
+            "(\"a\", 1)"
+        );
+        evalstr!("channel ()", "(channel)");
+        evalfails!(
+            "def ch := channel () send (ch, 1) send (ch, true)",
+            "Type error: expected (channel (t3), t3) but found (channel (integer), boolean)."
+        );
+        evalfails!(
+            "spawn (fn (x) -> x + 1 end)",
+            "Type error: expected unit -> unit but found integer -> integer."
+        );
+        evalfails!(
+            "def ch := channel () receive (ch) + 1",
+            "receive: nothing was sent, and nothing is left running to send a value."
+        );
+        evalstr!(
+            "def ch := channel () spawn (fn () -> send (ch, 1) end) receive (ch)",
+            "1"
+        );
+        // Waiting for a value counts towards the time limit.
+        let mut interpreter = Interpreter::new();
+        assert!(interpreter
+            .eval_str(
+                "def ch := channel () def stop := channel () spawn (fn () -> receive (stop) end)"
+            )
+            .is_ok());
+        interpreter.vm.max_time = Some(Duration::from_millis(50));
+        match interpreter.eval_str("receive (ch) + 1") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.code, Code::EvaluationLimit),
+            _ => unreachable!(),
+        }
+        assert!(interpreter.eval_str("send (stop, ())").is_ok());
+    }
+
+    #[test]
+    fn runs_coroutines() {
+        evalstr!(
+            "def next := coroutine (fn (yield) -> for i in range (0, 3) do yield (i * 10) end 99 end)
+             (next (), next (), next (), next ())",
+            "((true, 0), (true, 10), (true, 20), (false, 99))"
+        );
+        // Coroutines keep their calls across yields.
+        evalstr!(
+            "fn walk (yield, n) ->
+               if n < 8 then
+                 match (walk (yield, 2 * n), yield (n)) with _ -> walk (yield, 2 * n + 1) end
+               else () end
+             end
+             def next := coroutine (fn (yield) -> walk (yield, 1) 0 end)
+             (next (), next (), next (), next (), next (), next (), next (), next ())",
+            "((true, 4), (true, 2), (true, 5), (true, 1), (true, 6), (true, 3), (true, 7), (false, 0))"
+        );
+        evalstr!(
+            "fn count (yield, n) -> for i in range (0, n) do yield (n - i) end end
+             def outer := coroutine (fn (yield) ->
+               def inner := coroutine (fn (y) -> count (y, 2) 0 end)
+               yield (inner ())
+               yield (inner ())
+               (false, 5)
+             end)
+             (outer (), outer (), outer ())",
+            "((true, (true, 2)), (true, (true, 1)), (false, (false, 5)))"
+        );
+        evalfails!(
+            "coroutine (fn (yield) -> yield (1) yield (true) 2 end)",
+            "Type error: expected integer -> t3 but found boolean -> t4."
+        );
+        evalfails!(
+            "def next := coroutine (fn (yield) -> 1 end) (next (), next ())",
+            "resume: the coroutine has finished."
+        );
+        evalfails!(
+            "def next := coroutine (fn (yield) -> yield end)
+             match next () with (_, yield) -> yield (1) end",
+            "yield: called outside of its coroutine."
+        );
+        // A coroutine that fails is finished, and the program that resumed
+        // it can still run.
+        let mut interpreter = Interpreter::new();
+        assert!(interpreter
+            .eval_str("def next := coroutine (fn (yield) -> yield (1) yield (1 / 0) 2 end)")
+            .is_ok());
+        match interpreter.eval_str("(next (), next ())") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Division by zero."),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("next ()") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.err, "resume: the coroutine has finished.")
+            }
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("def x := 4 x + 1") {
+            Ok(value) => assert_eq!(value.to_string(), "5"),
+            _ => unreachable!(),
+        }
+        // The function a coroutine yields with doesn't keep it alive.
+        let mut interpreter = Interpreter::new();
+        let coroutine = match interpreter
+            .eval_str("def next := coroutine (fn (yield) -> yield (1) 2 end) next")
+        {
+            Ok(Value::Function(_, env)) => match env.values.get("<coroutine>") {
+                Some(Value::Coroutine(CoroutineRef::Strong(coroutine))) => {
+                    Arc::downgrade(coroutine)
+                }
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        assert!(interpreter.eval_str("next ()").is_ok());
+        assert!(interpreter.eval_str("def next := 0").is_ok());
+        assert!(coroutine.upgrade().is_none());
+    }
+}
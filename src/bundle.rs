@@ -0,0 +1,896 @@
+// Compiled programs, stored so that they can be run without their source. A
+// bundle holds the constant pool, the types of the definitions the program
+// adds, and every chunk of code, each function's before the chunks that
+// create it, with the top level last. Bundles are only read by the version
+// that wrote them, and end with a checksum of the rest, so that damaged ones
+// are rejected before they run. Their code is not checked to be safe to run
+// beyond that, so a bundle made by hand can still crash the virtual machine,
+// and only bundles from a trusted source should be loaded.
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::codegen;
+use crate::codegen::InterpreterError;
+use crate::diagnostics::Code;
+use crate::parser::{Span, AST};
+use crate::typeinfer::Type;
+use crate::vm::{Chunk, Opcode, Value, VirtualMachine};
+
+pub const EXTENSION: &str = "ploverc";
+
+const MAGIC: &[u8] = b"PLOVERC";
+const VERSION: u8 = 8;
+
+// Types and values in a bundle are nested no more deeply than this.
+const MAX_NESTING: usize = 256;
+
+// FNV-1a, under which changing any one byte changes the checksum.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn invalid(reason: &str) -> InterpreterError {
+    InterpreterError::new(
+        Code::InvalidBundle,
+        &format!("Invalid bundle: {}.", reason),
+        Span::unknown(),
+    )
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn byte(&mut self, b: u8) {
+        self.out.push(b);
+    }
+
+    fn usize(&mut self, n: usize) {
+        self.out.extend((n as u64).to_le_bytes());
+    }
+
+    fn i64(&mut self, i: i64) {
+        self.out.extend(i.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.usize(s.len());
+        self.out.extend(s.as_bytes());
+    }
+
+    fn typ(&mut self, typ: &Type) {
+        match typ {
+            Type::Any => self.byte(9),
+            Type::Boolean => self.byte(0),
+            Type::Channel(element) => {
+                self.byte(13);
+                self.typ(element);
+            }
+            Type::Char => self.byte(1),
+            Type::Datatype(name) => {
+                self.byte(2);
+                self.string(name);
+            }
+            Type::Function(param, body) => {
+                self.byte(3);
+                self.typ(param);
+                self.typ(body);
+            }
+            Type::Integer => self.byte(4),
+            Type::Never => self.byte(11),
+            Type::Polymorphic(name) => {
+                self.byte(5);
+                self.string(name);
+            }
+            Type::Record(fields) => {
+                self.byte(12);
+                self.usize(fields.len());
+                for (name, typ) in fields.iter() {
+                    self.string(name);
+                    self.typ(typ);
+                }
+            }
+            Type::Repeated(element) => {
+                self.byte(14);
+                self.typ(element);
+            }
+            Type::Row(fields, row) => {
+                self.byte(15);
+                self.usize(fields.len());
+                for (name, typ) in fields.iter() {
+                    self.string(name);
+                    self.typ(typ);
+                }
+                self.string(row);
+            }
+            Type::String => self.byte(6),
+            Type::Tuple(elements) => {
+                self.byte(7);
+                self.usize(elements.len());
+                for element in elements.iter() {
+                    self.typ(element);
+                }
+            }
+            Type::Union(members) => {
+                self.byte(10);
+                self.usize(members.len());
+                for member in members.iter() {
+                    self.typ(member);
+                }
+            }
+            Type::Unit => self.byte(8),
+        }
+    }
+
+    fn value(&mut self, value: &Value) -> Result<(), InterpreterError> {
+        match value {
+            Value::Boolean(b) => {
+                self.byte(0);
+                self.byte(*b as u8);
+            }
+            Value::Char(c) => {
+                self.byte(1);
+                self.usize(*c as usize);
+            }
+            Value::Datatype(typ, ctor, value) => {
+                self.byte(2);
+                self.string(typ);
+                self.string(ctor);
+                self.value(value)?;
+            }
+            Value::Integer(i) => {
+                self.byte(3);
+                self.i64(*i);
+            }
+            Value::Record(fields) => {
+                self.byte(7);
+                self.usize(fields.len());
+                for (name, value) in fields {
+                    self.string(name);
+                    self.value(value)?;
+                }
+            }
+            Value::String(s) => {
+                self.byte(4);
+                self.string(s);
+            }
+            Value::Tuple(elements) => {
+                self.byte(5);
+                self.usize(elements.len());
+                for element in elements {
+                    self.value(element)?;
+                }
+            }
+            Value::Unit => self.byte(6),
+            Value::Function(_, _) | Value::Native(_) => {
+                return Err(invalid("functions can't be stored as constants"))
+            }
+            Value::Channel(_) | Value::Coroutine(_) => {
+                return Err(invalid(
+                    "channels and coroutines can't be stored as constants",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn span(&mut self, span: &Span) {
+        self.usize(span.line);
+        self.usize(span.col);
+        self.usize(span.end_line);
+        self.usize(span.end_col);
+    }
+
+    fn op(&mut self, op: &Opcode, chunks: &HashMap<*const Chunk, usize>) {
+        match op {
+            Opcode::Add => self.byte(0),
+            Opcode::And => self.byte(1),
+            Opcode::Arg(n) => {
+                self.byte(2);
+                self.usize(*n);
+            }
+            Opcode::Bconst(b) => {
+                self.byte(3);
+                self.byte(*b as u8);
+            }
+            Opcode::Cconst(c) => {
+                self.byte(4);
+                self.usize(*c as usize);
+            }
+            Opcode::Call => self.byte(5),
+            Opcode::Check(typ) => {
+                self.byte(41);
+                self.typ(typ);
+            }
+            Opcode::Const(idx) => {
+                self.byte(6);
+                self.usize(*idx);
+            }
+            Opcode::Div => self.byte(7),
+            Opcode::Dup => self.byte(8),
+            Opcode::Equal => self.byte(9),
+            Opcode::Exit => self.byte(10),
+            Opcode::ExtVal => self.byte(11),
+            Opcode::Field(name) => {
+                self.byte(44);
+                self.string(name);
+            }
+            Opcode::Dconst(typ, ctor) => {
+                self.byte(12);
+                self.string(typ);
+                self.string(ctor);
+            }
+            // Upvalues are written in order of name, so that compiling the
+            // same program always gives the same bundle.
+            Opcode::Fconst(id, chunk, upvalues) => {
+                self.byte(13);
+                match id {
+                    Some(id) => {
+                        self.byte(1);
+                        self.string(id);
+                    }
+                    None => self.byte(0),
+                }
+                self.usize(chunks[&Arc::as_ptr(chunk)]);
+                let mut names: Vec<&String> = upvalues.keys().collect();
+                names.sort();
+                self.usize(names.len());
+                for name in names {
+                    let (offset, typ) = &upvalues[name];
+                    self.string(name);
+                    self.usize(*offset);
+                    self.typ(typ);
+                }
+            }
+            Opcode::GetEnv(id) => {
+                self.byte(14);
+                self.string(id);
+            }
+            Opcode::Greater => self.byte(15),
+            Opcode::GreaterEqual => self.byte(16),
+            Opcode::Index => self.byte(17),
+            Opcode::IsType(typ) => {
+                self.byte(42);
+                self.typ(typ);
+            }
+            Opcode::Element(n) => {
+                self.byte(43);
+                self.usize(*n);
+            }
+            Opcode::Jmp(offset) => {
+                self.byte(18);
+                self.i64(*offset);
+            }
+            Opcode::Jz(offset) => {
+                self.byte(19);
+                self.i64(*offset);
+            }
+            Opcode::Less => self.byte(20),
+            Opcode::LessEqual => self.byte(21),
+            Opcode::MakeRecord(names) => {
+                self.byte(45);
+                self.usize(names.len());
+                for name in names {
+                    self.string(name);
+                }
+            }
+            Opcode::MakeTuple(n) => {
+                self.byte(22);
+                self.usize(*n);
+            }
+            Opcode::Mod => self.byte(23),
+            Opcode::Mul => self.byte(24),
+            Opcode::NoMatch => self.byte(25),
+            Opcode::Not => self.byte(26),
+            Opcode::NotEqual => self.byte(27),
+            Opcode::Or => self.byte(28),
+            Opcode::Peek(n) => {
+                self.byte(29);
+                self.usize(*n);
+            }
+            Opcode::Pmap => self.byte(46),
+            Opcode::Pop => self.byte(30),
+            Opcode::Resume => self.byte(48),
+            Opcode::Receive => self.byte(50),
+            Opcode::Ret(n) => {
+                self.byte(31);
+                self.usize(*n);
+            }
+            Opcode::SetEnv(id) => {
+                self.byte(32);
+                self.string(id);
+            }
+            Opcode::Slice => self.byte(33),
+            Opcode::Spawn => self.byte(47),
+            Opcode::Spread => self.byte(34),
+            Opcode::Srcpos(span) => {
+                self.byte(35);
+                self.span(span);
+            }
+            Opcode::Store(n) => {
+                self.byte(36);
+                self.usize(*n);
+            }
+            Opcode::Sub => self.byte(37),
+            Opcode::TypeEq(variant) => {
+                self.byte(38);
+                self.string(variant);
+            }
+            Opcode::Uconst => self.byte(39),
+            Opcode::Unpack(n) => {
+                self.byte(40);
+                self.usize(*n);
+            }
+            Opcode::Yield => self.byte(49),
+        }
+    }
+}
+
+// Lists chunks so that those a chunk creates come before it. Chunks that are
+// shared, such as cached functions, are only listed once.
+fn collect(
+    chunk: &Arc<Chunk>,
+    order: &mut Vec<Arc<Chunk>>,
+    index: &mut HashMap<*const Chunk, usize>,
+) {
+    if index.contains_key(&Arc::as_ptr(chunk)) {
+        return;
+    }
+    for op in &chunk.code {
+        if let Opcode::Fconst(_, nested, _) = op {
+            collect(nested, order, index);
+        }
+    }
+    index.insert(Arc::as_ptr(chunk), order.len());
+    order.push(Arc::clone(chunk));
+}
+
+// Type checks and compiles a program into a bundle without running it. The
+// virtual machine is left as it was, so earlier definitions can be used by
+// the program but are not part of the bundle.
+pub fn compile(vm: &mut VirtualMachine, ast: &AST) -> Result<Vec<u8>, InterpreterError> {
+    let snapshot = vm.snapshot();
+    let before = vm.env.types.clone();
+    let bundle = codegen::compile(vm, ast).and_then(|_| {
+        let mut writer = Writer { out: Vec::new() };
+        writer.out.extend(MAGIC);
+        writer.byte(VERSION);
+
+        writer.usize(vm.constants.len());
+        for value in &vm.constants {
+            writer.value(value)?;
+        }
+
+        let mut types: Vec<(&String, &Type)> = vm
+            .env
+            .types
+            .iter()
+            .filter(|(name, typ)| {
+                before.get(*name).map(|typ| typ.to_string()) != Some(typ.to_string())
+            })
+            .collect();
+        types.sort_by(|a, b| a.0.cmp(b.0));
+        writer.usize(types.len());
+        for (name, typ) in types {
+            writer.string(name);
+            writer.typ(typ);
+        }
+
+        let mut order = Vec::new();
+        let mut index = HashMap::new();
+        collect(&vm.chunk, &mut order, &mut index);
+        writer.usize(order.len());
+        for chunk in &order {
+            match &chunk.signature {
+                Some((typ, span)) => {
+                    writer.byte(1);
+                    writer.typ(typ);
+                    writer.span(span);
+                }
+                None => writer.byte(0),
+            }
+            writer.usize(chunk.code.len());
+            for op in &chunk.code {
+                writer.op(op, &index);
+            }
+        }
+        let sum = checksum(&writer.out);
+        writer.out.extend(sum.to_le_bytes());
+        Ok(writer.out)
+    });
+    vm.restore(snapshot);
+    bundle
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], InterpreterError> {
+        match self.pos.checked_add(n) {
+            Some(end) if end <= self.bytes.len() => {
+                let taken = &self.bytes[self.pos..end];
+                self.pos = end;
+                Ok(taken)
+            }
+            _ => Err(invalid("unexpected end")),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, InterpreterError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, InterpreterError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("bad boolean")),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, InterpreterError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn usize(&mut self) -> Result<usize, InterpreterError> {
+        usize::try_from(self.u64()?).map_err(|_| invalid("number out of range"))
+    }
+
+    fn i64(&mut self) -> Result<i64, InterpreterError> {
+        Ok(self.u64()? as i64)
+    }
+
+    fn char(&mut self) -> Result<char, InterpreterError> {
+        u32::try_from(self.u64()?)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| invalid("bad character"))
+    }
+
+    fn string(&mut self) -> Result<String, InterpreterError> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("bad string"))
+    }
+
+    fn typ(&mut self, depth: usize) -> Result<Type, InterpreterError> {
+        if depth > MAX_NESTING {
+            return Err(invalid("type nested too deeply"));
+        }
+        Ok(match self.byte()? {
+            0 => Type::Boolean,
+            1 => Type::Char,
+            2 => Type::Datatype(self.string()?),
+            3 => {
+                let param = self.typ(depth + 1)?;
+                let body = self.typ(depth + 1)?;
+                Type::Function(Arc::new(param), Arc::new(body))
+            }
+            4 => Type::Integer,
+            5 => Type::Polymorphic(self.string()?),
+            6 => Type::String,
+            7 => {
+                let mut elements = Vec::new();
+                for _ in 0..self.usize()? {
+                    elements.push(self.typ(depth + 1)?);
+                }
+                Type::Tuple(elements.into())
+            }
+            8 => Type::Unit,
+            9 => Type::Any,
+            11 => Type::Never,
+            12 => {
+                let mut fields = Vec::new();
+                for _ in 0..self.usize()? {
+                    let name = self.string()?;
+                    fields.push((name, self.typ(depth + 1)?));
+                }
+                Type::Record(fields.into())
+            }
+            13 => Type::Channel(Arc::new(self.typ(depth + 1)?)),
+            14 => Type::Repeated(Arc::new(self.typ(depth + 1)?)),
+            15 => {
+                let mut fields = Vec::new();
+                for _ in 0..self.usize()? {
+                    let name = self.string()?;
+                    fields.push((name, self.typ(depth + 1)?));
+                }
+                Type::Row(fields.into(), self.string()?)
+            }
+            10 => {
+                let mut members = Vec::new();
+                for _ in 0..self.usize()? {
+                    members.push(self.typ(depth + 1)?);
+                }
+                Type::Union(members.into())
+            }
+            _ => return Err(invalid("bad type")),
+        })
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, InterpreterError> {
+        if depth > MAX_NESTING {
+            return Err(invalid("constant nested too deeply"));
+        }
+        Ok(match self.byte()? {
+            0 => Value::Boolean(self.bool()?),
+            1 => Value::Char(self.char()?),
+            2 => {
+                let typ = self.string()?;
+                let ctor = self.string()?;
+                Value::Datatype(typ, ctor, Arc::new(self.value(depth + 1)?))
+            }
+            3 => Value::Integer(self.i64()?),
+            4 => Value::String(self.string()?),
+            5 => {
+                let mut elements = Vec::new();
+                for _ in 0..self.usize()? {
+                    elements.push(self.value(depth + 1)?);
+                }
+                Value::Tuple(elements)
+            }
+            6 => Value::Unit,
+            7 => {
+                let mut fields = Vec::new();
+                for _ in 0..self.usize()? {
+                    let name = self.string()?;
+                    fields.push((name, self.value(depth + 1)?));
+                }
+                Value::Record(fields)
+            }
+            _ => return Err(invalid("bad constant")),
+        })
+    }
+
+    fn span(&mut self) -> Result<Span, InterpreterError> {
+        Ok(Span {
+            line: self.usize()?,
+            col: self.usize()?,
+            end_line: self.usize()?,
+            end_col: self.usize()?,
+        })
+    }
+
+    // Constants are numbered from base, where the bundle's constants start in
+    // the virtual machine's pool, and chunks can only create earlier ones.
+    fn op(
+        &mut self,
+        base: usize,
+        constants: usize,
+        chunks: &[Arc<Chunk>],
+    ) -> Result<Opcode, InterpreterError> {
+        Ok(match self.byte()? {
+            0 => Opcode::Add,
+            1 => Opcode::And,
+            2 => Opcode::Arg(self.usize()?),
+            3 => Opcode::Bconst(self.bool()?),
+            4 => Opcode::Cconst(self.char()?),
+            5 => Opcode::Call,
+            6 => match self.usize()? {
+                idx if idx < constants => Opcode::Const(base + idx),
+                _ => return Err(invalid("unknown constant")),
+            },
+            7 => Opcode::Div,
+            8 => Opcode::Dup,
+            9 => Opcode::Equal,
+            10 => Opcode::Exit,
+            11 => Opcode::ExtVal,
+            12 => Opcode::Dconst(self.string()?, self.string()?),
+            13 => {
+                let id = if self.bool()? {
+                    Some(self.string()?)
+                } else {
+                    None
+                };
+                let chunk = match chunks.get(self.usize()?) {
+                    Some(chunk) => Arc::clone(chunk),
+                    None => return Err(invalid("unknown function")),
+                };
+                let mut upvalues = HashMap::new();
+                for _ in 0..self.usize()? {
+                    let name = self.string()?;
+                    let offset = self.usize()?;
+                    upvalues.insert(name, (offset, self.typ(0)?));
+                }
+                Opcode::Fconst(id, chunk, upvalues)
+            }
+            14 => Opcode::GetEnv(self.string()?),
+            15 => Opcode::Greater,
+            16 => Opcode::GreaterEqual,
+            17 => Opcode::Index,
+            18 => Opcode::Jmp(self.i64()?),
+            19 => Opcode::Jz(self.i64()?),
+            20 => Opcode::Less,
+            21 => Opcode::LessEqual,
+            22 => Opcode::MakeTuple(self.usize()?),
+            23 => Opcode::Mod,
+            24 => Opcode::Mul,
+            25 => Opcode::NoMatch,
+            26 => Opcode::Not,
+            27 => Opcode::NotEqual,
+            28 => Opcode::Or,
+            29 => Opcode::Peek(self.usize()?),
+            30 => Opcode::Pop,
+            31 => Opcode::Ret(self.usize()?),
+            32 => Opcode::SetEnv(self.string()?),
+            33 => Opcode::Slice,
+            34 => Opcode::Spread,
+            35 => Opcode::Srcpos(self.span()?),
+            36 => Opcode::Store(self.usize()?),
+            37 => Opcode::Sub,
+            38 => Opcode::TypeEq(self.string()?),
+            39 => Opcode::Uconst,
+            40 => Opcode::Unpack(self.usize()?),
+            41 => Opcode::Check(self.typ(0)?),
+            42 => Opcode::IsType(self.typ(0)?),
+            43 => Opcode::Element(self.usize()?),
+            44 => Opcode::Field(self.string()?),
+            45 => {
+                let mut names = Vec::new();
+                for _ in 0..self.usize()? {
+                    names.push(self.string()?);
+                }
+                Opcode::MakeRecord(names)
+            }
+            46 => Opcode::Pmap,
+            47 => Opcode::Spawn,
+            48 => Opcode::Resume,
+            49 => Opcode::Yield,
+            50 => Opcode::Receive,
+            _ => return Err(invalid("bad instruction")),
+        })
+    }
+}
+
+// Jumps must stay within their chunk, and function code must end by
+// returning.
+fn check_chunk(code: &[Opcode], function: bool) -> Result<(), InterpreterError> {
+    for (ip, op) in code.iter().enumerate() {
+        if let Opcode::Jmp(offset) | Opcode::Jz(offset) = op {
+            match (ip as i64).checked_add(*offset) {
+                Some(target) if target >= 0 && target <= code.len() as i64 => {}
+                _ => return Err(invalid("jump out of range")),
+            }
+        }
+    }
+    if function && !matches!(code.last(), Some(Opcode::Ret(_))) {
+        return Err(invalid("function without return"));
+    }
+    Ok(())
+}
+
+// Loads a bundle into the virtual machine, ready to run. Names the bundle
+// uses without defining them, such as builtins, must be bound already.
+pub fn load(vm: &mut VirtualMachine, bytes: &[u8]) -> Result<(), InterpreterError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+        return Err(invalid("not a compiled program"));
+    }
+    if reader.byte()? != VERSION {
+        return Err(invalid("compiled by another version"));
+    }
+    let (bytes, sum) = match bytes.len().checked_sub(8) {
+        Some(end) if end >= reader.pos => bytes.split_at(end),
+        _ => return Err(invalid("unexpected end")),
+    };
+    if sum != checksum(bytes).to_le_bytes() {
+        return Err(invalid("damaged"));
+    }
+    reader.bytes = bytes;
+
+    let mut constants = Vec::new();
+    for _ in 0..reader.usize()? {
+        constants.push(reader.value(0)?);
+    }
+    let mut types = Vec::new();
+    for _ in 0..reader.usize()? {
+        let name = reader.string()?;
+        types.push((name, reader.typ(0)?));
+    }
+
+    let base = vm.constants.len();
+    let mut chunks = Vec::new();
+    let mut bound = HashSet::new();
+    let mut used = HashSet::new();
+    for _ in 0..reader.usize()? {
+        let signature = if reader.bool()? {
+            Some((reader.typ(0)?, reader.span()?))
+        } else {
+            None
+        };
+        let mut code = Vec::new();
+        for _ in 0..reader.usize()? {
+            let op = reader.op(base, constants.len(), &chunks)?;
+            match &op {
+                Opcode::Fconst(id, _, upvalues) => {
+                    bound.extend(id.iter().cloned());
+                    bound.extend(upvalues.keys().cloned());
+                }
+                Opcode::GetEnv(id) => {
+                    used.insert(id.clone());
+                }
+                Opcode::SetEnv(id) => {
+                    bound.insert(id.clone());
+                }
+                _ => {}
+            }
+            code.push(op);
+        }
+        chunks.push(match signature {
+            Some((typ, span)) => vm.new_function(code, typ, span),
+            None => vm.new_chunk(code),
+        });
+    }
+    if reader.pos != bytes.len() {
+        return Err(invalid("unexpected data at the end"));
+    }
+    let top = match chunks.pop() {
+        Some(top) => top,
+        None => return Err(invalid("no code")),
+    };
+    for chunk in &chunks {
+        check_chunk(&chunk.code, true)?;
+    }
+    check_chunk(&top.code, false)?;
+    let mut missing: Vec<&String> = used
+        .iter()
+        .filter(|id| !bound.contains(*id) && !vm.env.values.contains_key(*id))
+        .collect();
+    missing.sort();
+    if let Some(id) = missing.first() {
+        return Err(InterpreterError::new(
+            Code::UnknownIdentifier,
+            &format!("Unknown identifier: {}.", id),
+            Span::unknown(),
+        ));
+    }
+
+    vm.constants.extend(constants);
+    vm.env.types.extend(types);
+    vm.start(top);
+    Ok(())
+}
+
+// Runs a bundle, which is all or nothing like evaluating source.
+pub fn eval(vm: &mut VirtualMachine, bytes: &[u8]) -> Result<Value, InterpreterError> {
+    let snapshot = vm.snapshot();
+    let value = load(vm, bytes).and_then(|_| codegen::run(vm));
+    if value.is_err() {
+        vm.restore(snapshot);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bundle;
+    use crate::diagnostics::Code;
+    use crate::parser;
+    use crate::vm::VirtualMachine;
+
+    fn compile(src: &str) -> Vec<u8> {
+        let mut vm = VirtualMachine::new();
+        match parser::parse(src) {
+            Ok(ast) => match bundle::compile(&mut vm, &ast) {
+                Ok(bytes) => bytes,
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn runs_bundles() {
+        let src = "type Shape := Circle (r) | Square (s) end
+                   fn area (s) -> match s with Circle (r) -> 3 * r * r | Square (w) -> w * w end end
+                   fn add4 (x) -> def f := fn (y) -> x + y end f (4) end
+                   def total := area (Circle (2)) + area (Square (3)) + add4 (1)
+                   (total, \"done\", string_length (\"é\"))";
+        let bytes = compile(src);
+        assert_eq!(bytes, compile(src));
+        let mut vm = VirtualMachine::new();
+        match bundle::eval(&mut vm, &bytes) {
+            Ok(value) => assert_eq!(value.to_string(), "(26, \"done\", 1)"),
+            Err(_) => unreachable!(),
+        }
+        // The definitions can be used afterwards, as if the source had been
+        // evaluated.
+        match parser::parse("(add4, add4 (total))") {
+            Ok(ast) => match crate::codegen::eval(&mut vm, &ast) {
+                Ok(value) => assert_eq!(value.to_string(), "(fn integer -> integer @ 3:20, 30)"),
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+
+        let bytes = compile(
+            "def r := ({x = 1, y = \"a\"} : any)
+             ((r : {x: integer, y: string}).y, {n = 2}.n)",
+        );
+        match bundle::eval(&mut VirtualMachine::new(), &bytes) {
+            Ok(value) => assert_eq!(value.to_string(), "(\"a\", 2)"),
+            Err(_) => unreachable!(),
+        }
+
+        // Types of definitions that take any record with some fields are
+        // kept for later input.
+        let bytes = compile("fn getx (r) -> r.x end");
+        let mut vm = VirtualMachine::new();
+        assert!(bundle::eval(&mut vm, &bytes).is_ok());
+        match parser::parse("(getx, getx ({x = 5, y = 1}))") {
+            Ok(ast) => match crate::codegen::eval(&mut vm, &ast) {
+                Ok(value) => assert_eq!(value.to_string(), "(fn {x: t3, ..t4} -> t3 @ 1:1, 5)"),
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+
+        let bytes = compile("def x := 1\nx / (x - 1)");
+        match bundle::eval(&mut VirtualMachine::new(), &bytes) {
+            Err(err) => {
+                assert_eq!(err.code, Code::DivisionByZero);
+                assert_eq!((err.span.line, err.span.col), (2, 1));
+            }
+            Ok(_) => unreachable!(),
+        }
+
+        let bytes = compile("def p := ((1, \"a\") : any)\n(p : (integer, integer))");
+        match bundle::eval(&mut VirtualMachine::new(), &bytes) {
+            Err(err) => assert_eq!(err.code, Code::FailedCheck),
+            Ok(_) => unreachable!(),
+        }
+
+        let bytes = compile(
+            "def x := (('a', 1) : (char, integer) | unit)
+             match x with unit -> 0 end",
+        );
+        match bundle::eval(&mut VirtualMachine::new(), &bytes) {
+            Err(err) => assert_eq!(err.code, Code::NoMatch),
+            Ok(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_bundles() {
+        let bytes = compile("fn f (x) -> if x > 0 then x else 0 - x end end f (-2)");
+        for len in 0..bytes.len() {
+            match bundle::eval(&mut VirtualMachine::new(), &bytes[..len]) {
+                Err(err) => assert_eq!(err.code, Code::InvalidBundle),
+                Ok(_) => unreachable!(),
+            }
+        }
+        // Any one byte changed is noticed before the code runs.
+        for i in 0..bytes.len() {
+            for b in [0, 255, bytes[i] ^ 1] {
+                let mut damaged = bytes.clone();
+                damaged[i] = b;
+                if damaged != bytes {
+                    match bundle::eval(&mut VirtualMachine::new(), &damaged) {
+                        Err(err) => assert_eq!(err.code, Code::InvalidBundle),
+                        Ok(_) => unreachable!(),
+                    }
+                }
+            }
+        }
+        let mut vm = VirtualMachine::new();
+        match bundle::eval(&mut vm, b"def x := 1") {
+            Err(err) => assert_eq!(err.err, "Invalid bundle: not a compiled program."),
+            Ok(_) => unreachable!(),
+        }
+
+        let bytes = compile("read_file (\"input.txt\")");
+        vm.set_file_access(false);
+        match bundle::eval(&mut vm, &bytes) {
+            Err(err) => assert_eq!(err.err, "Unknown identifier: read_file."),
+            Ok(_) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// Type checking and code generation results for top level function
+// definitions, so that evaluating an unchanged definition again, for instance
+// when a file is loaded again, reuses them instead of compiling it again.
+//
+// A definition is keyed on its syntax, including where it is, so that error
+// positions stay right, on the optimization passes, and on what each name it
+// refers to was bound to. Only definitions with concrete types that refer to
+// names with concrete types are cached, since inferring a definition can
+// otherwise constrain the types of the names it uses.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Weak;
+
+use crate::dump;
+use crate::parser::{walk, Pattern, TypeAnnotation, AST};
+use crate::typeinfer::{is_concrete, Type, TypedAST};
+use crate::vm::Chunk;
+
+pub struct Entry {
+    pub typed: TypedAST,
+    // The name the closure is created with, and its code, which is only
+    // reused while some closure still refers to it.
+    pub fun: Option<String>,
+    pub chunk: Weak<Chunk>,
+}
+
+// The name a statement defines as a function, if it is a function definition.
+pub fn function_name(ast: &AST) -> Option<&str> {
+    match ast {
+        AST::Define(id, value, _) => match (&**id, &**value) {
+            (AST::Identifier(id, _), AST::Function(None, _, _, _)) => Some(id),
+            _ => None,
+        },
+        AST::Function(Some(id), _, _, _) => Some(id),
+        _ => None,
+    }
+}
+
+fn annotation_names(annotation: &TypeAnnotation, names: &mut BTreeSet<String>) {
+    match annotation {
+        TypeAnnotation::Function(param, body) => {
+            annotation_names(param, names);
+            annotation_names(body, names);
+        }
+        TypeAnnotation::Name(name) => {
+            names.insert(name.to_string());
+        }
+        TypeAnnotation::Record(fields) => {
+            for field in fields {
+                annotation_names(&field.1, names);
+            }
+        }
+        TypeAnnotation::Tuple(elements) | TypeAnnotation::Union(elements) => {
+            for element in elements {
+                annotation_names(element, names);
+            }
+        }
+        TypeAnnotation::Variable(_) => {}
+    }
+}
+
+fn pattern_names(pattern: &Pattern, names: &mut BTreeSet<String>) {
+    match pattern {
+        Pattern::As(pattern, name, _) => {
+            names.insert(name.to_string());
+            pattern_names(pattern, names);
+        }
+        Pattern::Identifier(name, _) => {
+            names.insert(name.to_string());
+        }
+        Pattern::Literal(_, _) | Pattern::Wildcard(_) => {}
+        Pattern::Or(patterns, _) | Pattern::Tuple(patterns, _) => {
+            for pattern in patterns {
+                pattern_names(pattern, names);
+            }
+        }
+        Pattern::Variant(name, param, _) => {
+            names.insert(name.to_string());
+            if let Some(param) = param {
+                pattern_names(param, names);
+            }
+        }
+    }
+}
+
+// Every name that ast refers to, including constructors in match cases and
+// types in annotations. Parameters are included as well, which only makes
+// caching more cautious.
+fn referenced(ast: &AST) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    walk(ast, &mut |ast| {
+        match ast {
+            AST::Ascription(_, annotation, _) => annotation_names(annotation, &mut names),
+            AST::Identifier(name, _) => {
+                names.insert(name.to_string());
+            }
+            AST::Match(_, cases, _) => {
+                for case in cases {
+                    pattern_names(&case.0, &mut names);
+                }
+            }
+            _ => {}
+        }
+        true
+    });
+    names
+}
+
+// Every name that ast defines anywhere inside it.
+fn defined(ast: &AST) -> Vec<String> {
+    let mut names = Vec::new();
+    walk(ast, &mut |ast| {
+        match ast {
+            AST::Datatype(name, variants, _) => {
+                names.push(name.to_string());
+                names.extend(variants.iter().map(|variant| variant.0.to_string()));
+            }
+            AST::Define(id, _, _) => {
+                if let AST::Identifier(id, _) = &**id {
+                    names.push(id.to_string());
+                }
+            }
+            AST::Function(Some(id), _, _, _) => names.push(id.to_string()),
+            _ => {}
+        }
+        true
+    });
+    names
+}
+
+// Keys for the statements of a program, or None for those that can't be
+// cached, along with the keys of the earlier definitions in the program that
+// each one refers to. Those must be cached as well for the entry to be used,
+// since otherwise inferring them could depend on the definition.
+pub fn keys(
+    statements: &[AST],
+    types: &HashMap<String, Type>,
+    passes: &[&str],
+) -> Vec<Option<(u64, Vec<u64>)>> {
+    let mut earlier: HashMap<String, Option<u64>> = HashMap::new();
+    let mut keys = Vec::new();
+    for statement in statements {
+        let key = function_name(statement).and_then(|name| {
+            let mut hasher = DefaultHasher::new();
+            let mut deps = Vec::new();
+            dump::ast(statement).hash(&mut hasher);
+            passes.hash(&mut hasher);
+            for id in referenced(statement) {
+                if id == name {
+                    continue;
+                }
+                match (earlier.get(&id), types.get(&id)) {
+                    (Some(Some(key)), _) => {
+                        (&id, key).hash(&mut hasher);
+                        deps.push(*key);
+                    }
+                    (Some(None), _) => return None,
+                    (None, Some(typ)) if is_concrete(typ) => {
+                        (&id, typ.to_string()).hash(&mut hasher)
+                    }
+                    (None, Some(_)) => return None,
+                    // Anything else must be bound inside the definition.
+                    (None, None) => id.hash(&mut hasher),
+                }
+            }
+            Some((hasher.finish(), deps))
+        });
+        for id in defined(statement) {
+            earlier.insert(id, None);
+        }
+        if let (Some(name), Some((key, _))) = (function_name(statement), &key) {
+            earlier.insert(name.to_string(), Some(*key));
+        }
+        keys.push(key);
+    }
+    keys
+}
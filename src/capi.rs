@@ -0,0 +1,252 @@
+// A C interface for embedding the interpreter in non-Rust hosts. Pointers
+// passed in must come from these functions and not have been freed, and
+// strings must be nul terminated.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::vm::Value;
+use crate::Interpreter;
+
+pub const PLOVER_BOOLEAN: c_int = 0;
+pub const PLOVER_DATATYPE: c_int = 1;
+pub const PLOVER_FUNCTION: c_int = 2;
+pub const PLOVER_INTEGER: c_int = 3;
+pub const PLOVER_NATIVE: c_int = 4;
+pub const PLOVER_TUPLE: c_int = 5;
+pub const PLOVER_UNIT: c_int = 6;
+pub const PLOVER_STRING: c_int = 7;
+pub const PLOVER_CHAR: c_int = 8;
+pub const PLOVER_RECORD: c_int = 9;
+pub const PLOVER_CHANNEL: c_int = 10;
+pub const PLOVER_COROUTINE: c_int = 11;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|err| *err.borrow_mut() = Some(msg));
+}
+
+#[no_mangle]
+pub extern "C" fn plover_new() -> *mut Interpreter {
+    Box::into_raw(Box::new(Interpreter::new()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn plover_free(interpreter: *mut Interpreter) {
+    if !interpreter.is_null() {
+        drop(Box::from_raw(interpreter));
+    }
+}
+
+// Evaluates a program, returning its value, or null after recording the
+// error for plover_last_error.
+#[no_mangle]
+pub unsafe extern "C" fn plover_eval(
+    interpreter: *mut Interpreter,
+    src: *const c_char,
+) -> *mut Value {
+    if interpreter.is_null() || src.is_null() {
+        set_last_error("Invalid argument.".to_string());
+        return ptr::null_mut();
+    }
+    let src = match CStr::from_ptr(src).to_str() {
+        Ok(src) => src,
+        Err(_) => {
+            set_last_error("Program is not valid UTF-8.".to_string());
+            return ptr::null_mut();
+        }
+    };
+    // Panics must not unwind into the caller, so they are reported as
+    // errors instead.
+    let interpreter = &mut *interpreter;
+    match panic::catch_unwind(AssertUnwindSafe(|| interpreter.eval_str(src))) {
+        Ok(Ok(value)) => Box::into_raw(Box::new(value)),
+        Ok(Err(err)) => {
+            set_last_error(err.to_string());
+            ptr::null_mut()
+        }
+        Err(_) => {
+            set_last_error("Internal error: the interpreter panicked.".to_string());
+            ptr::null_mut()
+        }
+    }
+}
+
+// The message for the most recent error on this thread, or null. It remains
+// valid until the next error.
+#[no_mangle]
+pub extern "C" fn plover_last_error() -> *const c_char {
+    LAST_ERROR.with(|err| match &*err.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_free(value: *mut Value) {
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_kind(value: *const Value) -> c_int {
+    match &*value {
+        Value::Boolean(_) => PLOVER_BOOLEAN,
+        Value::Channel(_) => PLOVER_CHANNEL,
+        Value::Char(_) => PLOVER_CHAR,
+        Value::Coroutine(_) => PLOVER_COROUTINE,
+        Value::Datatype(_, _, _) => PLOVER_DATATYPE,
+        Value::Function(_, _) => PLOVER_FUNCTION,
+        Value::Integer(_) => PLOVER_INTEGER,
+        Value::Native(_) => PLOVER_NATIVE,
+        Value::Record(_) => PLOVER_RECORD,
+        Value::String(_) => PLOVER_STRING,
+        Value::Tuple(_) => PLOVER_TUPLE,
+        Value::Unit => PLOVER_UNIT,
+    }
+}
+
+// Returns 0 for values that are not integers.
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_int(value: *const Value) -> i64 {
+    match &*value {
+        Value::Integer(i) => *i,
+        _ => 0,
+    }
+}
+
+// Returns 0 for values that are not booleans.
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_bool(value: *const Value) -> c_int {
+    match &*value {
+        Value::Boolean(b) => *b as c_int,
+        _ => 0,
+    }
+}
+
+// Returns 0 for values that are not tuples.
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_tuple_len(value: *const Value) -> usize {
+    match &*value {
+        Value::Tuple(elements) => elements.len(),
+        _ => 0,
+    }
+}
+
+// Borrows an element of a tuple, which remains valid as long as the tuple.
+// Returns null if the index is out of range.
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_tuple_get(value: *const Value, index: usize) -> *const Value {
+    match &*value {
+        Value::Tuple(elements) if index < elements.len() => &elements[index],
+        _ => ptr::null(),
+    }
+}
+
+// Formats a value as the REPL would. The string must be freed with
+// plover_string_free.
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_string(value: *const Value) -> *mut c_char {
+    CString::new((*value).to_string().replace('\0', ""))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+// Writes a value as JSON, as json::from_value does, returning null after
+// recording the error for plover_last_error if it can't be written. The
+// string must be freed with plover_string_free.
+#[cfg(feature = "json")]
+#[no_mangle]
+pub unsafe extern "C" fn plover_value_json(value: *const Value) -> *mut c_char {
+    match crate::json::from_value(&*value) {
+        Ok(json) => CString::new(json).unwrap_or_default().into_raw(),
+        Err(err) => {
+            set_last_error(err.to_string());
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn plover_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+
+    use crate::capi::*;
+
+    #[test]
+    fn evaluates() {
+        unsafe {
+            let interpreter = plover_new();
+            let src = CString::new("fn f (x) -> (x, x > 1) end\nf (2)").unwrap_or_default();
+            let value = plover_eval(interpreter, src.as_ptr());
+            assert!(!value.is_null());
+            assert_eq!(plover_value_kind(value), PLOVER_TUPLE);
+            assert_eq!(plover_value_tuple_len(value), 2);
+            let first = plover_value_tuple_get(value, 0);
+            assert_eq!(plover_value_kind(first), PLOVER_INTEGER);
+            assert_eq!(plover_value_int(first), 2);
+            assert_eq!(plover_value_bool(plover_value_tuple_get(value, 1)), 1);
+            assert!(plover_value_tuple_get(value, 2).is_null());
+            let s = plover_value_string(value);
+            assert_eq!(CStr::from_ptr(s).to_str(), Ok("(2, true)"));
+            plover_string_free(s);
+            plover_value_free(value);
+
+            let src = CString::new("1 / 0").unwrap_or_default();
+            assert!(plover_eval(interpreter, src.as_ptr()).is_null());
+            assert_eq!(
+                CStr::from_ptr(plover_last_error()).to_str(),
+                Ok("InterpreterError: Division by zero.")
+            );
+
+            let src = CString::new("# Nothing to do.").unwrap_or_default();
+            let value = plover_eval(interpreter, src.as_ptr());
+            assert_eq!(plover_value_kind(value), PLOVER_UNIT);
+            plover_value_free(value);
+            plover_free(interpreter);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn writes_json() {
+        unsafe {
+            let interpreter = plover_new();
+            let src = CString::new("(1, \"a\")").unwrap_or_default();
+            let value = plover_eval(interpreter, src.as_ptr());
+            let json = plover_value_json(value);
+            assert_eq!(
+                CStr::from_ptr(json).to_str(),
+                Ok("{\"kind\":\"Tuple\",\"elements\":[{\"kind\":\"Integer\",\"value\":1},{\"kind\":\"String\",\"value\":\"a\"}]}")
+            );
+            plover_string_free(json);
+            plover_value_free(value);
+
+            let src = CString::new("fn (x) -> x end").unwrap_or_default();
+            let value = plover_eval(interpreter, src.as_ptr());
+            assert!(plover_value_json(value).is_null());
+            assert_eq!(
+                CStr::from_ptr(plover_last_error()).to_str(),
+                Ok("Functions can't be written as JSON.")
+            );
+            plover_value_free(value);
+            plover_free(interpreter);
+        }
+    }
+}
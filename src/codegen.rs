@@ -1,16 +1,52 @@
+use crate::cache;
+use crate::diagnostics::{Code, Severity};
+use crate::dump;
+use crate::optimize;
 use crate::parser;
-use crate::typeinfer::{infer, type_of, Type, TypedAST};
+use crate::typeinfer::{
+    self, bindings, infer_cached, is_concrete, span_of, type_of, Type, TypedAST, TypedPattern,
+};
 use crate::vm;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
 
+// Besides the message and where it happened, an error carries a stable code,
+// notes with further explanation and spans of related code, each with a label.
 #[derive(Debug)]
 pub struct InterpreterError {
+    pub code: Code,
     pub err: String,
-    pub line: usize,
-    pub col: usize,
+    pub span: parser::Span,
+    pub notes: Vec<String>,
+    pub related: Vec<(parser::Span, String)>,
+}
+
+impl InterpreterError {
+    pub fn new(code: Code, err: &str, span: parser::Span) -> InterpreterError {
+        InterpreterError {
+            code,
+            err: err.to_string(),
+            span,
+            notes: Vec::new(),
+            related: Vec::new(),
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.code.severity()
+    }
+
+    pub fn with_note(mut self, note: &str) -> InterpreterError {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_related(mut self, span: parser::Span, label: &str) -> InterpreterError {
+        self.related.push((span, label.to_string()));
+        self
+    }
 }
 
 impl fmt::Display for InterpreterError {
@@ -21,61 +57,312 @@ impl fmt::Display for InterpreterError {
 
 impl Error for InterpreterError {}
 
+// Parameters of the enclosing function that are used in ast are upvalues.
+// Names bound by a def shadow parameters for the rest of their scope, which
+// is tracked as a stack so that nested functions can pop their own.
 fn find_upvalues(
     ast: &TypedAST,
-    ids: &mut HashMap<String, usize>,
+    ids: &HashMap<String, usize>,
+    shadowed: &mut Vec<String>,
     upvalues: &mut HashMap<String, (usize, Type)>,
 ) {
     match ast {
-        TypedAST::BinaryOp(_, _, lhs, rhs, _, _) => {
-            find_upvalues(lhs, ids, upvalues);
-            find_upvalues(rhs, ids, upvalues);
+        TypedAST::BinaryOp(_, _, _, _, _) => {
+            let (first, links) = typeinfer::chain(ast);
+            find_upvalues(first, ids, shadowed, upvalues);
+            for (_, rhs, _) in links {
+                find_upvalues(rhs, ids, shadowed, upvalues);
+            }
+        }
+        TypedAST::Call(fun, args, _) => {
+            find_upvalues(fun, ids, shadowed, upvalues);
+            find_upvalues(args, ids, shadowed, upvalues);
         }
-        TypedAST::Call(fun, args) => {
-            find_upvalues(fun, ids, upvalues);
-            find_upvalues(args, ids, upvalues);
+        TypedAST::Cast(_, ast, _) | TypedAST::Field(_, ast, _, _) | TypedAST::Spread(_, ast, _) => {
+            find_upvalues(ast, ids, shadowed, upvalues);
         }
-        TypedAST::Define(_, id, value) => {
+        TypedAST::Define(_, id, value, _) => {
             // Shadow id while it is in scope
-            if ids.get(id).is_some() {
-                ids.remove(id);
-            }
-            find_upvalues(value, ids, upvalues);
+            shadowed.push(id.to_string());
+            find_upvalues(value, ids, shadowed, upvalues);
         }
-        TypedAST::Function(_, param, body) => {
-            let mut local_ids = ids.clone();
-            find_upvalues(param, &mut local_ids, upvalues);
-            find_upvalues(body, &mut local_ids, upvalues);
+        TypedAST::For(pattern, iter, body, _) => {
+            find_upvalues(iter, ids, shadowed, upvalues);
+            let depth = shadowed.len();
+            find_upvalues(pattern, ids, shadowed, upvalues);
+            find_upvalues(body, ids, shadowed, upvalues);
+            shadowed.truncate(depth);
         }
-        TypedAST::If(conds, els) => {
+        TypedAST::Function(_, param, body, _) => {
+            let depth = shadowed.len();
+            find_upvalues(param, ids, shadowed, upvalues);
+            find_upvalues(body, ids, shadowed, upvalues);
+            shadowed.truncate(depth);
+        }
+        TypedAST::If(conds, els, _) => {
             for cond in conds {
-                find_upvalues(&cond.0, ids, upvalues);
-                find_upvalues(&cond.1, ids, upvalues);
+                find_upvalues(&cond.0, ids, shadowed, upvalues);
+                find_upvalues(&cond.1, ids, shadowed, upvalues);
             }
-            find_upvalues(&els, ids, upvalues);
+            find_upvalues(els, ids, shadowed, upvalues);
+        }
+        TypedAST::Index(value, index, _) => {
+            find_upvalues(value, ids, shadowed, upvalues);
+            find_upvalues(index, ids, shadowed, upvalues);
         }
-        TypedAST::Identifier(typ, id) => {
+        TypedAST::Identifier(typ, id, _) => {
             if let Some(offset) = ids.get(id) {
-                upvalues.insert(id.to_string(), (*offset, typ.clone()));
+                if !shadowed.contains(id) {
+                    upvalues.insert(id.to_string(), (*offset, typ.clone()));
+                }
             }
         }
-        TypedAST::Program(_, expressions) => {
+        TypedAST::Match(cond, _, cases, _) => {
+            find_upvalues(cond, ids, shadowed, upvalues);
+            for case in cases {
+                let depth = shadowed.len();
+                shadowed.extend(
+                    bindings(&case.0)
+                        .iter()
+                        .map(|binding| binding.0.to_string()),
+                );
+                find_upvalues(&case.1, ids, shadowed, upvalues);
+                shadowed.truncate(depth);
+            }
+        }
+        TypedAST::Program(_, expressions, _) => {
             for expression in expressions {
-                find_upvalues(expression, ids, upvalues);
+                find_upvalues(expression, ids, shadowed, upvalues);
             }
         }
-        TypedAST::Tuple(_, elements) => {
+        TypedAST::Slice(value, start, end, _) => {
+            find_upvalues(value, ids, shadowed, upvalues);
+            find_upvalues(start, ids, shadowed, upvalues);
+            find_upvalues(end, ids, shadowed, upvalues);
+        }
+        TypedAST::Record(_, fields, _) => {
+            for field in fields {
+                find_upvalues(&field.1, ids, shadowed, upvalues);
+            }
+        }
+        TypedAST::Tuple(_, elements, _) => {
             for element in elements {
-                find_upvalues(element, ids, upvalues);
+                find_upvalues(element, ids, shadowed, upvalues);
             }
         }
-        TypedAST::UnaryOp(_, _, ast) => {
-            find_upvalues(ast, ids, upvalues);
+        TypedAST::UnaryOp(_, _, ast, _) => {
+            find_upvalues(ast, ids, shadowed, upvalues);
         }
         _ => {}
     }
 }
 
+// The names in a tuple parameter, or None for parts that aren't named, along
+// with the elements to take to reach each of them from the argument.
+fn find_leaves(
+    param: &TypedAST,
+    path: &mut Vec<usize>,
+    leaves: &mut Vec<(Option<String>, Vec<usize>)>,
+) {
+    match param {
+        TypedAST::Tuple(_, elements, _) => {
+            for (i, element) in elements.iter().enumerate() {
+                path.push(i);
+                find_leaves(element, path, leaves);
+                path.pop();
+            }
+        }
+        TypedAST::Identifier(_, id, _) => leaves.push((Some(id.to_string()), path.clone())),
+        _ => leaves.push((None, path.clone())),
+    }
+}
+
+// An alternative of a match pattern, as the code for each test it makes of
+// the value matched, and the code that finds each name it binds. Each piece
+// starts from a copy of the value and replaces it with what it tests or finds.
+type Alternative = (Vec<Vec<vm::Opcode>>, Vec<(String, Vec<vm::Opcode>)>);
+
+// Adds what pattern tests and binds, at the part of the value that path
+// reaches, to each alternative in found. Patterns with alternatives of their
+// own split each of those into one for every way they can match.
+fn split_pattern(
+    pattern: &TypedPattern,
+    path: &[vm::Opcode],
+    mut found: Vec<Alternative>,
+    vm: &mut vm::VirtualMachine,
+) -> Vec<Alternative> {
+    let test = |found: &mut Vec<Alternative>, test: vm::Opcode| {
+        for alternative in found.iter_mut() {
+            let mut code = path.to_vec();
+            code.push(test.clone());
+            alternative.0.push(code);
+        }
+    };
+    match pattern {
+        TypedPattern::As(_, pattern, id, _) => {
+            for alternative in found.iter_mut() {
+                alternative.1.push((id.to_string(), path.to_vec()));
+            }
+            split_pattern(pattern, path, found, vm)
+        }
+        TypedPattern::Identifier(_, id, _) => {
+            for alternative in found.iter_mut() {
+                alternative.1.push((id.to_string(), path.to_vec()));
+            }
+            found
+        }
+        TypedPattern::Literal(literal, _) => {
+            let mut code = path.to_vec();
+            generate(literal, vm, &mut code, &HashMap::new());
+            code.push(vm::Opcode::Equal);
+            for alternative in found.iter_mut() {
+                alternative.0.push(code.clone());
+            }
+            found
+        }
+        TypedPattern::Member(typ, param, _) => {
+            test(&mut found, vm::Opcode::IsType(typ.clone()));
+            match param {
+                Some(param) => split_pattern(param, path, found, vm),
+                None => found,
+            }
+        }
+        TypedPattern::Or(alternatives, _) => alternatives
+            .iter()
+            .flat_map(|alternative| split_pattern(alternative, path, found.clone(), vm))
+            .collect(),
+        TypedPattern::Tuple(elements, _) => {
+            // The value of a variant may have any type where the datatype
+            // doesn't declare one, so its shape is checked first.
+            if path.iter().any(|op| matches!(op, vm::Opcode::ExtVal)) {
+                let typ = Type::Tuple(elements.iter().map(|_| Type::Any).collect());
+                test(&mut found, vm::Opcode::IsType(typ));
+            }
+            for (i, element) in elements.iter().enumerate() {
+                found = split_pattern(
+                    element,
+                    &[path, &[vm::Opcode::Element(i)]].concat(),
+                    found,
+                    vm,
+                );
+            }
+            found
+        }
+        TypedPattern::Variant(name, param, _) => {
+            test(&mut found, vm::Opcode::TypeEq(name.to_string()));
+            match param {
+                Some(param) => {
+                    split_pattern(param, &[path, &[vm::Opcode::ExtVal]].concat(), found, vm)
+                }
+                None => found,
+            }
+        }
+        TypedPattern::Wildcard(_) => found,
+    }
+}
+
+// The part of a pattern that holds every name it binds, when that part only
+// binds names, as a parameter along with the code that reaches it. The value
+// found there can then be passed to the arm as it is, rather than taken apart
+// and built again.
+fn find_param(pattern: &TypedPattern) -> Option<(Vec<vm::Opcode>, TypedAST)> {
+    fn param(pattern: &TypedPattern) -> Option<TypedAST> {
+        match pattern {
+            TypedPattern::Identifier(typ, id, span) => {
+                Some(TypedAST::Identifier(typ.clone(), id.to_string(), *span))
+            }
+            TypedPattern::Tuple(elements, span) => {
+                let elements = elements.iter().map(param).collect::<Option<Vec<_>>>()?;
+                let typ = Type::Tuple(elements.iter().map(type_of).collect());
+                Some(TypedAST::Tuple(typ, elements, *span))
+            }
+            _ => None,
+        }
+    }
+    if let Some(param) = param(pattern) {
+        return Some((Vec::new(), param));
+    }
+    match pattern {
+        TypedPattern::Member(_, Some(param), _) => find_param(param),
+        TypedPattern::Tuple(elements, _) => {
+            let mut binding = elements
+                .iter()
+                .enumerate()
+                .filter(|(_, element)| !bindings(element).is_empty());
+            match (binding.next(), binding.next()) {
+                (Some((i, element)), None) => find_param(element)
+                    .map(|(path, param)| ([&[vm::Opcode::Element(i)], &path[..]].concat(), param)),
+                _ => None,
+            }
+        }
+        TypedPattern::Variant(_, Some(param), _) => find_param(param)
+            .map(|(path, param)| ([&[vm::Opcode::ExtVal], &path[..]].concat(), param)),
+        _ => None,
+    }
+}
+
+// Whether a value of type found must be checked to have type typ, because
+// some part of it has type any or a union where typ requires something more
+// specific.
+fn needs_check(found: &Type, typ: &Type) -> bool {
+    match (found, typ) {
+        (_, Type::Any) => false,
+        (Type::Any, _) => true,
+        (Type::Union(found), Type::Union(members)) => {
+            found.iter().any(|member| !members.contains(member))
+        }
+        (Type::Union(_), _) => true,
+        (Type::Record(found), Type::Record(fields)) => found
+            .iter()
+            .zip(fields.iter())
+            .any(|(found, field)| needs_check(&found.1, &field.1)),
+        (Type::Tuple(found), Type::Tuple(elements)) => found
+            .iter()
+            .zip(elements.iter())
+            .any(|(found, typ)| needs_check(found, typ)),
+        _ => false,
+    }
+}
+
+// Coverable positions are recorded when they are generated so that code that
+// never runs still appears in coverage reports.
+fn srcpos(vm: &mut vm::VirtualMachine, instr: &mut Vec<vm::Opcode>, span: parser::Span) {
+    if let Some(hits) = &mut vm.coverage {
+        hits.entry(span).or_insert(0);
+    }
+    instr.push(vm::Opcode::Srcpos(span));
+}
+
+// Coverage is collected from unoptimized code, so that every expression is
+// still there to be counted.
+fn optimize_bytecode(vm: &vm::VirtualMachine, instr: &mut Vec<vm::Opcode>) {
+    if vm.coverage.is_none() {
+        optimize::bytecode(&vm.passes, instr);
+    }
+}
+
+// Statements and branches are only tagged with their position when collecting
+// coverage. Calls and arithmetic operations always tag themselves.
+fn generate_covered(
+    ast: &TypedAST,
+    vm: &mut vm::VirtualMachine,
+    instr: &mut Vec<vm::Opcode>,
+    ids: &HashMap<String, usize>,
+) {
+    let tagged = matches!(
+        ast,
+        TypedAST::BinaryOp(..)
+            | TypedAST::Call(..)
+            | TypedAST::Index(..)
+            | TypedAST::Slice(..)
+            | TypedAST::UnaryOp(_, parser::Operator::Minus, _, _)
+    );
+    if vm.coverage.is_some() && !tagged {
+        srcpos(vm, instr, span_of(ast));
+    }
+    generate(ast, vm, instr, ids);
+}
+
 #[allow(clippy::cognitive_complexity)]
 fn generate(
     ast: &TypedAST,
@@ -84,148 +371,181 @@ fn generate(
     ids: &HashMap<String, usize>,
 ) {
     match ast {
-        TypedAST::BinaryOp(_, op, lhs, rhs, line, col) => {
-            instr.push(vm::Opcode::Srcpos(*line, *col));
-            generate(rhs, vm, instr, ids);
-            generate(lhs, vm, instr, ids);
-            match op {
-                parser::Operator::And => {
-                    instr.push(vm::Opcode::And);
-                }
-                parser::Operator::Divide => {
-                    instr.push(vm::Opcode::Div);
-                }
-                parser::Operator::Equal => {
-                    if let Type::Tuple(types) = type_of(rhs) {
-                        instr.push(vm::Opcode::Equal);
-                        for _ in 1..types.len() {
-                            instr.push(vm::Opcode::Rot);
-                            instr.push(vm::Opcode::Equal);
-                            instr.push(vm::Opcode::And);
-                        }
-                    } else {
+        TypedAST::BinaryOp(_, _, _, _, _) => {
+            // Right operands are generated first, so a chain generates all
+            // of them, outermost first, before its leftmost operand.
+            let (first, links) = typeinfer::chain(ast);
+            for (_, rhs, _) in links.iter().rev() {
+                generate(rhs, vm, instr, ids);
+            }
+            generate(first, vm, instr, ids);
+            for (op, _, span) in links {
+                // Operands may set their own position, so this goes last.
+                srcpos(vm, instr, span);
+                match op {
+                    parser::Operator::And => {
+                        instr.push(vm::Opcode::And);
+                    }
+                    parser::Operator::Divide => {
+                        instr.push(vm::Opcode::Div);
+                    }
+                    parser::Operator::Equal => {
                         instr.push(vm::Opcode::Equal);
                     }
-                }
-                parser::Operator::Greater => {
-                    instr.push(vm::Opcode::Greater);
-                }
-                parser::Operator::GreaterEqual => {
-                    instr.push(vm::Opcode::GreaterEqual);
-                }
-                parser::Operator::Less => {
-                    instr.push(vm::Opcode::Less);
-                }
-                parser::Operator::LessEqual => {
-                    instr.push(vm::Opcode::LessEqual);
-                }
-                parser::Operator::Minus => {
-                    instr.push(vm::Opcode::Sub);
-                }
-                parser::Operator::Mod => {
-                    instr.push(vm::Opcode::Mod);
-                }
-                parser::Operator::Multiply => {
-                    instr.push(vm::Opcode::Mul);
-                }
-                parser::Operator::Not => {
-                    instr.push(vm::Opcode::Not);
-                }
-                parser::Operator::NotEqual => {
-                    if let Type::Tuple(types) = type_of(rhs) {
-                        instr.push(vm::Opcode::NotEqual);
-                        for _ in 1..types.len() {
-                            instr.push(vm::Opcode::Rot);
-                            instr.push(vm::Opcode::NotEqual);
-                            instr.push(vm::Opcode::Or);
-                        }
-                    } else {
+                    parser::Operator::Greater => {
+                        instr.push(vm::Opcode::Greater);
+                    }
+                    parser::Operator::GreaterEqual => {
+                        instr.push(vm::Opcode::GreaterEqual);
+                    }
+                    parser::Operator::Less => {
+                        instr.push(vm::Opcode::Less);
+                    }
+                    parser::Operator::LessEqual => {
+                        instr.push(vm::Opcode::LessEqual);
+                    }
+                    parser::Operator::Minus => {
+                        instr.push(vm::Opcode::Sub);
+                    }
+                    parser::Operator::Mod => {
+                        instr.push(vm::Opcode::Mod);
+                    }
+                    parser::Operator::Multiply => {
+                        instr.push(vm::Opcode::Mul);
+                    }
+                    parser::Operator::Not => {
+                        instr.push(vm::Opcode::Not);
+                    }
+                    parser::Operator::NotEqual => {
                         instr.push(vm::Opcode::NotEqual);
                     }
-                }
-                parser::Operator::Or => {
-                    instr.push(vm::Opcode::Or);
-                }
-                parser::Operator::Plus => {
-                    instr.push(vm::Opcode::Add);
+                    parser::Operator::Or => {
+                        instr.push(vm::Opcode::Or);
+                    }
+                    parser::Operator::Plus => {
+                        instr.push(vm::Opcode::Add);
+                    }
                 }
             }
         }
-        TypedAST::Boolean(b) => {
+        TypedAST::Boolean(b, _) => {
             instr.push(vm::Opcode::Bconst(*b));
         }
-        TypedAST::Call(fun, arg) => {
+        TypedAST::Char(c, _) => {
+            instr.push(vm::Opcode::Cconst(*c));
+        }
+        TypedAST::Call(fun, arg, span) => {
             generate(arg, vm, instr, ids);
             generate(fun, vm, instr, ids);
+            srcpos(vm, instr, *span);
             instr.push(vm::Opcode::Call);
         }
-        TypedAST::Datatype(typ, variants) => {
+        TypedAST::Cast(typ, ast, span) => {
+            generate(ast, vm, instr, ids);
+            if needs_check(&type_of(ast), typ) {
+                srcpos(vm, instr, *span);
+                instr.push(vm::Opcode::Check(typ.clone()));
+            }
+        }
+        TypedAST::Datatype(typ, variants, span) => {
             for variant in variants {
                 if let Type::Datatype(_) = &variant.1 {
                     instr.push(vm::Opcode::Uconst);
-                    instr.push(vm::Opcode::Dconst(
-                        typ.to_string(),
-                        variant.0.to_string(),
-                        1,
-                    ));
+                    instr.push(vm::Opcode::Dconst(typ.to_string(), variant.0.to_string()));
                     instr.push(vm::Opcode::SetEnv(variant.0.to_string()));
                 } else {
-                    let count;
-                    if let Type::Function(fun, _) = &variant.1 {
-                        match &**fun {
-                            Type::Tuple(elements) => {
-                                count = elements.len();
-                            }
-                            _ => {
-                                count = 1;
-                            }
-                        }
-                    } else {
-                        unreachable!();
-                    }
-
-                    let mut fn_instr = Vec::new();
-                    fn_instr.push(vm::Opcode::Dconst(
-                        typ.to_string(),
-                        variant.0.to_string(),
-                        count,
-                    ));
-
-                    fn_instr.push(vm::Opcode::Ret(0));
-                    let ip = vm.instructions.len();
-                    vm.instructions.extend(fn_instr);
-                    instr.push(vm::Opcode::Fconst(None, ip, HashMap::new()));
+                    let fn_instr = vec![
+                        vm::Opcode::Dconst(typ.to_string(), variant.0.to_string()),
+                        vm::Opcode::Ret(0),
+                    ];
+                    let chunk = vm.new_function(fn_instr, variant.1.clone(), *span);
+                    instr.push(vm::Opcode::Fconst(None, chunk, HashMap::new()));
                     instr.push(vm::Opcode::SetEnv(variant.0.to_string()));
                 }
             }
             instr.push(vm::Opcode::Uconst);
         }
-        TypedAST::Define(_, id, value) => {
-            generate(&value, vm, instr, ids);
+        TypedAST::Define(_, id, value, _) => {
+            generate(value, vm, instr, ids);
             instr.push(vm::Opcode::Dup);
             instr.push(vm::Opcode::SetEnv(id.to_string()));
         }
-        TypedAST::Function(id, param, body) => {
+        TypedAST::Field(_, value, field, _) => {
+            generate(value, vm, instr, ids);
+            instr.push(vm::Opcode::Field(field.to_string()));
+        }
+        TypedAST::For(pattern, iter, body, span) => {
+            // The iterator's state and functions stay on the stack while the
+            // loop runs, along with the body, which is called with each
+            // element as a function of the pattern.
+            generate(iter, vm, instr, ids);
+            instr.push(vm::Opcode::Spread);
+            let fun = TypedAST::Function(None, pattern.clone(), body.clone(), span_of(body));
+            generate_covered(&fun, vm, instr, ids);
+            let start_ip = instr.len();
+            instr.push(vm::Opcode::Peek(3));
+            instr.push(vm::Opcode::Peek(3));
+            srcpos(vm, instr, *span);
+            instr.push(vm::Opcode::Call);
+            let exit_ip = instr.len();
+            instr.push(vm::Opcode::Jz(0));
+            instr.push(vm::Opcode::Peek(3));
+            instr.push(vm::Opcode::Peek(2));
+            srcpos(vm, instr, *span);
+            instr.push(vm::Opcode::Call);
+            // Replace the state with the new one, leaving the element.
+            instr.push(vm::Opcode::Spread);
+            instr.push(vm::Opcode::Store(4));
+            instr.push(vm::Opcode::Peek(1));
+            instr.push(vm::Opcode::Call);
+            instr.push(vm::Opcode::Pop);
+            instr.push(vm::Opcode::Jmp(start_ip as i64 - instr.len() as i64));
+            instr[exit_ip] = vm::Opcode::Jz((instr.len() - exit_ip) as i64);
+            for _ in 0..4 {
+                instr.push(vm::Opcode::Pop);
+            }
+            instr.push(vm::Opcode::Uconst);
+        }
+        TypedAST::Function(id, param, body, span) => {
             let mut fn_instr = Vec::new();
-            let mut local_ids = ids.clone();
-            let mut param_ids = HashSet::new();
+            // Only this function's parameters are on the stack while it runs,
+            // enclosing ones are reached through the environment.
+            let mut local_ids = HashMap::new();
             let mut count = 0;
             match &**param {
-                TypedAST::Identifier(_, id) => {
-                    count = 2;
+                TypedAST::Identifier(_, id, _) => {
+                    count = 1;
                     local_ids.insert(id.to_string(), 0);
-                    param_ids.insert(id.to_string());
                 }
-                TypedAST::Tuple(_, elements) => {
-                    for element in elements {
-                        if let TypedAST::Identifier(_, id) = element {
-                            local_ids.insert(id.to_string(), count);
-                            param_ids.insert(id.to_string());
+                TypedAST::Tuple(_, elements, _) => {
+                    let mut leaves = Vec::new();
+                    for (i, element) in elements.iter().enumerate() {
+                        find_leaves(element, &mut vec![i], &mut leaves);
+                    }
+                    // Nested tuples are flattened first, by taking each leaf
+                    // out of the argument in turn and replacing it with a
+                    // tuple of them.
+                    if elements
+                        .iter()
+                        .any(|element| matches!(element, TypedAST::Tuple(..)))
+                    {
+                        for (i, (_, path)) in leaves.iter().enumerate() {
+                            fn_instr.push(vm::Opcode::Peek(i));
+                            fn_instr.extend(path.iter().map(|n| vm::Opcode::Element(*n)));
+                        }
+                        fn_instr.push(vm::Opcode::MakeTuple(leaves.len()));
+                        fn_instr.push(vm::Opcode::Store(0));
+                    }
+                    for (id, _) in leaves {
+                        if let Some(id) = id {
+                            local_ids.insert(id, count);
                         }
                         count += 1;
                     }
+                    // The elements of the argument are addressed separately.
+                    fn_instr.push(vm::Opcode::Unpack(count));
                 }
-                TypedAST::Unit => {
+                TypedAST::Unit(_) => {
                     count = 1;
                 }
                 _ => unreachable!(),
@@ -235,42 +555,33 @@ fn generate(
             // functions that are used in this function and place them in the
             // environment instead of retrieving them from the stack.
             let mut upvalues = HashMap::new();
-            let mut upvalue_ids = ids.clone();
-            find_upvalues(body, &mut upvalue_ids, &mut upvalues);
-            for upvalue in &upvalues {
-                let id = upvalue.0;
-                if param_ids.contains(id) {
-                    continue;
-                }
-                if ids.get(id).is_some() {
-                    local_ids.remove(id);
-                }
-            }
+            find_upvalues(body, ids, &mut Vec::new(), &mut upvalues);
+            upvalues.retain(|id, _| !local_ids.contains_key(id));
 
-            generate(&body, vm, &mut fn_instr, &local_ids);
-            fn_instr.push(vm::Opcode::Ret(count - 1));
-            let ip = vm.instructions.len();
-            vm.instructions.extend(fn_instr);
-            instr.push(vm::Opcode::Fconst(id.clone(), ip, upvalues));
+            generate(body, vm, &mut fn_instr, &local_ids);
+            fn_instr.push(vm::Opcode::Ret(count));
+            optimize_bytecode(vm, &mut fn_instr);
+            let chunk = vm.new_function(fn_instr, type_of(ast), *span);
+            instr.push(vm::Opcode::Fconst(id.clone(), chunk, upvalues));
 
             if let Some(id) = id {
                 instr.push(vm::Opcode::Dup);
                 instr.push(vm::Opcode::SetEnv(id.to_string()));
             }
         }
-        TypedAST::If(conds, els) => {
+        TypedAST::If(conds, els, _) => {
             let start_ip = instr.len();
             let els_ptr: *const TypedAST = &**els;
             for cond in conds {
                 let mut then = Vec::new();
                 generate(&cond.0, vm, instr, ids);
-                generate(&cond.1, vm, &mut then, ids);
+                generate_covered(&cond.1, vm, &mut then, ids);
                 let offset = 2 + then.len() as i64;
                 instr.push(vm::Opcode::Jz(offset));
                 instr.extend(then);
                 instr.push(vm::Opcode::Jmp(els_ptr as i64));
             }
-            generate(&els, vm, instr, ids);
+            generate_covered(els, vm, instr, ids);
 
             for i in start_ip..instr.len() {
                 if let vm::Opcode::Jmp(ptr) = instr[i] {
@@ -281,38 +592,124 @@ fn generate(
                 }
             }
         }
-        TypedAST::Identifier(_, id) => match ids.get(id) {
+        TypedAST::Identifier(_, id, _) => match ids.get(id) {
             Some(offset) => instr.push(vm::Opcode::Arg(*offset)),
             None => {
                 // type checking ensures this is a valid identifier
                 instr.push(vm::Opcode::GetEnv(id.to_string()))
             }
         },
-        TypedAST::Integer(i) => {
-            instr.push(vm::Opcode::Iconst(*i));
+        TypedAST::Index(value, index, span) => {
+            generate(value, vm, instr, ids);
+            generate(index, vm, instr, ids);
+            srcpos(vm, instr, *span);
+            instr.push(vm::Opcode::Index);
         }
-        TypedAST::Match(cond, _, cases) => {
-            generate(&cond, vm, instr, ids);
+        TypedAST::Integer(i, _) => {
+            let idx = vm.integer(*i);
+            instr.push(vm::Opcode::Const(idx));
+        }
+        TypedAST::Match(cond, _, cases, span) => {
+            generate(cond, vm, instr, ids);
             let start_ip = instr.len();
             let cond_ptr: *const TypedAST = &**cond;
             for case in cases {
-                let mut then = Vec::new();
-                instr.push(vm::Opcode::Dup);
-                instr.push(vm::Opcode::TypeEq(case.0.to_string()));
-                if let Some(param) = &case.1 {
-                    then.push(vm::Opcode::ExtVal);
-                    let fun =
-                        TypedAST::Function(None, Box::new(param.clone()), Box::new(case.2.clone()));
-                    generate(&fun, vm, &mut then, ids);
-                    then.push(vm::Opcode::Call);
+                // The body of an arm is a function of the names its pattern
+                // binds, which every alternative leaves in place of the value
+                // matched. Those that bind nothing run the body directly.
+                let names = bindings(&case.0);
+                let found = split_pattern(&case.0, &[], vec![(Vec::new(), Vec::new())], vm);
+                let mut thens = Vec::new();
+                let mut body = Vec::new();
+                if names.is_empty() {
+                    thens.resize(found.len(), vec![vm::Opcode::Pop]);
+                    generate_covered(&case.1, vm, &mut body, ids);
                 } else {
-                    generate(&case.2, vm, &mut then, ids);
+                    let param = match find_param(&case.0).filter(|_| found.len() == 1) {
+                        Some((path, param)) => {
+                            thens.push(path);
+                            param
+                        }
+                        None => {
+                            for (_, names) in &found {
+                                let mut then = Vec::new();
+                                let mut names = names.clone();
+                                names.sort_by(|a, b| a.0.cmp(&b.0));
+                                for (i, (_, path)) in names.iter().enumerate() {
+                                    then.push(vm::Opcode::Peek(i));
+                                    then.extend(path.iter().cloned());
+                                }
+                                if names.len() > 1 {
+                                    then.push(vm::Opcode::MakeTuple(names.len()));
+                                }
+                                then.push(vm::Opcode::Store(0));
+                                thens.push(then);
+                            }
+                            let mut params: Vec<TypedAST> = names
+                                .iter()
+                                .map(|(id, typ, span)| {
+                                    TypedAST::Identifier((*typ).clone(), id.to_string(), *span)
+                                })
+                                .collect();
+                            if params.len() == 1 {
+                                params.remove(0)
+                            } else {
+                                let typ = Type::Tuple(params.iter().map(type_of).collect());
+                                TypedAST::Tuple(typ, params, *span)
+                            }
+                        }
+                    };
+                    let fun = TypedAST::Function(
+                        None,
+                        Box::new(param),
+                        Box::new(case.1.clone()),
+                        span_of(&case.1),
+                    );
+                    generate_covered(&fun, vm, &mut body, ids);
+                    body.push(vm::Opcode::Call);
                 }
-                let offset = 2 + then.len() as i64;
-                instr.push(vm::Opcode::Jz(offset));
-                instr.extend(then);
-                instr.push(vm::Opcode::Jmp(cond_ptr as i64));
+                body.push(vm::Opcode::Jmp(cond_ptr as i64));
+
+                // Each alternative runs its tests in turn, going on to the
+                // next alternative, or past the body after the last, as soon
+                // as one fails. The last alternative runs into the body, while
+                // the others jump to it.
+                let last = found.len() - 1;
+                let lens: Vec<usize> = found
+                    .iter()
+                    .zip(thens.iter())
+                    .enumerate()
+                    .map(|(k, ((tests, _), then))| {
+                        let len: usize = tests.iter().map(|test| test.len() + 2).sum();
+                        len + then.len() + usize::from(k != last)
+                    })
+                    .collect();
+                let mut remaining: usize = lens.iter().sum();
+                for (k, ((tests, _), then)) in found.iter().zip(thens.iter()).enumerate() {
+                    let start = instr.len();
+                    let fail = if k == last {
+                        lens[k] + body.len()
+                    } else {
+                        lens[k]
+                    };
+                    for test in tests {
+                        instr.push(vm::Opcode::Dup);
+                        instr.extend(test.iter().cloned());
+                        let offset = fail - (instr.len() - start);
+                        instr.push(vm::Opcode::Jz(offset as i64));
+                    }
+                    instr.extend(then.iter().cloned());
+                    remaining -= lens[k];
+                    if k != last {
+                        instr.push(vm::Opcode::Jmp(remaining as i64 + 1));
+                    }
+                }
+                instr.extend(body);
             }
+            // This is not tagged for coverage, since it only runs when the
+            // match is not exhaustive.
+            instr.push(vm::Opcode::Srcpos(*span));
+            instr.push(vm::Opcode::NoMatch);
 
             for i in start_ip..instr.len() {
                 if let vm::Opcode::Jmp(ptr) = instr[i] {
@@ -323,24 +720,53 @@ fn generate(
                 }
             }
         }
-        TypedAST::Program(_, expressions) => {
+        TypedAST::Program(_, expressions, _) => {
+            if expressions.is_empty() {
+                instr.push(vm::Opcode::Uconst);
+            }
             for i in 0..expressions.len() {
-                generate(&expressions[i], vm, instr, ids);
+                generate_covered(&expressions[i], vm, instr, ids);
                 if i + 1 != expressions.len() {
                     instr.push(vm::Opcode::Pop);
                 }
             }
         }
-        TypedAST::Tuple(_, elements) => {
-            for element in elements.iter().rev() {
-                generate(&element, vm, instr, ids);
+        TypedAST::Slice(value, start, end, span) => {
+            generate(value, vm, instr, ids);
+            generate(start, vm, instr, ids);
+            generate(end, vm, instr, ids);
+            srcpos(vm, instr, *span);
+            instr.push(vm::Opcode::Slice);
+        }
+        TypedAST::Record(_, fields, _) => {
+            for field in fields {
+                generate(&field.1, vm, instr, ids);
             }
+            let names = fields.iter().map(|field| field.0.to_string()).collect();
+            instr.push(vm::Opcode::MakeRecord(names));
         }
-        TypedAST::UnaryOp(_, op, ast) => {
+        TypedAST::Spread(_, ast, _) => {
+            generate(ast, vm, instr, ids);
+            instr.push(vm::Opcode::Spread);
+        }
+        TypedAST::Tuple(typ, elements, _) => {
+            for element in elements {
+                generate(element, vm, instr, ids);
+            }
+            // Spread tuples leave their elements on the stack, so the size
+            // comes from the type rather than the number of expressions.
+            match typ {
+                Type::Tuple(types) => instr.push(vm::Opcode::MakeTuple(types.len())),
+                _ => unreachable!(),
+            }
+        }
+        TypedAST::UnaryOp(_, op, ast, span) => {
             generate(ast, vm, instr, ids);
             match op {
                 parser::Operator::Minus => {
-                    instr.push(vm::Opcode::Iconst(0));
+                    srcpos(vm, instr, *span);
+                    let idx = vm.integer(0);
+                    instr.push(vm::Opcode::Const(idx));
                     instr.push(vm::Opcode::Sub);
                 }
                 parser::Operator::Not => {
@@ -349,66 +775,233 @@ fn generate(
                 _ => unreachable!(),
             }
         }
-        TypedAST::Unit => {
+        TypedAST::String(s, _) => {
+            let idx = vm.string(s);
+            instr.push(vm::Opcode::Const(idx));
+        }
+        TypedAST::Unit(_) => {
             instr.push(vm::Opcode::Uconst);
         }
     }
 }
 
-fn to_typed_value(vm: &mut vm::VirtualMachine, typ: &Type) -> Option<vm::Value> {
-    match typ {
-        Type::Tuple(types) => {
-            let mut values = Vec::new();
-            for i in 0..types.len() {
-                match to_typed_value(vm, &types[types.len() - i - 1]) {
-                    Some(value) => {
-                        values.push(value);
-                    }
-                    None => {
-                        return None;
-                    }
+// Type checks and generates code for a program.
+// Type checking and code generation recurse once for each level of nesting,
+// so programs deeper than the limit are rejected before they can overflow the
+// stack. Chains of operators are followed without recursing, so only their
+// right operands count as nested.
+pub fn check_depth(ast: &parser::AST, limit: usize) -> Result<(), InterpreterError> {
+    match parser::too_deep(ast, limit) {
+        Some(span) => Err(InterpreterError::new(
+            Code::TooDeep,
+            &format!("Expression nested too deeply: the limit is {}.", limit),
+            span,
+        )),
+        None => Ok(()),
+    }
+}
+
+// A top level function definition that can be cached, either found in the
+// cache or to be stored there along with its typed AST.
+enum Cached {
+    Hit(Option<String>, Arc<vm::Chunk>),
+    Miss(u64, TypedAST),
+}
+
+// Generates a top level function definition, reusing its code if it is
+// cached, or otherwise storing it.
+fn generate_cached(
+    ast: &TypedAST,
+    cached: Cached,
+    vm: &mut vm::VirtualMachine,
+    instr: &mut Vec<vm::Opcode>,
+) {
+    let name = match ast {
+        TypedAST::Define(_, name, value, _) if matches!(**value, TypedAST::Function(None, ..)) => {
+            name
+        }
+        TypedAST::Function(Some(name), ..) => name,
+        _ => return generate(ast, vm, instr, &HashMap::new()),
+    };
+    match cached {
+        Cached::Hit(fun, chunk) => {
+            instr.push(vm::Opcode::Fconst(fun, chunk, HashMap::new()));
+            instr.push(vm::Opcode::Dup);
+            instr.push(vm::Opcode::SetEnv(name.to_string()));
+        }
+        Cached::Miss(key, typed) => {
+            let start = instr.len();
+            generate(ast, vm, instr, &HashMap::new());
+            if let vm::Opcode::Fconst(fun, chunk, _) = &instr[start] {
+                let entry = cache::Entry {
+                    typed,
+                    fun: fun.clone(),
+                    chunk: Arc::downgrade(chunk),
+                };
+                vm.cache.insert(key, entry);
+            }
+        }
+    }
+}
+
+// Type checks and compiles a program, leaving the virtual machine ready to
+// run it, and returns the type of the value it produces.
+pub fn compile(vm: &mut vm::VirtualMachine, ast: &parser::AST) -> Result<Type, InterpreterError> {
+    let start = vm.start_timing();
+    check_depth(ast, vm.max_depth)?;
+    if vm.dump_ast {
+        if let Ok(mut out) = vm.stdout.lock() {
+            let _ = write!(out, "{}", dump::ast(ast));
+        }
+    }
+    // Coverage needs every definition to be compiled again, so the cache is
+    // only used without it. An entry is only used if those of the definitions
+    // it refers to are as well, and if its code is still around.
+    vm.cache.retain(|_, entry| entry.chunk.strong_count() > 0);
+    let keys = match ast {
+        parser::AST::Program(statements, _) if vm.coverage.is_none() => {
+            let passes: Vec<&str> = vm.passes.iter().map(|pass| pass.name()).collect();
+            cache::keys(statements, &vm.env.types, &passes)
+        }
+        _ => Vec::new(),
+    };
+    let mut hits = HashMap::new();
+    let mut cached = Vec::new();
+    for key in &keys {
+        let entry = match key {
+            Some((key, deps)) if deps.iter().all(|dep| hits.contains_key(dep)) => vm
+                .cache
+                .get(key)
+                .and_then(|entry| Some((key, entry, entry.chunk.upgrade()?))),
+            _ => None,
+        };
+        match entry {
+            Some((key, entry, chunk)) => {
+                hits.insert(*key, (entry.fun.clone(), chunk));
+                cached.push(Some(entry.typed.clone()));
+            }
+            None => cached.push(None),
+        }
+    }
+    let typed = infer_cached(ast, &mut vm.env.types, &cached);
+    vm.record_timing(start, |timings| &mut timings.typecheck);
+    let mut typed_ast = typed?;
+    let typ = type_of(&typed_ast);
+    let start = vm.start_timing();
+    // Definitions to store are kept as they were before optimizing them.
+    let mut cacheable = HashMap::new();
+    if let TypedAST::Program(_, statements, _) = &typed_ast {
+        for (statement, key) in statements.iter().zip(&keys) {
+            if let Some((key, _)) = key {
+                if let Some((fun, chunk)) = hits.remove(key) {
+                    cacheable.insert(span_of(statement), Cached::Hit(fun, chunk));
+                } else if is_concrete(&type_of(statement)) {
+                    let miss = Cached::Miss(*key, statement.clone());
+                    cacheable.insert(span_of(statement), miss);
                 }
             }
-            Some(vm::Value::Tuple(values))
         }
-        _ => match vm.stack.pop() {
-            Some(value) => Some(value),
-            None => None,
-        },
+    }
+    // The typed tree is dumped as it was inferred, before any passes change it.
+    if vm.dump_ast {
+        if let Ok(mut out) = vm.stdout.lock() {
+            let _ = write!(out, "{}", dump::typed_ast(&typed_ast));
+        }
+    }
+    if vm.coverage.is_none() {
+        typed_ast = optimize::typed(&vm.passes, typed_ast);
+    }
+    let mut instr = Vec::new();
+    let ids = HashMap::new();
+    match &typed_ast {
+        TypedAST::Program(_, statements, _) if !cacheable.is_empty() => {
+            for (i, statement) in statements.iter().enumerate() {
+                if i > 0 {
+                    instr.push(vm::Opcode::Pop);
+                }
+                match cacheable.remove(&span_of(statement)) {
+                    Some(cached) => generate_cached(statement, cached, vm, &mut instr),
+                    None => generate(statement, vm, &mut instr, &ids),
+                }
+            }
+        }
+        _ => generate(&typed_ast, vm, &mut instr, &ids),
+    }
+    optimize_bytecode(vm, &mut instr);
+    let chunk = vm.new_chunk(instr);
+    vm.start(chunk);
+    vm.record_timing(start, |timings| &mut timings.codegen);
+    // TODO: This is useful for debugging. Add an argument to enable it.
+    //println!("disassembly:\n{}", vm.chunk);
+    Ok(typ)
+}
+
+fn result(vm: &mut vm::VirtualMachine) -> Result<vm::Value, InterpreterError> {
+    match vm.stack.pop() {
+        Some(value) => Ok(value),
+        None => Err(InterpreterError::new(
+            Code::StackUnderflow,
+            "Stack underflow.",
+            parser::Span::unknown(),
+        )),
     }
 }
 
+// Runs the compiled program, returning the value it leaves on the stack.
+pub fn run(vm: &mut vm::VirtualMachine) -> Result<vm::Value, InterpreterError> {
+    let start = vm.start_timing();
+    let ran = vm.run();
+    vm.record_timing(start, |timings| &mut timings.run);
+    ran?;
+    result(vm)
+}
+
+// Evaluation is all or nothing: if any part of the program fails, the
+// definitions and code from the parts before it are discarded as well.
 pub fn eval(vm: &mut vm::VirtualMachine, ast: &parser::AST) -> Result<vm::Value, InterpreterError> {
-    match infer(ast, &mut vm.env.types) {
-        Ok(typed_ast) => {
-            let mut instr = Vec::new();
-            let ids = HashMap::new();
-            generate(&typed_ast, vm, &mut instr, &ids);
-            vm.ip = vm.instructions.len();
-            vm.instructions.extend(instr);
-            // TODO: This is useful for debugging. Add an argument to enable it.
-            //println!("disassembly:");
-            //for i in 0..vm.instructions.len() {
-            //    println!("  {} {}", i, vm.instructions[i]);
-            //}
-            match vm.run() {
-                Ok(()) => match to_typed_value(vm, &type_of(&typed_ast)) {
-                    Some(value) => Ok(value),
-                    None => Err(InterpreterError {
-                        err: "Stack underflow.".to_string(),
-                        line: usize::max_value(),
-                        col: usize::max_value(),
-                    }),
-                },
-                Err(err) => Err(err),
-            }
+    eval_typed(vm, ast).map(|(value, _)| value)
+}
+
+// Evaluates a program as eval does, returning the type inferred for it along
+// with its value.
+pub fn eval_typed(
+    vm: &mut vm::VirtualMachine,
+    ast: &parser::AST,
+) -> Result<(vm::Value, Type), InterpreterError> {
+    let snapshot = vm.snapshot();
+    let value = compile(vm, ast).and_then(|typ| Ok((run(vm)?, typ)));
+    if value.is_err() {
+        vm.restore(snapshot);
+    }
+    value
+}
+
+// Evaluates a program that may call asynchronous native functions.
+pub async fn eval_async(
+    vm: &mut vm::VirtualMachine,
+    ast: &parser::AST,
+) -> Result<vm::Value, InterpreterError> {
+    let snapshot = vm.snapshot();
+    let value = match compile(vm, ast) {
+        Ok(_) => {
+            let start = vm.start_timing();
+            let ran = vm.run_async().await;
+            vm.record_timing(start, |timings| &mut timings.run);
+            ran.and_then(|_| result(vm))
         }
         Err(err) => Err(err),
+    };
+    if value.is_err() {
+        vm.restore(snapshot);
     }
+    value
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
     use crate::codegen;
     use crate::parser;
     use crate::vm;
@@ -563,8 +1156,183 @@ mod tests {
         );
         eval!("(1 + 2) * 5", Integer, 15);
         eval!("1 + 2 * 5", Integer, 11);
+        eval!("\"a\" == \"a\"", Boolean, true);
+        eval!("\"a\" ~= \"b\"", Boolean, true);
+        eval!("\"apple\" < \"banana\"", Boolean, true);
+        eval!("\"b\" >= \"abc\"", Boolean, true);
+        eval!("\"ab\" <= \"a\"", Boolean, false);
+        eval!("(1, \"z\") < (1, \"zz\")", Boolean, true);
+        eval!("(2, 0) <= (1, 9)", Boolean, false);
+        eval!("((1, 2), 3) > ((1, 1), 4)", Boolean, true);
+        eval!("(fn a -> (a, 1) < (\"x\", 2) end) (\"w\")", Boolean, true);
+        evalfails!(
+            "\"a\" < 1",
+            "Type error: expected string but found integer."
+        );
+        evalfails!(
+            "(1, true) < (1, false)",
+            "Type error: expected (integer, integer) but found (integer, boolean)."
+        );
+        eval!("((20 : any) : integer) + 1", Integer, 21);
+        eval!(
+            "def p := (((1, \"a\"), 'b') : any)
+             def q := (p : ((integer, any), char))
+             (q : ((integer, string), char)) == ((1, \"a\"), 'b')",
+            Boolean,
+            true
+        );
+        evalfails!(
+            "((\"a\" : any) : integer)",
+            "Type error: expected integer but found string."
+        );
+        evalfails!(
+            "(((1, fn x -> x + 1 end) : any) : (integer, integer))",
+            "Type error: expected (integer, integer) but found (integer, integer -> integer)."
+        );
+        eval!(
+            "fn size (x) ->
+                 match (x : integer | string | unit) with
+                   integer(n) -> n
+                 | string(s) -> string_length(s)
+                 | unit -> 0
+                 end
+             end
+             size((\"abc\" : integer | string | unit)) + size((4 : integer | string | unit))",
+            Integer,
+            7
+        );
+        eval!(
+            "def x := (if 1 < 2 then 5 else true end : integer | boolean)
+             (x : integer) + 1",
+            Integer,
+            6
+        );
+        evalfails!(
+            "((true : integer | boolean) : integer)",
+            "Type error: expected integer but found boolean."
+        );
+        evalfails!(
+            "match ((1, 2) : (integer, integer) | boolean) with boolean(b) -> b end",
+            "No match arm for type: (integer, integer)."
+        );
+        eval!(
+            "def p := ((1, 2), (3, (4, 5)))
+             match p with ((a, b), (c, (d, e))) -> a + b + c + d + e end",
+            Integer,
+            15
+        );
+        eval!(
+            "fn f ((a, b), c) -> a * b + c end
+             f ((2, 3), 4)",
+            Integer,
+            10
+        );
+        eval!(
+            "def f := fn (x) -> match (x, 1) with (a, b) -> fn y -> x + a + b + y end end end
+             def g := f (2)
+             g (3)",
+            Integer,
+            8
+        );
+        eval!(
+            "type Shape := Square (integer) | Rect (integer, integer) | Flipped (integer, integer) end
+             fn area (s) ->
+                 match s with
+                     Square (w) -> w * w
+                     | Rect (w, h) | Flipped (h, w) -> w * 10 + h
+                 end
+             end
+             area (Rect (2, 3)) * 100 + area (Flipped (2, 3))",
+            Integer,
+            2332
+        );
+        eval!(
+            "match ('a' : integer | char | unit) with integer | unit -> 0 | char (c) -> 1 end",
+            Integer,
+            1
+        );
+        eval!(
+            "type List := Cons (integer, List) | Nil end
+             fn push (xs, y) ->
+                 match xs with
+                     Cons (x, _) as whole -> if x < y then Cons (y, whole) else whole end
+                     | Nil as empty -> Cons (y, empty)
+                 end
+             end
+             fn len (xs) -> match xs with Cons ((_, rest) as p) -> 1 + len (rest) | Nil -> 0 end end
+             len (push (push (push (Nil, 1), 3), 2))",
+            Integer,
+            2
+        );
+        eval!(
+            "fn describe (n) ->
+                 match n with
+                     0 -> \"zero\"
+                     | 1 | 2 | -1 -> \"small\"
+                     | _ -> \"other\"
+                 end
+             end
+             (describe (0), describe (-1), describe (5))",
+            Tuple,
+            Value::String("zero".to_string()),
+            Value::String("small".to_string()),
+            Value::String("other".to_string())
+        );
+        eval!(
+            "match ((1, 'b'), true) with ((1, 'a'), _) -> 1 | ((_, 'b'), false) -> 2 | ((1, _), true) -> 3 | _ -> 4 end",
+            Integer,
+            3
+        );
+        eval!(
+            "type List := Cons (integer, List) | Nil end
+             fn pairs (xs) ->
+                 match xs with
+                     Cons (x, Cons (y, rest)) -> x * y + pairs (rest)
+                     | Cons (x, Nil) -> x
+                     | Nil -> 0
+                 end
+             end
+             pairs (Cons (2, Cons (3, Cons (4, Cons (5, Cons (6, Nil))))))",
+            Integer,
+            32
+        );
+        eval!(
+            "type Box := Box (a) end
+             type List := Cons (integer, List) | Nil end
+             fn f (b) -> match b with Box ((x, y)) -> 1 | Box (Cons (x, _)) -> 2 | Box (Nil) -> 3 | _ -> 4 end end
+             (f (Box (7)), f (Box (Nil)), f (Box ((1, 2))), f (Box (Cons (1, Nil))))",
+            Tuple,
+            Value::Integer(4),
+            Value::Integer(3),
+            Value::Integer(1),
+            Value::Integer(2)
+        );
         evalfails!("1 / 0", "Division by zero.");
         evalfails!("1 % 0", "Division by zero.");
+        evalfails!("9223372036854775807 + 1", "Integer overflow.");
+        evalfails!("-9223372036854775807 - 2", "Integer overflow.");
+        eval!(
+            "def f := fn (a, b, c) ->
+                 def d := 20
+                 a + c
+             end
+             f (1, false, 2) * 10",
+            Integer,
+            30
+        );
+        eval!(
+            "type E := A | B end
+             fn f (e) ->
+                 match e with
+                    A -> 0
+                    | B -> 1
+                 end
+             end
+             1 + f (B)
+            ",
+            Integer,
+            2
+        );
         evalfails!(
             "if true then 1 else false end",
             "Type error: expected integer but found boolean."
@@ -597,6 +1365,25 @@ mod tests {
             Value::Boolean(false)
         );
         eval!("(1, 1 + 2)", Tuple, Value::Integer(1), Value::Integer(3));
+        eval!(
+            "def a := (1, true)
+             (0, ..a, ..(2,), ..a)",
+            Tuple,
+            Value::Integer(0),
+            Value::Integer(1),
+            Value::Boolean(true),
+            Value::Integer(2),
+            Value::Integer(1),
+            Value::Boolean(true)
+        );
+        eval!(
+            "fn extend (p) -> (..(p : (integer, integer)), 0) end
+             extend ((1, 2))",
+            Tuple,
+            Value::Integer(1),
+            Value::Integer(2),
+            Value::Integer(0)
+        );
         eval!(
             "(1, 1, 2)",
             Tuple,
@@ -626,11 +1413,35 @@ mod tests {
             Value::Integer(2)
         );
         eval!("fn (x, y) -> x + y end (1, 2)", Integer, 3);
+        eval!("(1 + 2 : integer) * 2", Integer, 6);
+        evalfails!(
+            "(fn x -> x end : integer -> boolean)",
+            "Type error: expected integer -> boolean but found t1 -> t1."
+        );
         eval!("(1, 1) == (1, 0)", Boolean, false);
         eval!("(1, 1, 1) == (1, 1, 0)", Boolean, false);
         eval!("(1, 1, 1, 1) == (1, 1, 1, 0)", Boolean, false);
         eval!("(1, 1, 1, 1) == (1, 1, 1, 1)", Boolean, true);
         eval!("(1, 1) ~= (1, 0)", Boolean, true);
+        eval!("((1, 2), 3) == ((1, 2), 3)", Boolean, true);
+        eval!("((1, 2), 3) ~= ((1, 0), 3)", Boolean, true);
+        eval!(
+            "def t := (1, (false, 2))
+             (1, 2)
+             fn (x, y) -> (y, x) end (t)",
+            Tuple,
+            Value::Tuple(vec![Value::Boolean(false), Value::Integer(2)]),
+            Value::Integer(1)
+        );
+        eval!(
+            "type Pair := P (x, y) end
+             def p := P ((1, 2), 3)
+             match p with
+                 P (a, b) -> fn (c, d) -> c + d + b end (a)
+             end",
+            Integer,
+            6
+        );
         eval!("def x := 42", Integer, 42);
         eval!("def f := fn x -> x + 1 end (1)", Integer, 2);
         eval!(
@@ -657,11 +1468,17 @@ mod tests {
             Boolean,
             false
         );
-        eval!(
+        evalfails!(
+            "def c := channel (())
+             fn f (x) -> send (c, x) end
+             f (1)
+             f (\"s\")",
+            "Type error: expected integer but found string."
+        );
+        evalfails!(
             "def f := fn (x, y) -> x == y end
              f (1, false)",
-            Boolean,
-            false
+            "Type error: expected (t2, t2) but found (integer, boolean)."
         );
         eval!(
             "def f := fn (x, y) -> x == y end
@@ -669,31 +1486,30 @@ mod tests {
             Boolean,
             true
         );
-        eval!(
-            "def f := fn (x, y) -> x == y end
-             def g := fn (x, y) -> x == y end
+        evalfails!(
+            "fn f (x, y) -> x == y end
+             fn g (x, y) -> x == y end
              f (f, g)",
-            Boolean,
-            false
+            "Functions can't be compared."
         );
         eval!(
             "type Maybe := Some (x) | None end
              None",
             Datatype,
-            Box::new(vm::Value::Unit)
+            Arc::new(vm::Value::Unit)
         );
         eval!(
             "type Maybe := Some (x) | None end
              Some (42)",
             Datatype,
-            Box::new(vm::Value::Integer(42))
+            Arc::new(vm::Value::Integer(42))
         );
         eval!(
             "type Maybe := Some (x) | None end
              fn f(x) -> Some (x) end
              f (42)",
             Datatype,
-            Box::new(vm::Value::Integer(42))
+            Arc::new(vm::Value::Integer(42))
         );
         eval!("()", Unit);
         eval!("fn () -> 42 end ()", Integer, 42);
@@ -739,6 +1555,17 @@ mod tests {
             Integer,
             120
         );
+        eval!(
+            "fn f (x) ->
+                 fn (y) ->
+                     fn (z) -> x * 100 + y * 10 + z end (3)
+                 end (2)
+             end
+             f (1) + f (4)
+        ",
+            Integer,
+            546
+        );
         eval!(
             "type E := A | B end
              match B with
@@ -749,6 +1576,23 @@ mod tests {
             Integer,
             1
         );
+        eval!(
+            "type E := A | B end
+             match A with
+                A -> 0
+             end
+            ",
+            Integer,
+            0
+        );
+        evalfails!(
+            "type E := A | B end
+             match B with
+                A -> 0
+             end
+            ",
+            "No match arm for variant: B."
+        );
         eval!(
             "type Maybe := Some (x) | None end
              match Some (1) with
@@ -800,5 +1644,286 @@ mod tests {
             Integer,
             3
         );
+        eval!(
+            "type List := Nil | Cons (integer, List) end
+             fn sum (list) ->
+                 match list with
+                    Nil -> 0
+                    | Cons (head, tail) -> head + sum (tail)
+                 end
+             end
+             sum (Cons (1, Cons (2, Cons (3, Nil))))
+            ",
+            Integer,
+            6
+        );
+    }
+
+    #[test]
+    fn evals_empty_programs() {
+        for src in ["", "# Nothing but a comment.\n"] {
+            let mut vm = vm::VirtualMachine::new();
+            match parser::parse(src) {
+                Ok(ast) => assert!(matches!(codegen::eval(&mut vm, &ast), Ok(Value::Unit))),
+                Err(_) => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn indexes() {
+        eval!("\"abc\"[0]", Char, 'a');
+        eval!("\"héllo\"[1]", Char, 'é');
+        eval!("def s := \"abc\"\ns[1 + 1]", Char, 'c');
+        eval!("fn f (x) -> x end\nf (\"xy\")[1] == 'y'", Boolean, true);
+        evalfails!("\"abc\"[3]", "Index out of range: 3 for length 3.");
+        eval!("\"héllo\"[1..4]", String, "éll".to_string());
+        eval!("\"\"\"a\\n\nb\"\"\"[1..4]", String, "\\n\n".to_string());
+        eval!("\"abc\"[0..3][1..1]", String, "".to_string());
+        eval!("def s := \"abc\"\ns[1..3][0]", Char, 'b');
+        evalfails!("\"abc\"[2..4]", "Slice out of range: 2..4 for length 3.");
+        evalfails!("\"abc\"[2..1]", "Slice out of range: 2..1 for length 3.");
+        evalfails!("\"abc\"[-1]", "Index out of range: -1 for length 3.");
+        evalfails!(
+            "(1, 2)[0]",
+            "Type error: expected string but found (integer, integer)."
+        );
+        let mut vm = vm::VirtualMachine::new();
+        match parser::parse("def s := \"abc\"\n\n'a' == s[5]") {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(_) => unreachable!(),
+                Err(err) => assert_eq!((err.span.line, err.span.col), (3, 8)),
+            },
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn calls_parameters() {
+        eval!(
+            "fn apply (f, x) -> f (x) end\napply (fn (y) -> y + 1 end, 2)",
+            Integer,
+            3
+        );
+        eval!(
+            "fn compose (f, g) -> fn (x) -> f (g (x)) end end
+             def h := compose (fn (x) -> x + 1 end, fn (x) -> x * 2 end)
+             h (5)",
+            Integer,
+            11
+        );
+        eval!(
+            "fn map_pair (f, (a, b)) -> (f (a), f (b)) end
+             map_pair (fn (x) -> x * 10 end, (1, 2))",
+            Tuple,
+            Value::Integer(10),
+            Value::Integer(20)
+        );
+    }
+
+    #[test]
+    fn records() {
+        eval!("{x = 1, y = \"a\"}.x", Integer, 1);
+        eval!("def p := {y = 2, x = 1}\np.x - p.y", Integer, -1);
+        eval!(
+            "{x = (1, true), y = 'c'}.x",
+            Tuple,
+            Value::Integer(1),
+            Value::Boolean(true)
+        );
+        eval!("{a = {b = 3}}.a.b * 2", Integer, 6);
+        eval!("{x = 1, y = 2} == {y = 2, x = 1}", Boolean, true);
+        eval!("{x = 1, y = 2} ~= {x = 1, y = 3}", Boolean, true);
+        eval!(
+            "fn get (r) -> (r : {n: integer}).n end get ({n = 4})",
+            Integer,
+            4
+        );
+        eval!("def r := ({x = 1} : any)\n(r : {x: integer}).x", Integer, 1);
+        evalfails!(
+            "def r := ({x = 1} : any)\n(r : {x: string})",
+            "Type error: expected {x: string} but found {x: integer}."
+        );
+    }
+
+    #[test]
+    fn loops() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::typeinfer::Type;
+
+        let cases = [
+            ("for i in range (1, 4) do seen (i) end", vec![1, 2, 3]),
+            ("for i in range (3, 3) do seen (i) end", vec![]),
+            (
+                "fn pairs (n) -> ((0, n), fn (i, n) -> i < n end, fn (i, n) -> ((i, i * i), (i + 1, n)) end) end
+                 for (i, square) in pairs (3) do
+                     seen (i)
+                     seen (square)
+                 end",
+                vec![0, 0, 1, 1, 2, 4],
+            ),
+            (
+                "type List := Nil | Cons (integer, List) end
+                 fn more (list) -> match list with Nil -> false | Cons (x, rest) -> true end end
+                 fn next (list) -> match list with Nil -> (0, Nil) | Cons (x, rest) -> (x, rest) end end
+                 for x in (Cons (5, Cons (7, Nil)), more, next) do seen (x) end",
+                vec![5, 7],
+            ),
+            (
+                "fn scaled (k) ->
+                     for i in range (1, 3) do seen (k * i) end
+                 end
+                 scaled (10)",
+                vec![10, 20],
+            ),
+            (
+                "for i in range (0, 2) do
+                     for j in range (0, 2) do seen (10 * i + j) end
+                 end",
+                vec![0, 1, 10, 11],
+            ),
+        ];
+        for (src, expected) in cases.iter() {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let mut vm = vm::VirtualMachine::new();
+            let record = seen.clone();
+            vm.register_fn(
+                "seen",
+                Type::Function(Arc::new(Type::Integer), Arc::new(Type::Unit)),
+                move |args| {
+                    if let Value::Integer(i) = args[0] {
+                        record.lock().unwrap().push(i);
+                    }
+                    Ok(Value::Unit)
+                },
+            );
+            match parser::parse(src) {
+                Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                    Ok(v) => assert_eq!(v, Value::Unit),
+                    Err(_) => unreachable!(),
+                },
+                Err(_) => unreachable!(),
+            }
+            assert_eq!(*seen.lock().unwrap(), *expected);
+        }
+        evalfails!(
+            "for i in 1 do i end",
+            "Type error: expected (t2, t2 -> boolean, t2 -> (t1, t2)) but found integer."
+        );
+    }
+
+    #[test]
+    fn limits_depth() {
+        let mut vm = vm::VirtualMachine::new();
+        vm.max_depth = 3;
+        match parser::parse("1 + 2 + 3") {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(v) => assert_eq!(v, Value::Integer(6)),
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+        match parser::parse("def x := 1\n1 - (2 - (3 - x))") {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(_) => unreachable!(),
+                Err(err) => {
+                    assert_eq!(err.err, "Expression nested too deeply: the limit is 3.");
+                    assert_eq!((err.span.line, err.span.col), (2, 15));
+                }
+            },
+            Err(_) => unreachable!(),
+        }
+        // Nothing from a rejected program is defined.
+        match parser::parse("x") {
+            Ok(ast) => assert!(codegen::eval(&mut vm, &ast).is_err()),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn follows_long_chains() {
+        let mut vm = vm::VirtualMachine::new();
+        let src = format!("def x := 1\n{}", vec!["x"; 1000].join(" + "));
+        match parser::parse(&src) {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(v) => assert_eq!(v, Value::Integer(1000)),
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+        // Without folding, each operator is generated.
+        vm.passes.clear();
+        let src = format!("def y := 2\n{}", vec!["y"; 1000].join(" * 1 - "));
+        match parser::parse(&src) {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(v) => assert_eq!(v, Value::Integer(-1996)),
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn pools_constants() {
+        let mut vm = vm::VirtualMachine::new();
+        // Folding would leave nothing to pool.
+        vm.passes.clear();
+        match parser::parse("def x := 1000000 * 2 + 1000000\n2 + -x") {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(v) => assert_eq!(v, Value::Integer(-2999998)),
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+        assert_eq!(
+            vm.constants,
+            vec![
+                Value::Integer(1000000),
+                Value::Integer(2),
+                Value::Integer(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn displays_functions() {
+        let mut vm = vm::VirtualMachine::new();
+        let src = "fn less (x, y) -> x < y end\ntype T := A | B (integer) end\n(less, B, exit, fn () -> 1 end)";
+        match parser::parse(src) {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(v) => assert_eq!(
+                    v.to_string(),
+                    "(fn (integer, integer) -> boolean @ 1:1, fn integer -> T @ 2:1, \
+                     fn integer -> never, fn unit -> integer @ 3:17)"
+                ),
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn times_phases() {
+        let mut vm = vm::VirtualMachine::new();
+        let src = "fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end fib (15)";
+        match parser::parse(src) {
+            Ok(ast) => {
+                assert!(codegen::eval(&mut vm, &ast).is_ok());
+                assert_eq!(vm.timings, None);
+                vm.timings = Some(vm::Timings::default());
+                assert!(codegen::eval(&mut vm, &ast).is_ok());
+            }
+            Err(_) => unreachable!(),
+        }
+        match vm.timings {
+            Some(timings) => {
+                assert_eq!(timings.parse, Duration::default());
+                assert!(timings.typecheck > Duration::default());
+                assert!(timings.codegen > Duration::default());
+                assert!(timings.run > Duration::default());
+            }
+            None => unreachable!(),
+        }
     }
 }
@@ -0,0 +1,255 @@
+// Conversions between values and Rust types, for host code. Vectors convert
+// to tuples, and options to the constructors Some and None.
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::vm::Value;
+
+#[derive(Debug, PartialEq)]
+pub struct ConversionError {
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected {} but found {}.", self.expected, self.found)
+    }
+}
+
+impl Error for ConversionError {}
+
+// Native functions report errors as strings.
+impl From<ConversionError> for String {
+    fn from(err: ConversionError) -> String {
+        err.to_string()
+    }
+}
+
+fn mismatch<T>(expected: &str, found: Value) -> Result<T, ConversionError> {
+    Err(ConversionError {
+        expected: expected.to_string(),
+        found: found.to_string(),
+    })
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Integer(i)
+    }
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<i64, ConversionError> {
+        match value {
+            Value::Integer(i) => Ok(i),
+            _ => mismatch("integer", value),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Boolean(b)
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<bool, ConversionError> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            _ => mismatch("boolean", value),
+        }
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Value {
+        Value::Unit
+    }
+}
+
+impl TryFrom<Value> for () {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<(), ConversionError> {
+        match value {
+            Value::Unit => Ok(()),
+            _ => mismatch("unit", value),
+        }
+    }
+}
+
+impl From<char> for Value {
+    fn from(c: char) -> Value {
+        Value::Char(c)
+    }
+}
+
+impl TryFrom<Value> for char {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<char, ConversionError> {
+        match value {
+            Value::Char(c) => Ok(c),
+            _ => mismatch("char", value),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<String, ConversionError> {
+        match value {
+            Value::String(s) => Ok(s),
+            _ => mismatch("string", value),
+        }
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(elements: Vec<T>) -> Value {
+        Value::Tuple(elements.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: TryFrom<Value, Error = ConversionError>> TryFrom<Value> for Vec<T> {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Vec<T>, ConversionError> {
+        match value {
+            Value::Tuple(elements) => elements.into_iter().map(T::try_from).collect(),
+            _ => mismatch("tuple", value),
+        }
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(option: Option<T>) -> Value {
+        match option {
+            Some(value) => Value::Datatype(
+                "Option".to_string(),
+                "Some".to_string(),
+                Arc::new(value.into()),
+            ),
+            None => Value::Datatype(
+                "Option".to_string(),
+                "None".to_string(),
+                Arc::new(Value::Unit),
+            ),
+        }
+    }
+}
+
+impl<T: TryFrom<Value, Error = ConversionError>> TryFrom<Value> for Option<T> {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Option<T>, ConversionError> {
+        match value {
+            Value::Datatype(_, ctor, value) if ctor == "Some" => {
+                T::try_from(Arc::try_unwrap(value).unwrap_or_else(|value| Value::clone(&value)))
+                    .map(Some)
+            }
+            Value::Datatype(_, ctor, _) if ctor == "None" => Ok(None),
+            _ => mismatch("Some or None", value),
+        }
+    }
+}
+
+macro_rules! tuple {
+    ($len:expr, $($name:ident),*) => {
+        impl<$($name: Into<Value>),*> From<($($name,)*)> for Value {
+            #[allow(non_snake_case)]
+            fn from(($($name,)*): ($($name,)*)) -> Value {
+                Value::Tuple(vec![$($name.into()),*])
+            }
+        }
+
+        impl<$($name: TryFrom<Value, Error = ConversionError>),*> TryFrom<Value>
+            for ($($name,)*)
+        {
+            type Error = ConversionError;
+
+            fn try_from(value: Value) -> Result<($($name,)*), ConversionError> {
+                match value {
+                    Value::Tuple(elements) if elements.len() == $len => {
+                        let mut elements = elements.into_iter();
+                        Ok(($($name::try_from(elements.next().unwrap())?,)*))
+                    }
+                    _ => mismatch(concat!("tuple of ", $len), value),
+                }
+            }
+        }
+    };
+}
+
+tuple!(2, A, B);
+tuple!(3, A, B, C);
+tuple!(4, A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use crate::vm::Value;
+    use crate::Interpreter;
+
+    #[test]
+    fn converts() {
+        assert_eq!(Value::from(42), Value::Integer(42));
+        assert_eq!(i64::try_from(Value::Integer(42)), Ok(42));
+        assert_eq!(bool::try_from(Value::Boolean(true)), Ok(true));
+        assert_eq!(String::try_from(Value::from("hi")), Ok("hi".to_string()));
+        assert_eq!(char::try_from(Value::from('x')), Ok('x'));
+        assert_eq!(
+            Value::from((1, false)),
+            Value::Tuple(vec![Value::Integer(1), Value::Boolean(false)])
+        );
+        assert_eq!(
+            <(i64, bool, ())>::try_from(Value::from((1, true, ()))),
+            Ok((1, true, ()))
+        );
+        assert_eq!(
+            Vec::<i64>::try_from(Value::from(vec![1, 2, 3])),
+            Ok(vec![1, 2, 3])
+        );
+        assert_eq!(Option::<i64>::try_from(Value::from(Some(1))), Ok(Some(1)));
+        assert_eq!(Option::<i64>::try_from(Value::from(None::<i64>)), Ok(None));
+        match i64::try_from(Value::Boolean(false)) {
+            Err(err) => assert_eq!(err.to_string(), "Expected integer but found false."),
+            Ok(_) => unreachable!(),
+        }
+        match <(i64, i64)>::try_from(Value::from((1, 2, 3))) {
+            Err(err) => assert_eq!(err.to_string(), "Expected tuple of 2 but found (1, 2, 3)."),
+            Ok(_) => unreachable!(),
+        }
+
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str(
+            "type Option := Some (x) | None end
+             Some (1 + 2)",
+        ) {
+            Ok(value) => assert_eq!(Option::<i64>::try_from(value), Ok(Some(3))),
+            Err(_) => unreachable!(),
+        }
+    }
+}
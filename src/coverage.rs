@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use crate::parser::Span;
+
+pub fn merge(total: &mut HashMap<Span, usize>, hits: &HashMap<Span, usize>) {
+    for (span, count) in hits {
+        *total.entry(*span).or_insert(0) += count;
+    }
+}
+
+// Annotates each line of a program with the number of times it ran, in the
+// style of gcov. Lines without any tagged expressions are marked with - and
+// lines that never ran with #####.
+pub fn report(src: &str, hits: &HashMap<Span, usize>) -> String {
+    let lines: Vec<&str> = src.lines().collect();
+    let mut counts: Vec<Option<usize>> = vec![None; lines.len()];
+    for (span, count) in hits {
+        if span.line == 0 || span.line > lines.len() {
+            continue;
+        }
+        let line = &mut counts[span.line - 1];
+        *line = Some(line.map_or(*count, |c| c.max(*count)));
+    }
+
+    let mut out = String::new();
+    for (count, line) in counts.iter().zip(lines.iter()) {
+        let count = match count {
+            Some(0) => "#####".to_string(),
+            Some(count) => count.to_string(),
+            None => "-".to_string(),
+        };
+        out.push_str(&format!("{:>9}: {}\n", count, line));
+    }
+    let total = counts.iter().filter(|count| count.is_some()).count();
+    let covered = counts
+        .iter()
+        .filter(|count| matches!(count, Some(c) if *c > 0))
+        .count();
+    out.push_str(&format!("{} of {} lines covered.\n", covered, total));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::codegen;
+    use crate::coverage;
+    use crate::parser;
+    use crate::vm;
+
+    #[test]
+    fn reports() {
+        let src = "fn sign (x) ->
+    if x < 0 then
+        -1
+    else
+        1
+    end
+end
+sign (5)
+";
+        let mut vm = vm::VirtualMachine::new();
+        vm.coverage = Some(HashMap::new());
+        match parser::parse(src) {
+            Ok(ast) => match codegen::eval(&mut vm, &ast) {
+                Ok(_) => {
+                    let hits = vm.coverage.take().unwrap_or_default();
+                    assert_eq!(
+                        coverage::report(src, &hits),
+                        "        1: fn sign (x) ->
+        1:     if x < 0 then
+    #####:         -1
+        -:     else
+        1:         1
+        -:     end
+        -: end
+        1: sign (5)
+4 of 5 lines covered.
+"
+                    );
+                }
+                Err(_) => {
+                    unreachable!();
+                }
+            },
+            Err(_) => {
+                unreachable!();
+            }
+        }
+    }
+}
@@ -0,0 +1,359 @@
+// Every error and warning has a stable code, so that tools and tests don't
+// depend on the wording of messages. Codes starting with E are errors, E00xx
+// from parsing and loading compiled programs, E01xx from type checking and
+// E02xx from evaluation, and codes starting with W are warnings.
+use std::fmt;
+
+use crate::dump::{json_list, json_span, json_string};
+use crate::parser::Span;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Code {
+    Syntax,
+    TooDeep,
+    InvalidBundle,
+    UnknownIdentifier,
+    UnknownType,
+    TypeMismatch,
+    InfiniteType,
+    InvalidParameter,
+    NotCallable,
+    ExpectedIdentifier,
+    ExpectedDatatype,
+    UnknownVariant,
+    ExpectedTuple,
+    InvalidPattern,
+    DuplicateField,
+    UnknownField,
+    DivisionByZero,
+    IntegerOverflow,
+    NativeError,
+    AsyncCall,
+    StackUnderflow,
+    NoMatch,
+    IndexOutOfRange,
+    Exit,
+    AllocationLimit,
+    CompareFunctions,
+    FailedCheck,
+    EvaluationLimit,
+    UnusedBinding,
+    Shadowing,
+    NonExhaustiveMatch,
+    UnreachableArm,
+    DiscardedValue,
+}
+
+pub const CODES: [Code; 33] = [
+    Code::Syntax,
+    Code::TooDeep,
+    Code::InvalidBundle,
+    Code::UnknownIdentifier,
+    Code::UnknownType,
+    Code::TypeMismatch,
+    Code::InfiniteType,
+    Code::InvalidParameter,
+    Code::NotCallable,
+    Code::ExpectedIdentifier,
+    Code::ExpectedDatatype,
+    Code::UnknownVariant,
+    Code::ExpectedTuple,
+    Code::InvalidPattern,
+    Code::DuplicateField,
+    Code::UnknownField,
+    Code::DivisionByZero,
+    Code::IntegerOverflow,
+    Code::NativeError,
+    Code::AsyncCall,
+    Code::StackUnderflow,
+    Code::NoMatch,
+    Code::IndexOutOfRange,
+    Code::Exit,
+    Code::AllocationLimit,
+    Code::CompareFunctions,
+    Code::FailedCheck,
+    Code::EvaluationLimit,
+    Code::UnusedBinding,
+    Code::Shadowing,
+    Code::NonExhaustiveMatch,
+    Code::UnreachableArm,
+    Code::DiscardedValue,
+];
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Code::Syntax => "E0001",
+            Code::TooDeep => "E0002",
+            Code::InvalidBundle => "E0003",
+            Code::UnknownIdentifier => "E0101",
+            Code::UnknownType => "E0102",
+            Code::TypeMismatch => "E0103",
+            Code::InfiniteType => "E0104",
+            Code::InvalidParameter => "E0105",
+            Code::NotCallable => "E0106",
+            Code::ExpectedIdentifier => "E0107",
+            Code::ExpectedDatatype => "E0108",
+            Code::UnknownVariant => "E0109",
+            Code::ExpectedTuple => "E0110",
+            Code::InvalidPattern => "E0111",
+            Code::DuplicateField => "E0112",
+            Code::UnknownField => "E0113",
+            Code::DivisionByZero => "E0201",
+            Code::IntegerOverflow => "E0202",
+            Code::NativeError => "E0203",
+            Code::AsyncCall => "E0204",
+            Code::StackUnderflow => "E0205",
+            Code::NoMatch => "E0206",
+            Code::IndexOutOfRange => "E0207",
+            Code::Exit => "E0208",
+            Code::AllocationLimit => "E0209",
+            Code::CompareFunctions => "E0210",
+            Code::FailedCheck => "E0211",
+            Code::EvaluationLimit => "E0212",
+            Code::UnusedBinding => "W0001",
+            Code::Shadowing => "W0002",
+            Code::NonExhaustiveMatch => "W0003",
+            Code::UnreachableArm => "W0004",
+            Code::DiscardedValue => "W0005",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Code::UnusedBinding
+            | Code::Shadowing
+            | Code::NonExhaustiveMatch
+            | Code::UnreachableArm
+            | Code::DiscardedValue => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Code::Syntax => "The program could not be parsed.",
+            Code::TooDeep => "Expressions are nested more deeply than the limit.",
+            Code::InvalidBundle => "A compiled program is damaged or from another version.",
+            Code::UnknownIdentifier => "A name was used without being defined.",
+            Code::UnknownType => "A type annotation names a type that does not exist.",
+            Code::TypeMismatch => "An expression does not have the type its context requires.",
+            Code::InfiniteType => "A type would have to contain itself.",
+            Code::InvalidParameter => {
+                "A function parameter is not an identifier or tuple of identifiers."
+            }
+            Code::NotCallable => "A value that is not a function was called.",
+            Code::ExpectedIdentifier => "A definition does not bind an identifier.",
+            Code::ExpectedDatatype => "A match is not on a datatype.",
+            Code::UnknownVariant => "A match arm names a variant the datatype does not have.",
+            Code::ExpectedTuple => {
+                "A spread value, or the elements given to pmap, is not known to be a tuple."
+            }
+            Code::InvalidPattern => {
+                "A pattern binds a name twice, or not in every one of its alternatives."
+            }
+            Code::DuplicateField => "A record gives a field more than once.",
+            Code::UnknownField => "A field was used that a value is not known to have.",
+            Code::DivisionByZero => "An integer was divided by zero.",
+            Code::IntegerOverflow => "Integer arithmetic overflowed.",
+            Code::NativeError => "A native function reported an error.",
+            Code::AsyncCall => "An asynchronous native function was called synchronously.",
+            Code::StackUnderflow => "The virtual machine ran out of values, which is a bug.",
+            Code::NoMatch => "No arm of a match handled its value.",
+            Code::IndexOutOfRange => "An index was past the end of what it indexed.",
+            Code::Exit => "The program stopped itself by calling exit.",
+            Code::AllocationLimit => "A program built more values than it is allowed to.",
+            Code::CompareFunctions => "Two values were compared when one of them held a function.",
+            Code::FailedCheck => "A value of type any did not have the type it was given.",
+            Code::EvaluationLimit => "A program ran for longer than it is allowed to.",
+            Code::UnusedBinding => "A definition or parameter is never used.",
+            Code::Shadowing => "A binding hides another of the same name.",
+            Code::NonExhaustiveMatch => "A match does not handle every value of its type.",
+            Code::UnreachableArm => "A match arm handles a variant that an earlier arm handles.",
+            Code::DiscardedValue => "The value of an expression without effects is thrown away.",
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// A diagnostic as a single line of JSON, with its code, severity, message,
+// file, span, related code and notes. The code is null for problems that don't have
+// one, such as failing tests, and a span is null when it is not known.
+pub fn json(
+    file: &str,
+    code: Option<Code>,
+    severity: Severity,
+    message: &str,
+    span: &Span,
+    related: &[(Span, String)],
+    notes: &[String],
+) -> String {
+    let span_or_null = |span: &Span, out: &mut String| {
+        if *span == Span::unknown() {
+            out.push_str("null");
+        } else {
+            json_span(span, out);
+        }
+    };
+    let mut out = String::new();
+    out.push_str("{\"code\":");
+    match code {
+        Some(code) => json_string(code.as_str(), &mut out),
+        None => out.push_str("null"),
+    }
+    out.push_str(",\"severity\":");
+    json_string(&severity.to_string(), &mut out);
+    out.push_str(",\"message\":");
+    json_string(message, &mut out);
+    out.push_str(",\"file\":");
+    json_string(file, &mut out);
+    out.push_str(",\"span\":");
+    span_or_null(span, &mut out);
+    out.push_str(",\"related\":");
+    json_list(related, &mut out, |(span, label), out| {
+        out.push_str("{\"message\":");
+        json_string(label, out);
+        out.push_str(",\"span\":");
+        span_or_null(span, out);
+        out.push('}');
+    });
+    out.push_str(",\"notes\":");
+    json_list(notes, &mut out, |note, out| json_string(note, out));
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use crate::diagnostics::{json, Code, Severity, CODES};
+    use crate::parser;
+    use crate::typeinfer::Type;
+    use crate::warnings;
+    use crate::warnings::Settings;
+    use crate::{Error, Interpreter};
+
+    fn interpreter() -> Interpreter {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm.register_fn(
+            "fail",
+            Type::Function(Arc::new(Type::Integer), Arc::new(Type::Integer)),
+            |_| Err("Failed.".to_string()),
+        );
+        interpreter
+    }
+
+    fn code(src: &str) -> Code {
+        match interpreter().eval_str(src) {
+            Err(Error::Parse(errors)) => errors[0].code,
+            Err(Error::Interpreter(err)) => err.code,
+            Ok(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        let codes: HashSet<&str> = CODES.iter().map(|code| code.as_str()).collect();
+        assert_eq!(codes.len(), CODES.len());
+    }
+
+    #[test]
+    fn reports_codes() {
+        assert_eq!(code("1 +"), Code::Syntax);
+        assert_eq!(code("x"), Code::UnknownIdentifier);
+        assert_eq!(code("(1 : widget)"), Code::UnknownType);
+        assert_eq!(code("1 + true"), Code::TypeMismatch);
+        assert_eq!(code("def x := (1, 1)\nx (1)"), Code::NotCallable);
+        assert_eq!(
+            code("match (1, 2) with (a, a) -> a end"),
+            Code::InvalidPattern
+        );
+        assert_eq!(code("1 / 0"), Code::DivisionByZero);
+        assert_eq!(code("9223372036854775807 + 1"), Code::IntegerOverflow);
+        assert_eq!(code("fail (1)"), Code::NativeError);
+        assert_eq!(code("\"abc\"[3]"), Code::IndexOutOfRange);
+        assert_eq!(Code::TypeMismatch.to_string(), "E0103");
+        assert_eq!(Code::UnusedBinding.severity(), Severity::Warning);
+
+        match parser::parse("fn f (x) -> 1 end") {
+            Ok(ast) => {
+                let found = warnings::check(&ast, &Settings::default());
+                assert_eq!(found[0].code, Code::UnusedBinding);
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn adds_notes() {
+        match interpreter().eval_str("fail (1)") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.severity(), Severity::Error);
+                assert_eq!(err.err, "Failed.");
+                assert_eq!(err.notes, vec!["Raised by native function fail."]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn formats_json() {
+        match interpreter().eval_str("fail (1)") {
+            Err(Error::Interpreter(err)) => assert_eq!(
+                json(
+                    "a.plover",
+                    Some(err.code),
+                    err.severity(),
+                    &err.err,
+                    &err.span,
+                    &err.related,
+                    &err.notes
+                ),
+                concat!(
+                    r#"{"code":"E0203","severity":"error","message":"Failed.","file":"a.plover","#,
+                    r#""span":{"line":1,"col":1,"end_line":1,"end_col":9},"related":[],"#,
+                    r#""notes":["Raised by native function fail."]}"#
+                )
+            ),
+            _ => unreachable!(),
+        }
+        let related = vec![(parser::Span::unknown(), "Defined \"here\".".to_string())];
+        assert_eq!(
+            json(
+                "-",
+                None,
+                Severity::Warning,
+                "Line\nbreak.",
+                &parser::Span::unknown(),
+                &related,
+                &[]
+            ),
+            concat!(
+                r#"{"code":null,"severity":"warning","message":"Line\nbreak.","file":"-","#,
+                r#""span":null,"related":[{"message":"Defined \"here\".","span":null}],"notes":[]}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,753 @@
+use crate::parser;
+use crate::parser::{escape, escape_char, Pattern, Span, AST};
+use crate::typeinfer::{type_of, TypedAST, TypedPattern};
+
+fn line(depth: usize, text: &str, out: &mut String) {
+    for _ in 0..depth {
+        out.push_str("  ");
+    }
+    out.push_str(text);
+    out.push('\n');
+}
+
+fn span(span: &Span) -> String {
+    format!(
+        "{}:{}-{}:{}",
+        span.line, span.col, span.end_line, span.end_col
+    )
+}
+
+fn dump_pattern(pattern: &Pattern, depth: usize, out: &mut String) {
+    match pattern {
+        Pattern::As(pattern, id, s) => {
+            line(depth, &format!("As {} {}", id, span(s)), out);
+            dump_pattern(pattern, depth + 1, out);
+        }
+        Pattern::Identifier(id, s) => line(depth, &format!("Identifier {} {}", id, span(s)), out),
+        Pattern::Literal(literal, s) => {
+            line(depth, &format!("Literal {}", span(s)), out);
+            dump_ast(literal, depth + 1, out);
+        }
+        Pattern::Or(patterns, s) | Pattern::Tuple(patterns, s) => {
+            let name = if let Pattern::Or(_, _) = pattern {
+                "Or"
+            } else {
+                "Tuple"
+            };
+            line(depth, &format!("{} {}", name, span(s)), out);
+            for pattern in patterns {
+                dump_pattern(pattern, depth + 1, out);
+            }
+        }
+        Pattern::Variant(name, param, s) => {
+            line(depth, &format!("Variant {} {}", name, span(s)), out);
+            if let Some(param) = param {
+                dump_pattern(param, depth + 1, out);
+            }
+        }
+        Pattern::Wildcard(s) => line(depth, &format!("Wildcard {}", span(s)), out),
+    }
+}
+
+fn dump_ast(ast: &AST, depth: usize, out: &mut String) {
+    match ast {
+        AST::Ascription(expr, typ, s) => {
+            line(depth, &format!("Ascription {} {}", typ, span(s)), out);
+            dump_ast(expr, depth + 1, out);
+        }
+        AST::BinaryOp(_, _, _, _) => {
+            let (first, links) = parser::chain(ast);
+            for (i, (op, _, s)) in links.iter().rev().enumerate() {
+                line(depth + i, &format!("BinaryOp {} {}", op, span(s)), out);
+            }
+            dump_ast(first, depth + links.len(), out);
+            for (i, (_, rhs, _)) in links.iter().enumerate().rev() {
+                dump_ast(rhs, depth + i + 1, out);
+            }
+        }
+        AST::Boolean(b, s) => line(depth, &format!("Boolean {} {}", b, span(s)), out),
+        AST::Char(c, s) => line(depth, &format!("Char {} {}", escape_char(*c), span(s)), out),
+        AST::Call(fun, arg, s) => {
+            line(depth, &format!("Call {}", span(s)), out);
+            dump_ast(fun, depth + 1, out);
+            dump_ast(arg, depth + 1, out);
+        }
+        AST::Datatype(name, variants, s) => {
+            line(depth, &format!("Datatype {} {}", name, span(s)), out);
+            for variant in variants {
+                line(depth + 1, &format!("Variant {}", variant.0), out);
+                if let Some(param) = &variant.1 {
+                    dump_ast(param, depth + 2, out);
+                }
+            }
+        }
+        AST::Define(id, value, s) => {
+            line(depth, &format!("Define {}", span(s)), out);
+            dump_ast(id, depth + 1, out);
+            dump_ast(value, depth + 1, out);
+        }
+        AST::Field(value, field, s) => {
+            line(depth, &format!("Field {} {}", field, span(s)), out);
+            dump_ast(value, depth + 1, out);
+        }
+        AST::For(pattern, iter, body, s) => {
+            line(depth, &format!("For {}", span(s)), out);
+            dump_ast(pattern, depth + 1, out);
+            dump_ast(iter, depth + 1, out);
+            dump_ast(body, depth + 1, out);
+        }
+        AST::Function(id, param, body, s) => {
+            match id {
+                Some(id) => line(depth, &format!("Function {} {}", id, span(s)), out),
+                None => line(depth, &format!("Function {}", span(s)), out),
+            }
+            dump_ast(param, depth + 1, out);
+            dump_ast(body, depth + 1, out);
+        }
+        AST::Identifier(id, s) => line(depth, &format!("Identifier {} {}", id, span(s)), out),
+        AST::If(conds, els, s) => {
+            line(depth, &format!("If {}", span(s)), out);
+            for cond in conds {
+                dump_ast(&cond.0, depth + 1, out);
+                dump_ast(&cond.1, depth + 1, out);
+            }
+            dump_ast(els, depth + 1, out);
+        }
+        AST::Index(value, index, s) => {
+            line(depth, &format!("Index {}", span(s)), out);
+            dump_ast(value, depth + 1, out);
+            dump_ast(index, depth + 1, out);
+        }
+        AST::Integer(i, s) => line(depth, &format!("Integer {} {}", i, span(s)), out),
+        AST::Match(cond, cases, s) => {
+            line(depth, &format!("Match {}", span(s)), out);
+            dump_ast(cond, depth + 1, out);
+            for case in cases {
+                line(depth + 1, "Case", out);
+                dump_pattern(&case.0, depth + 2, out);
+                dump_ast(&case.1, depth + 2, out);
+            }
+        }
+        AST::Program(expressions, s) => {
+            line(depth, &format!("Program {}", span(s)), out);
+            for expression in expressions {
+                dump_ast(expression, depth + 1, out);
+            }
+        }
+        AST::Record(fields, s) => {
+            line(depth, &format!("Record {}", span(s)), out);
+            for field in fields {
+                line(depth + 1, &format!("Value {}", field.0), out);
+                dump_ast(&field.1, depth + 2, out);
+            }
+        }
+        AST::Slice(value, start, end, s) => {
+            line(depth, &format!("Slice {}", span(s)), out);
+            dump_ast(value, depth + 1, out);
+            dump_ast(start, depth + 1, out);
+            dump_ast(end, depth + 1, out);
+        }
+        AST::Spread(ast, s) => {
+            line(depth, &format!("Spread {}", span(s)), out);
+            dump_ast(ast, depth + 1, out);
+        }
+        AST::Tuple(elements, s) => {
+            line(depth, &format!("Tuple {}", span(s)), out);
+            for element in elements {
+                dump_ast(element, depth + 1, out);
+            }
+        }
+        AST::UnaryOp(op, ast, s) => {
+            line(depth, &format!("UnaryOp {} {}", op, span(s)), out);
+            dump_ast(ast, depth + 1, out);
+        }
+        AST::String(s, sp) => line(depth, &format!("String {} {}", escape(s), span(sp)), out),
+        AST::Unit(s) => line(depth, &format!("Unit {}", span(s)), out),
+    }
+}
+
+fn dump_typed_pattern(pattern: &TypedPattern, depth: usize, out: &mut String) {
+    match pattern {
+        TypedPattern::As(typ, pattern, id, _) => {
+            line(depth, &format!("As {} : {}", id, typ), out);
+            dump_typed_pattern(pattern, depth + 1, out);
+        }
+        TypedPattern::Identifier(typ, id, _) => {
+            line(depth, &format!("Identifier {} : {}", id, typ), out)
+        }
+        TypedPattern::Literal(literal, _) => {
+            line(depth, "Literal", out);
+            dump_typed_ast(literal, depth + 1, out);
+        }
+        TypedPattern::Member(typ, param, _) => {
+            line(depth, &format!("Member {}", typ), out);
+            if let Some(param) = param {
+                dump_typed_pattern(param, depth + 1, out);
+            }
+        }
+        TypedPattern::Or(patterns, _) | TypedPattern::Tuple(patterns, _) => {
+            let name = if let TypedPattern::Or(_, _) = pattern {
+                "Or"
+            } else {
+                "Tuple"
+            };
+            line(depth, name, out);
+            for pattern in patterns {
+                dump_typed_pattern(pattern, depth + 1, out);
+            }
+        }
+        TypedPattern::Variant(name, param, _) => {
+            line(depth, &format!("Variant {}", name), out);
+            if let Some(param) = param {
+                dump_typed_pattern(param, depth + 1, out);
+            }
+        }
+        TypedPattern::Wildcard(_) => line(depth, "Wildcard", out),
+    }
+}
+
+fn dump_typed_ast(ast: &TypedAST, depth: usize, out: &mut String) {
+    let typ = type_of(ast);
+    match ast {
+        TypedAST::BinaryOp(_, _, _, _, _) => {
+            let mut links = Vec::new();
+            let mut lhs = ast;
+            while let TypedAST::BinaryOp(typ, op, inner, rhs, _) = lhs {
+                line(
+                    depth + links.len(),
+                    &format!("BinaryOp {} : {}", op, typ),
+                    out,
+                );
+                links.push(rhs);
+                lhs = inner;
+            }
+            dump_typed_ast(lhs, depth + links.len(), out);
+            for (i, rhs) in links.iter().enumerate().rev() {
+                dump_typed_ast(rhs, depth + i + 1, out);
+            }
+        }
+        TypedAST::Boolean(b, _) => line(depth, &format!("Boolean {} : {}", b, typ), out),
+        TypedAST::Char(c, _) => line(depth, &format!("Char {} : {}", escape_char(*c), typ), out),
+        TypedAST::Call(fun, arg, _) => {
+            line(depth, &format!("Call : {}", typ), out);
+            dump_typed_ast(fun, depth + 1, out);
+            dump_typed_ast(arg, depth + 1, out);
+        }
+        TypedAST::Cast(_, ast, _) => {
+            line(depth, &format!("Cast : {}", typ), out);
+            dump_typed_ast(ast, depth + 1, out);
+        }
+        TypedAST::Datatype(_, variants, _) => {
+            line(depth, &format!("Datatype {}", typ), out);
+            for variant in variants {
+                line(
+                    depth + 1,
+                    &format!("Variant {} : {}", variant.0, variant.1),
+                    out,
+                );
+            }
+        }
+        TypedAST::Define(_, id, value, _) => {
+            line(depth, &format!("Define {} : {}", id, typ), out);
+            dump_typed_ast(value, depth + 1, out);
+        }
+        TypedAST::Field(_, value, field, _) => {
+            line(depth, &format!("Field {} : {}", field, typ), out);
+            dump_typed_ast(value, depth + 1, out);
+        }
+        TypedAST::For(pattern, iter, body, _) => {
+            line(depth, &format!("For : {}", typ), out);
+            dump_typed_ast(pattern, depth + 1, out);
+            dump_typed_ast(iter, depth + 1, out);
+            dump_typed_ast(body, depth + 1, out);
+        }
+        TypedAST::Function(id, param, body, _) => {
+            match id {
+                Some(id) => line(depth, &format!("Function {} : {}", id, typ), out),
+                None => line(depth, &format!("Function : {}", typ), out),
+            }
+            dump_typed_ast(param, depth + 1, out);
+            dump_typed_ast(body, depth + 1, out);
+        }
+        TypedAST::Identifier(_, id, _) => line(depth, &format!("Identifier {} : {}", id, typ), out),
+        TypedAST::If(conds, els, _) => {
+            line(depth, &format!("If : {}", typ), out);
+            for cond in conds {
+                dump_typed_ast(&cond.0, depth + 1, out);
+                dump_typed_ast(&cond.1, depth + 1, out);
+            }
+            dump_typed_ast(els, depth + 1, out);
+        }
+        TypedAST::Index(value, index, _) => {
+            line(depth, &format!("Index : {}", typ), out);
+            dump_typed_ast(value, depth + 1, out);
+            dump_typed_ast(index, depth + 1, out);
+        }
+        TypedAST::Integer(i, _) => line(depth, &format!("Integer {} : {}", i, typ), out),
+        TypedAST::Match(cond, datatype, cases, _) => {
+            line(depth, &format!("Match {} : {}", datatype, typ), out);
+            dump_typed_ast(cond, depth + 1, out);
+            for case in cases {
+                line(depth + 1, "Case", out);
+                dump_typed_pattern(&case.0, depth + 2, out);
+                dump_typed_ast(&case.1, depth + 2, out);
+            }
+        }
+        TypedAST::Program(_, expressions, _) => {
+            line(depth, &format!("Program : {}", typ), out);
+            for expression in expressions {
+                dump_typed_ast(expression, depth + 1, out);
+            }
+        }
+        TypedAST::Record(_, fields, _) => {
+            line(depth, &format!("Record : {}", typ), out);
+            for field in fields {
+                line(depth + 1, &format!("Value {}", field.0), out);
+                dump_typed_ast(&field.1, depth + 2, out);
+            }
+        }
+        TypedAST::Slice(value, start, end, _) => {
+            line(depth, &format!("Slice : {}", typ), out);
+            dump_typed_ast(value, depth + 1, out);
+            dump_typed_ast(start, depth + 1, out);
+            dump_typed_ast(end, depth + 1, out);
+        }
+        TypedAST::Spread(_, ast, _) => {
+            line(depth, &format!("Spread : {}", typ), out);
+            dump_typed_ast(ast, depth + 1, out);
+        }
+        TypedAST::Tuple(_, elements, _) => {
+            line(depth, &format!("Tuple : {}", typ), out);
+            for element in elements {
+                dump_typed_ast(element, depth + 1, out);
+            }
+        }
+        TypedAST::UnaryOp(_, op, ast, _) => {
+            line(depth, &format!("UnaryOp {} : {}", op, typ), out);
+            dump_typed_ast(ast, depth + 1, out);
+        }
+        TypedAST::String(s, _) => line(depth, &format!("String {} : {}", escape(s), typ), out),
+        TypedAST::Unit(_) => line(depth, &format!("Unit : {}", typ), out),
+    }
+}
+
+// Nodes are written as JSON objects with the name of the node as their kind,
+// their parts and their span, in that order.
+fn open(kind: &str, out: &mut String) {
+    out.push_str("{\"kind\":\"");
+    out.push_str(kind);
+    out.push('"');
+}
+
+fn key(name: &str, out: &mut String) {
+    out.push_str(",\"");
+    out.push_str(name);
+    out.push_str("\":");
+}
+
+fn close(span: &Span, out: &mut String) {
+    key("span", out);
+    json_span(span, out);
+    out.push('}');
+}
+
+pub(crate) fn json_span(span: &Span, out: &mut String) {
+    out.push_str(&format!(
+        "{{\"line\":{},\"col\":{},\"end_line\":{},\"end_col\":{}}}",
+        span.line, span.col, span.end_line, span.end_col
+    ));
+}
+
+pub(crate) fn json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+pub(crate) fn json_list<T>(items: &[T], out: &mut String, mut item: impl FnMut(&T, &mut String)) {
+    out.push('[');
+    for (i, element) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        item(element, out);
+    }
+    out.push(']');
+}
+
+fn json_pattern(pattern: &Pattern, out: &mut String) {
+    match pattern {
+        Pattern::As(pattern, id, s) => {
+            open("As", out);
+            key("pattern", out);
+            json_pattern(pattern, out);
+            key("name", out);
+            json_string(id, out);
+            close(s, out);
+        }
+        Pattern::Identifier(id, s) => {
+            open("Identifier", out);
+            key("name", out);
+            json_string(id, out);
+            close(s, out);
+        }
+        Pattern::Literal(literal, s) => {
+            open("Literal", out);
+            key("value", out);
+            json_ast(literal, out);
+            close(s, out);
+        }
+        Pattern::Or(patterns, s) => {
+            open("Or", out);
+            key("alternatives", out);
+            json_list(patterns, out, json_pattern);
+            close(s, out);
+        }
+        Pattern::Tuple(patterns, s) => {
+            open("Tuple", out);
+            key("elements", out);
+            json_list(patterns, out, json_pattern);
+            close(s, out);
+        }
+        Pattern::Variant(name, param, s) => {
+            open("Variant", out);
+            key("name", out);
+            json_string(name, out);
+            key("param", out);
+            match param {
+                Some(param) => json_pattern(param, out),
+                None => out.push_str("null"),
+            }
+            close(s, out);
+        }
+        Pattern::Wildcard(s) => {
+            open("Wildcard", out);
+            close(s, out);
+        }
+    }
+}
+
+fn json_ast(ast: &AST, out: &mut String) {
+    match ast {
+        AST::Ascription(expr, typ, s) => {
+            open("Ascription", out);
+            key("value", out);
+            json_ast(expr, out);
+            key("type", out);
+            json_string(&typ.to_string(), out);
+            close(s, out);
+        }
+        AST::BinaryOp(_, _, _, _) => {
+            let (first, links) = parser::chain(ast);
+            for (op, _, _) in links.iter().rev() {
+                open("BinaryOp", out);
+                key("op", out);
+                json_string(&op.to_string(), out);
+                key("lhs", out);
+            }
+            json_ast(first, out);
+            for (_, rhs, s) in links {
+                key("rhs", out);
+                json_ast(rhs, out);
+                close(&s, out);
+            }
+        }
+        AST::Boolean(b, s) => {
+            open("Boolean", out);
+            key("value", out);
+            out.push_str(&b.to_string());
+            close(s, out);
+        }
+        AST::Call(fun, arg, s) => {
+            open("Call", out);
+            key("fun", out);
+            json_ast(fun, out);
+            key("arg", out);
+            json_ast(arg, out);
+            close(s, out);
+        }
+        AST::Char(c, s) => {
+            open("Char", out);
+            key("value", out);
+            json_string(&c.to_string(), out);
+            close(s, out);
+        }
+        AST::Datatype(name, variants, s) => {
+            open("Datatype", out);
+            key("name", out);
+            json_string(name, out);
+            key("variants", out);
+            json_list(variants, out, |variant, out| {
+                out.push_str("{\"name\":");
+                json_string(&variant.0, out);
+                key("param", out);
+                match &variant.1 {
+                    Some(param) => json_ast(param, out),
+                    None => out.push_str("null"),
+                }
+                out.push('}');
+            });
+            close(s, out);
+        }
+        AST::Define(id, value, s) => {
+            open("Define", out);
+            key("id", out);
+            json_ast(id, out);
+            key("value", out);
+            json_ast(value, out);
+            close(s, out);
+        }
+        AST::Field(value, field, s) => {
+            open("Field", out);
+            key("value", out);
+            json_ast(value, out);
+            key("field", out);
+            json_string(field, out);
+            close(s, out);
+        }
+        AST::For(pattern, iter, body, s) => {
+            open("For", out);
+            key("pattern", out);
+            json_ast(pattern, out);
+            key("iter", out);
+            json_ast(iter, out);
+            key("body", out);
+            json_ast(body, out);
+            close(s, out);
+        }
+        AST::Function(id, param, body, s) => {
+            open("Function", out);
+            key("name", out);
+            match id {
+                Some(id) => json_string(id, out),
+                None => out.push_str("null"),
+            }
+            key("param", out);
+            json_ast(param, out);
+            key("body", out);
+            json_ast(body, out);
+            close(s, out);
+        }
+        AST::Identifier(id, s) => {
+            open("Identifier", out);
+            key("name", out);
+            json_string(id, out);
+            close(s, out);
+        }
+        AST::If(conds, els, s) => {
+            open("If", out);
+            key("conds", out);
+            json_list(conds, out, |cond, out| {
+                out.push_str("{\"cond\":");
+                json_ast(&cond.0, out);
+                key("then", out);
+                json_ast(&cond.1, out);
+                out.push('}');
+            });
+            key("else", out);
+            json_ast(els, out);
+            close(s, out);
+        }
+        AST::Index(value, index, s) => {
+            open("Index", out);
+            key("value", out);
+            json_ast(value, out);
+            key("index", out);
+            json_ast(index, out);
+            close(s, out);
+        }
+        AST::Integer(i, s) => {
+            open("Integer", out);
+            key("value", out);
+            out.push_str(&i.to_string());
+            close(s, out);
+        }
+        AST::Match(cond, cases, s) => {
+            open("Match", out);
+            key("value", out);
+            json_ast(cond, out);
+            key("cases", out);
+            json_list(cases, out, |case, out| {
+                out.push_str("{\"pattern\":");
+                json_pattern(&case.0, out);
+                key("body", out);
+                json_ast(&case.1, out);
+                out.push('}');
+            });
+            close(s, out);
+        }
+        AST::Program(expressions, s) => {
+            open("Program", out);
+            key("expressions", out);
+            json_list(expressions, out, json_ast);
+            close(s, out);
+        }
+        AST::Record(fields, s) => {
+            open("Record", out);
+            key("fields", out);
+            json_list(fields, out, |field, out| {
+                out.push_str("{\"name\":");
+                json_string(&field.0, out);
+                key("value", out);
+                json_ast(&field.1, out);
+                out.push('}');
+            });
+            close(s, out);
+        }
+        AST::Slice(value, start, end, s) => {
+            open("Slice", out);
+            key("value", out);
+            json_ast(value, out);
+            key("start", out);
+            json_ast(start, out);
+            key("end", out);
+            json_ast(end, out);
+            close(s, out);
+        }
+        AST::Spread(ast, s) => {
+            open("Spread", out);
+            key("value", out);
+            json_ast(ast, out);
+            close(s, out);
+        }
+        AST::String(string, s) => {
+            open("String", out);
+            key("value", out);
+            json_string(string, out);
+            close(s, out);
+        }
+        AST::Tuple(elements, s) => {
+            open("Tuple", out);
+            key("elements", out);
+            json_list(elements, out, json_ast);
+            close(s, out);
+        }
+        AST::UnaryOp(op, ast, s) => {
+            open("UnaryOp", out);
+            key("op", out);
+            json_string(&op.to_string(), out);
+            key("value", out);
+            json_ast(ast, out);
+            close(s, out);
+        }
+        AST::Unit(s) => {
+            open("Unit", out);
+            close(s, out);
+        }
+    }
+}
+
+pub fn ast(ast: &AST) -> String {
+    let mut out = String::new();
+    dump_ast(ast, 0, &mut out);
+    out
+}
+
+pub fn typed_ast(ast: &TypedAST) -> String {
+    let mut out = String::new();
+    dump_typed_ast(ast, 0, &mut out);
+    out
+}
+
+// The parsed AST as a single line of JSON, for tools written in other
+// languages.
+pub fn json(ast: &AST) -> String {
+    let mut out = String::new();
+    json_ast(ast, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::dump;
+    use crate::parser;
+    use crate::typeinfer;
+
+    #[test]
+    fn dumps() {
+        let mut ids = HashMap::new();
+        match parser::parse("def f := fn x -> x + 1 end\nf (2)") {
+            Ok(ast) => {
+                assert_eq!(
+                    dump::ast(&ast),
+                    "Program 1:1-2:6
+  Define 1:1-1:27
+    Identifier f 1:5-1:6
+    Function 1:10-1:27
+      Identifier x 1:13-1:14
+      Program 1:18-1:23
+        BinaryOp + 1:18-1:23
+          Identifier x 1:18-1:19
+          Integer 1 1:22-1:23
+  Call 2:1-2:6
+    Identifier f 2:1-2:2
+    Integer 2 2:4-2:5
+"
+                );
+                match typeinfer::infer(&ast, &mut ids) {
+                    Ok(typed_ast) => {
+                        assert_eq!(
+                            dump::typed_ast(&typed_ast),
+                            "Program : integer
+  Define f : integer -> integer
+    Function : integer -> integer
+      Identifier x : integer
+      Program : integer
+        BinaryOp + : integer
+          Identifier x : integer
+          Integer 1 : integer
+  Call : integer
+    Identifier f : integer -> integer
+    Integer 2 : integer
+"
+                        );
+                    }
+                    Err(_) => {
+                        unreachable!();
+                    }
+                }
+            }
+            Err(_) => {
+                unreachable!();
+            }
+        }
+    }
+
+    #[test]
+    fn dumps_json() {
+        match parser::parse("f (\"a\\\"\\n\", 'b')\nmatch x with Some (y) -> -y | _ -> 0 end") {
+            Ok(ast) => assert_eq!(
+                dump::json(&ast),
+                concat!(
+                    r#"{"kind":"Program","expressions":["#,
+                    r#"{"kind":"Call","fun":{"kind":"Identifier","name":"f","span":{"line":1,"col":1,"end_line":1,"end_col":2}},"#,
+                    r#""arg":{"kind":"Tuple","elements":["#,
+                    r#"{"kind":"String","value":"a\"\n","span":{"line":1,"col":4,"end_line":1,"end_col":11}},"#,
+                    r#"{"kind":"Char","value":"b","span":{"line":1,"col":13,"end_line":1,"end_col":16}}],"#,
+                    r#""span":{"line":1,"col":3,"end_line":1,"end_col":17}},"span":{"line":1,"col":1,"end_line":1,"end_col":17}},"#,
+                    r#"{"kind":"Match","value":{"kind":"Identifier","name":"x","span":{"line":2,"col":7,"end_line":2,"end_col":8}},"#,
+                    r#""cases":[{"pattern":{"kind":"Variant","name":"Some","param":{"kind":"Identifier","name":"y","span":{"line":2,"col":20,"end_line":2,"end_col":21}},"#,
+                    r#""span":{"line":2,"col":14,"end_line":2,"end_col":22}},"#,
+                    r#""body":{"kind":"UnaryOp","op":"-","value":{"kind":"Identifier","name":"y","span":{"line":2,"col":27,"end_line":2,"end_col":28}},"span":{"line":2,"col":26,"end_line":2,"end_col":28}}},"#,
+                    r#"{"pattern":{"kind":"Wildcard","span":{"line":2,"col":31,"end_line":2,"end_col":32}},"#,
+                    r#""body":{"kind":"Integer","value":0,"span":{"line":2,"col":36,"end_line":2,"end_col":37}}}],"#,
+                    r#""span":{"line":2,"col":1,"end_line":2,"end_col":41}}],"#,
+                    r#""span":{"line":1,"col":1,"end_line":2,"end_col":41}}"#,
+                )
+            ),
+            Err(_) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,458 @@
+// Line editing for the REPL, with tab completion of keywords and of the names
+// bound at the top level, and the usual keys for moving the cursor and
+// deleting. Terminals are put into a mode where keys arrive as they are typed,
+// which is only done on Unix. Elsewhere lines are read as they are, without
+// completion.
+use std::io::{self, Write};
+
+use crate::parser::KEYWORDS;
+use crate::vm::VirtualMachine;
+
+// Completions for the name being typed at the end of line, returned along
+// with the byte offset where that name starts. Numbers are not completed.
+pub fn complete(vm: &VirtualMachine, line: &str) -> (usize, Vec<String>) {
+    let start = line
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(0, |i| i + 1);
+    let word = &line[start..];
+    if word.is_empty() || word.starts_with(|c: char| c.is_ascii_digit()) {
+        return (start, Vec::new());
+    }
+    let mut candidates: Vec<String> = KEYWORDS
+        .iter()
+        .copied()
+        .chain(vm.env.names())
+        .filter(|name| name.starts_with(word))
+        .map(|name| name.to_string())
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    (start, candidates)
+}
+
+// The longest prefix shared by every candidate.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn common_prefix(candidates: &[String]) -> &str {
+    let first = match candidates.first() {
+        Some(first) => first,
+        None => return "",
+    };
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first[..len]
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(candidate.len()), |((i, _), _)| i);
+    }
+    &first[..len]
+}
+
+// Keys that the editor handles, decoded from the bytes a terminal sends.
+#[cfg_attr(not(unix), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+enum Key {
+    Backspace,
+    Char(u8),
+    Delete,
+    End,
+    EndOfInput,
+    Enter,
+    Home,
+    Interrupt,
+    KillLine,
+    KillToEnd,
+    KillWord,
+    Left,
+    Other,
+    Right,
+    Tab,
+}
+
+// Decodes the key that starts with byte, calling next for the rest of an
+// escape sequence. Sequences that aren't known are read in full and ignored.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn decode(byte: u8, next: &mut impl FnMut() -> io::Result<Option<u8>>) -> io::Result<Key> {
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::EndOfInput,
+        5 => Key::End,
+        6 => Key::Right,
+        8 | 127 => Key::Backspace,
+        11 => Key::KillToEnd,
+        21 => Key::KillLine,
+        23 => Key::KillWord,
+        // Arrow keys send ESC [ or ESC O and a letter, and keys such as
+        // Delete send ESC [, a number and ~.
+        27 => match next()? {
+            Some(b'[') | Some(b'O') => {
+                let mut params = Vec::new();
+                loop {
+                    match next()? {
+                        Some(byte) if (0x40..=0x7e).contains(&byte) => {
+                            break match (&params[..], byte) {
+                                (_, b'C') => Key::Right,
+                                (_, b'D') => Key::Left,
+                                (_, b'F') | (b"4", b'~') | (b"8", b'~') => Key::End,
+                                (_, b'H') | (b"1", b'~') | (b"7", b'~') => Key::Home,
+                                (b"3", b'~') => Key::Delete,
+                                _ => Key::Other,
+                            };
+                        }
+                        Some(byte) => params.push(byte),
+                        None => break Key::Other,
+                    }
+                }
+            }
+            _ => Key::Other,
+        },
+        byte if byte < 0x20 => Key::Other,
+        byte => Key::Char(byte),
+    };
+    Ok(key)
+}
+
+// The line being edited, with the cursor as a byte offset into it. Between
+// keys the cursor is always at the start of a character.
+#[cfg_attr(not(unix), allow(dead_code))]
+#[derive(Debug, Default)]
+struct Line {
+    text: Vec<u8>,
+    cursor: usize,
+}
+
+#[cfg_attr(not(unix), allow(dead_code))]
+impl Line {
+    // The offset of the character before the cursor, skipping back over the
+    // continuation bytes of its UTF-8 encoding.
+    fn previous(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 {
+            i -= 1;
+            if self.text[i] & 0xc0 != 0x80 {
+                break;
+            }
+        }
+        i
+    }
+
+    // The offset of the character after the one at the cursor.
+    fn next(&self) -> usize {
+        let mut i = self.cursor;
+        if i < self.text.len() {
+            i += 1;
+            while i < self.text.len() && self.text[i] & 0xc0 == 0x80 {
+                i += 1;
+            }
+        }
+        i
+    }
+
+    // Applies a key that moves the cursor or changes the text, returning
+    // false for other keys. Ctrl-U deletes everything before the cursor and
+    // Ctrl-W the word before it, along with any spaces after that word.
+    fn edit(&mut self, key: &Key) -> bool {
+        match key {
+            Key::Backspace => {
+                let start = self.previous();
+                self.text.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Char(byte) => {
+                self.text.insert(self.cursor, *byte);
+                self.cursor += 1;
+            }
+            Key::Delete => {
+                let end = self.next();
+                self.text.drain(self.cursor..end);
+            }
+            Key::End => self.cursor = self.text.len(),
+            Key::Home => self.cursor = 0,
+            Key::KillLine => {
+                self.text.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::KillToEnd => self.text.truncate(self.cursor),
+            Key::KillWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.text[start - 1].is_ascii_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.text[start - 1].is_ascii_whitespace() {
+                    start -= 1;
+                }
+                self.text.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Left => self.cursor = self.previous(),
+            Key::Right => self.cursor = self.next(),
+            _ => return false,
+        }
+        true
+    }
+
+    // Replaces the text from start up to the cursor, leaving the cursor after
+    // the replacement.
+    fn replace(&mut self, start: usize, with: &[u8]) {
+        self.text.splice(start..self.cursor, with.iter().copied());
+        self.cursor = start + with.len();
+    }
+}
+
+#[cfg(unix)]
+mod terminal {
+    use std::io::{self, Write};
+    use std::mem::MaybeUninit;
+
+    // Turns off line buffering, echo and signals from keys while it is
+    // alive, so that tab and control keys reach the editor.
+    pub struct Raw {
+        original: libc::termios,
+    }
+
+    impl Raw {
+        pub fn new() -> io::Result<Raw> {
+            let mut termios = MaybeUninit::uninit();
+            // These only read and write the termios structure they are given.
+            unsafe {
+                if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let original = termios.assume_init();
+                let mut raw = original;
+                raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+                raw.c_cc[libc::VMIN] = 1;
+                raw.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Raw { original })
+            }
+        }
+    }
+
+    impl Drop for Raw {
+        fn drop(&mut self) {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+            }
+        }
+    }
+
+    // Prints the prompt and line again, over whatever was on the line before,
+    // and moves back to the cursor.
+    pub fn redraw(out: &mut impl Write, prompt: &str, line: &super::Line) -> io::Result<()> {
+        write!(out, "\r\x1b[K{}", prompt)?;
+        out.write_all(&line.text)?;
+        let after = line.text[line.cursor..]
+            .iter()
+            .filter(|byte| *byte & 0xc0 != 0x80)
+            .count();
+        if after > 0 {
+            write!(out, "\x1b[{}D", after)?;
+        }
+        out.flush()
+    }
+
+    // Reads a byte from stdin, or None at the end of input.
+    pub fn read_byte() -> io::Result<Option<u8>> {
+        let mut byte = 0u8;
+        loop {
+            let read = unsafe { libc::read(libc::STDIN_FILENO, &mut byte as *mut u8 as *mut _, 1) };
+            match read {
+                1 => return Ok(Some(byte)),
+                0 => return Ok(None),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Reads a line from a terminal after printing prompt, returning None at the
+// end of input. Tab completes the name before the cursor, or lists the choices
+// if there is more than one, and Ctrl-C fails with an Interrupted error. The
+// arrow keys, Home, End, Ctrl-A, Ctrl-E, Ctrl-B and Ctrl-F move the cursor,
+// and Backspace, Delete, Ctrl-U, Ctrl-W and Ctrl-K delete as they do in other
+// line editors.
+#[cfg(unix)]
+pub fn read_line(
+    prompt: &str,
+    complete: impl Fn(&str) -> (usize, Vec<String>),
+) -> io::Result<Option<String>> {
+    let _raw = terminal::Raw::new()?;
+    let mut out = io::stdout();
+    let mut line = Line::default();
+    terminal::redraw(&mut out, prompt, &line)?;
+    loop {
+        let byte = match terminal::read_byte()? {
+            Some(byte) => byte,
+            None => {
+                if line.text.is_empty() {
+                    write!(out, "\r\n")?;
+                    return Ok(None);
+                }
+                break;
+            }
+        };
+        match decode(byte, &mut terminal::read_byte)? {
+            Key::Enter => break,
+            // Ctrl-D ends the input, but only on an empty line.
+            Key::EndOfInput if line.text.is_empty() => {
+                write!(out, "\r\n")?;
+                return Ok(None);
+            }
+            Key::Interrupt => {
+                write!(out, "^C\r\n")?;
+                out.flush()?;
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+            Key::Tab => {
+                let text = String::from_utf8_lossy(&line.text[..line.cursor]).to_string();
+                let (start, candidates) = complete(&text);
+                let prefix = common_prefix(&candidates);
+                if prefix.len() > text.len() - start {
+                    let mut with = prefix.as_bytes().to_vec();
+                    if candidates.len() == 1 {
+                        with.push(b' ');
+                    }
+                    line.replace(start, &with);
+                } else if candidates.len() > 1 {
+                    write!(out, "\r\n{}\r\n", candidates.join("  "))?;
+                }
+                terminal::redraw(&mut out, prompt, &line)?;
+            }
+            // Typing at the end of the line only needs the byte echoed.
+            Key::Char(byte) if line.cursor == line.text.len() => {
+                line.edit(&Key::Char(byte));
+                out.write_all(&[byte])?;
+                out.flush()?;
+            }
+            key => {
+                if line.edit(&key) {
+                    terminal::redraw(&mut out, prompt, &line)?;
+                }
+            }
+        }
+    }
+    write!(out, "\r\n")?;
+    out.flush()?;
+    Ok(Some(String::from_utf8_lossy(&line.text).to_string()))
+}
+
+#[cfg(not(unix))]
+pub fn read_line(
+    prompt: &str,
+    _complete: impl Fn(&str) -> (usize, Vec<String>),
+) -> io::Result<Option<String>> {
+    let mut out = io::stdout();
+    write!(out, "{}", prompt)?;
+    out.flush()?;
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::editor::{common_prefix, complete, decode, Key, Line};
+    use crate::Interpreter;
+
+    #[test]
+    fn completes() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("def total := 1 fn twice (x) -> 2 * x end") {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        let complete = |line| complete(&interpreter.vm, line);
+        assert_eq!(complete("tw"), (0, vec!["twice".to_string()]));
+        assert_eq!(complete("1 + tot"), (4, vec!["total".to_string()]));
+        let (start, candidates) = complete("if t");
+        assert_eq!(start, 3);
+        assert_eq!(
+            candidates,
+            ["then", "to_lower", "to_upper", "total", "true", "twice", "type"]
+        );
+        assert_eq!(complete("el").1, ["else", "elsif"]);
+        assert_eq!(complete("print (1"), (7, vec![]));
+        assert_eq!(complete("x + "), (4, vec![]));
+        assert_eq!(common_prefix(&complete("el").1), "els");
+        assert_eq!(common_prefix(&[]), "");
+    }
+
+    fn decoded(bytes: &[u8]) -> Key {
+        let mut rest = bytes[1..].iter().copied();
+        match decode(bytes[0], &mut || Ok(rest.next())) {
+            Ok(key) => {
+                assert_eq!(rest.next(), None);
+                key
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn decodes_keys() {
+        assert_eq!(decoded(b"a"), Key::Char(b'a'));
+        assert_eq!(decoded(b"\r"), Key::Enter);
+        assert_eq!(decoded(b"\x7f"), Key::Backspace);
+        assert_eq!(decoded(b"\x15"), Key::KillLine);
+        assert_eq!(decoded(b"\x17"), Key::KillWord);
+        assert_eq!(decoded(b"\x1b[C"), Key::Right);
+        assert_eq!(decoded(b"\x1b[D"), Key::Left);
+        assert_eq!(decoded(b"\x1bOD"), Key::Left);
+        assert_eq!(decoded(b"\x1b[H"), Key::Home);
+        assert_eq!(decoded(b"\x1b[4~"), Key::End);
+        assert_eq!(decoded(b"\x1b[3~"), Key::Delete);
+        assert_eq!(decoded(b"\x1b[1;5C"), Key::Right);
+        assert_eq!(decoded(b"\x1b[15~"), Key::Other);
+        assert_eq!(decoded(b"\x1b[A"), Key::Other);
+        assert_eq!(decoded(b"\x00"), Key::Other);
+    }
+
+    #[test]
+    fn edits_lines() {
+        let mut line = Line::default();
+        let text = |line: &Line| String::from_utf8_lossy(&line.text).to_string();
+        for byte in "def é := 1".bytes() {
+            assert!(line.edit(&Key::Char(byte)));
+        }
+        assert_eq!(line.cursor, 11);
+        assert!(line.edit(&Key::KillWord));
+        assert_eq!(text(&line), "def é := ");
+        assert!(line.edit(&Key::KillWord));
+        assert_eq!(text(&line), "def é ");
+        line.edit(&Key::Left);
+        line.edit(&Key::Left);
+        assert_eq!(line.cursor, 4);
+        line.edit(&Key::Delete);
+        assert_eq!(text(&line), "def  ");
+        line.edit(&Key::Char(b'x'));
+        line.edit(&Key::Right);
+        line.edit(&Key::Backspace);
+        assert_eq!((text(&line).as_str(), line.cursor), ("def x", 5));
+        line.edit(&Key::Home);
+        line.edit(&Key::Right);
+        line.edit(&Key::KillToEnd);
+        assert_eq!(text(&line), "d");
+        line.edit(&Key::End);
+        line.replace(0, b"double ");
+        assert_eq!((text(&line).as_str(), line.cursor), ("double ", 7));
+        line.edit(&Key::Left);
+        line.edit(&Key::KillLine);
+        assert_eq!((text(&line).as_str(), line.cursor), (" ", 0));
+        assert!(!line.edit(&Key::Tab));
+        assert!(!line.edit(&Key::Other));
+    }
+}
@@ -0,0 +1,59 @@
+// Entry points for fuzzing. Each one takes arbitrary bytes, such as those
+// provided by cargo fuzz, and must not panic whatever the input.
+use crate::arbitrary::{program, Input};
+use crate::codegen;
+use crate::diagnostics::Code;
+use crate::parser;
+use crate::vm;
+
+pub fn parse(data: &[u8]) {
+    let src = String::from_utf8_lossy(data);
+    let _ = parser::parse(&src);
+    let _ = parser::incomplete(&src);
+}
+
+pub fn eval(data: &[u8]) {
+    let ast = program(&mut Input::new(data));
+    let mut vm = vm::VirtualMachine::new();
+    if let Err(err) = codegen::eval(&mut vm, &ast) {
+        // Generated programs are well typed, so only runtime errors are
+        // expected.
+        assert!(
+            err.code == Code::DivisionByZero || err.code == Code::IntegerOverflow,
+            "{}",
+            err.err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arbitrary::Random;
+    use crate::fuzz;
+
+    #[test]
+    fn fuzzes() {
+        // Inputs that have made parsing panic before.
+        for src in [
+            "99999999999999999999",
+            "match x with 99999999999999999999 -> 1 end",
+            "match x with -99999999999999999999 -> 1 end",
+        ] {
+            fuzz::parse(src.as_bytes());
+        }
+        let alphabet = "fn if then elsif else end def match with type := -> | ( ) , : \
+                        x y 1 42 + - * / % == ~= < <= && || ~ true false # é\n"
+            .as_bytes();
+        let mut random = Random::new();
+        for _ in 0..1000 {
+            let bytes = random.bytes(64);
+            fuzz::parse(&bytes);
+            let src: Vec<u8> = bytes
+                .iter()
+                .map(|b| alphabet[*b as usize % alphabet.len()])
+                .collect();
+            fuzz::parse(&src);
+            fuzz::eval(&bytes);
+        }
+    }
+}
@@ -0,0 +1,621 @@
+use std::error;
+use std::fmt;
+
+use crate::codegen;
+use crate::codegen::InterpreterError;
+use crate::parser;
+use crate::parser::ParseError;
+use crate::typeinfer::Type;
+use crate::vm::{Value, VirtualMachine};
+
+#[derive(Debug)]
+pub enum Error {
+    Parse(Vec<ParseError>),
+    Interpreter(InterpreterError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(errors) => {
+                for i in 0..errors.len() {
+                    write!(f, "{}", errors[i].msg)?;
+                    if i + 1 != errors.len() {
+                        writeln!(f)?;
+                    }
+                }
+                Ok(())
+            }
+            Error::Interpreter(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<InterpreterError> for Error {
+    fn from(err: InterpreterError) -> Error {
+        Error::Interpreter(err)
+    }
+}
+
+impl From<Vec<ParseError>> for Error {
+    fn from(errors: Vec<ParseError>) -> Error {
+        Error::Parse(errors)
+    }
+}
+
+// Evaluates programs for an embedding application. Definitions persist from
+// one evaluation to the next, as in the REPL.
+pub struct Interpreter {
+    pub vm: VirtualMachine,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            vm: VirtualMachine::new(),
+        }
+    }
+
+    pub fn eval_str(&mut self, src: &str) -> Result<Value, Error> {
+        let ast = parser::parse(src)?;
+        Ok(codegen::eval(&mut self.vm, &ast)?)
+    }
+
+    // Evaluates a program as eval_str does, along with the type inferred for
+    // it.
+    pub fn eval_str_typed(&mut self, src: &str) -> Result<(Value, Type), Error> {
+        let ast = parser::parse(src)?;
+        Ok(codegen::eval_typed(&mut self.vm, &ast)?)
+    }
+
+    pub async fn eval_str_async(&mut self, src: &str) -> Result<Value, Error> {
+        let ast = parser::parse(src)?;
+        Ok(codegen::eval_async(&mut self.vm, &ast).await?)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::codegen;
+    use crate::diagnostics::Code;
+    use crate::parser;
+    use crate::typeinfer::Type;
+    use crate::vm::Value;
+    use crate::{Error, Interpreter};
+
+    #[test]
+    fn interprets() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("fn double (x) -> 2 * x end") {
+            Ok(Value::Function(_, _)) => {}
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("double (21)") {
+            Ok(value) => assert_eq!(value, Value::Integer(42)),
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("double (") {
+            Err(Error::Parse(errors)) => assert_eq!(errors[0].span.line, 1),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("double (1) / 0") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.to_string(), "InterpreterError: Division by zero.")
+            }
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("double (double (1))") {
+            Ok(value) => assert_eq!(value, Value::Integer(4)),
+            Err(_) => unreachable!(),
+        }
+        // Empty input does nothing.
+        for src in ["", "# double (2)"] {
+            match interpreter.eval_str(src) {
+                Ok(value) => assert_eq!(value, Value::Unit),
+                Err(_) => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn returns_types() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str_typed("fn double (x) -> 2 * x end") {
+            Ok((Value::Function(_, _), typ)) => assert_eq!(typ.to_string(), "integer -> integer"),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str_typed("double (21)") {
+            Ok((value, typ)) => {
+                assert_eq!(value, Value::Integer(42));
+                assert_eq!(typ, Type::Integer);
+                assert_eq!(format!("{} : {}", value, typ), "42 : integer");
+            }
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str_typed("(double, \"a\", ((1 : any) : integer | unit))") {
+            Ok((_, typ)) => {
+                assert_eq!(
+                    typ.to_string(),
+                    "(integer -> integer, string, integer | unit)"
+                )
+            }
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str_typed("double (true)") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.err, "Type error: expected integer but found boolean.")
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn discards_failed_evaluations() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("def x := 1") {
+            Ok(value) => assert_eq!(value, Value::Integer(1)),
+            Err(_) => unreachable!(),
+        }
+        let len = interpreter.vm.statistics().instructions;
+        match interpreter.eval_str("def x := 2\ndef y := 3\nx / 0") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Division by zero."),
+            _ => unreachable!(),
+        }
+        assert_eq!(interpreter.vm.statistics().instructions, len);
+        assert!(interpreter.vm.stack.is_empty());
+        match interpreter.eval_str("x") {
+            Ok(value) => assert_eq!(value, Value::Integer(1)),
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("y") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Unknown identifier: y."),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn keeps_closures_from_failed_evaluations() {
+        let mut interpreter = Interpreter::new();
+        assert!(interpreter.eval_str("def c := channel (())").is_ok());
+        match interpreter.eval_str("send (c, fn (x) -> \"hello\" end) 1 / 0") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Division by zero."),
+            _ => unreachable!(),
+        }
+        assert!(interpreter.eval_str("def f := receive (c)").is_ok());
+        match interpreter.eval_str("string_length (f (1))") {
+            Ok(value) => assert_eq!(value, Value::Integer(5)),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn calls_native_functions() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm.register_fn(
+            "max",
+            Type::Function(
+                Arc::new(Type::Tuple(Arc::new([Type::Integer, Type::Integer]))),
+                Arc::new(Type::Integer),
+            ),
+            |args| match (&args[0], &args[1]) {
+                (Value::Integer(x), Value::Integer(y)) => Ok(Value::Integer(*x.max(y))),
+                _ => Err("max: expected integers.".to_string()),
+            },
+        );
+        interpreter.vm.register_fn(
+            "check",
+            Type::Function(Arc::new(Type::Boolean), Arc::new(Type::Unit)),
+            |args| match args[0] {
+                Value::Boolean(true) => Ok(Value::Unit),
+                _ => Err("Check failed.".to_string()),
+            },
+        );
+        match interpreter.eval_str("max (3, 7) - max (7, 3)") {
+            Ok(value) => assert_eq!(value, Value::Integer(0)),
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("def biggest := max\nbiggest (1, 2)") {
+            Ok(value) => assert_eq!(value, Value::Integer(2)),
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("max (1, true)") {
+            Err(Error::Interpreter(err)) => assert_eq!(
+                err.err,
+                "Type error: expected (integer, integer) but found (integer, boolean)."
+            ),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("check (1 < 0)") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.err, "Check failed.");
+                assert_eq!((err.span.line, err.span.col), (1, 1));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn captures_output() {
+        let mut interpreter = Interpreter::new();
+        let out = Arc::new(Mutex::new(Vec::new()));
+        interpreter.vm.stdout = out.clone();
+        let stdout = interpreter.vm.stdout.clone();
+        interpreter.vm.register_fn(
+            "show",
+            Type::Function(Arc::new(Type::Integer), Arc::new(Type::Unit)),
+            move |args| {
+                let mut stdout = stdout.lock().map_err(|err| err.to_string())?;
+                writeln!(stdout, "{}", args[0]).map_err(|err| err.to_string())?;
+                Ok(Value::Unit)
+            },
+        );
+        match interpreter.eval_str("show (6 * 7)") {
+            Ok(value) => assert_eq!(value, Value::Unit),
+            Err(_) => unreachable!(),
+        }
+        interpreter.vm.dump_ast = true;
+        match interpreter.eval_str("true") {
+            Ok(value) => assert_eq!(value, Value::Boolean(true)),
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("1 + 2") {
+            Ok(value) => assert_eq!(value, Value::Integer(3)),
+            Err(_) => unreachable!(),
+        }
+        let out = match out.lock() {
+            Ok(out) => String::from_utf8(out.clone()).unwrap_or_default(),
+            Err(_) => unreachable!(),
+        };
+        assert!(out.starts_with("42\n"));
+        assert!(out.contains("true"));
+        // The typed tree is dumped before it is optimized.
+        assert!(out.contains("BinaryOp + : integer"));
+    }
+
+    #[test]
+    fn resets() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm.register_fn(
+            "double",
+            Type::Function(Arc::new(Type::Integer), Arc::new(Type::Integer)),
+            |args| match args[0] {
+                Value::Integer(x) => Ok(Value::Integer(2 * x)),
+                _ => Err("double: expected an integer.".to_string()),
+            },
+        );
+        match interpreter.eval_str("def x := double (2)") {
+            Ok(value) => assert_eq!(value, Value::Integer(4)),
+            Err(_) => unreachable!(),
+        }
+        let result = interpreter.vm.isolated(|vm| {
+            match parser::parse("def y := x + 1\nfn f () -> y end\nf ()") {
+                Ok(ast) => codegen::eval(vm, &ast).ok(),
+                Err(_) => None,
+            }
+        });
+        assert_eq!(result, Some(Value::Integer(5)));
+        let len = interpreter.vm.statistics().instructions;
+        match interpreter.eval_str("y") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Unknown identifier: y."),
+            _ => unreachable!(),
+        }
+        assert_eq!(interpreter.vm.statistics().instructions, len);
+        interpreter.vm.reset();
+        assert_eq!(interpreter.vm.statistics().instructions, 0);
+        match interpreter.eval_str("x") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Unknown identifier: x."),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("double (3)") {
+            Ok(value) => assert_eq!(value, Value::Integer(6)),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn restores_snapshots() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("def x := 1") {
+            Ok(value) => assert_eq!(value, Value::Integer(1)),
+            Err(_) => unreachable!(),
+        }
+        let snapshot = interpreter.vm.snapshot();
+        match interpreter.eval_str("def y := 2\ndef x := y / 0") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Division by zero."),
+            _ => unreachable!(),
+        }
+        interpreter.vm.restore(snapshot.clone());
+        match interpreter.eval_str("y") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Unknown identifier: y."),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("def x := x + 1") {
+            Ok(value) => assert_eq!(value, Value::Integer(2)),
+            Err(_) => unreachable!(),
+        }
+        interpreter.vm.restore(snapshot);
+        match interpreter.eval_str("x") {
+            Ok(value) => assert_eq!(value, Value::Integer(1)),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn reports_statistics() {
+        let mut interpreter = Interpreter::new();
+        let before = interpreter.vm.statistics();
+        assert_eq!(before.executed, 0);
+        assert_eq!(before.max_stack, 0);
+        match interpreter.eval_str("def x := (1, 2, 3)") {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        let after = interpreter.vm.statistics();
+        assert_eq!(after.env_entries, before.env_entries + 1);
+        assert!(after.instructions > before.instructions);
+        assert!(after.executed > 0);
+        assert!(after.max_stack >= 3);
+        // A closure and the tuple it returns.
+        match interpreter.eval_str("fn pair (y) -> (y, y) end pair (1)") {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        assert_eq!(interpreter.vm.statistics().allocated, after.allocated + 2);
+        interpreter.vm.reset();
+        assert_eq!(interpreter.vm.statistics(), before);
+    }
+
+    #[test]
+    fn limits_allocations() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm.max_allocations = Some(10);
+        let src = "type List := Nil | Cons (integer, List) end
+                   fn countdown (n) -> if n == 0 then Nil else Cons (n, countdown (n - 1)) end end";
+        match interpreter.eval_str(src) {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("def deep := countdown (20)") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.code, Code::AllocationLimit),
+            _ => unreachable!(),
+        }
+        // The limit applies to each program, and one that was stopped defines
+        // nothing.
+        assert!(interpreter.eval_str("countdown (2)").is_ok());
+        assert!(interpreter.eval_str("deep").is_err());
+    }
+
+    #[test]
+    fn limits_evaluation() {
+        let mut interpreter = Interpreter::new();
+        let src = "fn forever (n) -> forever (n + 1) end";
+        match interpreter.eval_str(src) {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        interpreter.vm.max_instructions = Some(1000);
+        match interpreter.eval_str("def x := forever (0)") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.code, Code::EvaluationLimit);
+                assert_eq!(
+                    err.err,
+                    "Evaluation exceeded limit: more than 1000 instructions."
+                );
+            }
+            _ => unreachable!(),
+        }
+        // The limit applies to each program, which can define things again
+        // once one has been stopped.
+        assert!(interpreter.eval_str("def x := 1").is_ok());
+        interpreter.vm.max_instructions = None;
+        interpreter.vm.max_time = Some(Duration::from_millis(10));
+        match interpreter.eval_str("forever (0)") {
+            Err(Error::Interpreter(err)) => {
+                assert_eq!(err.code, Code::EvaluationLimit);
+                assert_eq!(err.err, "Evaluation exceeded limit: more than 10 ms.");
+            }
+            _ => unreachable!(),
+        }
+        assert!(interpreter.eval_str("x + 1").is_ok());
+    }
+
+    #[test]
+    fn compares_functions() {
+        let mut interpreter = Interpreter::new();
+        let src = "type Box := Box (a) end fn f (x) -> x end";
+        match interpreter.eval_str(src) {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("Box (f) == Box (f)") {
+            Err(Error::Interpreter(err)) => assert_eq!(err.code, Code::CompareFunctions),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("Box (1) ~= Box (2)") {
+            Ok(Value::Boolean(true)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn frees_code() {
+        let mut interpreter = Interpreter::new();
+        let mut chunks = Vec::new();
+        for src in &[
+            "fn double (x) -> 2 * x end",
+            "fn (x) -> x + 1 end",
+            "fn adder (n) -> fn (x) -> x + double (n) end end",
+            "adder (2)",
+            "def add4 := adder (2)",
+        ] {
+            match interpreter.eval_str(src) {
+                Ok(Value::Function(chunk, _)) => chunks.push(Arc::downgrade(&chunk)),
+                _ => unreachable!(),
+            }
+        }
+        match interpreter.eval_str("add4 (1)") {
+            Ok(value) => assert_eq!(value, Value::Integer(5)),
+            Err(_) => unreachable!(),
+        }
+        let live: Vec<bool> = chunks
+            .iter()
+            .map(|chunk| chunk.upgrade().is_some())
+            .collect();
+        assert_eq!(live, vec![true, false, true, true, true]);
+        // The chunk is shared by every closure made from the same code.
+        assert!(chunks[3].ptr_eq(&chunks[4]));
+    }
+
+    #[test]
+    fn caches() {
+        let mut interpreter = Interpreter::new();
+        let src = "fn double (x) -> 2 * x end
+                   def quad := fn (x) -> double (2 * x) end
+                   quad (3)";
+        let mut lens = Vec::new();
+        for src in &[src, src, &src.replace("2 * x", "x + x")] {
+            match interpreter.eval_str(src) {
+                Ok(value) => assert_eq!(value.to_string(), "12"),
+                Err(_) => unreachable!(),
+            }
+            lens.push(interpreter.vm.statistics().instructions);
+        }
+        assert_eq!(interpreter.vm.cache.len(), 4);
+        assert!(lens[1] - lens[0] < lens[0]);
+        assert_eq!(lens[2] - lens[1], lens[0]);
+
+        // Definitions that aren't cached are still compiled again.
+        match interpreter.eval_str("fn double (x) -> x * \"twice\" end") {
+            Err(_) => {}
+            Ok(_) => unreachable!(),
+        }
+        match interpreter.eval_str("def k := 2 fn triple (x) -> x * 3 end triple (k)") {
+            Ok(value) => assert_eq!(value.to_string(), "6"),
+            Err(_) => unreachable!(),
+        }
+        match interpreter.eval_str("fn twice (f) -> f end") {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        assert_eq!(interpreter.vm.cache.len(), 5);
+    }
+
+    #[test]
+    fn moves_across_threads() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("fn double (x) -> 2 * x end") {
+            Ok(_) => {}
+            Err(_) => unreachable!(),
+        }
+        let interpreter = Arc::new(Mutex::new(interpreter));
+        let workers: Vec<_> = (1..=4)
+            .map(|i| {
+                let interpreter = Arc::clone(&interpreter);
+                thread::spawn(move || match interpreter.lock() {
+                    Ok(mut interpreter) => interpreter.eval_str(&format!("double ({})", i)).ok(),
+                    Err(_) => None,
+                })
+            })
+            .collect();
+        let mut results: Vec<_> = workers
+            .into_iter()
+            .filter_map(|worker| worker.join().ok().flatten())
+            .collect();
+        results.sort_by_key(|value| value.to_string());
+        assert_eq!(
+            results,
+            vec![
+                Value::Integer(2),
+                Value::Integer(4),
+                Value::Integer(6),
+                Value::Integer(8)
+            ]
+        );
+    }
+
+    // Completes on the second poll, as if waiting for IO.
+    struct Delay(bool);
+
+    impl Future for Delay {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let mut future = Box::pin(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                return value;
+            }
+        }
+    }
+
+    #[test]
+    fn calls_async_functions() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm.register_async_fn(
+            "fetch",
+            Type::Function(
+                Arc::new(Type::Integer),
+                Arc::new(Type::Tuple(Arc::new([Type::Integer, Type::Boolean]))),
+            ),
+            |args| {
+                let key = args[0].clone();
+                async move {
+                    Delay(false).await;
+                    match key {
+                        Value::Integer(0) => Err("Not found.".to_string()),
+                        Value::Integer(x) => Ok(Value::Tuple(vec![
+                            Value::Integer(10 * x),
+                            Value::Boolean(true),
+                        ])),
+                        _ => Err("fetch: expected an integer.".to_string()),
+                    }
+                }
+            },
+        );
+        match block_on(
+            interpreter
+                .eval_str_async("fn first (x, y) -> x end\nfirst (fetch (1)) + first (fetch (2))"),
+        ) {
+            Ok(value) => assert_eq!(value, Value::Integer(30)),
+            Err(_) => unreachable!(),
+        }
+        match block_on(interpreter.eval_str_async("fetch (0)")) {
+            Err(Error::Interpreter(err)) => assert_eq!(err.err, "Not found."),
+            _ => unreachable!(),
+        }
+        match interpreter.eval_str("fetch (1)") {
+            Err(Error::Interpreter(err)) => assert_eq!(
+                err.err,
+                "Asynchronous functions can only be called with eval_async."
+            ),
+            _ => unreachable!(),
+        }
+    }
+}
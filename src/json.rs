@@ -0,0 +1,638 @@
+// Values and types as JSON, so that results can be sent to other processes,
+// stored and compared against golden files. Like the JSON dump of syntax
+// trees, each is an object with a kind and its parts by name. Functions,
+// channels and coroutines only exist within a running program, so they can't
+// be written.
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::dump::{json_list, json_string};
+use crate::typeinfer::{self, Type};
+use crate::vm::Value;
+
+// Deeper input is rejected rather than risk overflowing the stack.
+const MAX_NESTING: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    pub msg: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for JsonError {}
+
+fn invalid<T>(reason: &str) -> Result<T, JsonError> {
+    Err(JsonError {
+        msg: format!("Invalid JSON: {}.", reason),
+    })
+}
+
+fn open(kind: &str, out: &mut String) {
+    out.push_str("{\"kind\":\"");
+    out.push_str(kind);
+    out.push('"');
+}
+
+fn key(name: &str, out: &mut String) {
+    out.push_str(",\"");
+    out.push_str(name);
+    out.push_str("\":");
+}
+
+fn write_value(value: &Value, out: &mut String) -> Result<(), JsonError> {
+    match value {
+        Value::Boolean(b) => {
+            open("Boolean", out);
+            key("value", out);
+            out.push_str(&b.to_string());
+        }
+        Value::Char(c) => {
+            open("Char", out);
+            key("value", out);
+            json_string(&c.to_string(), out);
+        }
+        Value::Datatype(typ, variant, value) => {
+            open("Datatype", out);
+            key("type", out);
+            json_string(typ, out);
+            key("variant", out);
+            json_string(variant, out);
+            key("value", out);
+            write_value(value, out)?;
+        }
+        Value::Integer(i) => {
+            open("Integer", out);
+            key("value", out);
+            out.push_str(&i.to_string());
+        }
+        Value::Record(fields) => {
+            open("Record", out);
+            key("fields", out);
+            out.push('[');
+            for (i, (name, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"name\":");
+                json_string(name, out);
+                key("value", out);
+                write_value(value, out)?;
+                out.push('}');
+            }
+            out.push(']');
+        }
+        Value::String(s) => {
+            open("String", out);
+            key("value", out);
+            json_string(s, out);
+        }
+        Value::Tuple(elements) => {
+            open("Tuple", out);
+            key("elements", out);
+            out.push('[');
+            for (i, element) in elements.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(element, out)?;
+            }
+            out.push(']');
+        }
+        Value::Unit => open("Unit", out),
+        Value::Function(_, _) | Value::Native(_) => {
+            return Err(JsonError {
+                msg: "Functions can't be written as JSON.".to_string(),
+            });
+        }
+        Value::Channel(_) | Value::Coroutine(_) => {
+            return Err(JsonError {
+                msg: "Channels and coroutines can't be written as JSON.".to_string(),
+            });
+        }
+    }
+    out.push('}');
+    Ok(())
+}
+
+fn write_fields(fields: &[(String, Type)], out: &mut String) {
+    json_list(fields, out, |(name, typ), out| {
+        out.push_str("{\"name\":");
+        json_string(name, out);
+        key("type", out);
+        write_type(typ, out);
+        out.push('}');
+    });
+}
+
+fn write_type(typ: &Type, out: &mut String) {
+    match typ {
+        Type::Any => open("Any", out),
+        Type::Boolean => open("Boolean", out),
+        Type::Channel(element) => {
+            open("Channel", out);
+            key("element", out);
+            write_type(element, out);
+        }
+        Type::Char => open("Char", out),
+        Type::Datatype(name) => {
+            open("Datatype", out);
+            key("name", out);
+            json_string(name, out);
+        }
+        Type::Function(param, body) => {
+            open("Function", out);
+            key("param", out);
+            write_type(param, out);
+            key("body", out);
+            write_type(body, out);
+        }
+        Type::Integer => open("Integer", out),
+        Type::Never => open("Never", out),
+        Type::Polymorphic(name) => {
+            open("Polymorphic", out);
+            key("name", out);
+            json_string(name, out);
+        }
+        Type::Record(fields) => {
+            open("Record", out);
+            key("fields", out);
+            write_fields(fields, out);
+        }
+        Type::Repeated(element) => {
+            open("Repeated", out);
+            key("element", out);
+            write_type(element, out);
+        }
+        Type::Row(fields, row) => {
+            open("Row", out);
+            key("fields", out);
+            write_fields(fields, out);
+            key("row", out);
+            json_string(row, out);
+        }
+        Type::String => open("String", out),
+        Type::Tuple(elements) => {
+            open("Tuple", out);
+            key("elements", out);
+            json_list(elements, out, write_type);
+        }
+        Type::Union(members) => {
+            open("Union", out);
+            key("members", out);
+            json_list(members, out, write_type);
+        }
+        Type::Unit => open("Unit", out),
+    }
+    out.push('}');
+}
+
+pub fn from_value(value: &Value) -> Result<String, JsonError> {
+    let mut out = String::new();
+    write_value(value, &mut out)?;
+    Ok(out)
+}
+
+pub fn from_type(typ: &Type) -> String {
+    let mut out = String::new();
+    write_type(typ, &mut out);
+    out
+}
+
+// JSON as it is read, before it is made into a value or type. Only integers
+// are written, so other numbers are rejected.
+enum Json {
+    Array(Vec<Json>),
+    Boolean(bool),
+    Integer(i64),
+    Null,
+    Object(Vec<(String, Json)>),
+    String(String),
+}
+
+// The members of an object, in the order they are written.
+type Members = [(String, Json)];
+
+struct Reader<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.next() != Some(c) {
+            return invalid(&format!("expected {}", c));
+        }
+        Ok(())
+    }
+
+    fn json(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_NESTING {
+            return invalid("nested too deeply");
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some('[') => self.array(depth),
+            Some('{') => self.object(depth),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.integer(),
+            Some(_) => self.word(),
+            None => invalid("unexpected end"),
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut elements = Vec::new();
+        if !self.closed(']') {
+            loop {
+                elements.push(self.json(depth + 1)?);
+                if !self.more(']')? {
+                    break;
+                }
+            }
+        }
+        Ok(Json::Array(elements))
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        if !self.closed('}') {
+            loop {
+                self.skip_whitespace();
+                let name = self.string()?;
+                self.expect(':')?;
+                members.push((name, self.json(depth + 1)?));
+                if !self.more('}')? {
+                    break;
+                }
+            }
+        }
+        Ok(Json::Object(members))
+    }
+
+    fn word(&mut self) -> Result<Json, JsonError> {
+        for (word, json) in [
+            ("true", Json::Boolean(true)),
+            ("false", Json::Boolean(false)),
+            ("null", Json::Null),
+        ] {
+            if self.text[self.pos..].starts_with(word) {
+                self.pos += word.len();
+                return Ok(json);
+            }
+        }
+        invalid("unexpected character")
+    }
+
+    // Whether an array or object is empty, closing it if so.
+    fn closed(&mut self, close: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    // Whether another element follows the one just read, or the array or
+    // object is closed.
+    fn more(&mut self, close: char) -> Result<bool, JsonError> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(',') => Ok(true),
+            Some(c) if c == close => Ok(false),
+            _ => invalid(&format!("expected , or {}", close)),
+        }
+    }
+
+    fn integer(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if matches!(self.peek(), Some('.' | 'e' | 'E')) {
+            return invalid("only integers are supported");
+        }
+        match self.text[start..self.pos].parse() {
+            Ok(i) => Ok(Json::Integer(i)),
+            Err(_) => invalid("integer out of range"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        if self.next() != Some('"') {
+            return invalid("expected a string");
+        }
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => s.push(self.escape()?),
+                    _ => return invalid("bad escape"),
+                },
+                Some(c) if (c as u32) < 0x20 => return invalid("control character in string"),
+                Some(c) => s.push(c),
+                None => return invalid("unterminated string"),
+            }
+        }
+    }
+
+    // Characters outside the basic plane are escaped as a surrogate pair.
+    fn escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.text[self.pos..].starts_with("\\u") {
+                return invalid("unpaired surrogate");
+            }
+            self.pos += 2;
+            let low = self.hex()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return invalid("unpaired surrogate");
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => invalid("unpaired surrogate"),
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32, JsonError> {
+        let digits = self.text.get(self.pos..self.pos + 4).unwrap_or_default();
+        match u32::from_str_radix(digits, 16) {
+            Ok(code) if digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            _ => invalid("bad escape"),
+        }
+    }
+}
+
+fn read(text: &str) -> Result<Json, JsonError> {
+    let mut reader = Reader { text, pos: 0 };
+    let json = reader.json(0)?;
+    reader.skip_whitespace();
+    if reader.pos != text.len() {
+        return invalid("unexpected text after the end");
+    }
+    Ok(json)
+}
+
+// The kind of an object, along with all of its members.
+fn kind(json: &Json) -> Result<(&str, &Members), JsonError> {
+    if let Json::Object(members) = json {
+        for (name, member) in members {
+            if let ("kind", Json::String(kind)) = (name.as_str(), member) {
+                return Ok((kind, members));
+            }
+        }
+    }
+    invalid("expected an object with a kind")
+}
+
+fn member<'a>(members: &'a Members, name: &str) -> Result<&'a Json, JsonError> {
+    match members.iter().find(|member| member.0 == name) {
+        Some((_, json)) => Ok(json),
+        None => invalid(&format!("missing {}", name)),
+    }
+}
+
+fn string<'a>(members: &'a Members, name: &str) -> Result<&'a str, JsonError> {
+    match member(members, name)? {
+        Json::String(s) => Ok(s),
+        _ => invalid(&format!("expected {} to be a string", name)),
+    }
+}
+
+fn array<'a>(members: &'a Members, name: &str) -> Result<&'a [Json], JsonError> {
+    match member(members, name)? {
+        Json::Array(elements) => Ok(elements),
+        _ => invalid(&format!("expected {} to be an array", name)),
+    }
+}
+
+// The fields of records are kept sorted by name, however they were written.
+fn fields<T>(
+    members: &Members,
+    part: &str,
+    mut read_part: impl FnMut(&Json) -> Result<T, JsonError>,
+) -> Result<Vec<(String, T)>, JsonError> {
+    let mut fields = Vec::new();
+    for field in array(members, "fields")? {
+        match field {
+            Json::Object(members) => {
+                let name = string(members, "name")?.to_string();
+                fields.push((name, read_part(member(members, part)?)?));
+            }
+            _ => return invalid("expected a field"),
+        }
+    }
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    if fields.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return invalid("repeated field");
+    }
+    Ok(fields)
+}
+
+fn make_value(json: &Json) -> Result<Value, JsonError> {
+    let (kind, members) = kind(json)?;
+    Ok(match kind {
+        "Boolean" => match member(members, "value")? {
+            Json::Boolean(b) => Value::Boolean(*b),
+            _ => return invalid("expected a boolean"),
+        },
+        "Char" => {
+            let mut chars = string(members, "value")?.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Value::Char(c),
+                _ => return invalid("expected a single character"),
+            }
+        }
+        "Datatype" => Value::Datatype(
+            string(members, "type")?.to_string(),
+            string(members, "variant")?.to_string(),
+            Arc::new(make_value(member(members, "value")?)?),
+        ),
+        "Integer" => match member(members, "value")? {
+            Json::Integer(i) => Value::Integer(*i),
+            _ => return invalid("expected an integer"),
+        },
+        "Record" => Value::Record(fields(members, "value", make_value)?),
+        "String" => Value::String(string(members, "value")?.to_string()),
+        "Tuple" => Value::Tuple(
+            array(members, "elements")?
+                .iter()
+                .map(make_value)
+                .collect::<Result<_, _>>()?,
+        ),
+        "Unit" => Value::Unit,
+        _ => return invalid(&format!("unknown kind of value {}", kind)),
+    })
+}
+
+fn make_type(json: &Json) -> Result<Type, JsonError> {
+    let types = |members, name| -> Result<Vec<Type>, JsonError> {
+        array(members, name)?.iter().map(make_type).collect()
+    };
+    let (kind, members) = kind(json)?;
+    Ok(match kind {
+        "Any" => Type::Any,
+        "Boolean" => Type::Boolean,
+        "Channel" => Type::Channel(Arc::new(make_type(member(members, "element")?)?)),
+        "Char" => Type::Char,
+        "Datatype" => Type::Datatype(string(members, "name")?.to_string()),
+        "Function" => Type::Function(
+            Arc::new(make_type(member(members, "param")?)?),
+            Arc::new(make_type(member(members, "body")?)?),
+        ),
+        "Integer" => Type::Integer,
+        "Never" => Type::Never,
+        "Polymorphic" => Type::Polymorphic(string(members, "name")?.to_string()),
+        "Record" => Type::Record(fields(members, "type", make_type)?.into()),
+        "Repeated" => Type::Repeated(Arc::new(make_type(member(members, "element")?)?)),
+        "Row" => Type::Row(
+            fields(members, "type", make_type)?.into(),
+            string(members, "row")?.to_string(),
+        ),
+        "String" => Type::String,
+        "Tuple" => Type::Tuple(types(members, "elements")?.into()),
+        "Union" => typeinfer::union(types(members, "members")?),
+        "Unit" => Type::Unit,
+        _ => return invalid(&format!("unknown kind of type {}", kind)),
+    })
+}
+
+pub fn to_value(text: &str) -> Result<Value, JsonError> {
+    make_value(&read(text)?)
+}
+
+pub fn to_type(text: &str) -> Result<Type, JsonError> {
+    make_type(&read(text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::json;
+    use crate::typeinfer::Type;
+    use crate::vm::Value;
+    use crate::Interpreter;
+
+    #[test]
+    fn values() {
+        let mut interpreter = Interpreter::new();
+        let value = match interpreter.eval_str(
+            "type Option := Some (x) | None end
+            (1, -2, true, 'é', \"a\\\"b\\n\", {y = (), x = Some (3)}, None)",
+        ) {
+            Ok(value) => value,
+            Err(_) => unreachable!(),
+        };
+        let text = match json::from_value(&value) {
+            Ok(text) => text,
+            Err(_) => unreachable!(),
+        };
+        assert_eq!(json::to_value(&text), Ok(value));
+        assert_eq!(
+            json::to_value(
+                " { \"kind\" : \"Record\", \"fields\" : [{\"name\": \"b\", \"value\": {\"kind\": \"Unit\"}},
+                    {\"value\": {\"kind\": \"String\", \"value\": \"\\ud83d\\ude00\\u00e9\"}, \"name\": \"a\"}] } "
+            ),
+            Ok(Value::Record(vec![
+                ("a".to_string(), Value::String("😀é".to_string())),
+                ("b".to_string(), Value::Unit),
+            ]))
+        );
+        assert!(json::from_value(&Value::Native("len".to_string())).is_err());
+        for text in [
+            "",
+            "{\"kind\": \"Integer\", \"value\": 1.5}",
+            "{\"kind\": \"Integer\", \"value\": 99999999999999999999}",
+            "{\"kind\": \"Char\", \"value\": \"ab\"}",
+            "{\"kind\": \"Integer\"}",
+            "{\"kind\": \"Unit\"} 1",
+            "{\"kind\": \"Function\"}",
+            "{\"kind\": \"String\", \"value\": \"\\ud83d\"}",
+            "{\"kind\": \"Record\", \"fields\": [{\"name\": \"a\", \"value\": {\"kind\": \"Unit\"}},
+                {\"name\": \"a\", \"value\": {\"kind\": \"Unit\"}}]}",
+            &("[".repeat(1002) + &"]".repeat(1002)),
+        ] {
+            assert!(json::to_value(text).is_err());
+        }
+        // Lists of a few hundred elements are read back.
+        let mut list =
+            Value::Datatype("List".to_string(), "Nil".to_string(), Arc::new(Value::Unit));
+        for i in 0..300 {
+            let cons = Value::Tuple(vec![Value::Integer(i), list]);
+            list = Value::Datatype("List".to_string(), "Cons".to_string(), Arc::new(cons));
+        }
+        let text = match json::from_value(&list) {
+            Ok(text) => text,
+            Err(_) => unreachable!(),
+        };
+        assert_eq!(json::to_value(&text), Ok(list));
+    }
+
+    #[test]
+    fn types() {
+        let mut interpreter = Interpreter::new();
+        for src in [
+            "fn (x) -> x.a end",
+            "fn (f, x) -> f (x) end",
+            "(1, \"a\", 'a', true, (), {b = 1, a = ()})",
+        ] {
+            let typ = match interpreter.eval_str_typed(src) {
+                Ok((_, typ)) => typ,
+                Err(_) => unreachable!(),
+            };
+            assert_eq!(json::to_type(&json::from_type(&typ)), Ok(typ));
+        }
+        let union = Type::Union(vec![Type::Integer, Type::String].into());
+        let typ = Type::Channel(Arc::new(Type::Repeated(Arc::new(union))));
+        assert_eq!(
+            json::from_type(&typ),
+            "{\"kind\":\"Channel\",\"element\":{\"kind\":\"Repeated\",\"element\":\
+             {\"kind\":\"Union\",\"members\":[{\"kind\":\"Integer\"},{\"kind\":\"String\"}]}}}"
+        );
+        assert_eq!(json::to_type(&json::from_type(&typ)), Ok(typ));
+    }
+}
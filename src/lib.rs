@@ -0,0 +1,32 @@
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+
+pub mod arbitrary;
+mod builtins;
+pub mod bundle;
+pub mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod codegen;
+pub mod convert;
+pub mod coverage;
+pub mod diagnostics;
+pub mod dump;
+pub mod editor;
+pub mod fuzz;
+mod interpreter;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod optimize;
+pub mod parser;
+pub mod playground;
+pub mod pretty;
+pub mod profile;
+pub mod testing;
+pub mod typeinfer;
+pub mod unification;
+pub mod vm;
+pub mod warnings;
+
+pub use interpreter::{Error, Interpreter};
@@ -1,72 +1,948 @@
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-extern crate pest;
-#[macro_use]
-extern crate pest_derive;
+use plover::diagnostics::{self, Code, Severity};
+use plover::warnings::{Level, Settings};
+use plover::{
+    bundle, codegen, coverage, dump, editor, optimize, parser, pretty, profile, testing, typeinfer,
+    vm, warnings,
+};
 
-mod codegen;
-mod parser;
-mod typeinfer;
-mod unification;
-mod vm;
+use std::io::{self, BufRead, IsTerminal, Write};
 
-use std::io::{self, BufRead, Write};
+// Diagnostics go to stderr, and are colored when it is a terminal unless
+// --no-color is given or NO_COLOR is set.
+static COLOR: AtomicBool = AtomicBool::new(false);
 
-fn eval(filename: &str, src: &str, vm: &mut vm::VirtualMachine) {
+const ERROR: &str = "1;31";
+const WARNING: &str = "1;33";
+const NOTE: &str = "1;36";
+const LOCATION: &str = "34";
+
+// With --error-format=json, each diagnostic is written to stderr as a line of
+// JSON instead, without the source it refers to.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// Whether an error format's name is JSON, or None if the name is not one.
+fn json_format(name: &str) -> Option<bool> {
+    match name {
+        "human" => Some(false),
+        "json" => Some(true),
+        _ => None,
+    }
+}
+
+fn report_json(
+    filename: &str,
+    code: Option<Code>,
+    severity: Severity,
+    msg: &str,
+    span: &parser::Span,
+    err: Option<&codegen::InterpreterError>,
+) {
+    let (related, notes) = match err {
+        Some(err) => (&err.related[..], &err.notes[..]),
+        None => (&[][..], &[][..]),
+    };
+    eprintln!(
+        "{}",
+        diagnostics::json(filename, code, severity, msg, span, related, notes)
+    );
+}
+
+fn paint(style: &str, text: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+// Results are pretty printed, broken over lines to fit and cut short if they
+// are huge, when stdout is a terminal unless --format=compact is given, and
+// otherwise printed in full on one line.
+static PRETTY: AtomicBool = AtomicBool::new(false);
+
+const WIDTH: usize = 80;
+
+// With --dump-ast=json, each parsed program is printed to stdout as a line of
+// JSON before it is checked or run.
+static JSON_AST: AtomicBool = AtomicBool::new(false);
+
+fn dump_json(ast: &parser::AST) {
+    if JSON_AST.load(Ordering::Relaxed) {
+        println!("{}", dump::json(ast));
+    }
+}
+
+fn show(value: &vm::Value) {
+    if PRETTY.load(Ordering::Relaxed) {
+        println!("{}", pretty::value(value, WIDTH));
+    } else {
+        println!("{}", value);
+    }
+}
+
+// Whether a format's name is pretty, or None if the name is not a format.
+fn pretty_format(name: &str) -> Option<bool> {
+    match name {
+        "compact" => Some(false),
+        "pretty" => Some(true),
+        _ => None,
+    }
+}
+
+fn report(filename: &str, lines: &[&str], style: &str, msg: &str, span: &parser::Span) {
+    // Errors raised before any code with a position ran have nothing to show.
+    if *span == parser::Span::unknown() {
+        eprintln!("{}", paint(style, msg));
+        eprintln!("{}", paint(LOCATION, &format!("--> {}", filename)));
+        return;
+    }
+    let line = min(lines.len(), span.line);
+    let col = min(lines[line - 1].len(), span.col);
+    // Spans that continue onto later lines are underlined to the end of the
+    // first line.
+    let end_col = if span.end_line == span.line {
+        min(lines[line - 1].len() + 1, span.end_col)
+    } else {
+        lines[line - 1].len() + 1
+    };
+    let underline = "^".repeat(max(1, end_col.saturating_sub(col)));
+    let width = line.to_string().len() + 2;
+    let gutter = paint(LOCATION, "|");
+    eprintln!("{}", paint(style, msg));
+    eprintln!("{s:>width$}{}", gutter, s = " ", width = width);
+    eprintln!(
+        " {} {} {}",
+        paint(LOCATION, &line.to_string()),
+        gutter,
+        lines[line - 1]
+    );
+    eprintln!(
+        "{s:>width$}{}{s:>col$}{}",
+        gutter,
+        paint(style, &underline),
+        s = " ",
+        width = width,
+        col = col
+    );
+    eprintln!(
+        "{}",
+        paint(LOCATION, &format!("--> {}:{}:{}", filename, line, col))
+    );
+}
+
+// Errors are prefixed with their code, and followed by the related code and
+// any notes.
+fn report_error(filename: &str, lines: &[&str], err: &codegen::InterpreterError) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let severity = err.severity();
+        return report_json(
+            filename,
+            Some(err.code),
+            severity,
+            &err.err,
+            &err.span,
+            Some(err),
+        );
+    }
+    report(
+        filename,
+        lines,
+        ERROR,
+        &format!("[{}] {}", err.code, err),
+        &err.span,
+    );
+    for (span, label) in &err.related {
+        report(filename, lines, NOTE, label, span);
+    }
+    for note in &err.notes {
+        eprintln!("{} {}", paint(NOTE, "Note:"), note);
+    }
+}
+
+// Denied warnings are reported as errors, in which case this returns false.
+fn report_warnings(filename: &str, lines: &[&str], ast: &parser::AST, settings: &Settings) -> bool {
+    let mut ok = true;
+    let json = JSON_ERRORS.load(Ordering::Relaxed);
+    for warning in warnings::check(ast, settings) {
+        let denied = settings.level(warning.category) == Level::Deny;
+        ok &= !denied;
+        if json {
+            let severity = if denied {
+                Severity::Error
+            } else {
+                Severity::Warning
+            };
+            let (code, msg, span) = (Some(warning.code), &warning.msg, &warning.span);
+            report_json(filename, code, severity, msg, span, None);
+        } else if denied {
+            let msg = format!("[{}] Error: {}", warning.code, warning.msg);
+            report(filename, lines, ERROR, &msg, &warning.span);
+        } else {
+            let msg = format!("[{}] {}", warning.code, warning);
+            report(filename, lines, WARNING, &msg, &warning.span);
+        }
+    }
+    ok
+}
+
+fn eval(filename: &str, src: &str, vm: &mut vm::VirtualMachine, settings: &Settings) -> bool {
     let lines: Vec<&str> = src.split('\n').collect();
-    match parser::parse(&src) {
-        Ok(ast) => match codegen::eval(vm, &ast) {
-            Ok(v) => {
-                println!("{}", v);
-            }
-            Err(err) => {
-                let line = min(lines.len(), err.line);
-                let col = min(lines[line - 1].len(), err.col);
-                let width = line.to_string().len() + 2;
-                println!("{}", err);
-                println!("{s:>width$}|", s = " ", width = width);
-                println!(" {} | {}", line, lines[line - 1]);
-                print!("{s:>width$}|", s = " ", width = width);
-                println!("{s:>width$}^", s = " ", width = col);
-                println!("--> {}:{}", filename, line);
-                vm.stack.drain(0..);
+    let start = vm.start_timing();
+    let parsed = parser::parse(src);
+    vm.record_timing(start, |timings| &mut timings.parse);
+    match parsed {
+        Ok(ast) => {
+            if let Err(err) = codegen::check_depth(&ast, vm.max_depth) {
+                report_error(filename, &lines, &err);
+                return false;
             }
+            dump_json(&ast);
+            if !report_warnings(filename, &lines, &ast, settings) {
+                return false;
+            }
+            match codegen::eval(vm, &ast) {
+                Ok(v) => {
+                    show(&v);
+                    true
+                }
+                // Programs that call exit end the process, even from the REPL.
+                Err(err) if err.code == Code::Exit => {
+                    let _ = io::stdout().flush();
+                    process::exit(vm.exit_status.unwrap_or(0));
+                }
+                Err(err) => {
+                    report_error(filename, &lines, &err);
+                    false
+                }
+            }
+        }
+        Err(errors) => {
+            report_parse_errors(filename, &errors);
+            false
+        }
+    }
+}
+
+// Report every diagnostic for a program without running it.
+fn check(
+    filename: &str,
+    src: &str,
+    ids: &mut HashMap<String, typeinfer::Type>,
+    settings: &Settings,
+    max_depth: usize,
+) -> bool {
+    let lines: Vec<&str> = src.split('\n').collect();
+    match parser::parse(src) {
+        Ok(ast) => {
+            if let Err(err) = codegen::check_depth(&ast, max_depth) {
+                report_error(filename, &lines, &err);
+                return false;
+            }
+            dump_json(&ast);
+            let ok = report_warnings(filename, &lines, &ast, settings);
+            match typeinfer::infer(&ast, ids) {
+                Ok(_) => ok,
+                Err(err) => {
+                    report_error(filename, &lines, &err);
+                    false
+                }
+            }
+        }
+        Err(errors) => {
+            report_parse_errors(filename, &errors);
+            false
+        }
+    }
+}
+
+// Run the tests in a program, returning the number that passed and failed.
+fn test(filename: &str, src: &str, coverage: bool) -> (usize, usize) {
+    let lines: Vec<&str> = src.split('\n').collect();
+    match parser::parse(src) {
+        Ok(ast) => {
+            let mut passed = 0;
+            let mut failed = 0;
+            let mut hits = HashMap::new();
+            let results = testing::run(&ast, if coverage { Some(&mut hits) } else { None });
+            for result in results {
+                match result.outcome {
+                    testing::Outcome::Passed => {
+                        println!("test {} ... ok", result.name);
+                        passed += 1;
+                    }
+                    testing::Outcome::Failed(span) => {
+                        println!("test {} ... FAILED", result.name);
+                        let msg = "Test did not evaluate to true.";
+                        if JSON_ERRORS.load(Ordering::Relaxed) {
+                            report_json(filename, None, Severity::Error, msg, &span, None);
+                        } else {
+                            report(filename, &lines, ERROR, msg, &span);
+                        }
+                        failed += 1;
+                    }
+                    testing::Outcome::Error(err) => {
+                        println!("test {} ... FAILED", result.name);
+                        report_error(filename, &lines, &err);
+                        failed += 1;
+                    }
+                }
+            }
+            if coverage {
+                print!("{}", coverage::report(src, &hits));
+            }
+            (passed, failed)
+        }
+        Err(errors) => {
+            report_parse_errors(filename, &errors);
+            (0, 1)
+        }
+    }
+}
+
+// Print the type of an expression without running it or changing the
+// environment.
+fn show_type(filename: &str, src: &str, vm: &vm::VirtualMachine) {
+    let lines: Vec<&str> = src.split('\n').collect();
+    match parser::parse(src) {
+        Ok(ast) => {
+            let mut ids = vm.env.types.clone();
+            match typeinfer::infer(&ast, &mut ids) {
+                Ok(typed_ast) => {
+                    println!("{}", typeinfer::type_of(&typed_ast));
+                }
+                Err(err) => {
+                    report_error(filename, &lines, &err);
+                }
+            }
+        }
+        Err(errors) => report_parse_errors(filename, &errors),
+    }
+}
+
+// Lists what the session has defined, with types and values, which are cut
+// short to fit on a line. Builtins and values from the host are left out
+// unless all is given.
+fn show_env(arg: &str, vm: &vm::VirtualMachine) {
+    const WIDTH: usize = 60;
+    let all = match arg {
+        "" => false,
+        "all" => true,
+        _ => {
+            println!("Usage: :env [all]");
+            return;
+        }
+    };
+    let mut names: Vec<&str> = vm
+        .env
+        .names()
+        .filter(|name| all || !(vm.natives.contains_key(*name) || vm.values.contains_key(*name)))
+        .collect();
+    names.sort_unstable();
+    for name in names {
+        let typ = match vm.env.types.get(name) {
+            Some(typ) => typ.to_string(),
+            None => "?".to_string(),
+        };
+        let mut value = vm.env.values[name].to_string();
+        if let Some((i, _)) = value.char_indices().nth(WIDTH) {
+            value.truncate(i);
+            value.push_str("...");
+        }
+        println!("{} : {} = {}", name, typ, value);
+    }
+}
+
+// Whether a program has anything besides whitespace and comments, since
+// inputs without any code are left out of the history that gets saved.
+fn has_code(program: &str) -> bool {
+    let mut end = 0;
+    for start in parser::comment_starts(program) {
+        if !program[end..start].trim().is_empty() {
+            return true;
+        }
+        end = program[start..]
+            .find('\n')
+            .map_or(program.len(), |i| start + i);
+    }
+    !program[end..].trim().is_empty()
+}
+
+// Evaluate a file into the session, remembering it so that it can be saved.
+fn load(
+    filename: &str,
+    vm: &mut vm::VirtualMachine,
+    history: &mut Vec<String>,
+    settings: &Settings,
+) {
+    match read_file(filename) {
+        Ok(program) => {
+            if eval(filename, &program, vm, settings) && has_code(&program) {
+                history.push(program);
+            }
+        }
+        Err(err) => println!("Could not read {}: {}.", filename, err),
+    }
+}
+
+// Write out every input that evaluated successfully, in order.
+fn save(filename: &str, history: &[String]) {
+    let mut program = String::new();
+    for input in history {
+        program.push_str(input.trim_end());
+        program.push('\n');
+    }
+    if let Err(err) = File::create(filename).and_then(|mut file| file.write_all(program.as_bytes()))
+    {
+        println!("Could not write {}: {}.", filename, err);
+    }
+}
+
+// Shows the limits on each input, or sets one of them, where off removes it.
+fn limit(arg: &str, vm: &mut vm::VirtualMachine) {
+    let words: Vec<&str> = arg.split_whitespace().collect();
+    match words[..] {
+        [] => {
+            let off = || "off".to_string();
+            println!(
+                "instructions {}, time {}",
+                vm.max_instructions.map_or_else(off, |max| max.to_string()),
+                vm.max_time
+                    .map_or_else(off, |max| format!("{} ms", max.as_millis()))
+            );
+        }
+        ["instructions", "off"] => vm.max_instructions = None,
+        ["instructions", max] => match max.parse() {
+            Ok(max) => vm.max_instructions = Some(max),
+            Err(_) => println!("Invalid instruction limit: {}.", max),
+        },
+        ["time", "off"] => vm.max_time = None,
+        ["time", max] => match max.parse() {
+            Ok(max) => vm.max_time = Some(Duration::from_millis(max)),
+            Err(_) => println!("Invalid time limit: {}.", max),
         },
+        _ => println!("Usage: :limit instructions|time N|off"),
+    }
+}
+
+fn command(
+    line: &str,
+    vm: &mut vm::VirtualMachine,
+    history: &mut Vec<String>,
+    settings: &mut Settings,
+) {
+    let (name, arg) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    match name {
+        ":c" | ":clear" => {
+            vm.reset();
+            history.clear();
+        }
+        ":e" | ":env" => show_env(arg, vm),
+        ":f" | ":format" => match pretty_format(arg) {
+            Some(format) => PRETTY.store(format, Ordering::Relaxed),
+            None => println!("Usage: :format compact|pretty"),
+        },
+        ":l" | ":load" => load(arg, vm, history, settings),
+        ":limit" => limit(arg, vm),
+        ":s" | ":save" => save(arg, history),
+        ":stats" => {
+            let stats = vm.statistics();
+            println!(
+                "{} bindings, {} instructions, {} constants, {} executed, stack {} (max {}), {} allocated",
+                stats.env_entries,
+                stats.instructions,
+                stats.constants,
+                stats.executed,
+                stats.stack,
+                stats.max_stack,
+                stats.allocated
+            );
+        }
+        ":t" | ":type" => show_type("<stdin>", arg, vm),
+        ":w" | ":warn" => {
+            for flag in arg.split_whitespace() {
+                if let Err(err) = settings.apply(flag.trim_start_matches("-W")) {
+                    println!("{}", err);
+                }
+            }
+        }
+        _ => println!("Unknown command: {}.", name),
+    }
+}
+
+// Compiled programs have no source to show, so errors from them only give
+// where they happened, if that is known.
+fn report_bundle_error(filename: &str, err: &codegen::InterpreterError) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let severity = err.severity();
+        return report_json(
+            filename,
+            Some(err.code),
+            severity,
+            &err.err,
+            &err.span,
+            Some(err),
+        );
+    }
+    eprintln!("{}", paint(ERROR, &format!("[{}] {}", err.code, err)));
+    if err.span != parser::Span::unknown() {
+        eprintln!(
+            "{}",
+            paint(
+                LOCATION,
+                &format!("--> {}:{}:{}", filename, err.span.line, err.span.col)
+            )
+        );
+    }
+    for note in &err.notes {
+        eprintln!("{} {}", paint(NOTE, "Note:"), note);
+    }
+}
+
+fn run_bundle(filename: &str, bytes: &[u8], vm: &mut vm::VirtualMachine) -> bool {
+    match bundle::eval(vm, bytes) {
+        Ok(v) => {
+            show(&v);
+            true
+        }
+        Err(err) if err.code == Code::Exit => {
+            let _ = io::stdout().flush();
+            process::exit(vm.exit_status.unwrap_or(0));
+        }
         Err(err) => {
-            println!("{}", err.msg);
+            report_bundle_error(filename, &err);
+            false
         }
     }
 }
 
+// Writes the compiled program next to its source, or to stdout for a program
+// read from stdin.
+fn compile(filename: &str, src: &str, vm: &mut vm::VirtualMachine, settings: &Settings) -> bool {
+    let name = source_name(filename);
+    let lines: Vec<&str> = src.split('\n').collect();
+    let ast = match parser::parse(src) {
+        Ok(ast) => ast,
+        Err(errors) => {
+            report_parse_errors(name, &errors);
+            return false;
+        }
+    };
+    if let Err(err) = codegen::check_depth(&ast, vm.max_depth) {
+        report_error(name, &lines, &err);
+        return false;
+    }
+    if !report_warnings(name, &lines, &ast, settings) {
+        return false;
+    }
+    let bytes = match bundle::compile(vm, &ast) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            report_error(name, &lines, &err);
+            return false;
+        }
+    };
+    let written = if filename == "-" {
+        io::stdout().write_all(&bytes)
+    } else {
+        fs::write(
+            Path::new(filename).with_extension(bundle::EXTENSION),
+            &bytes,
+        )
+    };
+    match written {
+        Ok(()) => true,
+        Err(err) if JSON_ERRORS.load(Ordering::Relaxed) => {
+            let (msg, span) = (err.to_string(), parser::Span::unknown());
+            report_json(name, None, Severity::Error, &msg, &span, None);
+            false
+        }
+        Err(err) => {
+            eprintln!("{}", paint(ERROR, &format!("{}: {}", name, err)));
+            false
+        }
+    }
+}
+
+// With --time, the time each phase took is reported after every evaluation,
+// whether or not it succeeded, and with --memory, figures for the memory used
+// so far.
+fn report_usage(vm: &mut vm::VirtualMachine, memory: bool) {
+    if let Some(timings) = vm.timings.as_mut().map(std::mem::take) {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        eprintln!(
+            "{} parse {:.3} ms, typecheck {:.3} ms, codegen {:.3} ms, run {:.3} ms",
+            paint(NOTE, "Time:"),
+            ms(timings.parse),
+            ms(timings.typecheck),
+            ms(timings.codegen),
+            ms(timings.run)
+        );
+    }
+    if memory {
+        let stats = vm.statistics();
+        eprintln!(
+            "{} stack peak {} values, {} bindings, {} values allocated",
+            paint(NOTE, "Memory:"),
+            stats.max_stack,
+            stats.env_entries,
+            stats.allocated
+        );
+    }
+}
+
+// With --profile=FILE, the instructions executed in each stack of functions so
+// far are written to the file after every evaluation, in the folded format
+// that flame graph tools read.
+fn save_profile(vm: &vm::VirtualMachine, path: Option<&str>) {
+    if let (Some(profile), Some(path)) = (&vm.profile, path) {
+        if let Err(err) = fs::write(path, profile::folded(&profile.counts)) {
+            eprintln!("{}", paint(ERROR, &format!("{}: {}", path, err)));
+        }
+    }
+}
+
+fn report_parse_errors(filename: &str, errors: &[parser::ParseError]) {
+    for err in errors {
+        if JSON_ERRORS.load(Ordering::Relaxed) {
+            report_json(
+                filename,
+                Some(err.code),
+                Severity::Error,
+                &err.msg,
+                &err.span,
+                None,
+            );
+            continue;
+        }
+        eprintln!("{}", paint(ERROR, &format!("[{}] {}", err.code, err.msg)));
+        eprintln!(
+            "{}",
+            paint(
+                LOCATION,
+                &format!("--> {}:{}:{}", filename, err.span.line, err.span.col)
+            )
+        );
+    }
+}
+
+fn format(filename: &str, src: &str) -> bool {
+    match pretty::format(src) {
+        Ok(formatted) => {
+            print!("{}", formatted);
+            true
+        }
+        Err(errors) => {
+            report_parse_errors(filename, &errors);
+            false
+        }
+    }
+}
+
+// A filename of - reads the whole of stdin, which is reported as <stdin>.
+fn read_file(filename: &str) -> io::Result<String> {
+    let mut program = String::new();
+    if filename == "-" {
+        io::stdin().read_to_string(&mut program)?;
+    } else {
+        File::open(filename)?.read_to_string(&mut program)?;
+    }
+    Ok(program)
+}
+
+fn source_name(filename: &str) -> &str {
+    if filename == "-" {
+        "<stdin>"
+    } else {
+        filename
+    }
+}
+
 fn main() -> io::Result<()> {
     let mut vm = vm::VirtualMachine::new();
-    let args: Vec<String> = env::args().collect();
-    for filename in args.iter().skip(1) {
-        let mut file = File::open(&filename)?;
-        let mut program = String::new();
-        file.read_to_string(&mut program)?;
-        eval(&filename, &program, &mut vm);
+    let (options, args): (Vec<String>, Vec<String>) = env::args()
+        .skip(1)
+        .partition(|arg| arg.starts_with("--") || arg.starts_with("-W"));
+    let mut check_only = false;
+    let mut coverage = false;
+    let mut memory = false;
+    let mut profile_path = None;
+    let mut settings = Settings::default();
+    let mut color = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let mut pretty = io::stdout().is_terminal();
+    for option in options {
+        match option.as_str() {
+            "--check" => check_only = true,
+            "--coverage" => coverage = true,
+            "--dump-ast" => vm.dump_ast = true,
+            "--dump-ast=json" => JSON_AST.store(true, Ordering::Relaxed),
+            "--memory" => memory = true,
+            "--no-color" => color = false,
+            "--sandbox" => vm.set_file_access(false),
+            "--time" => vm.timings = Some(vm::Timings::default()),
+            _ if option.starts_with("--format=") => match pretty_format(&option[9..]) {
+                Some(format) => pretty = format,
+                None => {
+                    println!("Unknown format: {}.", &option[9..]);
+                    process::exit(1);
+                }
+            },
+            _ if option.starts_with("--error-format=") => match json_format(&option[15..]) {
+                Some(json) => JSON_ERRORS.store(json, Ordering::Relaxed),
+                None => {
+                    println!("Unknown error format: {}.", &option[15..]);
+                    process::exit(1);
+                }
+            },
+            _ if option.starts_with("--profile=") => {
+                profile_path = Some(option[10..].to_string());
+                vm.profile = Some(profile::Profile::new());
+            }
+            _ if option.starts_with("--max-depth=") => match option[12..].parse() {
+                Ok(depth) => vm.max_depth = depth,
+                Err(_) => {
+                    println!("Invalid depth: {}.", &option[12..]);
+                    process::exit(1);
+                }
+            },
+            _ if option.starts_with("--max-allocations=") => match option[18..].parse() {
+                Ok(max) => vm.max_allocations = Some(max),
+                Err(_) => {
+                    println!("Invalid allocation limit: {}.", &option[18..]);
+                    process::exit(1);
+                }
+            },
+            _ if option.starts_with("--max-instructions=") => match option[19..].parse() {
+                Ok(max) => vm.max_instructions = Some(max),
+                Err(_) => {
+                    println!("Invalid instruction limit: {}.", &option[19..]);
+                    process::exit(1);
+                }
+            },
+            _ if option.starts_with("--max-time=") => match option[11..].parse() {
+                Ok(max) => vm.max_time = Some(Duration::from_millis(max)),
+                Err(_) => {
+                    println!("Invalid time limit: {}.", &option[11..]);
+                    process::exit(1);
+                }
+            },
+            _ if option.starts_with("--opt-level=") => {
+                match option[12..]
+                    .parse()
+                    .ok()
+                    .and_then(optimize::Level::from_number)
+                {
+                    Some(level) => vm.passes = optimize::pipeline(level),
+                    None => {
+                        println!("Invalid optimization level: {}.", &option[12..]);
+                        process::exit(1);
+                    }
+                }
+            }
+            _ if option.starts_with("-W") => {
+                if let Err(err) = settings.apply(&option[2..]) {
+                    println!("{}", err);
+                    process::exit(1);
+                }
+            }
+            _ => {
+                println!("Unknown option: {}.", option);
+                process::exit(1);
+            }
+        }
+    }
+
+    COLOR.store(color, Ordering::Relaxed);
+    PRETTY.store(pretty, Ordering::Relaxed);
+
+    // A program piped in without any files is run as if it were named -.
+    let args = if args.is_empty() && !io::stdin().is_terminal() {
+        vec!["-".to_string()]
+    } else {
+        args
+    };
+
+    if !args.is_empty() && args[0] == "fmt" {
+        let mut formatted = true;
+        for filename in args.iter().skip(1) {
+            let program = read_file(filename)?;
+            formatted &= format(source_name(filename), &program);
+        }
+        if !formatted {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if !args.is_empty() && args[0] == "compile" {
+        let mut compiled = true;
+        for filename in args.iter().skip(1) {
+            let program = read_file(filename)?;
+            compiled &= compile(filename, &program, &mut vm, &settings);
+        }
+        if !compiled {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if !args.is_empty() && args[0] == "test" {
+        let mut passed = 0;
+        let mut failed = 0;
+        for filename in args.iter().skip(1) {
+            let program = read_file(filename)?;
+            let (p, f) = test(source_name(filename), &program, coverage);
+            passed += p;
+            failed += f;
+        }
+        println!("{} passed; {} failed", passed, failed);
+        if failed != 0 {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if check_only {
+        let mut checked = true;
+        let mut ids = vm.env.types.clone();
+        for filename in &args {
+            let program = read_file(filename)?;
+            checked &= check(
+                source_name(filename),
+                &program,
+                &mut ids,
+                &settings,
+                vm.max_depth,
+            );
+        }
+        if !checked {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Files that fail to evaluate make the process fail once the REPL ends.
+    let mut failed = false;
+    let mut history = Vec::new();
+    for filename in &args {
+        if Path::new(filename).extension() == Some(bundle::EXTENSION.as_ref()) {
+            failed |= !run_bundle(filename, &fs::read(filename)?, &mut vm);
+            report_usage(&mut vm, memory);
+            save_profile(&vm, profile_path.as_deref());
+            continue;
+        }
+        let program = read_file(filename)?;
+        if coverage {
+            vm.coverage = Some(HashMap::new());
+        }
+        let evaluated = eval(source_name(filename), &program, &mut vm, &settings);
+        report_usage(&mut vm, memory);
+        save_profile(&vm, profile_path.as_deref());
+        if let Some(hits) = vm.coverage.take() {
+            print!("{}", coverage::report(&program, &hits));
+        }
+        if !evaluated {
+            failed = true;
+        } else if has_code(&program) {
+            history.push(program);
+        }
+    }
+
+    // Once stdin has been read as a program, there's nothing left for the REPL.
+    if args.iter().any(|filename| filename == "-") {
+        if failed {
+            process::exit(1);
+        }
+        return Ok(());
     }
 
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
     println!("Welcome to Plover!");
-    print!("> ");
-    stdout.flush()?;
 
-    for line in stdin.lock().lines() {
+    // Lines are collected until they form a complete program. An empty line
+    // evaluates whatever has been entered so far, so that errors in
+    // incomplete input can still be reported. On a terminal, tab completes
+    // keywords and the names defined so far.
+    let terminal = io::stdin().is_terminal();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { ". " };
+        let line = if terminal {
+            editor::read_line(prompt, |line| editor::complete(&vm, line))
+        } else {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+            lines.next().transpose()
+        };
         match line {
-            Ok(src) => {
-                eval("<stdin>", &src, &mut vm);
+            Ok(Some(src)) if input.is_empty() && src.trim_start().starts_with(':') => {
+                command(src.trim(), &mut vm, &mut history, &mut settings);
+            }
+            Ok(Some(src)) => {
+                let blank = src.trim().is_empty();
+                input.push_str(&src);
+                input.push('\n');
+                if !blank && parser::incomplete(&input) {
+                    continue;
+                }
+                if !input.trim().is_empty() {
+                    if eval("<stdin>", &input, &mut vm, &settings) && has_code(&input) {
+                        history.push(input.clone());
+                    }
+                    report_usage(&mut vm, memory);
+                    save_profile(&vm, profile_path.as_deref());
+                }
+                input.clear();
             }
             _ => break,
         }
-        print!("> ");
-        stdout.flush()?;
     }
 
+    if failed {
+        process::exit(1);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{check, has_code};
+    use plover::vm;
+    use plover::warnings::Settings;
+
+    #[test]
+    fn checks_calls_to_builtins() {
+        let vm = vm::VirtualMachine::new();
+        let mut ids = vm.env.types.clone();
+        assert!(check(
+            "test",
+            "abs (-3)",
+            &mut ids,
+            &Settings::default(),
+            vm.max_depth
+        ));
+    }
+
+    #[test]
+    fn finds_code() {
+        assert!(!has_code(""));
+        assert!(!has_code("\n  \n"));
+        assert!(!has_code("# a\n  # b \"c\"\n"));
+        assert!(has_code("# a\n1 # b\n"));
+        assert!(has_code("\"#\""));
+        assert!(has_code("# a\n# b\nx"));
+    }
+}
@@ -0,0 +1,1143 @@
+// Optimization passes, which run on the typed AST after type checking and on
+// the bytecode for each function and top level program as it is generated.
+// The virtual machine holds the pipeline of passes to run, chosen by
+// optimization level, and other passes can be added to it.
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::{Operator, Span};
+use crate::typeinfer::{bindings, chain, span_of, type_of, walk, Type, TypedAST};
+use crate::vm::Opcode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    O0,
+    O1,
+    O2,
+}
+
+impl Level {
+    pub fn from_number(n: usize) -> Option<Level> {
+        match n {
+            0 => Some(Level::O0),
+            1 => Some(Level::O1),
+            2 => Some(Level::O2),
+            _ => None,
+        }
+    }
+}
+
+// Passes must not change what a program does, including which errors it
+// raises, only how quickly it does it. A bytecode pass is given a complete
+// chunk, so every jump in it lands inside it, and may use remove to delete
+// instructions without breaking them.
+pub trait Pass: Send {
+    fn name(&self) -> &str;
+
+    fn typed(&self, ast: TypedAST) -> TypedAST {
+        ast
+    }
+
+    fn bytecode(&self, _instr: &mut Vec<Opcode>) {}
+}
+
+pub fn pipeline(level: Level) -> Vec<Box<dyn Pass>> {
+    let mut passes: Vec<Box<dyn Pass>> = Vec::new();
+    if level == Level::O2 {
+        passes.push(Box::new(Inline));
+    }
+    if level != Level::O0 {
+        passes.push(Box::new(Fold));
+        passes.push(Box::new(Simplify));
+        passes.push(Box::new(Propagate));
+        passes.push(Box::new(DeadCode));
+    }
+    if level == Level::O2 {
+        passes.push(Box::new(CommonSubexpressions));
+        passes.push(Box::new(Peephole));
+    }
+    passes
+}
+
+pub fn typed(passes: &[Box<dyn Pass>], mut ast: TypedAST) -> TypedAST {
+    for pass in passes {
+        ast = pass.typed(ast);
+    }
+    ast
+}
+
+pub fn bytecode(passes: &[Box<dyn Pass>], instr: &mut Vec<Opcode>) {
+    for pass in passes {
+        pass.bytecode(instr);
+    }
+}
+
+// Applies f to each child of an expression. Function parameters, loop
+// patterns and match patterns are left alone.
+pub fn map_children(ast: TypedAST, f: &mut impl FnMut(TypedAST) -> TypedAST) -> TypedAST {
+    match ast {
+        TypedAST::BinaryOp(typ, op, lhs, rhs, span) => {
+            TypedAST::BinaryOp(typ, op, Box::new(f(*lhs)), Box::new(f(*rhs)), span)
+        }
+        TypedAST::Call(fun, arg, span) => {
+            TypedAST::Call(Box::new(f(*fun)), Box::new(f(*arg)), span)
+        }
+        TypedAST::Cast(typ, ast, span) => TypedAST::Cast(typ, Box::new(f(*ast)), span),
+        TypedAST::Define(typ, id, value, span) => {
+            TypedAST::Define(typ, id, Box::new(f(*value)), span)
+        }
+        TypedAST::Field(typ, value, field, span) => {
+            TypedAST::Field(typ, Box::new(f(*value)), field, span)
+        }
+        TypedAST::For(pattern, iter, body, span) => {
+            TypedAST::For(pattern, Box::new(f(*iter)), Box::new(f(*body)), span)
+        }
+        TypedAST::Function(id, param, body, span) => {
+            TypedAST::Function(id, param, Box::new(f(*body)), span)
+        }
+        TypedAST::If(conds, els, span) => TypedAST::If(
+            conds
+                .into_iter()
+                .map(|(cond, then)| (f(cond), f(then)))
+                .collect(),
+            Box::new(f(*els)),
+            span,
+        ),
+        TypedAST::Index(value, index, span) => {
+            TypedAST::Index(Box::new(f(*value)), Box::new(f(*index)), span)
+        }
+        TypedAST::Match(cond, typ, cases, span) => TypedAST::Match(
+            Box::new(f(*cond)),
+            typ,
+            cases
+                .into_iter()
+                .map(|(pattern, body)| (pattern, f(body)))
+                .collect(),
+            span,
+        ),
+        TypedAST::Program(typ, expressions, span) => {
+            TypedAST::Program(typ, expressions.into_iter().map(f).collect(), span)
+        }
+        TypedAST::Record(typ, fields, span) => TypedAST::Record(
+            typ,
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, f(value)))
+                .collect(),
+            span,
+        ),
+        TypedAST::Slice(value, start, end, span) => TypedAST::Slice(
+            Box::new(f(*value)),
+            Box::new(f(*start)),
+            Box::new(f(*end)),
+            span,
+        ),
+        TypedAST::Spread(typ, ast, span) => TypedAST::Spread(typ, Box::new(f(*ast)), span),
+        TypedAST::Tuple(typ, elements, span) => {
+            TypedAST::Tuple(typ, elements.into_iter().map(f).collect(), span)
+        }
+        TypedAST::UnaryOp(typ, op, ast, span) => {
+            TypedAST::UnaryOp(typ, op, Box::new(f(*ast)), span)
+        }
+        TypedAST::Boolean(..)
+        | TypedAST::Char(..)
+        | TypedAST::Datatype(..)
+        | TypedAST::Identifier(..)
+        | TypedAST::Integer(..)
+        | TypedAST::String(..)
+        | TypedAST::Unit(..) => ast,
+    }
+}
+
+type Link = (Type, Operator, Box<TypedAST>, Span);
+
+// Takes apart a chain of operators, as for parser::chain, so that passes can
+// rebuild it without recursing.
+fn unchain(ast: TypedAST) -> (TypedAST, Vec<Link>) {
+    let mut links = Vec::new();
+    let mut lhs = ast;
+    while let TypedAST::BinaryOp(typ, op, inner, rhs, span) = lhs {
+        links.push((typ, op, rhs, span));
+        lhs = *inner;
+    }
+    links.reverse();
+    (lhs, links)
+}
+
+// Rebuilds an AST from the bottom up, applying f to each expression after its
+// children.
+pub fn rewrite(ast: TypedAST, f: &mut impl FnMut(TypedAST) -> TypedAST) -> TypedAST {
+    if let TypedAST::BinaryOp(..) = ast {
+        let (first, links) = unchain(ast);
+        let mut ast = rewrite(first, f);
+        for (typ, op, rhs, span) in links {
+            let rhs = rewrite(*rhs, f);
+            ast = f(TypedAST::BinaryOp(
+                typ,
+                op,
+                Box::new(ast),
+                Box::new(rhs),
+                span,
+            ));
+        }
+        return ast;
+    }
+    let ast = map_children(ast, &mut |child| rewrite(child, f));
+    f(ast)
+}
+
+fn is_literal(ast: &TypedAST) -> bool {
+    matches!(
+        ast,
+        TypedAST::Boolean(..)
+            | TypedAST::Char(..)
+            | TypedAST::Integer(..)
+            | TypedAST::String(..)
+            | TypedAST::Unit(..)
+    )
+}
+
+// Evaluates operators on literals. Operations that would raise an error, such
+// as division by zero, are left for the virtual machine to report.
+pub struct Fold;
+
+fn fold_integers(op: &Operator, x: i64, y: i64, span: Span) -> Option<TypedAST> {
+    let integer = |z: Option<i64>| z.map(|z| TypedAST::Integer(z, span));
+    let boolean = |b: bool| Some(TypedAST::Boolean(b, span));
+    match op {
+        Operator::Divide if y != 0 => integer(x.checked_div(y)),
+        Operator::Equal => boolean(x == y),
+        Operator::Greater => boolean(x > y),
+        Operator::GreaterEqual => boolean(x >= y),
+        Operator::Less => boolean(x < y),
+        Operator::LessEqual => boolean(x <= y),
+        Operator::Minus => integer(x.checked_sub(y)),
+        Operator::Mod if y != 0 => integer(x.checked_rem(y)),
+        Operator::Multiply => integer(x.checked_mul(y)),
+        Operator::NotEqual => boolean(x != y),
+        Operator::Plus => integer(x.checked_add(y)),
+        _ => None,
+    }
+}
+
+fn fold_booleans(op: &Operator, x: bool, y: bool) -> Option<bool> {
+    match op {
+        Operator::And => Some(x && y),
+        Operator::Equal => Some(x == y),
+        Operator::NotEqual => Some(x != y),
+        Operator::Or => Some(x || y),
+        _ => None,
+    }
+}
+
+fn fold_chars(op: &Operator, x: char, y: char) -> Option<bool> {
+    match op {
+        Operator::Equal => Some(x == y),
+        Operator::Greater => Some(x > y),
+        Operator::GreaterEqual => Some(x >= y),
+        Operator::Less => Some(x < y),
+        Operator::LessEqual => Some(x <= y),
+        Operator::NotEqual => Some(x != y),
+        _ => None,
+    }
+}
+
+fn fold(ast: TypedAST) -> TypedAST {
+    match ast {
+        TypedAST::BinaryOp(typ, op, lhs, rhs, span) => {
+            let folded = match (&*lhs, &*rhs) {
+                (TypedAST::Integer(x, _), TypedAST::Integer(y, _)) => {
+                    fold_integers(&op, *x, *y, span)
+                }
+                (TypedAST::Boolean(x, _), TypedAST::Boolean(y, _)) => {
+                    fold_booleans(&op, *x, *y).map(|b| TypedAST::Boolean(b, span))
+                }
+                (TypedAST::Char(x, _), TypedAST::Char(y, _)) => {
+                    fold_chars(&op, *x, *y).map(|b| TypedAST::Boolean(b, span))
+                }
+                _ => None,
+            };
+            match folded {
+                Some(folded) => folded,
+                None => TypedAST::BinaryOp(typ, op, lhs, rhs, span),
+            }
+        }
+        TypedAST::UnaryOp(typ, op, ast, span) => match (&op, &*ast) {
+            (Operator::Minus, TypedAST::Integer(i, _)) if i.checked_neg().is_some() => {
+                TypedAST::Integer(-i, span)
+            }
+            (Operator::Not, TypedAST::Boolean(b, _)) => TypedAST::Boolean(!b, span),
+            _ => TypedAST::UnaryOp(typ, op, ast, span),
+        },
+        _ => ast,
+    }
+}
+
+impl Pass for Fold {
+    fn name(&self) -> &str {
+        "fold"
+    }
+
+    fn typed(&self, ast: TypedAST) -> TypedAST {
+        rewrite(ast, &mut fold)
+    }
+}
+
+// Applies algebraic identities, such as x * 1 = x, and folds the result.
+// Operands are never dropped unless they are safe, that is, pure and unable
+// to fail, so programs raise the same errors.
+pub struct Simplify;
+
+fn is_safe(ast: &TypedAST) -> bool {
+    let mut pending = vec![ast];
+    while let Some(ast) = pending.pop() {
+        if pure_key(ast).is_none() || is_risky(ast) {
+            return false;
+        }
+        pending.extend(scope_children(ast).into_iter().map(|(child, _)| child));
+    }
+    true
+}
+
+// Whether values of a type can be compared by comparing their contents.
+// Functions can't, and datatypes may contain functions.
+fn is_comparable(typ: &Type) -> bool {
+    match typ {
+        Type::Boolean | Type::Char | Type::Integer | Type::String | Type::Unit => true,
+        Type::Record(fields) => fields.iter().all(|field| is_comparable(&field.1)),
+        Type::Tuple(types) => types.iter().all(is_comparable),
+        _ => false,
+    }
+}
+
+fn is_integer(ast: &TypedAST, n: i64) -> bool {
+    matches!(ast, TypedAST::Integer(i, _) if *i == n)
+}
+
+fn is_boolean(ast: &TypedAST, b: bool) -> bool {
+    matches!(ast, TypedAST::Boolean(c, _) if *c == b)
+}
+
+fn simplify(ast: TypedAST) -> TypedAST {
+    match ast {
+        TypedAST::BinaryOp(typ, op, lhs, rhs, span) => {
+            let same = || {
+                is_safe(&lhs) && is_comparable(&type_of(&lhs)) && pure_key(&lhs) == pure_key(&rhs)
+            };
+            match op {
+                Operator::Plus if is_integer(&lhs, 0) => *rhs,
+                Operator::Plus | Operator::Minus if is_integer(&rhs, 0) => *lhs,
+                Operator::Multiply if is_integer(&lhs, 1) => *rhs,
+                Operator::Multiply | Operator::Divide if is_integer(&rhs, 1) => *lhs,
+                Operator::Multiply if is_integer(&lhs, 0) && is_safe(&rhs) => *lhs,
+                Operator::Multiply if is_integer(&rhs, 0) && is_safe(&lhs) => *rhs,
+                Operator::And if is_boolean(&lhs, true) => *rhs,
+                Operator::And if is_boolean(&rhs, true) => *lhs,
+                Operator::Or if is_boolean(&lhs, false) => *rhs,
+                Operator::Or if is_boolean(&rhs, false) => *lhs,
+                Operator::Equal | Operator::GreaterEqual | Operator::LessEqual if same() => {
+                    TypedAST::Boolean(true, span)
+                }
+                Operator::Greater | Operator::Less | Operator::NotEqual if same() => {
+                    TypedAST::Boolean(false, span)
+                }
+                _ => TypedAST::BinaryOp(typ, op, lhs, rhs, span),
+            }
+        }
+        TypedAST::UnaryOp(typ, Operator::Not, ast, span) => match *ast {
+            TypedAST::UnaryOp(_, Operator::Not, ast, _) => *ast,
+            ast => TypedAST::UnaryOp(typ, Operator::Not, Box::new(ast), span),
+        },
+        _ => ast,
+    }
+}
+
+impl Pass for Simplify {
+    fn name(&self) -> &str {
+        "simplify"
+    }
+
+    fn typed(&self, ast: TypedAST) -> TypedAST {
+        rewrite(ast, &mut |ast| fold(simplify(ast)))
+    }
+}
+
+// Removes branches whose conditions are known, and statements that are
+// literals other than the last, which can't affect the program.
+pub struct DeadCode;
+
+fn eliminate(ast: TypedAST) -> TypedAST {
+    match ast {
+        TypedAST::If(conds, els, span) => {
+            let mut live = Vec::new();
+            let mut els = *els;
+            for (cond, then) in conds {
+                match cond {
+                    TypedAST::Boolean(false, _) => {}
+                    TypedAST::Boolean(true, _) => {
+                        els = then;
+                        break;
+                    }
+                    _ => live.push((cond, then)),
+                }
+            }
+            if live.is_empty() {
+                els
+            } else {
+                TypedAST::If(live, Box::new(els), span)
+            }
+        }
+        TypedAST::Program(typ, expressions, span) => {
+            let last = expressions.len().saturating_sub(1);
+            let expressions = expressions
+                .into_iter()
+                .enumerate()
+                .filter(|(i, e)| *i == last || !is_literal(e))
+                .map(|(_, e)| e)
+                .collect();
+            TypedAST::Program(typ, expressions, span)
+        }
+        _ => ast,
+    }
+}
+
+impl Pass for DeadCode {
+    fn name(&self) -> &str {
+        "dead-code"
+    }
+
+    fn typed(&self, ast: TypedAST) -> TypedAST {
+        rewrite(ast, &mut eliminate)
+    }
+}
+
+// Replaces calls to small functions defined earlier in the same program with
+// their bodies. Only functions whose bodies refer to nothing but their own
+// parameters, and bind no names of their own, are inlined, so they can't be
+// recursive and inlining them can't capture or miss a binding. Arguments must
+// be literals, identifiers or tuples of them, since they may be used any
+// number of times in the body, or not at all.
+pub struct Inline;
+
+const INLINE_SIZE: usize = 16;
+
+fn param_names(param: &TypedAST) -> Vec<String> {
+    match param {
+        TypedAST::Identifier(_, id, _) => vec![id.to_string()],
+        TypedAST::Tuple(_, elements, _) => elements.iter().flat_map(param_names).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn inlinable(param: &TypedAST, body: &TypedAST) -> bool {
+    let params = param_names(param);
+    let mut size = 0;
+    let mut closed = true;
+    walk(body, &mut |ast| {
+        size += 1;
+        match ast {
+            TypedAST::Identifier(_, id, _) => closed &= params.contains(id),
+            TypedAST::Datatype(..)
+            | TypedAST::Define(..)
+            | TypedAST::For(..)
+            | TypedAST::Function(..)
+            | TypedAST::Match(..)
+            | TypedAST::Program(..) => closed = false,
+            _ => {}
+        }
+        closed
+    });
+    closed && size <= INLINE_SIZE
+}
+
+fn is_trivial(ast: &TypedAST) -> bool {
+    match ast {
+        TypedAST::Identifier(..) => true,
+        TypedAST::Tuple(_, elements, _) => elements.iter().all(is_trivial),
+        _ => is_literal(ast),
+    }
+}
+
+fn bind(param: &TypedAST, arg: &TypedAST, bindings: &mut HashMap<String, TypedAST>) -> bool {
+    match (param, arg) {
+        (TypedAST::Identifier(_, id, _), _) => {
+            bindings.insert(id.to_string(), arg.clone());
+            true
+        }
+        (TypedAST::Tuple(_, params, _), TypedAST::Tuple(_, args, _)) => {
+            params.len() == args.len() && params.iter().zip(args).all(|(p, a)| bind(p, a, bindings))
+        }
+        (TypedAST::Unit(_), TypedAST::Unit(_)) => true,
+        _ => false,
+    }
+}
+
+fn inline_call(fun: &TypedAST, arg: &TypedAST, known: &Functions) -> Option<TypedAST> {
+    let (param, body) = match fun {
+        TypedAST::Identifier(_, id, _) => known.get(id)?,
+        _ => return None,
+    };
+    let mut bindings = HashMap::new();
+    if !is_trivial(arg) || !bind(param, arg, &mut bindings) {
+        return None;
+    }
+    Some(rewrite(body.clone(), &mut |ast| match &ast {
+        TypedAST::Identifier(_, id, _) => bindings.get(id).cloned().unwrap_or(ast),
+        _ => ast,
+    }))
+}
+
+type Functions = HashMap<String, (TypedAST, TypedAST)>;
+
+fn without<T: Clone>(known: &HashMap<String, T>, names: &[String]) -> HashMap<String, T> {
+    let mut known = known.clone();
+    for name in names {
+        known.remove(name);
+    }
+    known
+}
+
+// Forgets every name defined anywhere inside a statement, such as in a
+// branch of an if, since what it is bound to afterwards isn't known.
+fn forget<T>(statement: &TypedAST, known: &mut HashMap<String, T>) {
+    walk(statement, &mut |ast| {
+        match ast {
+            TypedAST::Define(_, id, _, _) | TypedAST::Function(Some(id), _, _, _) => {
+                known.remove(id);
+            }
+            TypedAST::Datatype(_, variants, _) => {
+                for (name, _) in variants {
+                    known.remove(name);
+                }
+            }
+            _ => {}
+        }
+        true
+    });
+}
+
+// Records what a statement defines, so that later calls in the same program
+// use the right definition.
+fn define(statement: &TypedAST, known: &mut Functions) {
+    forget(statement, known);
+    let (id, value) = match statement {
+        TypedAST::Define(_, id, value, _) => (id, &**value),
+        TypedAST::Function(Some(id), _, _, _) => (id, statement),
+        _ => return,
+    };
+    if let TypedAST::Function(_, param, body, _) = value {
+        if inlinable(param, body) {
+            known.insert(id.to_string(), ((**param).clone(), (**body).clone()));
+        }
+    }
+}
+
+fn inline(ast: TypedAST, known: &Functions) -> TypedAST {
+    match ast {
+        TypedAST::BinaryOp(..) => {
+            let (first, links) = unchain(ast);
+            let mut ast = inline(first, known);
+            for (typ, op, rhs, span) in links {
+                let rhs = inline(*rhs, known);
+                ast = TypedAST::BinaryOp(typ, op, Box::new(ast), Box::new(rhs), span);
+            }
+            ast
+        }
+        TypedAST::Call(fun, arg, span) => {
+            let fun = inline(*fun, known);
+            let arg = inline(*arg, known);
+            match inline_call(&fun, &arg, known) {
+                Some(body) => body,
+                None => TypedAST::Call(Box::new(fun), Box::new(arg), span),
+            }
+        }
+        TypedAST::For(pattern, iter, body, span) => {
+            let iter = inline(*iter, known);
+            let body = inline(*body, &without(known, &param_names(&pattern)));
+            TypedAST::For(pattern, Box::new(iter), Box::new(body), span)
+        }
+        TypedAST::Function(id, param, body, span) => {
+            let mut names = param_names(&param);
+            names.extend(id.clone());
+            let body = inline(*body, &without(known, &names));
+            TypedAST::Function(id, param, Box::new(body), span)
+        }
+        TypedAST::Match(cond, typ, cases, span) => {
+            let cond = inline(*cond, known);
+            let cases = cases
+                .into_iter()
+                .map(|(pattern, body)| {
+                    let names: Vec<String> = bindings(&pattern)
+                        .iter()
+                        .map(|binding| binding.0.to_string())
+                        .collect();
+                    let body = inline(body, &without(known, &names));
+                    (pattern, body)
+                })
+                .collect();
+            TypedAST::Match(Box::new(cond), typ, cases, span)
+        }
+        TypedAST::Program(typ, expressions, span) => {
+            let mut known = known.clone();
+            let mut statements = Vec::new();
+            for expression in expressions {
+                let statement = inline(expression, &known);
+                define(&statement, &mut known);
+                statements.push(statement);
+            }
+            TypedAST::Program(typ, statements, span)
+        }
+        _ => map_children(ast, &mut |child| inline(child, known)),
+    }
+}
+
+impl Pass for Inline {
+    fn name(&self) -> &str {
+        "inline"
+    }
+
+    fn typed(&self, ast: TypedAST) -> TypedAST {
+        inline(ast, &HashMap::new())
+    }
+}
+
+// Replaces identifiers bound by definitions of literals with the literals,
+// folding and simplifying the expressions that use them. Closures capture
+// their environment when they are created, so definitions made earlier in a
+// program are still known inside functions defined later.
+pub struct Propagate;
+
+fn propagate(ast: TypedAST, known: &HashMap<String, TypedAST>) -> TypedAST {
+    let ast = match ast {
+        TypedAST::BinaryOp(..) => {
+            let (first, links) = unchain(ast);
+            let mut ast = propagate(first, known);
+            for (typ, op, rhs, span) in links {
+                let rhs = propagate(*rhs, known);
+                ast = fold(simplify(TypedAST::BinaryOp(
+                    typ,
+                    op,
+                    Box::new(ast),
+                    Box::new(rhs),
+                    span,
+                )));
+            }
+            return ast;
+        }
+        TypedAST::Identifier(_, ref id, _) => match known.get(id) {
+            Some(value) => value.clone(),
+            None => ast,
+        },
+        TypedAST::For(pattern, iter, body, span) => {
+            let iter = propagate(*iter, known);
+            let body = propagate(*body, &without(known, &param_names(&pattern)));
+            TypedAST::For(pattern, Box::new(iter), Box::new(body), span)
+        }
+        TypedAST::Function(id, param, body, span) => {
+            let mut names = param_names(&param);
+            names.extend(id.clone());
+            let body = propagate(*body, &without(known, &names));
+            TypedAST::Function(id, param, Box::new(body), span)
+        }
+        TypedAST::Match(cond, typ, cases, span) => {
+            let cond = propagate(*cond, known);
+            let cases = cases
+                .into_iter()
+                .map(|(pattern, body)| {
+                    let names: Vec<String> = bindings(&pattern)
+                        .iter()
+                        .map(|binding| binding.0.to_string())
+                        .collect();
+                    let body = propagate(body, &without(known, &names));
+                    (pattern, body)
+                })
+                .collect();
+            TypedAST::Match(Box::new(cond), typ, cases, span)
+        }
+        TypedAST::Program(typ, expressions, span) => {
+            let mut known = known.clone();
+            let mut statements = Vec::new();
+            for expression in expressions {
+                let statement = propagate(expression, &known);
+                forget(&statement, &mut known);
+                if let TypedAST::Define(_, id, value, _) = &statement {
+                    if is_literal(value) {
+                        known.insert(id.to_string(), (**value).clone());
+                    }
+                }
+                statements.push(statement);
+            }
+            TypedAST::Program(typ, statements, span)
+        }
+        _ => map_children(ast, &mut |child| propagate(child, known)),
+    };
+    fold(simplify(ast))
+}
+
+impl Pass for Propagate {
+    fn name(&self) -> &str {
+        "propagate"
+    }
+
+    fn typed(&self, ast: TypedAST) -> TypedAST {
+        propagate(ast, &HashMap::new())
+    }
+}
+
+// Computes repeated pure expressions once. The first occurrence is passed to
+// a function whose body is the enclosing expression, with each occurrence
+// replaced by the parameter, in the same way that loops bind their elements.
+// The first occurrence must run before anything that could fail or have an
+// effect, so that computing it earlier can't change what the program does.
+pub struct CommonSubexpressions;
+
+// A key that is the same for expressions that always have the same value,
+// with its size, or None for expressions that aren't pure. Calls are never
+// pure, since native functions can have effects.
+fn pure_key(ast: &TypedAST) -> Option<(String, usize)> {
+    match ast {
+        TypedAST::BinaryOp(..) => {
+            let (first, links) = chain(ast);
+            let (mut key, mut size) = pure_key(first)?;
+            for (op, rhs, _) in links {
+                let (rhs, n) = pure_key(rhs)?;
+                key = format!("({} {} {})", op, key, rhs);
+                size += n + 1;
+            }
+            Some((key, size))
+        }
+        TypedAST::Boolean(b, _) => Some((b.to_string(), 1)),
+        TypedAST::Char(c, _) => Some((format!("{:?}", c), 1)),
+        TypedAST::Field(_, value, field, _) => {
+            let (value, n) = pure_key(value)?;
+            Some((format!("{}.{}", value, field), n + 1))
+        }
+        TypedAST::Identifier(_, id, _) => Some((id.to_string(), 1)),
+        TypedAST::Index(value, index, _) => {
+            let (value, m) = pure_key(value)?;
+            let (index, n) = pure_key(index)?;
+            Some((format!("{}[{}]", value, index), m + n + 1))
+        }
+        TypedAST::Integer(i, _) => Some((i.to_string(), 1)),
+        TypedAST::Record(_, fields, _) => {
+            let mut keys = Vec::new();
+            let mut size = 1;
+            for (name, value) in fields {
+                let (key, n) = pure_key(value)?;
+                keys.push(format!("{} = {}", name, key));
+                size += n;
+            }
+            Some((format!("{{{}}}", keys.join(", ")), size))
+        }
+        TypedAST::Slice(value, start, end, _) => {
+            let (value, m) = pure_key(value)?;
+            let (start, n) = pure_key(start)?;
+            let (end, o) = pure_key(end)?;
+            Some((format!("{}[{}..{}]", value, start, end), m + n + o + 1))
+        }
+        TypedAST::String(s, _) => Some((format!("{:?}", s), 1)),
+        TypedAST::Tuple(_, elements, _) => {
+            let mut keys = Vec::new();
+            let mut size = 1;
+            for element in elements {
+                let (key, n) = pure_key(element)?;
+                keys.push(key);
+                size += n;
+            }
+            Some((format!("({})", keys.join(", ")), size))
+        }
+        TypedAST::UnaryOp(_, op, ast, _) => {
+            let (ast, n) = pure_key(ast)?;
+            Some((format!("({} {})", op, ast), n + 1))
+        }
+        TypedAST::Unit(_) => Some(("()".to_string(), 1)),
+        _ => None,
+    }
+}
+
+// Whether evaluating ast itself, once its children are evaluated, could raise
+// an error or have an effect.
+fn is_risky(ast: &TypedAST) -> bool {
+    match ast {
+        TypedAST::BinaryOp(_, op, _, _, _) => matches!(
+            op,
+            Operator::Divide
+                | Operator::Minus
+                | Operator::Mod
+                | Operator::Multiply
+                | Operator::Plus
+        ),
+        TypedAST::UnaryOp(_, op, _, _) => matches!(op, Operator::Minus),
+        TypedAST::Call(..)
+        | TypedAST::Cast(..)
+        | TypedAST::Datatype(..)
+        | TypedAST::Define(..)
+        | TypedAST::For(..)
+        | TypedAST::Index(..)
+        | TypedAST::Match(..)
+        | TypedAST::Slice(..) => true,
+        _ => false,
+    }
+}
+
+// The children of ast that are evaluated in the same scope, in the order the
+// generated code evaluates them. Those that are only evaluated sometimes are
+// marked as conditional.
+fn scope_children(ast: &TypedAST) -> Vec<(&TypedAST, bool)> {
+    match ast {
+        TypedAST::BinaryOp(_, _, lhs, rhs, _) => vec![(&**rhs, false), (&**lhs, false)],
+        TypedAST::Call(fun, arg, _) => vec![(&**arg, false), (&**fun, false)],
+        TypedAST::Define(_, _, value, _) => vec![(&**value, false)],
+        TypedAST::For(_, iter, _, _) => vec![(&**iter, false)],
+        TypedAST::If(conds, els, _) => {
+            let mut children = Vec::new();
+            for (cond, then) in conds {
+                children.push((cond, !children.is_empty()));
+                children.push((then, true));
+            }
+            children.push((&**els, true));
+            children
+        }
+        TypedAST::Index(value, index, _) => vec![(&**value, false), (&**index, false)],
+        TypedAST::Match(cond, _, _, _) => vec![(&**cond, false)],
+        TypedAST::Program(_, expressions, _) => expressions.iter().map(|e| (e, false)).collect(),
+        TypedAST::Slice(value, start, end, _) => {
+            vec![(&**value, false), (&**start, false), (&**end, false)]
+        }
+        TypedAST::Cast(_, ast, _)
+        | TypedAST::Field(_, ast, _, _)
+        | TypedAST::Spread(_, ast, _)
+        | TypedAST::UnaryOp(_, _, ast, _) => vec![(&**ast, false)],
+        TypedAST::Record(_, fields, _) => fields.iter().map(|field| (&field.1, false)).collect(),
+        TypedAST::Tuple(_, elements, _) => elements.iter().map(|e| (e, false)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn count_pure(ast: &TypedAST, counts: &mut HashMap<String, (usize, usize)>) {
+    let mut pending = vec![ast];
+    while let Some(ast) = pending.pop() {
+        if let Some((key, size)) = pure_key(ast) {
+            let entry = counts.entry(key).or_insert((0, size));
+            entry.0 += 1;
+        }
+        pending.extend(scope_children(ast).into_iter().map(|(child, _)| child));
+    }
+}
+
+// Finds the first occurrence of key to be evaluated, and whether it is
+// evaluated unconditionally before anything risky.
+fn first_occurrence<'a>(
+    ast: &'a TypedAST,
+    key: &str,
+    risky: &mut bool,
+    conditional: bool,
+) -> Option<(&'a TypedAST, bool)> {
+    if pure_key(ast).is_some_and(|(k, _)| k == key) {
+        return Some((ast, !*risky && !conditional));
+    }
+    // Right operands are evaluated first, so a chain of operators is searched
+    // from the outermost one down, and its operators are risky afterwards.
+    if let TypedAST::BinaryOp(..) = ast {
+        let mut links = vec![ast];
+        let mut lhs = ast;
+        while let TypedAST::BinaryOp(_, _, inner, rhs, _) = lhs {
+            if let Some(found) = first_occurrence(rhs, key, risky, conditional) {
+                return Some(found);
+            }
+            lhs = inner;
+            if pure_key(lhs).is_some_and(|(k, _)| k == key) {
+                return Some((lhs, !*risky && !conditional));
+            }
+            if let TypedAST::BinaryOp(..) = lhs {
+                links.push(lhs);
+            }
+        }
+        if let Some(found) = first_occurrence(lhs, key, risky, conditional) {
+            return Some(found);
+        }
+        for link in links.into_iter().rev() {
+            *risky |= is_risky(link);
+        }
+        return None;
+    }
+    for (child, sometimes) in scope_children(ast) {
+        if let Some(found) = first_occurrence(child, key, risky, conditional || sometimes) {
+            return Some(found);
+        }
+    }
+    *risky |= is_risky(ast);
+    None
+}
+
+fn replace_pure(ast: TypedAST, key: &str, param: &TypedAST) -> TypedAST {
+    if pure_key(&ast).is_some_and(|(k, _)| k == key) {
+        return param.clone();
+    }
+    match ast {
+        TypedAST::BinaryOp(..) => {
+            let mut links = Vec::new();
+            let mut lhs = ast;
+            while let TypedAST::BinaryOp(typ, op, inner, rhs, span) = lhs {
+                links.push((typ, op, replace_pure(*rhs, key, param), span));
+                lhs = *inner;
+                if pure_key(&lhs).is_some_and(|(k, _)| k == key) {
+                    break;
+                }
+            }
+            let mut ast = replace_pure(lhs, key, param);
+            for (typ, op, rhs, span) in links.into_iter().rev() {
+                ast = TypedAST::BinaryOp(typ, op, Box::new(ast), Box::new(rhs), span);
+            }
+            ast
+        }
+        TypedAST::For(pattern, iter, body, span) => TypedAST::For(
+            pattern,
+            Box::new(replace_pure(*iter, key, param)),
+            body,
+            span,
+        ),
+        TypedAST::Function(..) => ast,
+        TypedAST::Match(cond, typ, cases, span) => {
+            TypedAST::Match(Box::new(replace_pure(*cond, key, param)), typ, cases, span)
+        }
+        _ => map_children(ast, &mut |child| replace_pure(child, key, param)),
+    }
+}
+
+fn share_common(ast: TypedAST, count: &mut usize) -> TypedAST {
+    if !matches!(
+        ast,
+        TypedAST::BinaryOp(..)
+            | TypedAST::Call(..)
+            | TypedAST::If(..)
+            | TypedAST::Index(..)
+            | TypedAST::Slice(..)
+            | TypedAST::Tuple(..)
+            | TypedAST::UnaryOp(..)
+    ) {
+        return ast;
+    }
+    let mut counts = HashMap::new();
+    count_pure(&ast, &mut counts);
+    let mut repeated: Vec<(String, usize)> = counts
+        .into_iter()
+        .filter(|(_, (n, size))| *n > 1 && *size >= 3)
+        .map(|(key, (_, size))| (key, size))
+        .collect();
+    // Larger expressions first, and otherwise in a fixed order.
+    repeated.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    for (key, _) in repeated {
+        let value = match first_occurrence(&ast, &key, &mut false, false) {
+            Some((value, true)) => value.clone(),
+            _ => continue,
+        };
+        let span = span_of(&ast);
+        let param = TypedAST::Identifier(type_of(&value), format!("%{}", count), span);
+        *count += 1;
+        let body = share_common(replace_pure(ast, &key, &param), count);
+        let fun = TypedAST::Function(None, Box::new(param), Box::new(body), span);
+        return TypedAST::Call(Box::new(fun), Box::new(value), span);
+    }
+    ast
+}
+
+impl Pass for CommonSubexpressions {
+    fn name(&self) -> &str {
+        "common-subexpressions"
+    }
+
+    fn typed(&self, ast: TypedAST) -> TypedAST {
+        let mut count = 0;
+        rewrite(ast, &mut |ast| share_common(ast, &mut count))
+    }
+}
+
+fn jump_targets(instr: &[Opcode]) -> HashSet<usize> {
+    let mut targets = HashSet::new();
+    for (i, op) in instr.iter().enumerate() {
+        if let Opcode::Jmp(offset) | Opcode::Jz(offset) = op {
+            targets.insert((i as i64 + offset) as usize);
+        }
+    }
+    targets
+}
+
+// Deletes the instructions marked dead, adjusting jumps over them. A jump to
+// a dead instruction goes to the next live one instead.
+pub fn remove(instr: &mut Vec<Opcode>, dead: &[bool]) {
+    // The new position of each instruction, and of the end of the chunk.
+    let mut positions = Vec::with_capacity(instr.len() + 1);
+    let mut next = 0;
+    for is_dead in dead {
+        positions.push(next);
+        if !is_dead {
+            next += 1;
+        }
+    }
+    positions.push(next);
+    let old = std::mem::take(instr);
+    for (i, op) in old.into_iter().enumerate() {
+        if dead[i] {
+            continue;
+        }
+        let moved = |offset: i64| {
+            let target = (i as i64 + offset) as usize;
+            positions[target] as i64 - positions[i] as i64
+        };
+        instr.push(match op {
+            Opcode::Jmp(offset) => Opcode::Jmp(moved(offset)),
+            Opcode::Jz(offset) => Opcode::Jz(moved(offset)),
+            op => op,
+        });
+    }
+}
+
+// Replaces short sequences of instructions with shorter ones: definitions
+// whose value is discarded, jumps to the next instruction, and positions that
+// are overwritten before anything can report them.
+pub struct Peephole;
+
+impl Pass for Peephole {
+    fn name(&self) -> &str {
+        "peephole"
+    }
+
+    fn bytecode(&self, instr: &mut Vec<Opcode>) {
+        let targets = jump_targets(instr);
+        let mut dead = vec![false; instr.len()];
+        let mut i = 0;
+        while i < instr.len() {
+            match &instr[i..] {
+                [Opcode::Dup, Opcode::SetEnv(_), Opcode::Pop, ..]
+                    if !targets.contains(&(i + 1)) && !targets.contains(&(i + 2)) =>
+                {
+                    dead[i] = true;
+                    dead[i + 2] = true;
+                    i += 3;
+                }
+                [Opcode::Jmp(1), ..] => {
+                    dead[i] = true;
+                    i += 1;
+                }
+                [Opcode::Srcpos(_), Opcode::Srcpos(_), ..] if !targets.contains(&(i + 1)) => {
+                    dead[i] = true;
+                    i += 1;
+                }
+                _ => i += 1,
+            }
+        }
+        if dead.contains(&true) {
+            remove(instr, &dead);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen;
+    use crate::optimize;
+    use crate::optimize::{Level, Pass};
+    use crate::parser;
+    use crate::typeinfer::TypedAST;
+    use crate::vm;
+    use crate::vm::{Opcode, Value};
+
+    fn eval(level: Level, src: &str) -> (Result<Value, String>, usize) {
+        let mut vm = vm::VirtualMachine::new();
+        vm.passes = optimize::pipeline(level);
+        match parser::parse(src) {
+            Ok(ast) => {
+                let result = codegen::eval(&mut vm, &ast).map_err(|err| err.err);
+                (result, vm.statistics().instructions)
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn optimizes() {
+        let programs = [
+            "def x := 2 * 3 + 1\nx",
+            "if 1 > 2 then 0 elsif true then 1 else 2 end",
+            "fn f (n) -> if n < 0 then -1 else n * (4 - 3) end end\nf (5) + f (-2)",
+            "1\n2\n~(true && false)",
+            "'a' < 'b'",
+            "1 / (1 - 1)",
+            "9223372036854775807 + 1",
+            "fn double (x) -> 2 * x end\nfn add (a, b) -> a + b end\nadd (double (3), 1)",
+            "fn f (x) -> x + 1 end\nfn g (y) -> f (y) * 2 end\ng (f (1))",
+            "fn f (x) -> x end\ndef a := f (1)\nfn f (x) -> x + 1 end\na + f (1)",
+            "fn f (n) -> (n % 3 == 0) || (n % 3 == 1) end\n(f (3), f (4), f (5))",
+            "fn g (n) -> if n > 0 then 10 / n + 10 / n else 10 / n end end\ng (1) + g (0)",
+            "fn h (s) -> s[1] == s[1] end\nh (\"ab\")",
+            "fn f (x, s) -> (x * 1 + 0 == x, ~~(s <= s), 0 * x, x / 1 - 0) end\nf (3, \"a\")",
+            "fn f (x) -> (0 * (x / 0), x - x, (true && (x > 0)) || false) end\nf (1)",
+            "fn f (g) -> g == g end\nf (fn (x) -> x end)",
+            "def n := 10\ndef m := n * n\nfn f (n) -> n + m end\n(f (1), if m > 50 then n else 0 end)",
+            "def n := 1\nif n > 0 then def n := 2 else 3 end\nn",
+            "type Option := Some (x) | None end\ndef n := 1\nfor n in range ((0, 2)) do n end\nmatch Some (5) with Some (n) -> n | None -> n end",
+            "fn fact (n) -> if n < 2 then 1 else n * fact (n - 1) end end\nfact (5)",
+        ];
+        for program in &programs {
+            let (unoptimized, before) = eval(Level::O0, program);
+            let (optimized, after) = eval(Level::O2, program);
+            assert_eq!(unoptimized, optimized);
+            assert!(after <= before);
+        }
+        assert_eq!(eval(Level::O1, "2 * 3 + 1").1, 1);
+        assert_eq!(
+            eval(Level::O1, "fn f (x) -> x * 1 + 0 end").1,
+            eval(Level::O1, "fn f (x) -> x end").1
+        );
+        assert_eq!(
+            eval(Level::O1, "def n := 10\nn * n").1,
+            eval(Level::O1, "def n := 10\n100").1
+        );
+        let calls = "fn double (x) -> 2 * x end\ndouble (3) + double (4)";
+        assert!(eval(Level::O2, calls).1 < eval(Level::O1, calls).1);
+        let repeated = "fn f (n) -> (n % 3 == 0) || (n % 3 == 1) end\nf (4)";
+        assert_eq!(eval(Level::O2, repeated).0, Ok(Value::Boolean(true)));
+        let mut vm = vm::VirtualMachine::new();
+        match parser::parse("fn f (n) -> (n % 3 == 0) || (n % 3 == 1) end") {
+            Ok(ast) => match crate::typeinfer::infer(&ast, &mut vm.env.types) {
+                Ok(typed) => match optimize::CommonSubexpressions.typed(typed) {
+                    TypedAST::Program(_, statements, _) => match &statements[0] {
+                        TypedAST::Function(_, _, body, _) => match &**body {
+                            TypedAST::Program(_, body, _) => {
+                                assert!(matches!(&body[0], TypedAST::Call(..)))
+                            }
+                            _ => unreachable!(),
+                        },
+                        _ => unreachable!(),
+                    },
+                    _ => unreachable!(),
+                },
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        }
+        let redefined =
+            "fn f (x) -> x end\nif true then def f := fn (x) -> 2 end else f end\nf (1)";
+        assert_eq!(eval(Level::O2, redefined).0, Ok(Value::Integer(2)));
+        assert_eq!(
+            eval(Level::O1, "def x := 1 / 0").0,
+            Err("Division by zero.".to_string())
+        );
+    }
+
+    #[test]
+    fn removes_instructions() {
+        let mut instr = vec![
+            Opcode::Bconst(true),
+            Opcode::Jz(4),
+            Opcode::Dup,
+            Opcode::SetEnv("x".to_string()),
+            Opcode::Pop,
+            Opcode::Jmp(1),
+            Opcode::Uconst,
+        ];
+        optimize::Peephole.bytecode(&mut instr);
+        let instr: Vec<String> = instr.iter().map(|op| op.to_string()).collect();
+        assert_eq!(instr, vec!["const true", "jz 2", "setenv x", "const"]);
+    }
+}
@@ -1,5 +1,7 @@
+use std::cell::RefCell;
 use std::fmt;
 
+use crate::diagnostics::Code;
 use crate::pest::Parser;
 use pest::iterators::Pair;
 
@@ -7,6 +9,12 @@ use pest::iterators::Pair;
 #[grammar = "plover.pest"]
 pub struct PloverParser;
 
+// The reserved words of the grammar, which can't be used as identifiers.
+pub const KEYWORDS: [&str; 16] = [
+    "as", "def", "do", "else", "elsif", "end", "false", "fn", "for", "if", "in", "match", "then",
+    "true", "type", "with",
+];
+
 #[derive(Clone, Debug)]
 pub enum Operator {
     And,
@@ -46,30 +54,185 @@ impl fmt::Display for Operator {
     }
 }
 
+#[derive(Clone, Debug)]
+pub enum TypeAnnotation {
+    Function(Box<TypeAnnotation>, Box<TypeAnnotation>),
+    Name(String),
+    Record(Vec<(String, TypeAnnotation)>),
+    Tuple(Vec<TypeAnnotation>),
+    Union(Vec<TypeAnnotation>),
+    Variable(String),
+}
+
+impl fmt::Display for TypeAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeAnnotation::Function(param, body) => match &**param {
+                TypeAnnotation::Function(_, _) => write!(f, "({}) -> {}", param, body),
+                _ => write!(f, "{} -> {}", param, body),
+            },
+            TypeAnnotation::Name(name) => write!(f, "{}", name),
+            TypeAnnotation::Record(fields) => {
+                write!(f, "{{")?;
+                for i in 0..fields.len() {
+                    write!(f, "{}: {}", fields[i].0, fields[i].1)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            TypeAnnotation::Tuple(elements) => {
+                write!(f, "(")?;
+                for i in 0..elements.len() {
+                    write!(f, "{}", elements[i])?;
+                    if i + 1 != elements.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, ")")
+            }
+            TypeAnnotation::Union(members) => {
+                for i in 0..members.len() {
+                    match &members[i] {
+                        TypeAnnotation::Function(_, _) => write!(f, "({})", members[i])?,
+                        member => write!(f, "{}", member)?,
+                    }
+                    if i + 1 != members.len() {
+                        write!(f, " | ")?;
+                    }
+                }
+                Ok(())
+            }
+            TypeAnnotation::Variable(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+// A region of source text. Lines and columns start at one and the end
+// position is exclusive.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+thread_local! {
+    // The address of the source being parsed and the offset each of its lines
+    // starts at, while parse builds a tree from it.
+    static LINE_STARTS: RefCell<Option<(usize, Vec<usize>)>> = const { RefCell::new(None) };
+}
+
+// Finding the line and column of a position in the source by counting from
+// its start makes building a tree take time quadratic in the length of the
+// source, so while parsing, the line is found from where each one starts.
+fn line_col(position: &pest::Position) -> (usize, usize) {
+    let input = position.span(position).get_input();
+    let pos = position.pos();
+    LINE_STARTS.with(|starts| match &*starts.borrow() {
+        Some((address, starts)) if *address == input.as_ptr() as usize => {
+            let line = starts.partition_point(|&start| start <= pos) - 1;
+            (line + 1, input[starts[line]..pos].chars().count() + 1)
+        }
+        _ => position.line_col(),
+    })
+}
+
+impl Span {
+    pub fn new(start: &pest::Position, end: &pest::Position) -> Span {
+        let (line, col) = line_col(start);
+        let (end_line, end_col) = line_col(end);
+        Span {
+            line,
+            col,
+            end_line,
+            end_col,
+        }
+    }
+
+    // Used for errors that can not be attributed to any particular source.
+    pub fn unknown() -> Span {
+        Span {
+            line: usize::MAX,
+            col: usize::MAX,
+            end_line: usize::MAX,
+            end_col: usize::MAX,
+        }
+    }
+}
+
+// Rules that end with a repetition also consume the whitespace and comments
+// that follow them, which are not part of the expression.
+fn trimmed_end<'i>(span: &pest::Span<'i>) -> pest::Position<'i> {
+    let mut text = span.as_str();
+    loop {
+        let trimmed = text.trim_end();
+        match comment_starts(trimmed).last() {
+            Some(&i) if !trimmed[i..].contains('\n') => text = &trimmed[..i],
+            _ => {
+                text = trimmed;
+                break;
+            }
+        }
+    }
+    pest::Position::new(span.get_input(), span.start() + text.len()).unwrap()
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span) -> Span {
+        Span::new(&span.start_pos(), &trimmed_end(&span))
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub enum AST {
-    BinaryOp(Operator, Box<AST>, Box<AST>, usize, usize),
-    Boolean(bool, usize, usize),
-    Call(Box<AST>, Box<AST>, usize, usize),
-    Datatype(String, Vec<(String, Option<AST>)>, usize, usize),
-    Define(Box<AST>, Box<AST>, usize, usize),
-    Function(Option<String>, Box<AST>, Box<AST>, usize, usize),
-    Identifier(String, usize, usize),
-    If(Vec<(AST, AST)>, Box<AST>, usize, usize),
-    Integer(i64, usize, usize),
-    Match(Box<AST>, Vec<(String, Option<AST>, AST)>, usize, usize),
-    Program(Vec<AST>, usize, usize),
-    Tuple(Vec<AST>, usize, usize),
-    UnaryOp(Operator, Box<AST>, usize, usize),
-    Unit(usize, usize),
+    Ascription(Box<AST>, TypeAnnotation, Span),
+    BinaryOp(Operator, Box<AST>, Box<AST>, Span),
+    Boolean(bool, Span),
+    Call(Box<AST>, Box<AST>, Span),
+    Char(char, Span),
+    Datatype(String, Vec<(String, Option<AST>)>, Span),
+    Define(Box<AST>, Box<AST>, Span),
+    Field(Box<AST>, String, Span),
+    For(Box<AST>, Box<AST>, Box<AST>, Span),
+    Function(Option<String>, Box<AST>, Box<AST>, Span),
+    Identifier(String, Span),
+    If(Vec<(AST, AST)>, Box<AST>, Span),
+    Index(Box<AST>, Box<AST>, Span),
+    Integer(i64, Span),
+    Match(Box<AST>, Vec<(Pattern, AST)>, Span),
+    Program(Vec<AST>, Span),
+    Record(Vec<(String, AST)>, Span),
+    Slice(Box<AST>, Box<AST>, Box<AST>, Span),
+    Spread(Box<AST>, Span),
+    String(String, Span),
+    Tuple(Vec<AST>, Span),
+    UnaryOp(Operator, Box<AST>, Span),
+    Unit(Span),
 }
 
 impl fmt::Display for AST {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            AST::BinaryOp(op, lhs, rhs, _, _) => write!(f, "({} {} {})", op, lhs, rhs),
-            AST::Boolean(b, _, _) => write!(f, "{}:Boolean", b),
-            AST::Call(fun, args, _, _) => write!(f, "(apply {} {})", fun, args),
-            AST::Datatype(name, variants, _, _) => {
+            AST::Ascription(expr, typ, _) => write!(f, "(ascribe {} {})", expr, typ),
+            AST::BinaryOp(_, _, _, _) => {
+                let (first, links) = chain(self);
+                for (op, _, _) in links.iter().rev() {
+                    write!(f, "({} ", op)?;
+                }
+                write!(f, "{}", first)?;
+                for (_, rhs, _) in links {
+                    write!(f, " {})", rhs)?;
+                }
+                Ok(())
+            }
+            AST::Boolean(b, _) => write!(f, "{}:Boolean", b),
+            AST::Call(fun, args, _) => write!(f, "(apply {} {})", fun, args),
+            AST::Char(c, _) => write!(f, "{}:Char", escape_char(*c)),
+            AST::Datatype(name, variants, _) => {
                 write!(f, "(")?;
                 for i in 0..variants.len() {
                     write!(f, "{}", variants[i].0)?;
@@ -82,38 +245,39 @@ impl fmt::Display for AST {
                 }
                 write!(f, ") {}:Type", name)
             }
-            AST::Define(id, value, _, _) => write!(f, "(define {} {})", id, value),
-            AST::Function(id, param, body, _, _) => {
+            AST::Define(id, value, _) => write!(f, "(define {} {})", id, value),
+            AST::Field(value, field, _) => write!(f, "(field {} {})", value, field),
+            AST::For(pattern, iter, body, _) => {
+                write!(f, "(for {} {} {})", pattern, iter, body)
+            }
+            AST::Function(id, param, body, _) => {
                 if let Some(id) = id {
                     write!(f, "({} {} {})", id, param, body)
                 } else {
                     write!(f, "(fn {} {})", param, body)
                 }
             }
-            AST::Identifier(id, _, _) => write!(f, "{}:Identifier", id),
-            AST::If(conds, els, _, _) => {
+            AST::Identifier(id, _) => write!(f, "{}:Identifier", id),
+            AST::If(conds, els, _) => {
                 write!(f, "(if ")?;
                 for cond in conds {
                     write!(f, "(cond {} {}) ", cond.0, cond.1)?;
                 }
                 write!(f, "(else {}))", els)
             }
-            AST::Integer(n, _, _) => write!(f, "{}:Integer", n),
-            AST::Match(id, cases, _, _) => {
+            AST::Index(value, index, _) => write!(f, "(index {} {})", value, index),
+            AST::Integer(n, _) => write!(f, "{}:Integer", n),
+            AST::Match(id, cases, _) => {
                 write!(f, "(match {} ", id)?;
                 for i in 0..cases.len() {
-                    if let Some(param) = &cases[i].1 {
-                        write!(f, "(case {}: {} {})", cases[i].0, param, cases[i].2)?;
-                    } else {
-                        write!(f, "(case {} {})", cases[i].0, cases[i].2)?;
-                    }
+                    write!(f, "(case {} {})", cases[i].0, cases[i].1)?;
                     if i + 1 != cases.len() {
                         write!(f, " ")?;
                     }
                 }
                 write!(f, ")")
             }
-            AST::Program(expressions, _, _) => {
+            AST::Program(expressions, _) => {
                 if expressions.len() > 1 {
                     write!(f, "(")?;
                 }
@@ -128,7 +292,17 @@ impl fmt::Display for AST {
                 }
                 Ok(())
             }
-            AST::Tuple(elements, _, _) => {
+            AST::Record(fields, _) => {
+                write!(f, "{{")?;
+                for i in 0..fields.len() {
+                    write!(f, "{} = {}", fields[i].0, fields[i].1)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}:Record")
+            }
+            AST::Tuple(elements, _) => {
                 write!(f, "(")?;
                 for i in 0..elements.len() {
                     write!(f, "{}", elements[i])?;
@@ -138,22 +312,452 @@ impl fmt::Display for AST {
                 }
                 write!(f, "):Tuple")
             }
-            AST::UnaryOp(op, ast, _, _) => write!(f, "({} {})", op, ast),
-            AST::Unit(_, _) => write!(f, "():Unit"),
+            AST::Slice(value, start, end, _) => write!(f, "(slice {} {} {})", value, start, end),
+            AST::Spread(ast, _) => write!(f, "(spread {})", ast),
+            AST::String(s, _) => write!(f, "{}:String", escape(s)),
+            AST::UnaryOp(op, ast, _) => write!(f, "({} {})", op, ast),
+            AST::Unit(_) => write!(f, "():Unit"),
         }
     }
 }
 
+// What the arms of a match take their value apart with. Names at the top of
+// an arm, or followed by a pattern for a value, are variants, or members of a
+// union. Other names inside a pattern are bound to the part of the value they
+// match, unless type checking finds they name a variant without a value.
+#[derive(Clone)]
+pub enum Pattern {
+    // Binds the name to the whole value that the pattern matches.
+    As(Box<Pattern>, String, Span),
+    Identifier(String, Span),
+    // Matches values equal to a boolean, character, integer or string.
+    Literal(Box<AST>, Span),
+    // Matches if any of the alternatives does, each of which must bind the
+    // same names.
+    Or(Vec<Pattern>, Span),
+    Tuple(Vec<Pattern>, Span),
+    Variant(String, Option<Box<Pattern>>, Span),
+    // Matches anything, without binding it.
+    Wildcard(Span),
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::As(pattern, id, _) => write!(f, "(as {} {}:Identifier)", pattern, id),
+            Pattern::Identifier(id, _) => write!(f, "{}:Identifier", id),
+            Pattern::Literal(literal, _) => write!(f, "{}", literal),
+            Pattern::Or(alternatives, _) => {
+                write!(f, "(or")?;
+                for alternative in alternatives {
+                    write!(f, " {}", alternative)?;
+                }
+                write!(f, ")")
+            }
+            Pattern::Tuple(elements, _) => {
+                write!(f, "(")?;
+                for i in 0..elements.len() {
+                    write!(f, "{}", elements[i])?;
+                    if i + 1 != elements.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "):Tuple")
+            }
+            Pattern::Variant(name, Some(pattern), _) => write!(f, "{}: {}", name, pattern),
+            Pattern::Variant(name, None, _) => write!(f, "{}", name),
+            Pattern::Wildcard(_) => write!(f, "_"),
+        }
+    }
+}
+
+pub fn pattern_span(pattern: &Pattern) -> Span {
+    match pattern {
+        Pattern::As(_, _, span)
+        | Pattern::Identifier(_, span)
+        | Pattern::Literal(_, span)
+        | Pattern::Or(_, span)
+        | Pattern::Tuple(_, span)
+        | Pattern::Variant(_, _, span)
+        | Pattern::Wildcard(span) => *span,
+    }
+}
+
+// The expressions directly inside of an expression, in source order.
+pub fn children(ast: &AST) -> Vec<&AST> {
+    match ast {
+        AST::Ascription(expr, _, _)
+        | AST::Field(expr, _, _)
+        | AST::Spread(expr, _)
+        | AST::UnaryOp(_, expr, _) => vec![expr],
+        AST::BinaryOp(_, lhs, rhs, _) => vec![lhs, rhs],
+        AST::Call(fun, arg, _) => vec![fun, arg],
+        AST::Datatype(_, variants, _) => variants
+            .iter()
+            .filter_map(|variant| variant.1.as_ref())
+            .collect(),
+        AST::Define(id, value, _) => vec![id, value],
+        AST::For(pattern, iter, body, _) => vec![pattern, iter, body],
+        AST::Function(_, param, body, _) => vec![param, body],
+        AST::If(conds, els, _) => {
+            let mut children = Vec::new();
+            for cond in conds {
+                children.push(&cond.0);
+                children.push(&cond.1);
+            }
+            children.push(els);
+            children
+        }
+        AST::Index(value, index, _) => vec![value, index],
+        AST::Match(cond, cases, _) => {
+            let mut children = vec![&**cond];
+            children.extend(cases.iter().map(|case| &case.1));
+            children
+        }
+        AST::Program(expressions, _) | AST::Tuple(expressions, _) => expressions.iter().collect(),
+        AST::Record(fields, _) => fields.iter().map(|field| &field.1).collect(),
+        AST::Slice(value, start, end, _) => vec![value, start, end],
+        AST::Boolean(_, _)
+        | AST::Char(_, _)
+        | AST::Identifier(_, _)
+        | AST::Integer(_, _)
+        | AST::String(_, _)
+        | AST::Unit(_) => Vec::new(),
+    }
+}
+
+// Calls visit on ast and every expression inside it, each before the ones
+// inside it and otherwise in source order, without recursing. Returning false
+// from visit skips the expressions inside that one. Patterns and type
+// annotations are not visited, only the expressions they belong to.
+pub fn walk<'a>(ast: &'a AST, visit: &mut impl FnMut(&'a AST) -> bool) {
+    let mut pending = vec![ast];
+    while let Some(ast) = pending.pop() {
+        if visit(ast) {
+            pending.extend(children(ast).into_iter().rev());
+        }
+    }
+}
+
+// Operators associate to the left, so a chain such as 1 + 2 + 3 nests as
+// deeply as it is long. Passes follow chains with this instead of recursing:
+// it returns the leftmost operand, then each operator with its right operand
+// and span, innermost first.
+pub fn chain(ast: &AST) -> (&AST, Vec<(&Operator, &AST, Span)>) {
+    let mut links = Vec::new();
+    let mut lhs = ast;
+    while let AST::BinaryOp(op, inner, rhs, span) = lhs {
+        links.push((op, &**rhs, *span));
+        lhs = inner;
+    }
+    links.reverse();
+    (lhs, links)
+}
+
+// Finds an expression nested more than limit levels deep, without recursing,
+// so that programs too deep to compile can be rejected safely. The left
+// operands of a chain of operators don't count as nested, since passes follow
+// chains without recursing.
+pub fn too_deep(ast: &AST, limit: usize) -> Option<Span> {
+    let mut pending = vec![(ast, 0)];
+    while let Some((ast, depth)) = pending.pop() {
+        if depth > limit {
+            return Some(span_of(ast));
+        }
+        if let AST::BinaryOp(_, lhs, rhs, _) = ast {
+            pending.push((rhs, depth + 1));
+            pending.push((lhs, depth));
+            continue;
+        }
+        for child in children(ast).into_iter().rev() {
+            pending.push((child, depth + 1));
+        }
+    }
+    None
+}
+
+pub fn span_of(ast: &AST) -> Span {
+    match ast {
+        AST::Ascription(_, _, span)
+        | AST::BinaryOp(_, _, _, span)
+        | AST::Boolean(_, span)
+        | AST::Call(_, _, span)
+        | AST::Char(_, span)
+        | AST::Datatype(_, _, span)
+        | AST::Define(_, _, span)
+        | AST::Field(_, _, span)
+        | AST::For(_, _, _, span)
+        | AST::Function(_, _, _, span)
+        | AST::Identifier(_, span)
+        | AST::If(_, _, span)
+        | AST::Index(_, _, span)
+        | AST::Integer(_, span)
+        | AST::Match(_, _, span)
+        | AST::Program(_, span)
+        | AST::Record(_, span)
+        | AST::Slice(_, _, _, span)
+        | AST::Spread(_, span)
+        | AST::String(_, span)
+        | AST::Tuple(_, span)
+        | AST::UnaryOp(_, _, span)
+        | AST::Unit(span) => *span,
+    }
+}
+
+// Quotes a string, escaping the characters that can't appear in a string
+// literal as they are.
+pub fn escape(s: &str) -> String {
+    quote(s, '"')
+}
+
+pub fn escape_char(c: char) -> String {
+    quote(&c.to_string(), '\'')
+}
+
+fn quote(s: &str, delimiter: char) -> String {
+    let mut escaped = delimiter.to_string();
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            _ if c == delimiter => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped.push(delimiter);
+    escaped
+}
+
+// The grammar only allows the escapes that escape produces.
+fn unescape(literal: &str) -> String {
+    let mut s = String::new();
+    let mut chars = literal[1..literal.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some(c) => s.push(c),
+                None => unreachable!(),
+            }
+        } else {
+            s.push(c);
+        }
+    }
+    s
+}
+
+// Returns the length of the character literal that text starts with, if any.
+// A quote can also start a type variable, which has no closing quote.
+fn character_len(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+    let end = match chars.next() {
+        Some((_, '\\')) => chars.nth(1),
+        Some((_, '\'')) | Some((_, '\n')) | None => None,
+        Some(_) => chars.next(),
+    };
+    match end {
+        Some((i, '\'')) => Some(i + 1),
+        _ => None,
+    }
+}
+
+// Returns the length of the string literal that text starts with. Strings
+// that are not closed end with the line, and raw strings with the text.
+fn string_len(text: &str) -> usize {
+    if let Some(rest) = text.strip_prefix("\"\"\"") {
+        return rest.find("\"\"\"").map_or(text.len(), |i| i + 6);
+    }
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return i + 1,
+            '\n' => return i,
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+// Calls visit with where each comment starts, skipping over string and
+// character literals, and returns whether the text ends inside a raw string.
+// Raw strings can span lines, so the text must not start inside of one.
+fn scan(text: &str, mut visit: impl FnMut(usize)) -> bool {
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        match c {
+            '"' if text[i..].starts_with("\"\"\"") && !text[i + 3..].contains("\"\"\"") => {
+                return true;
+            }
+            '"' => i += string_len(&text[i..]),
+            '\'' => i += character_len(&text[i..]).unwrap_or(1),
+            '#' => {
+                visit(i);
+                i += text[i..].find('\n').unwrap_or(text.len() - i);
+            }
+            _ => i += c.len_utf8(),
+        }
+    }
+    false
+}
+
+pub fn comment_starts(text: &str) -> Vec<usize> {
+    let mut starts = Vec::new();
+    scan(text, |i| starts.push(i));
+    starts
+}
+
+#[derive(Debug)]
 pub struct ParseError {
+    pub code: Code,
     pub msg: String,
-    pub line: usize,
-    pub col: usize,
+    pub span: Span,
+}
+
+// The pattern of a match arm, where a single parenthesized pattern is the
+// pattern itself rather than a tuple of one.
+fn patternify(pair: Pair<Rule>) -> Pattern {
+    let span = Span::from(pair.as_span());
+    match pair.as_rule() {
+        Rule::identifier => Pattern::Identifier(pair.as_str().trim().to_string(), span),
+        Rule::literal => {
+            let mut inner = pair.into_inner();
+            let first = inner.next().unwrap();
+            let literal = match (first.as_rule(), inner.next()) {
+                (Rule::minus, Some(number)) => match astify(number) {
+                    AST::Integer(i, _) => AST::Integer(-i, span),
+                    _ => unreachable!(),
+                },
+                _ => astify(first),
+            };
+            Pattern::Literal(Box::new(literal), span)
+        }
+        Rule::wildcard => Pattern::Wildcard(span),
+        Rule::pattern | Rule::pattern_element => {
+            let rule = pair.as_rule();
+            let mut inner = pair.into_inner();
+            let first = inner.next().unwrap();
+            let variant = match inner.peek().map(|pair| pair.as_rule()) {
+                Some(Rule::pattern_tuple) => true,
+                _ => rule == Rule::pattern,
+            };
+            let mut pattern = match first.as_rule() {
+                Rule::identifier if variant => {
+                    let name = first.as_str().trim().to_string();
+                    let mut end = Span::from(first.as_span());
+                    let param = match inner.peek().map(|pair| pair.as_rule()) {
+                        Some(Rule::pattern_tuple) => {
+                            let pair = inner.next().unwrap();
+                            end = Span::from(pair.as_span());
+                            Some(Box::new(patternify(pair)))
+                        }
+                        _ => None,
+                    };
+                    Pattern::Variant(
+                        name,
+                        param,
+                        Span {
+                            end_line: end.end_line,
+                            end_col: end.end_col,
+                            ..span
+                        },
+                    )
+                }
+                _ => patternify(first),
+            };
+            if let Some(alias) = inner.next() {
+                pattern = Pattern::As(Box::new(pattern), alias.as_str().trim().to_string(), span);
+            }
+            pattern
+        }
+        Rule::pattern_tuple => {
+            let mut elements: Vec<Pattern> = pair.into_inner().map(patternify).collect();
+            if elements.len() == 1 {
+                elements.remove(0)
+            } else {
+                Pattern::Tuple(elements, span)
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn typify(pair: Pair<Rule>) -> TypeAnnotation {
+    match pair.as_rule() {
+        Rule::type_expr => {
+            let mut inner = pair.into_inner();
+            let param = typify(inner.next().unwrap());
+            match inner.next() {
+                Some(body) => TypeAnnotation::Function(Box::new(param), Box::new(typify(body))),
+                None => param,
+            }
+        }
+        Rule::type_name => TypeAnnotation::Name(pair.as_str().trim().to_string()),
+        Rule::type_record => TypeAnnotation::Record(
+            pair.into_inner()
+                .map(|field| {
+                    let mut inner = field.into_inner();
+                    let name = inner.next().unwrap().as_str().trim().to_string();
+                    (name, typify(inner.next().unwrap()))
+                })
+                .collect(),
+        ),
+        Rule::type_term => typify(pair.into_inner().next().unwrap()),
+        Rule::type_tuple => TypeAnnotation::Tuple(pair.into_inner().map(typify).collect()),
+        Rule::type_union => {
+            let mut members: Vec<TypeAnnotation> = pair.into_inner().map(typify).collect();
+            if members.len() == 1 {
+                members.remove(0)
+            } else {
+                TypeAnnotation::Union(members)
+            }
+        }
+        Rule::type_variable => TypeAnnotation::Variable(pair.as_str().trim().to_string()),
+        _ => unreachable!(),
+    }
 }
 
 #[allow(clippy::cognitive_complexity)]
+// Rules that only pass a single operand through, as they do for every level
+// of precedence that an operand doesn't use. Skipping them without recursing
+// keeps each level of nesting in the source to a few frames.
+fn operand(mut pair: Pair<Rule>) -> Pair<Rule> {
+    loop {
+        let mut inner = pair.clone().into_inner();
+        let passes = match pair.as_rule() {
+            Rule::addition
+            | Rule::call
+            | Rule::comparison
+            | Rule::equality
+            | Rule::index
+            | Rule::multiplication => inner.clone().count() == 1,
+            Rule::arguments | Rule::callee | Rule::value => true,
+            Rule::parenthesized => {
+                inner.clone().count() == 1
+                    && inner.peek().map(|pair| pair.as_rule()) != Some(Rule::spread)
+            }
+            Rule::conditional => !pair.as_str().starts_with("if"),
+            Rule::unary => inner.peek().map(|pair| pair.as_rule()) != Some(Rule::unary_op),
+            _ => false,
+        };
+        if !passes {
+            return pair;
+        }
+        pair = inner.next().unwrap();
+    }
+}
+
 fn astify(pair: Pair<Rule>) -> AST {
+    let pair = operand(pair);
     match pair.as_rule() {
         Rule::addition => {
+            let start = pair.as_span().start_pos();
             let mut inner = pair.into_inner();
             let mut lhs = astify(inner.next().unwrap());
             loop {
@@ -162,7 +766,6 @@ fn astify(pair: Pair<Rule>) -> AST {
                 } else {
                     let pair = inner.next().unwrap();
                     if let Rule::addition_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
                         let op = match pair.into_inner().next().unwrap().as_rule() {
                             Rule::minus => Operator::Minus,
                             Rule::or => Operator::Or,
@@ -170,7 +773,8 @@ fn astify(pair: Pair<Rule>) -> AST {
                             _ => unreachable!(),
                         };
                         let rhs = inner.next().unwrap();
-                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), line, col)
+                        let span = Span::new(&start, &trimmed_end(&rhs.as_span()));
+                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), span)
                     } else {
                         unreachable!();
                     }
@@ -179,25 +783,26 @@ fn astify(pair: Pair<Rule>) -> AST {
             lhs
         }
         Rule::boolean => {
-            let (line, col) = pair.as_span().start_pos().line_col();
-            AST::Boolean(pair.as_str().trim().parse().unwrap(), line, col)
+            let span = Span::from(pair.as_span());
+            AST::Boolean(pair.as_str().trim().parse().unwrap(), span)
         }
+        Rule::arguments | Rule::callee => astify(pair.into_inner().next().unwrap()),
         Rule::call => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let span = Span::from(pair.as_span());
             let mut inner = pair.into_inner();
             let value_or_fn = astify(inner.next().unwrap());
             if inner.peek().is_some() {
                 AST::Call(
                     Box::new(value_or_fn),
                     Box::new(astify(inner.next().unwrap())),
-                    line,
-                    col,
+                    span,
                 )
             } else {
                 value_or_fn
             }
         }
         Rule::comparison => {
+            let start = pair.as_span().start_pos();
             let mut inner = pair.into_inner();
             let mut lhs = astify(inner.next().unwrap());
             loop {
@@ -206,7 +811,6 @@ fn astify(pair: Pair<Rule>) -> AST {
                 } else {
                     let pair = inner.next().unwrap();
                     if let Rule::comparison_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
                         let op = match pair.into_inner().next().unwrap().as_rule() {
                             Rule::greater => Operator::Greater,
                             Rule::greater_equal => Operator::GreaterEqual,
@@ -215,7 +819,8 @@ fn astify(pair: Pair<Rule>) -> AST {
                             _ => unreachable!(),
                         };
                         let rhs = inner.next().unwrap();
-                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), line, col)
+                        let span = Span::new(&start, &trimmed_end(&rhs.as_span()));
+                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), span)
                     } else {
                         unreachable!();
                     }
@@ -225,7 +830,7 @@ fn astify(pair: Pair<Rule>) -> AST {
         }
         Rule::conditional => {
             if pair.as_str().starts_with("if") {
-                let (line, col) = pair.as_span().start_pos().line_col();
+                let span = Span::from(pair.as_span());
                 let mut conds = Vec::<(AST, AST)>::new();
                 let mut inner = pair.into_inner();
                 loop {
@@ -234,7 +839,7 @@ fn astify(pair: Pair<Rule>) -> AST {
                         let then = astify(inner.next().unwrap());
                         conds.push((cond_or_else, then));
                     } else {
-                        return AST::If(conds, Box::new(cond_or_else), line, col);
+                        return AST::If(conds, Box::new(cond_or_else), span);
                     }
                 }
             } else {
@@ -242,11 +847,12 @@ fn astify(pair: Pair<Rule>) -> AST {
             }
         }
         Rule::datatype => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let span = Span::from(pair.as_span());
             let mut inner = pair.into_inner();
             let name = inner.next().unwrap().as_str().trim();
             let mut variants = Vec::new();
             for variant in inner {
+                let variant_span = Span::from(variant.as_span());
                 let mut inner = variant.into_inner();
                 let id = inner.next().unwrap().as_str().trim().to_string();
                 let mut params = Vec::new();
@@ -256,20 +862,21 @@ fn astify(pair: Pair<Rule>) -> AST {
                 let param = match params.len() {
                     0 => None,
                     1 => Some(params.pop().unwrap()),
-                    _ => Some(AST::Tuple(params, line, col)),
+                    _ => Some(AST::Tuple(params, variant_span)),
                 };
                 variants.push((id, param));
             }
-            AST::Datatype(name.into(), variants, line, col)
+            AST::Datatype(name.into(), variants, span)
         }
         Rule::def => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let span = Span::from(pair.as_span());
             let mut inner = pair.into_inner();
             let id = astify(inner.next().unwrap());
             let value = astify(inner.next().unwrap());
-            AST::Define(Box::new(id), Box::new(value), line, col)
+            AST::Define(Box::new(id), Box::new(value), span)
         }
         Rule::equality => {
+            let start = pair.as_span().start_pos();
             let mut inner = pair.into_inner();
             let mut lhs = astify(inner.next().unwrap());
             loop {
@@ -278,14 +885,14 @@ fn astify(pair: Pair<Rule>) -> AST {
                 } else {
                     let pair = inner.next().unwrap();
                     if let Rule::equality_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
                         let op = match pair.into_inner().next().unwrap().as_rule() {
                             Rule::equal => Operator::Equal,
                             Rule::not_equal => Operator::NotEqual,
                             _ => unreachable!(),
                         };
                         let rhs = inner.next().unwrap();
-                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), line, col)
+                        let span = Span::new(&start, &trimmed_end(&rhs.as_span()));
+                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), span)
                     } else {
                         unreachable!();
                     }
@@ -293,8 +900,16 @@ fn astify(pair: Pair<Rule>) -> AST {
             }
             lhs
         }
+        Rule::for_loop => {
+            let span = Span::from(pair.as_span());
+            let mut inner = pair.into_inner();
+            let pattern = astify(inner.next().unwrap());
+            let iter = astify(inner.next().unwrap());
+            let body = astify(inner.next().unwrap());
+            AST::For(Box::new(pattern), Box::new(iter), Box::new(body), span)
+        }
         Rule::function => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let span = Span::from(pair.as_span());
             let mut inner = pair.into_inner();
             let id_or_param = inner.next().unwrap();
             let param_or_body = astify(inner.next().unwrap());
@@ -303,8 +918,7 @@ fn astify(pair: Pair<Rule>) -> AST {
                     None,
                     Box::new(astify(id_or_param)),
                     Box::new(param_or_body),
-                    line,
-                    col,
+                    span,
                 )
             } else {
                 let body = astify(inner.next().unwrap());
@@ -312,42 +926,68 @@ fn astify(pair: Pair<Rule>) -> AST {
                     Some(id_or_param.as_str().to_string()),
                     Box::new(param_or_body),
                     Box::new(body),
-                    line,
-                    col,
+                    span,
                 )
             }
         }
+        Rule::index => {
+            let start = pair.as_span().start_pos();
+            let mut inner = pair.into_inner();
+            let mut value = astify(inner.next().unwrap());
+            for suffix in inner {
+                let span = Span::new(&start, &trimmed_end(&suffix.as_span()));
+                let rule = suffix.as_rule();
+                let pair = suffix.into_inner().next().unwrap();
+                value = match (rule, pair.as_rule()) {
+                    (Rule::field, _) => {
+                        AST::Field(Box::new(value), pair.as_str().trim().to_string(), span)
+                    }
+                    (_, Rule::slice) => {
+                        let mut bounds = pair.into_inner();
+                        let lower = astify(bounds.next().unwrap());
+                        let upper = astify(bounds.next().unwrap());
+                        AST::Slice(Box::new(value), Box::new(lower), Box::new(upper), span)
+                    }
+                    _ => AST::Index(Box::new(value), Box::new(astify(pair)), span),
+                };
+            }
+            value
+        }
         Rule::identifier => {
-            let (line, col) = pair.as_span().start_pos().line_col();
-            AST::Identifier(pair.as_str().trim().parse().unwrap(), line, col)
+            let span = Span::from(pair.as_span());
+            AST::Identifier(pair.as_str().trim().parse().unwrap(), span)
         }
         Rule::match_expr => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let span = Span::from(pair.as_span());
             let mut inner = pair.into_inner();
             let cond = astify(inner.next().unwrap());
             let mut cases = Vec::new();
-            loop {
-                let variant = inner.next().unwrap();
-                let mut variant_inner = variant.into_inner();
-                let id = variant_inner.next().unwrap().as_str().to_string();
-                let mut params = Vec::new();
-                for param in variant_inner {
-                    params.push(astify(param));
+            for arm in inner {
+                let span = Span::from(arm.as_span());
+                let mut inner = arm.into_inner();
+                let mut alternatives = Vec::new();
+                while let Some(Rule::pattern) = inner.peek().map(|pair| pair.as_rule()) {
+                    alternatives.push(patternify(inner.next().unwrap()));
                 }
-                let param = match params.len() {
-                    0 => None,
-                    1 => Some(params.pop().unwrap()),
-                    _ => Some(AST::Tuple(params, line, col)),
+                let pattern = if alternatives.len() == 1 {
+                    alternatives.remove(0)
+                } else {
+                    let end = pattern_span(alternatives.last().unwrap());
+                    Pattern::Or(
+                        alternatives,
+                        Span {
+                            end_line: end.end_line,
+                            end_col: end.end_col,
+                            ..span
+                        },
+                    )
                 };
-                let expr = astify(inner.next().unwrap());
-                cases.push((id, param, expr));
-                if inner.peek().is_none() {
-                    break;
-                }
+                cases.push((pattern, astify(inner.next().unwrap())));
             }
-            AST::Match(Box::new(cond), cases, line, col)
+            AST::Match(Box::new(cond), cases, span)
         }
         Rule::multiplication => {
+            let start = pair.as_span().start_pos();
             let mut inner = pair.into_inner();
             let mut lhs = astify(inner.next().unwrap());
             loop {
@@ -356,7 +996,6 @@ fn astify(pair: Pair<Rule>) -> AST {
                 } else {
                     let pair = inner.next().unwrap();
                     if let Rule::multiplication_op = pair.as_rule() {
-                        let (line, col) = pair.as_span().start_pos().line_col();
                         let op = match pair.into_inner().next().unwrap().as_rule() {
                             Rule::and => Operator::And,
                             Rule::divide => Operator::Divide,
@@ -365,7 +1004,8 @@ fn astify(pair: Pair<Rule>) -> AST {
                             _ => unreachable!(),
                         };
                         let rhs = inner.next().unwrap();
-                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), line, col)
+                        let span = Span::new(&start, &trimmed_end(&rhs.as_span()));
+                        lhs = AST::BinaryOp(op, Box::new(lhs), Box::new(astify(rhs)), span)
                     } else {
                         unreachable!();
                     }
@@ -374,60 +1014,278 @@ fn astify(pair: Pair<Rule>) -> AST {
             lhs
         }
         Rule::number => {
-            let (line, col) = pair.as_span().start_pos().line_col();
-            AST::Integer(pair.as_str().trim().parse().unwrap(), line, col)
+            let span = Span::from(pair.as_span());
+            AST::Integer(pair.as_str().trim().parse().unwrap(), span)
+        }
+        Rule::raw_string => {
+            let span = Span::from(pair.as_span());
+            let literal = pair.as_str().trim();
+            AST::String(literal[3..literal.len() - 3].to_string(), span)
+        }
+        Rule::string => {
+            let span = Span::from(pair.as_span());
+            AST::String(unescape(pair.as_str().trim()), span)
+        }
+        Rule::character => {
+            let span = Span::from(pair.as_span());
+            match unescape(pair.as_str().trim()).chars().next() {
+                Some(c) => AST::Char(c, span),
+                None => unreachable!(),
+            }
         }
         Rule::body | Rule::program => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let span = Span::from(pair.as_span());
             let mut exprs = Vec::new();
             for expr in pair.into_inner() {
                 if expr.as_rule() != Rule::EOI {
                     exprs.push(astify(expr));
                 }
             }
-            AST::Program(exprs, line, col)
+            AST::Program(exprs, span)
         }
         Rule::unary => {
+            let span = Span::from(pair.as_span());
             let mut inner = pair.into_inner();
             let pair = inner.next().unwrap();
             if let Rule::unary_op = pair.as_rule() {
-                let (line, col) = pair.as_span().start_pos().line_col();
                 let op = match pair.into_inner().next().unwrap().as_rule() {
                     Rule::minus => Operator::Minus,
                     Rule::not => Operator::Not,
                     _ => unreachable!(),
                 };
-                AST::UnaryOp(op, Box::new(astify(inner.next().unwrap())), line, col)
+                AST::UnaryOp(op, Box::new(astify(inner.next().unwrap())), span)
             } else {
                 astify(pair)
             }
         }
+        // A grouped expression is passed through by operand, so one element
+        // on its own here is a spread.
+        Rule::parenthesized => {
+            let span = Span::from(pair.as_span());
+            let mut inner = pair.into_inner();
+            let first = astify(inner.next().unwrap());
+            match inner.next() {
+                Some(typ) if typ.as_rule() == Rule::type_expr => {
+                    AST::Ascription(Box::new(first), typify(typ), span)
+                }
+                Some(rest) => {
+                    let mut elements = vec![first];
+                    elements.extend(rest.into_inner().map(astify));
+                    AST::Tuple(elements, span)
+                }
+                None => AST::Tuple(vec![first], span),
+            }
+        }
+        Rule::record => {
+            let span = Span::from(pair.as_span());
+            let mut fields = Vec::new();
+            for field in pair.into_inner() {
+                let mut inner = field.into_inner();
+                let name = inner.next().unwrap().as_str().trim().to_string();
+                fields.push((name, astify(inner.next().unwrap())));
+            }
+            AST::Record(fields, span)
+        }
+        Rule::spread => {
+            let span = Span::from(pair.as_span());
+            AST::Spread(Box::new(astify(pair.into_inner().next().unwrap())), span)
+        }
         Rule::tuple => {
-            let (line, col) = pair.as_span().start_pos().line_col();
+            let span = Span::from(pair.as_span());
             let mut elements = Vec::new();
             let mut inner = pair.into_inner();
             while inner.peek().is_some() {
                 elements.push(astify(inner.next().unwrap()));
             }
-            AST::Tuple(elements, line, col)
+            AST::Tuple(elements, span)
         }
         Rule::unit => {
-            let (line, col) = pair.as_span().start_pos().line_col();
-            AST::Unit(line, col)
+            let span = Span::from(pair.as_span());
+            AST::Unit(span)
         }
         Rule::value => astify(pair.into_inner().next().unwrap()),
         _ => unreachable!(),
     }
 }
 
-pub fn parse(src: &str) -> Result<AST, ParseError> {
+fn to_parse_error(err: pest::error::Error<Rule>) -> ParseError {
+    let ((line, col), (end_line, end_col)) = match err.line_col {
+        pest::error::LineColLocation::Pos(pos) => (pos, pos),
+        pest::error::LineColLocation::Span(start, end) => (start, end),
+    };
+    ParseError {
+        code: Code::Syntax,
+        msg: err.variant.message().to_string(),
+        span: Span {
+            line,
+            col,
+            end_line,
+            end_col,
+        },
+    }
+}
+
+// Blank out everything before start so that a statement can be parsed in
+// isolation while positions still refer to the original source.
+fn mask(src: &str, start: usize) -> String {
+    let mut masked = String::with_capacity(src.len());
+    for c in src[..start].chars() {
+        if c == '\n' {
+            masked.push('\n');
+        } else {
+            for _ in 0..c.len_utf8() {
+                masked.push(' ');
+            }
+        }
+    }
+    masked.push_str(&src[start..]);
+    masked
+}
+
+// Find a place to resume parsing after an error: either just past the `end`
+// that closes the broken statement, or the next line that starts in the
+// first column, which is where top level statements usually begin.
+fn synchronize(src: &str, start: usize, error: usize) -> usize {
+    let bytes = src.as_bytes();
+    let mut depth = 0;
+    let mut line_start = false;
+    let mut i = start;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'\n' {
+            line_start = true;
+            i += 1;
+            continue;
+        }
+        if line_start && i >= error && i > start && !c.is_ascii_whitespace() {
+            let rest = &src[i..];
+            if !["end", "else", "elsif", "then", "with", "|"]
+                .iter()
+                .any(|keyword| rest.starts_with(keyword))
+            {
+                return i;
+            }
+        }
+        line_start = false;
+        if c == b'#' {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+        } else if c == b'"' {
+            i += string_len(&src[i..]);
+        } else if c == b'\'' {
+            i += character_len(&src[i..]).unwrap_or(1);
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let word_start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            match &src[word_start..i] {
+                "fn" | "for" | "if" | "match" | "type" => depth += 1,
+                "end" => {
+                    depth -= 1;
+                    if depth <= 0 && i > error {
+                        return i;
+                    }
+                }
+                _ => {}
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+fn recover(src: &str) -> Vec<ParseError> {
+    let mut errors = Vec::new();
+    let mut start = 0;
+    loop {
+        start += src[start..].len() - src[start..].trim_start().len();
+        if start >= src.len() {
+            break;
+        }
+        let masked = mask(src, start);
+        match PloverParser::parse(Rule::statement, &masked) {
+            Ok(mut statement) => {
+                start = statement.next().unwrap().as_span().end();
+            }
+            Err(err) => {
+                let pos = match err.location {
+                    pest::error::InputLocation::Pos(pos) => pos,
+                    pest::error::InputLocation::Span((pos, _)) => pos,
+                };
+                let pos = pos.min(src.len());
+                let position = pest::Position::new(src, pos).unwrap();
+                errors.push(to_parse_error(pest::error::Error::new_from_pos(
+                    err.variant,
+                    position,
+                )));
+                start = synchronize(src, start, pos);
+            }
+        }
+    }
+    errors
+}
+
+// Input is incomplete when parsing fails only because it ran out, as when a
+// function has not been closed by end yet, or a raw string by its quotes,
+// rather than because of a mistake.
+pub fn incomplete(src: &str) -> bool {
     match PloverParser::parse(Rule::program, src) {
-        Ok(mut program) => Ok(astify(program.next().unwrap())),
-        Err(err) => Err(ParseError {
-            msg: err.to_string(),
-            line: 0,
-            col: 0,
-        }),
+        Ok(_) => false,
+        Err(err) => {
+            let pos = match err.location {
+                pest::error::InputLocation::Pos(pos) => pos,
+                pest::error::InputLocation::Span((pos, _)) => pos,
+            };
+            pos >= src.trim_end().len() || scan(src, |_| {})
+        }
+    }
+}
+
+// Numbers that the grammar accepts but that are too large to be integers.
+fn out_of_range(program: &Pair<Rule>) -> Vec<ParseError> {
+    program
+        .clone()
+        .into_inner()
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::number && pair.as_str().parse::<i64>().is_err())
+        .map(|pair| ParseError {
+            code: Code::Syntax,
+            msg: "integer literal out of range".to_string(),
+            span: Span::from(pair.as_span()),
+        })
+        .collect()
+}
+
+pub fn parse(src: &str) -> Result<AST, Vec<ParseError>> {
+    match PloverParser::parse(Rule::program, src) {
+        Ok(mut program) => {
+            let program = program.next().unwrap();
+            let starts = src.match_indices('\n').map(|(i, _)| i + 1);
+            let starts = Some((
+                src.as_ptr() as usize,
+                std::iter::once(0).chain(starts).collect(),
+            ));
+            LINE_STARTS.with(|line_starts| *line_starts.borrow_mut() = starts);
+            let errors = out_of_range(&program);
+            let parsed = if errors.is_empty() {
+                Ok(astify(program))
+            } else {
+                Err(errors)
+            };
+            LINE_STARTS.with(|line_starts| *line_starts.borrow_mut() = None);
+            parsed
+        }
+        Err(err) => {
+            let errors = recover(src);
+            if errors.is_empty() {
+                Err(vec![to_parse_error(err)])
+            } else {
+                Err(errors)
+            }
+        }
     }
 }
 
@@ -441,10 +1299,28 @@ mod tests {
                 Ok(ast) => {
                     assert_eq!(ast.to_string(), $value);
                 }
-                Err(err) => {
-                    println!("ParseError: {} {} {}", err.msg, err.line, err.col);
+                Err(errors) => {
+                    for err in errors {
+                        println!("ParseError: {} {} {}", err.msg, err.span.line, err.span.col);
+                    }
+                    assert!(false);
+                }
+            }
+        }};
+    }
+
+    macro_rules! parsefails {
+        ($input:expr, $($line:expr, $col:expr),*) => {{
+            match parser::parse($input) {
+                Ok(_) => {
                     assert!(false);
                 }
+                Err(errors) => {
+                    let expected: Vec<(usize, usize)> = vec![$(($line, $col)),*];
+                    let found: Vec<(usize, usize)> =
+                        errors.iter().map(|err| (err.span.line, err.span.col)).collect();
+                    assert_eq!(found, expected);
+                }
             }
         }};
     }
@@ -455,6 +1331,18 @@ mod tests {
         parse!("  42", "42:Integer");
         parse!("  42 ", "42:Integer");
         parse!("true", "true:Boolean");
+        parse!("\"hi\"", "\"hi\":String");
+        parse!("\"a \\\"b\\\" # c\\n\"", "\"a \\\"b\\\" # c\\n\":String");
+        parse!("\"\"\"a \\n\"b\" c\"\"\"", "\"a \\\\n\\\"b\\\" c\":String");
+        parse!(
+            "\"\"\"line # one\n\"line\" two\"\"\" # comment",
+            "\"line # one\\n\\\"line\\\" two\":String"
+        );
+        parse!("\"\"\"\"\"\"", "\"\":String");
+        parse!("'a'", "'a':Char");
+        parse!("'\\''", "'\\'':Char");
+        parse!("'\\n' # '", "'\\n':Char");
+        parse!("'\"'", "'\"':Char");
         parse!("false", "false:Boolean");
         parse!("-42", "(- 42:Integer)");
         parse!("- 42", "(- 42:Integer)");
@@ -537,6 +1425,7 @@ mod tests {
         parse!("(1)", "1:Integer");
         parse!("(1,)", "(1:Integer):Tuple");
         parse!("(1, 2, 3)", "(1:Integer, 2:Integer, 3:Integer):Tuple");
+        parse!("(1, 2,)", "(1:Integer, 2:Integer):Tuple");
         parse!(
             "(1, 2, (2 + 3))",
             "(1:Integer, 2:Integer, (+ 2:Integer 3:Integer)):Tuple"
@@ -592,6 +1481,28 @@ mod tests {
             "fn f (x) -> x + 1 end",
             "(f x:Identifier (+ x:Identifier 1:Integer))"
         );
+        parse!(
+            "for i in range (0, 3) do f (i) end",
+            "(for i:Identifier (apply range:Identifier (0:Integer, 3:Integer):Tuple) (apply f:Identifier i:Identifier))"
+        );
+        parse!(
+            "for (k, v) in pairs do v end",
+            "(for (k:Identifier, v:Identifier):Tuple pairs:Identifier v:Identifier)"
+        );
+        parse!("define", "define:Identifier");
+        parse!("s[0]", "(index s:Identifier 0:Integer)");
+        parse!(
+            "s[i..i + 2][0]",
+            "(index (slice s:Identifier i:Identifier (+ i:Identifier 2:Integer)) 0:Integer)"
+        );
+        parse!(
+            "-f (x) [i + 1][2]",
+            "(- (index (index (apply f:Identifier x:Identifier) (+ i:Identifier 1:Integer)) 2:Integer))"
+        );
+        parse!(
+            "index_of (done)",
+            "(apply index_of:Identifier done:Identifier)"
+        );
         parse!(
             "match p with A -> 0 end",
             "(match p:Identifier (case A 0:Integer))"
@@ -612,6 +1523,30 @@ mod tests {
             ",
             "(match (apply f:Identifier ():Unit) (case A 0:Integer) (case B 1:Integer))"
         );
+        parse!(
+            "match p with A | B (x) -> 0 | C -> 1 end",
+            "(match p:Identifier (case (or A B: x:Identifier) 0:Integer) (case C 1:Integer))"
+        );
+        parse!(
+            "match p with A ((x, y) as t) as a -> t end",
+            "(match p:Identifier (case (as A: (as (x:Identifier, y:Identifier):Tuple t:Identifier) a:Identifier) t:Identifier))"
+        );
+        parse!(
+            "match n with 0 | -1 -> 'a' | (true, \"b\") -> 'b' | _ -> 'c' end",
+            "(match n:Identifier (case (or 0:Integer -1:Integer) 'a':Char) (case (true:Boolean, \"b\":String):Tuple 'b':Char) (case _ 'c':Char))"
+        );
+        parse!(
+            "match xs with Cons (x, Cons ((y, _) as p)) -> x end",
+            "(match xs:Identifier (case Cons: (x:Identifier, Cons: (as (y:Identifier, _):Tuple p:Identifier)):Tuple x:Identifier))"
+        );
+        parse!(
+            "match p with | A -> 0 | B -> 1 end",
+            "(match p:Identifier (case A 0:Integer) (case B 1:Integer))"
+        );
+        parse!(
+            "match p with (a, (b, c)) -> a end",
+            "(match p:Identifier (case (a:Identifier, (b:Identifier, c:Identifier):Tuple):Tuple a:Identifier))"
+        );
         parse!(
             "def t := 1
              def f := fn x ->
@@ -621,6 +1556,43 @@ mod tests {
              f(1)",
             "((define t:Identifier 1:Integer) (define f:Identifier (fn x:Identifier ((define t:Identifier 2:Integer) (+ x:Identifier t:Identifier)))) (apply f:Identifier 1:Integer))"
         );
+        parse!("(1 : integer)", "(ascribe 1:Integer integer)");
+        parse!(
+            "(x + 1 : integer) * 2",
+            "(* (ascribe (+ x:Identifier 1:Integer) integer) 2:Integer)"
+        );
+        parse!(
+            "(f : (integer, 'a) -> 'a -> boolean)",
+            "(ascribe f:Identifier (integer, 'a) -> 'a -> boolean)"
+        );
+        parse!(
+            "((1, true) : (integer, boolean))",
+            "(ascribe (1:Integer, true:Boolean):Tuple (integer, boolean))"
+        );
+        parse!(
+            "(x : integer | (boolean, char) -> unit | string)",
+            "(ascribe x:Identifier integer | (boolean, char) -> unit | string)"
+        );
+        parse!(
+            "(f : ('a -> 'b) -> 'a -> 'b)",
+            "(ascribe f:Identifier ('a -> 'b) -> 'a -> 'b)"
+        );
+        parse!(
+            "(f : ('a -> 'a) | integer)",
+            "(ascribe f:Identifier ('a -> 'a) | integer)"
+        );
+        parse!(
+            "{x = 1, y = \"a\",}.x",
+            "(field {x = 1:Integer, y = \"a\":String}:Record x)"
+        );
+        parse!(
+            "p.x.y[0]",
+            "(index (field (field p:Identifier x) y) 0:Integer)"
+        );
+        parse!(
+            "(p : {x: integer, y: (boolean, char)})",
+            "(ascribe p:Identifier {x: integer, y: (boolean, char)})"
+        );
         parse!(
             "fn fact (n) ->
                  fn iter(n, acc) ->
@@ -634,5 +1606,182 @@ mod tests {
              end",
             "(fact n:Identifier ((iter (n:Identifier, acc:Identifier):Tuple (if (cond (== n:Identifier 0:Integer) acc:Identifier) (else (apply iter:Identifier ((- n:Identifier 1:Integer), (* n:Identifier acc:Identifier)):Tuple)))) (apply iter:Identifier (n:Identifier, 1:Integer):Tuple)))"
         );
+
+        // Each of these took time exponential in how deeply they nest.
+        let n = 30;
+        parse!(&format!("{}1{}", "(".repeat(n), ")".repeat(n)), "1:Integer");
+        parse!(
+            &format!("{}1{}", "(1 + ".repeat(n), ")".repeat(n)),
+            format!("{}1:Integer{}", "(+ 1:Integer ".repeat(n), ")".repeat(n))
+        );
+        parse!(
+            &format!("{}1{}", "(".repeat(n), " : integer)".repeat(n)),
+            format!(
+                "{}1:Integer{}",
+                "(ascribe ".repeat(n),
+                " integer)".repeat(n)
+            )
+        );
+        parse!(
+            &format!("{}1{}", "f (".repeat(n), ", 2)".repeat(n)),
+            format!(
+                "{}1:Integer{}",
+                "(apply f:Identifier (".repeat(n),
+                ", 2:Integer):Tuple)".repeat(n)
+            )
+        );
+    }
+
+    #[test]
+    fn spans() {
+        match parser::parse("def x := 1 +\n  (2, 3)") {
+            Ok(parser::AST::Program(expressions, _)) => match &expressions[0] {
+                parser::AST::Define(_, value, span) => {
+                    assert_eq!(
+                        (span.line, span.col, span.end_line, span.end_col),
+                        (1, 1, 2, 9)
+                    );
+                    if let parser::AST::BinaryOp(_, _, rhs, span) = &**value {
+                        assert_eq!(
+                            (span.line, span.col, span.end_line, span.end_col),
+                            (1, 10, 2, 9)
+                        );
+                        if let parser::AST::Tuple(_, span) = &**rhs {
+                            assert_eq!(
+                                (span.line, span.col, span.end_line, span.end_col),
+                                (2, 3, 2, 9)
+                            );
+                        } else {
+                            unreachable!();
+                        }
+                    } else {
+                        unreachable!();
+                    }
+                }
+                _ => {
+                    unreachable!();
+                }
+            },
+            _ => {
+                unreachable!();
+            }
+        }
+        match parser::parse("def s := \"\"\"a\n# b\"\"\" # c\ns") {
+            Ok(parser::AST::Program(expressions, _)) => {
+                let spans: Vec<(usize, usize, usize, usize)> = expressions
+                    .iter()
+                    .map(parser::span_of)
+                    .map(|span| (span.line, span.col, span.end_line, span.end_col))
+                    .collect();
+                assert_eq!(spans, vec![(1, 1, 2, 7), (3, 1, 3, 2)]);
+            }
+            _ => {
+                unreachable!();
+            }
+        }
+    }
+
+    #[test]
+    fn walks() {
+        let ast = match parser::parse("def a := f (b, fn (c) -> d end)\ng (e.x, {y = h})") {
+            Ok(ast) => ast,
+            Err(_) => unreachable!(),
+        };
+        let mut names = Vec::new();
+        parser::walk(&ast, &mut |ast| match ast {
+            parser::AST::Identifier(name, _) => {
+                names.push(name.to_string());
+                true
+            }
+            // Function bodies are skipped.
+            parser::AST::Function(_, _, _, _) => false,
+            _ => true,
+        });
+        assert_eq!(names, ["a", "f", "b", "g", "e", "h"]);
+    }
+
+    #[test]
+    fn keywords() {
+        for keyword in parser::KEYWORDS.iter() {
+            assert!(parser::parse(&format!("def {} := 1", keyword)).is_err());
+            assert!(parser::parse(&format!("def {}s := 1", keyword)).is_ok());
+        }
+    }
+
+    #[test]
+    fn incomplete() {
+        assert!(!parser::incomplete("1 + 2"));
+        assert!(parser::incomplete("1 +"));
+        assert!(parser::incomplete("def x := (1, 2"));
+        assert!(parser::incomplete("fn f (x) ->\n    x + 1\n"));
+        assert!(parser::incomplete("if x then 1 # comment\n"));
+        assert!(parser::incomplete("match p with\n    A -> 0\n"));
+        assert!(parser::incomplete("for i in range (0, 3) do\n"));
+        assert!(parser::incomplete("def s := \"\"\"first line\n"));
+        assert!(!parser::incomplete("1 + )"));
+        assert!(!parser::incomplete("def := 1"));
+    }
+
+    #[test]
+    fn recovery() {
+        parsefails!("1 +", 1, 3);
+        parsefails!("1 + 99999999999999999999", 1, 5);
+        parsefails!(
+            "match x with 1 -> 0 | -99999999999999999999 -> 1 end",
+            1,
+            24
+        );
+        parsefails!("def x := (1, 2", 1, 15);
+        parsefails!(
+            "def x := 1 +
+def y := 2
+def z := (3
+def w := 4",
+            1,
+            12,
+            4,
+            1
+        );
+        parsefails!(
+            "fn f (x) ->
+    x + * 2
+end
+def y := * 2
+f (y)",
+            2,
+            9,
+            4,
+            10
+        );
+        parsefails!(
+            "def s := \"\"\"
+fn end # \"
+\"\"\" +
+def t := 1 +",
+            3,
+            5,
+            4,
+            12
+        );
+        parsefails!(
+            "fn f (x) ->
+    x +
+def y := 2 )
+def z := ~",
+            3,
+            1,
+            3,
+            12,
+            4,
+            11
+        );
+        // Messages leave the position to the span.
+        match parser::parse("1 +") {
+            Err(errors) => assert_eq!(
+                errors[0].msg,
+                "expected datatype, match_expr, def, for_loop, or unary"
+            ),
+            Ok(_) => unreachable!(),
+        }
     }
 }
@@ -0,0 +1,77 @@
+// An evaluation API for a browser playground. It only passes strings across
+// the boundary, so it can be exported with wasm-bindgen as it is, and output
+// is collected instead of being written to the process stdout.
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, Interpreter};
+
+pub struct Playground {
+    interpreter: Interpreter,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Playground {
+    pub fn new() -> Playground {
+        let mut interpreter = Interpreter::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        interpreter.vm.stdout = output.clone();
+        interpreter.vm.stderr = output.clone();
+        Playground {
+            interpreter,
+            output,
+        }
+    }
+
+    // Evaluates a program, returning everything it wrote followed by its
+    // value or the errors it caused. Definitions persist between calls.
+    pub fn eval(&mut self, src: &str) -> String {
+        let result = self.interpreter.eval_str(src);
+        let written = match self.output.lock() {
+            Ok(mut output) => std::mem::take(&mut *output),
+            Err(_) => Vec::new(),
+        };
+        let mut out = String::from_utf8_lossy(&written).to_string();
+        match result {
+            Ok(value) => out.push_str(&format!("{}\n", value)),
+            Err(Error::Parse(errors)) => {
+                for err in errors {
+                    out.push_str(&format!(
+                        "{}\n--> {}:{}\n",
+                        err.msg, err.span.line, err.span.col
+                    ));
+                }
+            }
+            Err(Error::Interpreter(err)) => {
+                out.push_str(&format!(
+                    "{}\n--> {}:{}\n",
+                    err, err.span.line, err.span.col
+                ));
+            }
+        }
+        out
+    }
+}
+
+impl Default for Playground {
+    fn default() -> Playground {
+        Playground::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::playground::Playground;
+
+    #[test]
+    fn evaluates() {
+        let mut playground = Playground::new();
+        assert_eq!(playground.eval("def x := 6 * 7"), "42\n");
+        assert_eq!(
+            playground.eval("x / 0"),
+            "InterpreterError: Division by zero.\n--> 1:1\n"
+        );
+        assert!(playground.eval("x +").ends_with("--> 1:3\n"));
+        assert_eq!(playground.eval("x == 42"), "true\n");
+        assert_eq!(playground.eval(""), "()\n");
+    }
+}
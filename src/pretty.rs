@@ -0,0 +1,1082 @@
+use std::collections::VecDeque;
+
+use crate::parser;
+use crate::parser::{span_of, Operator, ParseError, Pattern, Span, TypeAnnotation, AST};
+use crate::typeinfer;
+use crate::typeinfer::{Type, TypedAST, TypedPattern};
+use crate::vm::Value;
+
+const INDENT: &str = "    ";
+
+// Values are cut short past these limits, with a count of what was left out,
+// so that printing a huge value doesn't flood the terminal.
+const MAX_ELEMENTS: usize = 100;
+const MAX_CHARS: usize = 1000;
+const MAX_NESTING: usize = 50;
+
+// A comment from the source, with the line it is on and, if there is code
+// before it on that line, the column where that code ends.
+type Comment = (usize, Option<usize>, String);
+
+// Printed text along with the comments from the source that have not been
+// placed yet. Comments and blank lines are only kept when formatting source
+// code, where the spans on nodes refer to real lines. The code end is the
+// furthest line and column in the source that code has been printed up to.
+struct Output {
+    text: String,
+    comments: VecDeque<Comment>,
+    layout: bool,
+    last_line: usize,
+    code_end: (usize, usize),
+}
+
+impl Output {
+    fn new(comments: VecDeque<Comment>, layout: bool) -> Output {
+        Output {
+            text: String::new(),
+            comments,
+            layout,
+            last_line: 0,
+            code_end: (0, 0),
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.text.push_str(s);
+    }
+}
+
+fn precedence(op: &Operator) -> usize {
+    match op {
+        Operator::Equal | Operator::NotEqual => 1,
+        Operator::Greater | Operator::GreaterEqual | Operator::Less | Operator::LessEqual => 2,
+        Operator::Minus | Operator::Or | Operator::Plus => 3,
+        Operator::And | Operator::Divide | Operator::Mod | Operator::Multiply => 4,
+        Operator::Not => 5,
+    }
+}
+
+// A comment after code on its line stays at the end of the line that code is
+// printed on, even inside a statement, such as after the arm of a match. It
+// is placed when a line is done once the code before it has been printed, or
+// once code from its line has and the next statement, which starts before
+// line, comes from a later one, as when the comment follows a keyword.
+fn write_trailing(before: usize, out: &mut Output) {
+    while let Some(&(line, Some(end), _)) = out.comments.front() {
+        if (line, end) > out.code_end && (line >= before || line > out.code_end.0) {
+            break;
+        }
+        let (_, _, comment) = out.comments.pop_front().unwrap();
+        out.push(' ');
+        out.push_str(&comment);
+    }
+}
+
+fn newline(indent: usize, out: &mut Output) {
+    write_trailing(0, out);
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str(INDENT);
+    }
+}
+
+// Binary operators are left associative, so a right hand operand at the same
+// precedence level needs parentheses to keep its grouping.
+fn needs_parens(ast: &AST, prec: usize, rhs: bool) -> bool {
+    match ast {
+        AST::BinaryOp(op, _, _, _) => {
+            precedence(op) < prec || (rhs && precedence(op) == prec) || prec > 4
+        }
+        AST::Datatype(_, _, _)
+        | AST::Define(_, _, _)
+        | AST::For(_, _, _, _)
+        | AST::If(_, _, _)
+        | AST::Match(_, _, _)
+        | AST::Program(_, _) => true,
+        AST::Integer(i, _) => prec > 4 && *i < 0,
+        _ => false,
+    }
+}
+
+fn write_operand(ast: &AST, prec: usize, rhs: bool, indent: usize, out: &mut Output) {
+    if needs_parens(ast, prec, rhs) {
+        out.push('(');
+        write_ast(ast, indent, out);
+        out.push(')');
+    } else {
+        write_ast(ast, indent, out);
+    }
+}
+
+// Only values and calls can be indexed, sliced or have their fields taken
+// without parentheses.
+fn write_subscripted(ast: &AST, indent: usize, out: &mut Output) {
+    match ast {
+        AST::Ascription(_, _, _)
+        | AST::Boolean(_, _)
+        | AST::Call(_, _, _)
+        | AST::Char(_, _)
+        | AST::Field(_, _, _)
+        | AST::Function(_, _, _, _)
+        | AST::Identifier(_, _)
+        | AST::Index(_, _, _)
+        | AST::Record(_, _)
+        | AST::Slice(_, _, _, _)
+        | AST::String(_, _)
+        | AST::Tuple(_, _)
+        | AST::Unit(_) => write_ast(ast, indent, out),
+        AST::Integer(i, _) if *i >= 0 => write_ast(ast, indent, out),
+        _ => {
+            out.push('(');
+            write_ast(ast, indent, out);
+            out.push(')');
+        }
+    }
+}
+
+fn write_variant(name: &str, param: &Option<AST>, indent: usize, out: &mut Output) {
+    out.push_str(name);
+    match param {
+        Some(AST::Tuple(elements, _)) => {
+            out.push_str(" (");
+            for i in 0..elements.len() {
+                write_ast(&elements[i], indent, out);
+                if i + 1 != elements.len() {
+                    out.push_str(", ");
+                }
+            }
+            out.push(')');
+        }
+        Some(param) => {
+            out.push_str(" (");
+            write_ast(param, indent, out);
+            out.push(')');
+        }
+        None => {}
+    }
+}
+
+fn write_pattern(pattern: &Pattern, out: &mut Output) {
+    match pattern {
+        Pattern::As(pattern, id, _) => {
+            write_pattern(pattern, out);
+            out.push_str(" as ");
+            out.push_str(id);
+        }
+        Pattern::Identifier(id, _) => out.push_str(id),
+        Pattern::Literal(literal, _) => write_ast(literal, 0, out),
+        Pattern::Or(alternatives, _) => {
+            for i in 0..alternatives.len() {
+                write_pattern(&alternatives[i], out);
+                if i + 1 != alternatives.len() {
+                    out.push_str(" | ");
+                }
+            }
+        }
+        Pattern::Tuple(elements, _) => {
+            out.push('(');
+            for i in 0..elements.len() {
+                write_pattern(&elements[i], out);
+                if i + 1 != elements.len() {
+                    out.push_str(", ");
+                }
+            }
+            out.push(')');
+        }
+        Pattern::Variant(name, param, _) => {
+            out.push_str(name);
+            match param.as_deref() {
+                Some(param @ Pattern::Tuple(_, _)) => {
+                    out.push(' ');
+                    write_pattern(param, out);
+                }
+                Some(param) => {
+                    out.push_str(" (");
+                    write_pattern(param, out);
+                    out.push(')');
+                }
+                None => {}
+            }
+        }
+        Pattern::Wildcard(_) => out.push('_'),
+    }
+}
+
+// Moves to a new line for something that starts at line in the source,
+// keeping a single blank line wherever the source had one or more.
+fn separate(line: usize, first: bool, leading: bool, indent: usize, out: &mut Output) {
+    write_trailing(0, out);
+    if !first && out.layout && line > out.last_line + 1 {
+        out.push('\n');
+    }
+    if !first || leading {
+        newline(indent, out);
+    }
+}
+
+fn write_comments(before: usize, mut first: bool, leading: bool, indent: usize, out: &mut Output) {
+    write_trailing(before, out);
+    while let Some((line, _, _)) = out.comments.front() {
+        if *line >= before {
+            break;
+        }
+        let (line, _, comment) = out.comments.pop_front().unwrap();
+        separate(line, first, leading, indent, out);
+        out.push_str(&comment);
+        out.last_line = line;
+        first = false;
+    }
+}
+
+// A statement that starts with a minus sign would continue the statement
+// before it as a subtraction.
+fn starts_with_minus(ast: &AST) -> bool {
+    matches!(parser::chain(ast).0, AST::UnaryOp(Operator::Minus, _, _))
+}
+
+fn write_statements(expressions: &[AST], leading: bool, indent: usize, out: &mut Output) {
+    let mut first = true;
+    for expression in expressions {
+        let span = span_of(expression);
+        if out.layout {
+            write_trailing(span.line, out);
+            let before = out.text.len();
+            write_comments(span.line, first, leading, indent, out);
+            first = first && out.text.len() == before;
+        }
+        separate(span.line, first, leading, indent, out);
+        if !first && starts_with_minus(expression) {
+            out.push('(');
+            write_ast(expression, indent, out);
+            out.push(')');
+        } else {
+            write_ast(expression, indent, out);
+        }
+        if out.layout {
+            out.last_line = span.end_line;
+        }
+        first = false;
+    }
+}
+
+fn write_body(ast: &AST, indent: usize, out: &mut Output) {
+    match ast {
+        AST::Program(expressions, _) => write_statements(expressions, true, indent, out),
+        _ => write_statements(std::slice::from_ref(ast), true, indent, out),
+    }
+}
+
+fn write_ast(ast: &AST, indent: usize, out: &mut Output) {
+    match ast {
+        AST::Ascription(expr, typ, _) => {
+            out.push('(');
+            write_ast(expr, indent, out);
+            out.push_str(" : ");
+            out.push_str(&typ.to_string());
+            out.push(')');
+        }
+        AST::BinaryOp(_, _, _, _) => {
+            // Left operands that need no parentheses continue the chain, which
+            // is written without recursing.
+            let mut links = Vec::new();
+            let mut lhs = ast;
+            while let AST::BinaryOp(op, inner, rhs, _) = lhs {
+                links.push((precedence(op), op, rhs));
+                lhs = inner;
+                if !matches!(lhs, AST::BinaryOp(_, _, _, _))
+                    || needs_parens(lhs, precedence(op), false)
+                {
+                    break;
+                }
+            }
+            write_operand(lhs, links[links.len() - 1].0, false, indent, out);
+            for (prec, op, rhs) in links.into_iter().rev() {
+                out.push(' ');
+                out.push_str(&op.to_string());
+                out.push(' ');
+                write_operand(rhs, prec, true, indent, out);
+            }
+        }
+        AST::Boolean(b, _) => out.push_str(&b.to_string()),
+        AST::Char(c, _) => out.push_str(&parser::escape_char(*c)),
+        AST::Call(fun, arg, _) => {
+            match &**fun {
+                AST::Function(_, _, _, _) | AST::Identifier(_, _) => {
+                    write_ast(fun, indent, out);
+                }
+                _ => {
+                    out.push('(');
+                    write_ast(fun, indent, out);
+                    out.push(')');
+                }
+            }
+            out.push(' ');
+            match &**arg {
+                AST::Tuple(_, _) | AST::Unit(_) => write_ast(arg, indent, out),
+                _ => {
+                    out.push('(');
+                    write_ast(arg, indent, out);
+                    out.push(')');
+                }
+            }
+        }
+        AST::Datatype(name, variants, _) => {
+            out.push_str("type ");
+            out.push_str(name);
+            out.push_str(" := ");
+            for i in 0..variants.len() {
+                write_variant(&variants[i].0, &variants[i].1, indent, out);
+                if i + 1 != variants.len() {
+                    out.push_str(" | ");
+                }
+            }
+            out.push_str(" end");
+        }
+        AST::Define(id, value, _) => {
+            out.push_str("def ");
+            write_ast(id, indent, out);
+            out.push_str(" := ");
+            write_ast(value, indent, out);
+        }
+        AST::Field(value, field, _) => {
+            write_subscripted(value, indent, out);
+            out.push('.');
+            out.push_str(field);
+        }
+        AST::For(pattern, iter, body, _) => {
+            out.push_str("for ");
+            write_ast(pattern, indent, out);
+            out.push_str(" in ");
+            write_ast(iter, indent, out);
+            out.push_str(" do");
+            write_body(body, indent + 1, out);
+            newline(indent, out);
+            out.push_str("end");
+        }
+        AST::Function(id, param, body, _) => {
+            out.push_str("fn ");
+            if let Some(id) = id {
+                out.push_str(id);
+                out.push(' ');
+            }
+            match &**param {
+                AST::Tuple(_, _) | AST::Unit(_) => write_ast(param, indent, out),
+                _ => {
+                    out.push('(');
+                    write_ast(param, indent, out);
+                    out.push(')');
+                }
+            }
+            out.push_str(" ->");
+            write_body(body, indent + 1, out);
+            newline(indent, out);
+            out.push_str("end");
+        }
+        AST::Identifier(id, _) => out.push_str(id),
+        AST::Index(value, index, _) => {
+            write_subscripted(value, indent, out);
+            out.push('[');
+            write_ast(index, indent, out);
+            out.push(']');
+        }
+        AST::If(conds, els, _) => {
+            for (i, cond) in conds.iter().enumerate() {
+                out.push_str(if i == 0 { "if " } else { "elsif " });
+                write_ast(&cond.0, indent, out);
+                out.push_str(" then");
+                write_body(&cond.1, indent + 1, out);
+                newline(indent, out);
+            }
+            out.push_str("else");
+            write_body(els, indent + 1, out);
+            newline(indent, out);
+            out.push_str("end");
+        }
+        AST::Integer(i, _) => out.push_str(&i.to_string()),
+        AST::Match(cond, cases, _) => {
+            out.push_str("match ");
+            write_ast(cond, indent, out);
+            out.push_str(" with");
+            for (i, case) in cases.iter().enumerate() {
+                newline(indent + 1, out);
+                if i != 0 {
+                    out.push_str("| ");
+                }
+                write_pattern(&case.0, out);
+                out.push_str(" -> ");
+                write_ast(&case.1, indent + 1, out);
+            }
+            newline(indent, out);
+            out.push_str("end");
+        }
+        AST::Program(expressions, _) => write_statements(expressions, false, indent, out),
+        AST::Record(fields, _) => {
+            out.push('{');
+            for i in 0..fields.len() {
+                out.push_str(&fields[i].0);
+                out.push_str(" = ");
+                write_ast(&fields[i].1, indent, out);
+                if i + 1 != fields.len() {
+                    out.push_str(", ");
+                }
+            }
+            out.push('}');
+        }
+        AST::Slice(value, start, end, _) => {
+            write_subscripted(value, indent, out);
+            out.push('[');
+            write_ast(start, indent, out);
+            out.push_str("..");
+            write_ast(end, indent, out);
+            out.push(']');
+        }
+        AST::Spread(ast, _) => {
+            out.push_str("..");
+            write_ast(ast, indent, out);
+        }
+        AST::Tuple(elements, _) => {
+            out.push('(');
+            for i in 0..elements.len() {
+                write_ast(&elements[i], indent, out);
+                if i + 1 != elements.len() {
+                    out.push_str(", ");
+                }
+            }
+            if elements.len() == 1 {
+                out.push(',');
+            }
+            out.push(')');
+        }
+        AST::UnaryOp(op, ast, _) => {
+            out.push_str(&op.to_string());
+            write_operand(ast, 5, false, indent, out);
+        }
+        AST::String(s, _) => out.push_str(&parser::escape(s)),
+        AST::Unit(_) => out.push_str("()"),
+    }
+    if out.layout {
+        let span = span_of(ast);
+        out.code_end = out.code_end.max((span.end_line, span.end_col));
+    }
+}
+
+// Datatype declarations only record the types of constructor parameters, so
+// parameters of a named type are printed as that name, and fresh names are
+// made up for the rest when printing a typed AST.
+fn untyped_param(typ: &Type, name: String) -> AST {
+    match typ {
+        Type::Polymorphic(_) | Type::Function(_, _) | Type::Tuple(_) => {
+            AST::Identifier(name, Span::unknown())
+        }
+        _ => AST::Identifier(typ.to_string(), Span::unknown()),
+    }
+}
+
+fn untyped_variant(typ: &Type) -> Option<AST> {
+    match typ {
+        Type::Function(param, _) => match &**param {
+            Type::Tuple(elements) => Some(AST::Tuple(
+                elements
+                    .iter()
+                    .enumerate()
+                    .map(|(i, element)| untyped_param(element, format!("x{}", i + 1)))
+                    .collect(),
+                Span::unknown(),
+            )),
+            _ => Some(untyped_param(param, "x".to_string())),
+        },
+        _ => None,
+    }
+}
+
+fn annotation(typ: &Type) -> TypeAnnotation {
+    match typ {
+        Type::Function(param, body) => {
+            TypeAnnotation::Function(Box::new(annotation(param)), Box::new(annotation(body)))
+        }
+        Type::Polymorphic(name) => {
+            TypeAnnotation::Variable(format!("'{}", name.trim_start_matches('\'')))
+        }
+        Type::Record(fields) => TypeAnnotation::Record(
+            fields
+                .iter()
+                .map(|field| (field.0.to_string(), annotation(&field.1)))
+                .collect(),
+        ),
+        Type::Tuple(elements) => TypeAnnotation::Tuple(elements.iter().map(annotation).collect()),
+        Type::Union(members) => TypeAnnotation::Union(members.iter().map(annotation).collect()),
+        _ => TypeAnnotation::Name(typ.to_string()),
+    }
+}
+
+fn untyped_pattern(pattern: &TypedPattern) -> Pattern {
+    let param = |param: &Option<Box<TypedPattern>>| {
+        param.as_ref().map(|param| Box::new(untyped_pattern(param)))
+    };
+    match pattern {
+        TypedPattern::As(_, pattern, id, span) => {
+            Pattern::As(Box::new(untyped_pattern(pattern)), id.to_string(), *span)
+        }
+        TypedPattern::Identifier(_, id, span) => Pattern::Identifier(id.to_string(), *span),
+        TypedPattern::Literal(literal, span) => Pattern::Literal(Box::new(untyped(literal)), *span),
+        TypedPattern::Member(typ, param_pattern, span) => {
+            Pattern::Variant(typ.to_string(), param(param_pattern), *span)
+        }
+        TypedPattern::Or(alternatives, span) => {
+            Pattern::Or(alternatives.iter().map(untyped_pattern).collect(), *span)
+        }
+        TypedPattern::Tuple(elements, span) => {
+            Pattern::Tuple(elements.iter().map(untyped_pattern).collect(), *span)
+        }
+        TypedPattern::Variant(name, param_pattern, span) => {
+            Pattern::Variant(name.to_string(), param(param_pattern), *span)
+        }
+        TypedPattern::Wildcard(span) => Pattern::Wildcard(*span),
+    }
+}
+
+fn untyped(ast: &TypedAST) -> AST {
+    match ast {
+        TypedAST::BinaryOp(_, _, _, _, _) => {
+            let (first, links) = typeinfer::chain(ast);
+            let mut lhs = untyped(first);
+            for (op, rhs, span) in links {
+                lhs = AST::BinaryOp(op.clone(), Box::new(lhs), Box::new(untyped(rhs)), span);
+            }
+            lhs
+        }
+        TypedAST::Boolean(b, span) => AST::Boolean(*b, *span),
+        TypedAST::Char(c, span) => AST::Char(*c, *span),
+        TypedAST::Call(fun, arg, span) => {
+            AST::Call(Box::new(untyped(fun)), Box::new(untyped(arg)), *span)
+        }
+        TypedAST::Cast(typ, ast, span) => {
+            AST::Ascription(Box::new(untyped(ast)), annotation(typ), *span)
+        }
+        TypedAST::Datatype(typ, variants, span) => AST::Datatype(
+            typ.to_string(),
+            variants
+                .iter()
+                .map(|variant| (variant.0.to_string(), untyped_variant(&variant.1)))
+                .collect(),
+            *span,
+        ),
+        TypedAST::Define(_, id, value, span) => AST::Define(
+            Box::new(AST::Identifier(id.to_string(), Span::unknown())),
+            Box::new(untyped(value)),
+            *span,
+        ),
+        TypedAST::Field(_, value, field, span) => {
+            AST::Field(Box::new(untyped(value)), field.to_string(), *span)
+        }
+        TypedAST::For(pattern, iter, body, span) => AST::For(
+            Box::new(untyped(pattern)),
+            Box::new(untyped(iter)),
+            Box::new(untyped(body)),
+            *span,
+        ),
+        TypedAST::Function(id, param, body, span) => AST::Function(
+            id.clone(),
+            Box::new(untyped(param)),
+            Box::new(untyped(body)),
+            *span,
+        ),
+        TypedAST::Identifier(_, id, span) => AST::Identifier(id.to_string(), *span),
+        TypedAST::If(conds, els, span) => AST::If(
+            conds
+                .iter()
+                .map(|cond| (untyped(&cond.0), untyped(&cond.1)))
+                .collect(),
+            Box::new(untyped(els)),
+            *span,
+        ),
+        TypedAST::Index(value, index, span) => {
+            AST::Index(Box::new(untyped(value)), Box::new(untyped(index)), *span)
+        }
+        TypedAST::Integer(i, span) => AST::Integer(*i, *span),
+        TypedAST::Match(cond, _, cases, span) => AST::Match(
+            Box::new(untyped(cond)),
+            cases
+                .iter()
+                .map(|case| (untyped_pattern(&case.0), untyped(&case.1)))
+                .collect(),
+            *span,
+        ),
+        TypedAST::Program(_, expressions, span) => {
+            AST::Program(expressions.iter().map(untyped).collect(), *span)
+        }
+        TypedAST::Record(_, fields, span) => AST::Record(
+            fields
+                .iter()
+                .map(|field| (field.0.to_string(), untyped(&field.1)))
+                .collect(),
+            *span,
+        ),
+        TypedAST::Slice(value, start, end, span) => AST::Slice(
+            Box::new(untyped(value)),
+            Box::new(untyped(start)),
+            Box::new(untyped(end)),
+            *span,
+        ),
+        TypedAST::Spread(_, ast, span) => AST::Spread(Box::new(untyped(ast)), *span),
+        TypedAST::Tuple(_, elements, span) => {
+            AST::Tuple(elements.iter().map(untyped).collect(), *span)
+        }
+        TypedAST::UnaryOp(_, op, ast, span) => {
+            AST::UnaryOp(op.clone(), Box::new(untyped(ast)), *span)
+        }
+        TypedAST::String(s, span) => AST::String(s.to_string(), *span),
+        TypedAST::Unit(span) => AST::Unit(*span),
+    }
+}
+
+pub fn print(ast: &parser::AST) -> String {
+    let mut out = Output::new(VecDeque::new(), false);
+    write_ast(ast, 0, &mut out);
+    out.text
+}
+
+pub fn print_typed(ast: &TypedAST) -> String {
+    print(&untyped(ast))
+}
+
+// A value on one line, as it is displayed, but cut short where it is too big.
+// Writing stops early once out is longer than limit bytes.
+fn write_compact(value: &Value, depth: usize, limit: usize, out: &mut String) {
+    if out.len() > limit {
+        return;
+    }
+    if depth > MAX_NESTING {
+        out.push_str("...");
+        return;
+    }
+    match value {
+        Value::Datatype(name, _, value) => {
+            out.push_str(name);
+            if let Value::Unit = **value {
+                return;
+            }
+            out.push(' ');
+            write_compact(value, depth + 1, limit, out);
+        }
+        Value::String(s) => match s.char_indices().nth(MAX_CHARS) {
+            Some((i, _)) => {
+                out.push_str(&parser::escape(&s[..i]));
+                let more = s[i..].chars().count();
+                out.push_str(&format!("... ({} more characters)", more));
+            }
+            None => out.push_str(&parser::escape(s)),
+        },
+        Value::Record(fields) => {
+            out.push('{');
+            for (i, field) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                out.push_str(&field.0);
+                out.push_str(" = ");
+                write_compact(&field.1, depth + 1, limit, out);
+            }
+            out.push('}');
+        }
+        Value::Tuple(elements) => {
+            out.push('(');
+            for (i, element) in elements.iter().take(MAX_ELEMENTS).enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_compact(element, depth + 1, limit, out);
+            }
+            if elements.len() > MAX_ELEMENTS {
+                out.push_str(&format!(", ... ({} more)", elements.len() - MAX_ELEMENTS));
+            }
+            out.push(')');
+        }
+        _ => out.push_str(&value.to_string()),
+    }
+}
+
+// Tuples that don't fit in what is left of the line, after column, are broken
+// up with one element per line.
+fn write_value(
+    value: &Value,
+    width: usize,
+    indent: usize,
+    column: usize,
+    depth: usize,
+    out: &mut String,
+) {
+    // Only enough is written to tell whether the value fits, since a
+    // character takes at most four bytes.
+    let room = width.saturating_sub(column);
+    let mut compact = String::new();
+    write_compact(value, depth, room * 4, &mut compact);
+    if compact.chars().count() <= room || depth > MAX_NESTING {
+        out.push_str(&compact);
+        return;
+    }
+    match value {
+        Value::Datatype(name, _, value) if !matches!(**value, Value::Unit) => {
+            out.push_str(name);
+            out.push(' ');
+            let column = column + name.chars().count() + 1;
+            write_value(value, width, indent, column, depth + 1, out);
+        }
+        Value::Record(fields) => {
+            out.push('{');
+            for field in fields {
+                out.push('\n');
+                out.push_str(&INDENT.repeat(indent + 1));
+                out.push_str(&field.0);
+                out.push_str(" = ");
+                let column = (indent + 1) * INDENT.len() + field.0.chars().count() + 3;
+                write_value(&field.1, width, indent + 1, column, depth + 1, out);
+                out.push(',');
+            }
+            out.push('\n');
+            out.push_str(&INDENT.repeat(indent));
+            out.push('}');
+        }
+        Value::Tuple(elements) => {
+            out.push('(');
+            for element in elements.iter().take(MAX_ELEMENTS) {
+                out.push('\n');
+                out.push_str(&INDENT.repeat(indent + 1));
+                let column = (indent + 1) * INDENT.len();
+                write_value(element, width, indent + 1, column, depth + 1, out);
+                out.push(',');
+            }
+            if elements.len() > MAX_ELEMENTS {
+                out.push('\n');
+                out.push_str(&INDENT.repeat(indent + 1));
+                out.push_str(&format!("... ({} more)", elements.len() - MAX_ELEMENTS));
+            }
+            out.push('\n');
+            out.push_str(&INDENT.repeat(indent));
+            out.push(')');
+        }
+        _ => write_compact(value, depth, usize::MAX, out),
+    }
+}
+
+// Prints a value to fit within width columns where it can, indenting nested
+// tuples, and cutting short long strings and tuples and deeply nested values.
+pub fn value(value: &Value, width: usize) -> String {
+    let mut out = String::new();
+    write_value(value, width, 0, 0, 0, &mut out);
+    out
+}
+
+// Reformats source code with canonical indentation and spacing. Comments run
+// from # to the end of the line. Those that follow a statement on its last
+// line stay there, and others are kept on their own lines before the next
+// statement.
+pub fn format(src: &str) -> Result<String, Vec<ParseError>> {
+    let ast = parser::parse(src)?;
+    let mut comments = VecDeque::new();
+    let mut line = 1;
+    let mut counted = 0;
+    for start in parser::comment_starts(src) {
+        line += src[counted..start].matches('\n').count();
+        counted = start;
+        let end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let code = src[..start].rsplit('\n').next().unwrap_or("").trim_end();
+        let code_end = if code.trim_start().is_empty() {
+            None
+        } else {
+            Some(code.chars().count() + 1)
+        };
+        comments.push_back((line, code_end, src[start..end].trim_end().to_string()));
+    }
+    let mut out = Output::new(comments, true);
+    write_ast(&ast, 0, &mut out);
+    let empty = out.text.is_empty();
+    write_comments(usize::MAX, empty, false, 0, &mut out);
+    if !out.text.is_empty() {
+        out.push('\n');
+    }
+    Ok(out.text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::parser;
+    use crate::pretty;
+    use crate::typeinfer;
+    use crate::vm::Value;
+
+    macro_rules! roundtrip {
+        ($input:expr, $value:expr) => {{
+            match parser::parse($input) {
+                Ok(ast) => {
+                    let printed = pretty::print(&ast);
+                    assert_eq!(printed, $value);
+                    match parser::parse(&printed) {
+                        Ok(reparsed) => {
+                            assert_eq!(reparsed.to_string(), ast.to_string());
+                        }
+                        Err(_) => {
+                            unreachable!();
+                        }
+                    }
+                }
+                Err(_) => {
+                    unreachable!();
+                }
+            }
+        }};
+    }
+
+    #[test]
+    fn prints() {
+        roundtrip!("1+2*3", "1 + 2 * 3");
+        roundtrip!("(1 + 2) * 3", "(1 + 2) * 3");
+        roundtrip!("1 - (2 - 3)", "1 - (2 - 3)");
+        roundtrip!("1 - 2 - 3", "1 - 2 - 3");
+        roundtrip!("-(1 + 2)", "-(1 + 2)");
+        roundtrip!("~ ~true", "~~true");
+        roundtrip!("(1,)", "(1,)");
+        roundtrip!("(1, (true, ()))", "(1, (true, ()))");
+        roundtrip!("(..a, ..(1, 2), 3)", "(..a, ..(1, 2), 3)");
+        roundtrip!("(..a)", "(..a,)");
+        roundtrip!("s [i] [0] + \"ab\"[1]", "s[i][0] + \"ab\"[1]");
+        roundtrip!("(a + b)[0]", "(a + b)[0]");
+        roundtrip!("s[1 .. n - 1]", "s[1..n - 1]");
+        roundtrip!("('\\'', '#')", "('\\'', '#')");
+        roundtrip!("{x = 1, y = (a, b),}.x", "{x = 1, y = (a, b)}.x");
+        roundtrip!("p.x[0].y", "p.x[0].y");
+        roundtrip!(
+            "(p : {x: integer, y: string})",
+            "(p : {x: integer, y: string})"
+        );
+        roundtrip!("def x := (1 : integer)", "def x := (1 : integer)");
+        roundtrip!("(f : ('a -> 'b) -> 'a)", "(f : ('a -> 'b) -> 'a)");
+        roundtrip!("(f(1))(2)", "(f (1)) (2)");
+        roundtrip!("g(f(1, 2))", "g (f (1, 2))");
+        roundtrip!("f\n(1, 2)", "f\n(1, 2)");
+        roundtrip!("def x := 1\n(-x + 1)", "def x := 1\n(-x + 1)");
+        roundtrip!(
+            "fn x -> x + 1 end (1)",
+            "fn (x) ->
+    x + 1
+end (1)"
+        );
+        roundtrip!(
+            "def f := fn (x, y) -> def t := 2 x + t end",
+            "def f := fn (x, y) ->
+    def t := 2
+    x + t
+end"
+        );
+        roundtrip!(
+            "fn fact (n) -> fn iter (n, acc) -> if n == 0 then acc else iter(n - 1, n*acc) end end iter (n, 1) end",
+            "fn fact (n) ->
+    fn iter (n, acc) ->
+        if n == 0 then
+            acc
+        else
+            iter (n - 1, n * acc)
+        end
+    end
+    iter (n, 1)
+end"
+        );
+        roundtrip!(
+            "for (i, x) in items do f(i) g(x) end",
+            "for (i, x) in items do
+    f (i)
+    g (x)
+end"
+        );
+        roundtrip!(
+            "if a then 1 elsif b then 2 else 3 end",
+            "if a then
+    1
+elsif b then
+    2
+else
+    3
+end"
+        );
+        roundtrip!(
+            "type Pair := Cons (a, b) | Null end
+             match p with Null -> 0 | Cons (a, b) -> a + b end",
+            "type Pair := Cons (a, b) | Null end
+match p with
+    Null -> 0
+    | Cons (a, b) -> a + b
+end"
+        );
+    }
+
+    #[test]
+    fn formats() {
+        let src = "# Compute factorials
+fn fact (n) ->
+  # helper
+  fn iter (n, acc) ->
+    if n == 0 then acc else iter(n - 1, n*acc) end
+  end  # done
+  iter (n,1)
+end
+
+
+fact (10)  # call it
+";
+        let expected = "# Compute factorials
+fn fact (n) ->
+    # helper
+    fn iter (n, acc) ->
+        if n == 0 then
+            acc
+        else
+            iter (n - 1, n * acc)
+        end
+    end # done
+    iter (n, 1)
+end
+
+fact (10) # call it
+";
+        match pretty::format(src) {
+            Ok(formatted) => {
+                assert_eq!(formatted, expected);
+                match pretty::format(&formatted) {
+                    Ok(reformatted) => assert_eq!(reformatted, expected),
+                    Err(_) => unreachable!(),
+                }
+            }
+            Err(_) => unreachable!(),
+        }
+        match pretty::format("# only a comment\n") {
+            Ok(formatted) => assert_eq!(formatted, "# only a comment\n"),
+            Err(_) => unreachable!(),
+        }
+        match pretty::format("def s := \"# not a comment\\n\" # a comment\n") {
+            Ok(formatted) => {
+                assert_eq!(formatted, "def s := \"# not a comment\\n\" # a comment\n")
+            }
+            Err(_) => unreachable!(),
+        }
+        match pretty::format("def s := \"\"\"\n# not a comment\"\"\" # a comment\n") {
+            Ok(formatted) => {
+                assert_eq!(formatted, "def s := \"\\n# not a comment\" # a comment\n")
+            }
+            Err(_) => unreachable!(),
+        }
+        // Comments after the code that ends a statement go with it.
+        match pretty::format(
+            "def s := \"a#b\" # c\nfn f () -> 1 end # f\ndef x := 1 def y := 2 # y\n",
+        ) {
+            Ok(formatted) => assert_eq!(
+                formatted,
+                "def s := \"a#b\" # c\nfn f () ->\n    1\nend # f\ndef x := 1\ndef y := 2 # y\n"
+            ),
+            Err(_) => unreachable!(),
+        }
+        // Comments after code inside a statement stay on that code's line.
+        match pretty::format(
+            "fn len (l) -> match l with C (_, r) -> 1 + len (r) # arm\n| N -> 0 end end\nfn f (b) -> if b > 2 then # cond\nb else 0 end end\n",
+        ) {
+            Ok(formatted) => assert_eq!(
+                formatted,
+                "fn len (l) ->\n    match l with\n        C (_, r) -> 1 + len (r) # arm\n        | N -> 0\n    end\nend\nfn f (b) ->\n    if b > 2 then # cond\n        b\n    else\n        0\n    end\nend\n"
+            ),
+            Err(_) => unreachable!(),
+        }
+        assert!(pretty::format("def x := 1 +").is_err());
+    }
+
+    #[test]
+    fn prints_typed() {
+        let mut ids = HashMap::new();
+        match parser::parse(
+            "type List := Nil | Cons (integer, List) end
+             type Maybe := Some (x) | None end fn (x) -> Some ((x : integer)) end",
+        ) {
+            Ok(ast) => match typeinfer::infer(&ast, &mut ids) {
+                Ok(typed_ast) => {
+                    assert_eq!(
+                        pretty::print_typed(&typed_ast),
+                        "type List := Nil | Cons (integer, List) end
+type Maybe := Some (x) | None end
+fn (x) ->
+    Some (x)
+end"
+                    );
+                }
+                Err(_) => {
+                    unreachable!();
+                }
+            },
+            Err(_) => {
+                unreachable!();
+            }
+        }
+    }
+
+    #[test]
+    fn values() {
+        let small = Value::Tuple(vec![Value::Integer(1), Value::String("a".to_string())]);
+        assert_eq!(pretty::value(&small, 80), "(1, \"a\")");
+        let nested = Value::Tuple(vec![
+            small.clone(),
+            Value::Datatype(
+                "Pair".to_string(),
+                "Pair".to_string(),
+                Arc::new(Value::Tuple(vec![small.clone(), Value::Boolean(true)])),
+            ),
+        ]);
+        assert_eq!(
+            pretty::value(&nested, 20),
+            "(
+    (1, \"a\"),
+    Pair (
+        (1, \"a\"),
+        true,
+    ),
+)"
+        );
+        assert_eq!(pretty::value(&nested, 80), nested.to_string());
+        let record = Value::Record(vec![
+            ("x".to_string(), Value::Integer(1)),
+            ("y".to_string(), small.clone()),
+        ]);
+        assert_eq!(pretty::value(&record, 80), "{x = 1, y = (1, \"a\")}");
+        assert_eq!(
+            pretty::value(&record, 20),
+            "{
+    x = 1,
+    y = (1, \"a\"),
+}"
+        );
+
+        let long = Value::Tuple((0..150).map(Value::Integer).collect());
+        let printed = pretty::value(&long, 80);
+        assert!(printed.starts_with("(\n    0,\n    1,\n"));
+        assert!(printed.ends_with("    99,\n    ... (50 more)\n)"));
+        let s = Value::String("é".repeat(1005));
+        assert_eq!(
+            pretty::value(&s, 80),
+            format!("\"{}\"... (5 more characters)", "é".repeat(1000))
+        );
+
+        // Nesting is cut off rather than overflowing the stack.
+        let mut deep = Value::Unit;
+        for _ in 0..100000 {
+            deep = Value::Tuple(vec![deep]);
+        }
+        let printed = pretty::value(&deep, 80);
+        assert!(printed.contains("..."));
+        assert!(printed.lines().count() < 110);
+        // Dropping it would overflow the stack as well.
+        std::mem::forget(deep);
+    }
+}
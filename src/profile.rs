@@ -0,0 +1,299 @@
+// Counts of the instructions executed in each stack of functions, for finding
+// where programs spend their time. Counting instructions rather than reading
+// a clock makes profiles the same from run to run. They are written in the
+// folded format that flame graph tools such as inferno and flamegraph.pl read.
+//
+// For long running programs, a sampler instead notes the function and
+// instruction running now each time a timer goes off.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+use std::time::Duration;
+
+use crate::vm::{Chunk, Environment};
+
+// The frame that the top level of every program runs in.
+pub const TOP_LEVEL: &str = "<toplevel>";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    // Instructions executed in each stack, outermost function first.
+    pub counts: HashMap<Vec<String>, usize>,
+    // The stack running now, and how many instructions the virtual machine
+    // had executed when it last changed.
+    frames: Vec<String>,
+    mark: usize,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            counts: HashMap::new(),
+            frames: vec![TOP_LEVEL.to_string()],
+            mark: 0,
+        }
+    }
+
+    // Starts a program at the top level, executed instructions in.
+    pub fn start(&mut self, executed: usize) {
+        self.frames.truncate(1);
+        self.mark = executed;
+    }
+
+    // Adds the instructions executed since the stack last changed to it.
+    pub fn flush(&mut self, executed: usize) {
+        if executed > self.mark {
+            *self.counts.entry(self.frames.clone()).or_insert(0) += executed - self.mark;
+        }
+        self.mark = executed;
+    }
+
+    pub fn enter(&mut self, name: String, executed: usize) {
+        self.flush(executed);
+        self.frames.push(name);
+    }
+
+    pub fn leave(&mut self, executed: usize) {
+        self.flush(executed);
+        if self.frames.len() > 1 {
+            self.frames.pop();
+        }
+    }
+
+    // Takes the innermost n frames off the stack, for a coroutine that
+    // yields, to be put back when it is resumed.
+    pub fn suspend(&mut self, n: usize, executed: usize) -> Vec<String> {
+        self.flush(executed);
+        let len = self.frames.len().saturating_sub(n).max(1);
+        self.frames.split_off(len)
+    }
+
+    pub fn resume(&mut self, frames: Vec<String>, executed: usize) {
+        self.flush(executed);
+        self.frames.extend(frames);
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::new()
+    }
+}
+
+// The function and instruction that were running when samples were taken,
+// and how many were.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub function: String,
+    pub ip: usize,
+    pub count: usize,
+}
+
+// A thread sets due once every interval, and the virtual machine takes a
+// sample at the next instruction it executes, so that running costs one
+// check of a flag per instruction. The thread stops once the sampler is
+// dropped. There are no threads on wasm32, so no samples are taken there.
+pub struct Sampler {
+    pub interval: Duration,
+    due: Arc<AtomicBool>,
+    counts: HashMap<(String, usize), usize>,
+}
+
+impl Sampler {
+    pub fn new(interval: Duration) -> Sampler {
+        let due = Arc::new(AtomicBool::new(false));
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let due = Arc::downgrade(&due);
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                match due.upgrade() {
+                    Some(due) => due.store(true, Ordering::Relaxed),
+                    None => break,
+                }
+            });
+        }
+        Sampler {
+            interval,
+            due,
+            counts: HashMap::new(),
+        }
+    }
+
+    // Whether a sample is due, clearing the flag if it is. Checking it first
+    // keeps the usual case to a load.
+    pub fn due(&self) -> bool {
+        self.due.load(Ordering::Relaxed) && self.due.swap(false, Ordering::Relaxed)
+    }
+
+    // Forgets a timer that went off while nothing was running.
+    pub fn skip(&self) {
+        self.due.store(false, Ordering::Relaxed);
+    }
+
+    pub fn record(&mut self, function: String, ip: usize) {
+        *self.counts.entry((function, ip)).or_insert(0) += 1;
+    }
+
+    // The samples taken so far, the most frequent first.
+    pub fn samples(&self) -> Vec<Sample> {
+        let mut samples: Vec<Sample> = self
+            .counts
+            .iter()
+            .map(|((function, ip), count)| Sample {
+                function: function.to_string(),
+                ip: *ip,
+                count: *count,
+            })
+            .collect();
+        samples.sort_by(|a, b| (b.count, &a.function, a.ip).cmp(&(a.count, &b.function, b.ip)));
+        samples
+    }
+
+    // The number of samples taken in each function, the most frequent first.
+    pub fn functions(&self) -> Vec<(String, usize)> {
+        let mut functions: HashMap<&str, usize> = HashMap::new();
+        for ((function, _), count) in &self.counts {
+            *functions.entry(function).or_insert(0) += count;
+        }
+        let mut functions: Vec<(String, usize)> = functions
+            .into_iter()
+            .map(|(function, count)| (function.to_string(), count))
+            .collect();
+        functions.sort_by(|a, b| (b.1, &a.0).cmp(&(a.1, &b.0)));
+        functions
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+    }
+}
+
+// Functions are named for the name they were defined with, and anonymous
+// functions for where they start.
+pub fn frame_name(chunk: &Arc<Chunk>, env: &Environment) -> String {
+    match (&env.fun, &chunk.signature) {
+        (Some((name, fun)), _) if Arc::ptr_eq(fun, chunk) => name.to_string(),
+        (_, Some((_, span))) => format!("lambda@{}:{}", span.line, span.col),
+        (_, None) => "lambda".to_string(),
+    }
+}
+
+// One line for each stack, with its frames separated by semicolons and
+// followed by its count, sorted so that the output is the same every time.
+pub fn folded(counts: &HashMap<Vec<String>, usize>) -> String {
+    let mut lines: Vec<String> = counts
+        .iter()
+        .map(|(frames, count)| format!("{} {}", frames.join(";"), count))
+        .collect();
+    lines.sort();
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen;
+    use crate::parser;
+    use std::time::Duration;
+
+    use crate::profile::{folded, Profile, Sampler};
+    use crate::vm;
+
+    fn profile(src: &str) -> String {
+        let mut vm = vm::VirtualMachine::new();
+        vm.profile = Some(Profile::new());
+        match parser::parse(src) {
+            Ok(ast) => {
+                let _ = codegen::eval(&mut vm, &ast);
+            }
+            Err(_) => unreachable!(),
+        }
+        match &vm.profile {
+            Some(profile) => folded(&profile.counts),
+            None => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn counts_stacks() {
+        assert_eq!(
+            profile(
+                "fn double (x) -> 2 * x end
+fn twice (x) -> double (x) + double (x) end
+fn apply (f, x) -> f (x) end
+apply (twice, string_length (\"abc\"))
+apply (fn (x) -> twice (x) end, 1)"
+            ),
+            concat!(
+                "<toplevel> 27\n",
+                "<toplevel>;apply 12\n",
+                "<toplevel>;apply;lambda@5:8 5\n",
+                "<toplevel>;apply;lambda@5:8;twice 11\n",
+                "<toplevel>;apply;lambda@5:8;twice;double 10\n",
+                "<toplevel>;apply;twice 11\n",
+                "<toplevel>;apply;twice;double 10\n",
+                "<toplevel>;string_length 1\n",
+            )
+        );
+        // Programs that fail are counted up to where they failed.
+        assert_eq!(
+            profile("fn divide (x) -> 1 / x end\ndivide (0)"),
+            "<toplevel> 8\n<toplevel>;divide 4\n"
+        );
+        // Coroutines are counted in the stack that resumed them.
+        assert_eq!(
+            profile("def next := coroutine (fn (yield) -> yield (1) 2 end)\n(next (), next ())"),
+            concat!(
+                "<toplevel> 15\n",
+                "<toplevel>;coroutine 1\n",
+                "<toplevel>;resume 6\n",
+                "<toplevel>;resume;lambda@1:24 7\n",
+                "<toplevel>;resume;lambda@1:24;yield 2\n",
+            )
+        );
+    }
+
+    #[test]
+    fn samples() {
+        let mut vm = vm::VirtualMachine::new();
+        vm.sampler = Some(Sampler::new(Duration::from_millis(1)));
+        let src = "fn fib (n) -> if n < 2 then n else fib (n - 1) + fib (n - 2) end end
+fib (20)";
+        match parser::parse(src) {
+            Ok(ast) => {
+                // Evaluations are repeated until the timer has gone off at
+                // least once while one was running.
+                for _ in 0..100 {
+                    assert!(codegen::eval(&mut vm, &ast).is_ok());
+                    if vm.sampler.as_ref().map_or(0, |s| s.samples().len()) > 0 {
+                        break;
+                    }
+                }
+            }
+            Err(_) => unreachable!(),
+        }
+        match &mut vm.sampler {
+            Some(sampler) => {
+                let samples = sampler.samples();
+                assert!(!samples.is_empty());
+                assert!(samples
+                    .windows(2)
+                    .all(|pair| pair[0].count >= pair[1].count));
+                let functions = sampler.functions();
+                assert_eq!(functions[0].0, "fib");
+                let total: usize = samples.iter().map(|sample| sample.count).sum();
+                assert_eq!(functions.iter().map(|f| f.1).sum::<usize>(), total);
+                sampler.clear();
+                assert!(sampler.samples().is_empty());
+            }
+            None => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use crate::codegen;
+use crate::codegen::InterpreterError;
+use crate::coverage;
+use crate::parser;
+use crate::parser::{span_of, AST};
+use crate::vm;
+
+pub enum Outcome {
+    Passed,
+    Failed(parser::Span),
+    Error(InterpreterError),
+}
+
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+// A test is a top level definition or function whose name starts with test_.
+// Functions are called with unit.
+fn test_name(ast: &AST) -> Option<(&str, bool)> {
+    match ast {
+        AST::Define(id, value, _) => match &**id {
+            AST::Identifier(name, _) if name.starts_with("test_") => {
+                Some((name, matches!(&**value, AST::Function(_, _, _, _))))
+            }
+            _ => None,
+        },
+        AST::Function(Some(name), _, _, _) if name.starts_with("test_") => Some((name, true)),
+        _ => None,
+    }
+}
+
+fn run_test(
+    vm: &mut vm::VirtualMachine,
+    statements: &[AST],
+    test: &AST,
+    name: &str,
+    call: bool,
+) -> Result<vm::Value, InterpreterError> {
+    for statement in statements {
+        if test_name(statement).is_none() {
+            codegen::eval(vm, statement)?;
+        }
+    }
+    let value = codegen::eval(vm, test)?;
+    if call {
+        let span = span_of(test);
+        let call = AST::Call(
+            Box::new(AST::Identifier(name.to_string(), span)),
+            Box::new(AST::Unit(span)),
+            span,
+        );
+        codegen::eval(vm, &call)
+    } else {
+        Ok(value)
+    }
+}
+
+// Run each test in a fresh virtual machine, after evaluating the other
+// definitions that precede it. A test passes if it evaluates to true. When
+// coverage is given, execution counts from every test are added to it.
+pub fn run(ast: &AST, mut coverage: Option<&mut HashMap<parser::Span, usize>>) -> Vec<TestResult> {
+    let statements = match ast {
+        AST::Program(statements, _) => &statements[..],
+        _ => std::slice::from_ref(ast),
+    };
+    let mut results = Vec::new();
+    for i in 0..statements.len() {
+        if let Some((name, call)) = test_name(&statements[i]) {
+            let mut vm = vm::VirtualMachine::new();
+            if coverage.is_some() {
+                vm.coverage = Some(HashMap::new());
+            }
+            let result = run_test(&mut vm, &statements[..i], &statements[i], name, call);
+            if let (Some(total), Some(hits)) = (coverage.as_mut(), &vm.coverage) {
+                coverage::merge(total, hits);
+            }
+            let outcome = match result {
+                Ok(vm::Value::Boolean(true)) => Outcome::Passed,
+                Ok(_) => Outcome::Failed(span_of(&statements[i])),
+                Err(err) => Outcome::Error(err),
+            };
+            results.push(TestResult {
+                name: name.to_string(),
+                outcome,
+            });
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser;
+    use crate::testing;
+    use crate::testing::Outcome;
+
+    #[test]
+    fn runs() {
+        match parser::parse(
+            "fn double (x) -> 2 * x end
+             def test_constant := double (2) == 4
+             fn test_function () -> double (3) == 5 end
+             def limit := 1
+             def test_lambda := fn () -> double (limit) / (limit - 1) == 0 end
+             def test_independent := limit == 1",
+        ) {
+            Ok(ast) => {
+                let results = testing::run(&ast, None);
+                let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+                assert_eq!(
+                    names,
+                    vec![
+                        "test_constant",
+                        "test_function",
+                        "test_lambda",
+                        "test_independent"
+                    ]
+                );
+                assert!(matches!(results[0].outcome, Outcome::Passed));
+                match &results[1].outcome {
+                    Outcome::Failed(span) => assert_eq!((span.line, span.col), (3, 14)),
+                    _ => unreachable!(),
+                }
+                match &results[2].outcome {
+                    Outcome::Error(err) => {
+                        assert_eq!(err.err, "Division by zero.");
+                        assert_eq!(err.span.line, 5);
+                    }
+                    _ => unreachable!(),
+                }
+                assert!(matches!(results[3].outcome, Outcome::Passed));
+            }
+            Err(_) => {
+                unreachable!();
+            }
+        }
+    }
+}
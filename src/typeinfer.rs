@@ -1,19 +1,49 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
+use std::sync::Arc;
 
 use crate::codegen::InterpreterError;
+use crate::diagnostics::Code;
 use crate::parser;
-use crate::unification::unify;
+use crate::unification::{occurs, unify};
 
+// Types are copied into every node of the typed tree, the environment and
+// upvalue maps, so compound types share their parts. Arc rather than Rc keeps
+// the virtual machine Send.
 #[derive(Clone, Debug)]
 pub enum Type {
+    // Values of any type, which must be checked before they are used as one.
+    Any,
     Boolean,
+    // Channels carry values of one type between threads.
+    Channel(Arc<Type>),
+    Char,
     Datatype(String),
-    Function(Box<Type>, Box<Type>),
+    Function(Arc<Type>, Arc<Type>),
     Integer,
+    // The type of expressions that never produce a value, such as calls to
+    // exit, which fits wherever any other type is expected.
+    Never,
     Polymorphic(String),
-    Tuple(Vec<Type>),
+    // The fields of a record, which are kept sorted by name, so that records
+    // with the same fields have the same type whatever order they are
+    // written in.
+    Record(Arc<[(String, Type)]>),
+    // Tuples of any length whose elements all have one type, which only
+    // builtins such as pmap are declared with. Each call to one is typed for
+    // the length of the tuple it is made with.
+    Repeated(Arc<Type>),
+    // Records with at least the fields, which are kept sorted by name, and
+    // any others, which the variable stands for. Functions that read fields
+    // from a parameter take these, so they can be given any record with the
+    // fields they use.
+    Row(Arc<[(String, Type)]>, String),
+    String,
+    Tuple(Arc<[Type]>),
+    // Values of any one of several types, which are kept sorted by name and
+    // without repeats so that the same union is always written the same way.
+    Union(Arc<[Type]>),
     Unit,
 }
 
@@ -27,9 +57,22 @@ impl PartialEq for Type {
             }
         }
         match self {
+            Type::Any => {
+                matches!(other, Type::Any)
+            }
             Type::Boolean => {
                 matches!(other, Type::Boolean)
             }
+            Type::Channel(element) => {
+                if let Type::Channel(other_element) = other {
+                    element == other_element
+                } else {
+                    false
+                }
+            }
+            Type::Char => {
+                matches!(other, Type::Char)
+            }
             Type::Function(param, body) => {
                 if let Type::Function(other_param, other_body) = other {
                     param == other_param && body == other_body
@@ -40,6 +83,9 @@ impl PartialEq for Type {
             Type::Integer => {
                 matches!(other, Type::Integer)
             }
+            Type::Never => {
+                matches!(other, Type::Never)
+            }
             Type::Polymorphic(s) => {
                 if let Type::Polymorphic(t) = other {
                     s == t
@@ -47,8 +93,44 @@ impl PartialEq for Type {
                     true
                 }
             }
+            Type::Record(fields) => {
+                if let Type::Record(other_fields) = other {
+                    fields.len() == other_fields.len()
+                        && fields
+                            .iter()
+                            .zip(other_fields.iter())
+                            .all(|(field, other)| field.0 == other.0 && field.1 == other.1)
+                } else {
+                    false
+                }
+            }
+            Type::Repeated(element) => {
+                if let Type::Repeated(other_element) = other {
+                    element == other_element
+                } else {
+                    false
+                }
+            }
+            Type::Row(fields, row) => {
+                if let Type::Row(other_fields, other_row) = other {
+                    row == other_row
+                        && fields.len() == other_fields.len()
+                        && fields
+                            .iter()
+                            .zip(other_fields.iter())
+                            .all(|(field, other)| field.0 == other.0 && field.1 == other.1)
+                } else {
+                    false
+                }
+            }
+            Type::String => {
+                matches!(other, Type::String)
+            }
             Type::Tuple(elements) => {
                 if let Type::Tuple(other_elements) = other {
+                    if elements.len() != other_elements.len() {
+                        return false;
+                    }
                     for i in 0..elements.len() {
                         if elements[i] != other_elements[i] {
                             return false;
@@ -59,6 +141,13 @@ impl PartialEq for Type {
                     false
                 }
             }
+            Type::Union(members) => {
+                if let Type::Union(other_members) = other {
+                    members == other_members
+                } else {
+                    false
+                }
+            }
             Type::Unit => {
                 matches!(other, Type::Unit)
             }
@@ -76,10 +165,36 @@ impl PartialEq for Type {
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Type::Any => write!(f, "any"),
             Type::Boolean => write!(f, "boolean"),
-            Type::Function(param, body) => write!(f, "{} -> {}", param, body),
+            Type::Channel(element) => write!(f, "channel ({})", element),
+            Type::Char => write!(f, "char"),
+            Type::Function(param, body) => match &**param {
+                Type::Function(_, _) => write!(f, "({}) -> {}", param, body),
+                _ => write!(f, "{} -> {}", param, body),
+            },
             Type::Integer => write!(f, "integer"),
+            Type::Never => write!(f, "never"),
             Type::Polymorphic(s) => write!(f, "{}", s),
+            Type::Record(fields) => {
+                write!(f, "{{")?;
+                for i in 0..fields.len() {
+                    write!(f, "{}: {}", fields[i].0, fields[i].1)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Type::Repeated(element) => write!(f, "({}, ...)", element),
+            Type::Row(fields, row) => {
+                write!(f, "{{")?;
+                for field in fields.iter() {
+                    write!(f, "{}: {}, ", field.0, field.1)?;
+                }
+                write!(f, "..{}}}", row)
+            }
+            Type::String => write!(f, "string"),
             Type::Tuple(elements) => {
                 write!(f, "(")?;
                 for i in 0..elements.len() {
@@ -90,6 +205,11 @@ impl fmt::Display for Type {
                 }
                 write!(f, ")")
             }
+            Type::Union(members) => {
+                let members: Vec<String> =
+                    members.iter().map(|member| member.to_string()).collect();
+                write!(f, "{}", members.join(" | "))
+            }
             Type::Datatype(s) => write!(f, "{}", s),
             Type::Unit => write!(f, "unit"),
         }
@@ -103,55 +223,307 @@ pub enum TypedAST {
         parser::Operator,
         Box<TypedAST>,
         Box<TypedAST>,
-        usize,
-        usize,
+        parser::Span,
     ),
-    Boolean(bool),
-    Call(Box<TypedAST>, Box<TypedAST>),
-    Datatype(Type, Vec<(String, Type)>),
-    Define(Type, String, Box<TypedAST>),
-    Function(Option<String>, Box<TypedAST>, Box<TypedAST>),
-    Identifier(Type, String),
-    If(Vec<(TypedAST, TypedAST)>, Box<TypedAST>),
-    Integer(i64),
+    Boolean(bool, parser::Span),
+    Call(Box<TypedAST>, Box<TypedAST>, parser::Span),
+    // An expression ascribed a type involving any, which is checked at
+    // runtime where the expression's value is of type any.
+    Cast(Type, Box<TypedAST>, parser::Span),
+    Char(char, parser::Span),
+    Datatype(Type, Vec<(String, Type)>, parser::Span),
+    Define(Type, String, Box<TypedAST>, parser::Span),
+    Field(Type, Box<TypedAST>, String, parser::Span),
+    For(Box<TypedAST>, Box<TypedAST>, Box<TypedAST>, parser::Span),
+    Function(Option<String>, Box<TypedAST>, Box<TypedAST>, parser::Span),
+    Identifier(Type, String, parser::Span),
+    If(Vec<(TypedAST, TypedAST)>, Box<TypedAST>, parser::Span),
+    Index(Box<TypedAST>, Box<TypedAST>, parser::Span),
+    Integer(i64, parser::Span),
     Match(
         Box<TypedAST>,
         Type,
-        Vec<(String, Option<TypedAST>, TypedAST)>,
+        Vec<(TypedPattern, TypedAST)>,
+        parser::Span,
     ),
-    Program(Type, Vec<TypedAST>),
-    Tuple(Type, Vec<TypedAST>),
-    UnaryOp(Type, parser::Operator, Box<TypedAST>),
-    Unit,
+    Program(Type, Vec<TypedAST>, parser::Span),
+    // The fields of a record literal, in the order they are written.
+    Record(Type, Vec<(String, TypedAST)>, parser::Span),
+    Slice(Box<TypedAST>, Box<TypedAST>, Box<TypedAST>, parser::Span),
+    Spread(Type, Box<TypedAST>, parser::Span),
+    String(String, parser::Span),
+    Tuple(Type, Vec<TypedAST>, parser::Span),
+    UnaryOp(Type, parser::Operator, Box<TypedAST>, parser::Span),
+    Unit(parser::Span),
+}
+
+// A pattern of a match arm, with the types of the names it binds. Arms that
+// name a member of a union test for the member type instead.
+#[derive(Clone, Debug)]
+pub enum TypedPattern {
+    As(Type, Box<TypedPattern>, String, parser::Span),
+    Identifier(Type, String, parser::Span),
+    Literal(Box<TypedAST>, parser::Span),
+    Member(Type, Option<Box<TypedPattern>>, parser::Span),
+    Or(Vec<TypedPattern>, parser::Span),
+    Tuple(Vec<TypedPattern>, parser::Span),
+    Variant(String, Option<Box<TypedPattern>>, parser::Span),
+    Wildcard(parser::Span),
+}
+
+// The names a pattern binds, in order of name, with their types and where
+// they are bound. Every alternative binds the same names, so those bound by
+// the first are the names bound by the pattern.
+pub fn bindings(pattern: &TypedPattern) -> Vec<(&str, &Type, parser::Span)> {
+    fn find<'a>(pattern: &'a TypedPattern, found: &mut Vec<(&'a str, &'a Type, parser::Span)>) {
+        match pattern {
+            TypedPattern::As(typ, pattern, name, span) => {
+                found.push((name, typ, *span));
+                find(pattern, found);
+            }
+            TypedPattern::Identifier(typ, name, span) => found.push((name, typ, *span)),
+            TypedPattern::Literal(_, _) | TypedPattern::Wildcard(_) => {}
+            TypedPattern::Member(_, param, _) | TypedPattern::Variant(_, param, _) => {
+                if let Some(param) = param {
+                    find(param, found);
+                }
+            }
+            TypedPattern::Or(alternatives, _) => find(&alternatives[0], found),
+            TypedPattern::Tuple(elements, _) => {
+                for element in elements {
+                    find(element, found);
+                }
+            }
+        }
+    }
+    let mut found = Vec::new();
+    find(pattern, &mut found);
+    found.sort_by_key(|binding| binding.0);
+    found
 }
 
 pub fn type_of(ast: &TypedAST) -> Type {
     match ast {
-        TypedAST::BinaryOp(typ, _, _, _, _, _)
-        | TypedAST::Datatype(typ, _)
-        | TypedAST::Define(typ, _, _)
-        | TypedAST::Identifier(typ, _)
-        | TypedAST::Program(typ, _)
-        | TypedAST::Tuple(typ, _)
-        | TypedAST::UnaryOp(typ, _, _) => typ.clone(),
-        TypedAST::Boolean(_) => Type::Boolean,
-        TypedAST::Call(fun, _) => match type_of(fun) {
-            Type::Function(_, body) => *body,
+        TypedAST::BinaryOp(typ, _, _, _, _)
+        | TypedAST::Cast(typ, _, _)
+        | TypedAST::Datatype(typ, _, _)
+        | TypedAST::Define(typ, _, _, _)
+        | TypedAST::Field(typ, _, _, _)
+        | TypedAST::Identifier(typ, _, _)
+        | TypedAST::Program(typ, _, _)
+        | TypedAST::Record(typ, _, _)
+        | TypedAST::Spread(typ, _, _)
+        | TypedAST::Tuple(typ, _, _)
+        | TypedAST::UnaryOp(typ, _, _, _) => typ.clone(),
+        TypedAST::Boolean(_, _) => Type::Boolean,
+        TypedAST::Char(_, _) => Type::Char,
+        TypedAST::Call(fun, _, _) => match type_of(fun) {
+            Type::Function(_, body) => (*body).clone(),
             _ => unreachable!(),
         },
-        TypedAST::Function(_, param, body) => {
-            Type::Function(Box::new(type_of(param)), Box::new(type_of(body)))
-        }
-        TypedAST::If(_, els) => type_of(&els),
-        TypedAST::Integer(_) => Type::Integer,
-        TypedAST::Match(_, _, cases) => {
-            if !cases.is_empty() {
-                type_of(&cases[0].2)
-            } else {
-                unreachable!()
+        TypedAST::For(_, _, _, _) => Type::Unit,
+        TypedAST::Function(_, param, body, _) => {
+            Type::Function(Arc::new(type_of(param)), Arc::new(type_of(body)))
+        }
+        TypedAST::If(conds, els, _) => {
+            branch_type(conds.iter().map(|cond| &cond.1).chain(Some(&**els)))
+        }
+        TypedAST::Index(_, _, _) => Type::Char,
+        TypedAST::Integer(_, _) => Type::Integer,
+        TypedAST::Match(_, _, cases, _) => branch_type(cases.iter().map(|case| &case.1)),
+        TypedAST::Slice(_, _, _, _) | TypedAST::String(_, _) => Type::String,
+        TypedAST::Unit(_) => Type::Unit,
+    }
+}
+
+// The type of an if or match is that of its first branch that produces a
+// value, if any do.
+fn branch_type<'a>(branches: impl Iterator<Item = &'a TypedAST>) -> Type {
+    let mut typ = Type::Never;
+    for branch in branches {
+        typ = type_of(branch);
+        if !matches!(typ, Type::Never) {
+            break;
+        }
+    }
+    typ
+}
+
+// The leftmost operand of a chain of operators, then each operator with its
+// right operand and span, innermost first, as for parser::chain.
+pub fn chain(ast: &TypedAST) -> (&TypedAST, Vec<(&parser::Operator, &TypedAST, parser::Span)>) {
+    let mut links = Vec::new();
+    let mut lhs = ast;
+    while let TypedAST::BinaryOp(_, op, inner, rhs, span) = lhs {
+        links.push((op, &**rhs, *span));
+        lhs = inner;
+    }
+    links.reverse();
+    (lhs, links)
+}
+
+// The expressions directly inside of an expression, in source order, as for
+// parser::children.
+pub fn children(ast: &TypedAST) -> Vec<&TypedAST> {
+    match ast {
+        TypedAST::Cast(_, expr, _)
+        | TypedAST::Field(_, expr, _, _)
+        | TypedAST::Spread(_, expr, _)
+        | TypedAST::UnaryOp(_, _, expr, _) => vec![expr],
+        TypedAST::BinaryOp(_, _, lhs, rhs, _) => vec![lhs, rhs],
+        TypedAST::Call(fun, arg, _) => vec![fun, arg],
+        TypedAST::Define(_, _, value, _) => vec![value],
+        TypedAST::For(pattern, iter, body, _) => vec![pattern, iter, body],
+        TypedAST::Function(_, param, body, _) => vec![param, body],
+        TypedAST::If(conds, els, _) => {
+            let mut children = Vec::new();
+            for cond in conds {
+                children.push(&cond.0);
+                children.push(&cond.1);
+            }
+            children.push(els);
+            children
+        }
+        TypedAST::Index(value, index, _) => vec![value, index],
+        TypedAST::Match(cond, _, cases, _) => {
+            let mut children = vec![&**cond];
+            children.extend(cases.iter().map(|case| &case.1));
+            children
+        }
+        TypedAST::Program(_, expressions, _) | TypedAST::Tuple(_, expressions, _) => {
+            expressions.iter().collect()
+        }
+        TypedAST::Record(_, fields, _) => fields.iter().map(|field| &field.1).collect(),
+        TypedAST::Slice(value, start, end, _) => vec![value, start, end],
+        TypedAST::Boolean(_, _)
+        | TypedAST::Char(_, _)
+        | TypedAST::Datatype(_, _, _)
+        | TypedAST::Identifier(_, _, _)
+        | TypedAST::Integer(_, _)
+        | TypedAST::String(_, _)
+        | TypedAST::Unit(_) => Vec::new(),
+    }
+}
+
+pub fn span_of(ast: &TypedAST) -> parser::Span {
+    match ast {
+        TypedAST::BinaryOp(_, _, _, _, span)
+        | TypedAST::Boolean(_, span)
+        | TypedAST::Char(_, span)
+        | TypedAST::Call(_, _, span)
+        | TypedAST::Cast(_, _, span)
+        | TypedAST::Datatype(_, _, span)
+        | TypedAST::Define(_, _, _, span)
+        | TypedAST::Field(_, _, _, span)
+        | TypedAST::For(_, _, _, span)
+        | TypedAST::Function(_, _, _, span)
+        | TypedAST::Identifier(_, _, span)
+        | TypedAST::If(_, _, span)
+        | TypedAST::Index(_, _, span)
+        | TypedAST::Integer(_, span)
+        | TypedAST::Match(_, _, _, span)
+        | TypedAST::Program(_, _, span)
+        | TypedAST::Record(_, _, span)
+        | TypedAST::Slice(_, _, _, span)
+        | TypedAST::Spread(_, _, span)
+        | TypedAST::String(_, span)
+        | TypedAST::Tuple(_, _, span)
+        | TypedAST::UnaryOp(_, _, _, span)
+        | TypedAST::Unit(span) => *span,
+    }
+}
+
+// Calls visit on ast and every expression inside it, as parser::walk does.
+pub fn walk<'a>(ast: &'a TypedAST, visit: &mut impl FnMut(&'a TypedAST) -> bool) {
+    let mut pending = vec![ast];
+    while let Some(ast) = pending.pop() {
+        if visit(ast) {
+            pending.extend(children(ast).into_iter().rev());
+        }
+    }
+}
+
+// The type of the innermost expression at a position in the source, or of the
+// name a match arm binds there, for showing types in editors.
+pub fn type_at(ast: &TypedAST, line: usize, col: usize) -> Option<Type> {
+    let contains = |span: parser::Span| {
+        (span.line, span.col) <= (line, col) && (line, col) < (span.end_line, span.end_col)
+    };
+    let mut ast = ast;
+    if !contains(span_of(ast)) {
+        return None;
+    }
+    while let Some(child) = children(ast)
+        .into_iter()
+        .find(|child| contains(span_of(child)))
+    {
+        ast = child;
+    }
+    if let TypedAST::Match(_, _, cases, _) = ast {
+        for case in cases {
+            for (_, typ, span) in bindings(&case.0) {
+                if contains(span) {
+                    return Some(typ.clone());
+                }
             }
         }
-        TypedAST::Unit => Type::Unit,
+    }
+    Some(type_of(ast))
+}
+
+pub fn is_concrete(typ: &Type) -> bool {
+    match typ {
+        Type::Channel(element) => is_concrete(element),
+        Type::Function(param, body) => is_concrete(param) && is_concrete(body),
+        Type::Polymorphic(_) | Type::Row(_, _) => false,
+        Type::Record(fields) => fields.iter().all(|field| is_concrete(&field.1)),
+        Type::Repeated(element) => is_concrete(element),
+        Type::Tuple(elements) => elements.iter().all(is_concrete),
+        _ => true,
+    }
+}
+
+// The union of several types, with unions among them flattened into it. A
+// union of a single type is just that type.
+pub(crate) fn union(types: impl IntoIterator<Item = Type>) -> Type {
+    let mut members = Vec::new();
+    for typ in types {
+        match typ {
+            Type::Union(inner) => members.extend(inner.iter().cloned()),
+            typ => members.push(typ),
+        }
+    }
+    members.sort_by_key(|member| member.to_string());
+    members.dedup_by_key(|member| member.to_string());
+    if members.len() == 1 {
+        members.remove(0)
+    } else {
+        Type::Union(members.into())
+    }
+}
+
+// Types of earlier definitions can still contain type variables, so fresh
+// ones are numbered after them to keep the two apart.
+fn next_type_id(typ: &Type) -> u64 {
+    match typ {
+        Type::Channel(element) => next_type_id(element),
+        Type::Function(param, body) => next_type_id(param).max(next_type_id(body)),
+        Type::Polymorphic(name) => name
+            .strip_prefix('t')
+            .and_then(|n| n.parse::<u64>().ok())
+            .map_or(0, |n| n + 1),
+        Type::Record(fields) => fields
+            .iter()
+            .map(|field| next_type_id(&field.1))
+            .max()
+            .unwrap_or(0),
+        Type::Row(fields, row) => fields
+            .iter()
+            .map(|field| next_type_id(&field.1))
+            .fold(next_type_id(&Type::Polymorphic(row.to_string())), u64::max),
+        Type::Repeated(element) => next_type_id(element),
+        Type::Tuple(elements) => elements.iter().map(next_type_id).max().unwrap_or(0),
+        _ => 0,
     }
 }
 
@@ -161,152 +533,829 @@ fn fresh_type(id: &mut u64) -> Type {
     typ
 }
 
+// The variables numbered by inference in typ.
+fn variables(typ: &Type, found: &mut HashSet<String>) {
+    match typ {
+        Type::Channel(element) | Type::Repeated(element) => variables(element, found),
+        Type::Function(param, body) => {
+            variables(param, found);
+            variables(body, found);
+        }
+        Type::Polymorphic(name) if !is_declared_variable(name) => {
+            found.insert(name.to_string());
+        }
+        Type::Record(fields) => {
+            for field in fields.iter() {
+                variables(&field.1, found);
+            }
+        }
+        Type::Row(fields, row) => {
+            for field in fields.iter() {
+                variables(&field.1, found);
+            }
+            if !is_declared_variable(row) {
+                found.insert(row.to_string());
+            }
+        }
+        Type::Tuple(elements) => {
+            for element in elements.iter() {
+                variables(element, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+// A variable named as if by the host, so that each use of a name with it in
+// its type is given a variable of its own.
+fn declared_variable(name: &str) -> Type {
+    Type::Polymorphic(format!("'{}", name))
+}
+
+// Once a named function is defined, it can be called with values of any types
+// its variables could be replaced with, so they are renamed to look as if the
+// host had declared them, and each call is given variables of its own. Those
+// it shares with other names in scope, such as the element type of a channel
+// it sends on, stand for the one type, and are kept.
+fn generalize(constraints: &mut Constraints, ids: &HashMap<String, Type>, name: &str) -> Type {
+    let typ = solve(constraints, ids[name].clone());
+    let mut free = HashSet::new();
+    variables(&typ, &mut free);
+    if free.is_empty() {
+        return typ;
+    }
+    for (other, other_typ) in ids {
+        if other != name {
+            let mut shared = HashSet::new();
+            variables(&solve(constraints, other_typ.clone()), &mut shared);
+            free.retain(|var| !shared.contains(var));
+        }
+    }
+    let renamed: HashMap<String, Type> = free
+        .into_iter()
+        .map(|var| (var.to_string(), declared_variable(&var)))
+        .collect();
+    let mut typ = typ;
+    substitute_in_type(&renamed, &mut typ);
+    typ
+}
+
+// Whether a variable was named by the host that declared a type, such as
+// the a in the type of receive, rather than numbered by inference.
+fn is_declared_variable(name: &str) -> bool {
+    name.strip_prefix('t')
+        .is_none_or(|n| n.parse::<u64>().is_err())
+}
+
+fn has_declared_variables(typ: &Type) -> bool {
+    match typ {
+        Type::Channel(element) => has_declared_variables(element),
+        Type::Function(param, body) => {
+            has_declared_variables(param) || has_declared_variables(body)
+        }
+        Type::Polymorphic(name) => is_declared_variable(name),
+        Type::Record(fields) => fields.iter().any(|field| has_declared_variables(&field.1)),
+        Type::Repeated(element) => has_declared_variables(element),
+        Type::Row(fields, row) => {
+            is_declared_variable(row) || fields.iter().any(|field| has_declared_variables(&field.1))
+        }
+        Type::Tuple(elements) => elements.iter().any(has_declared_variables),
+        _ => false,
+    }
+}
+
+// Types declared by the host stand for any types their variables could be
+// replaced with, so each use of a name with one is given fresh variables in
+// their place, and two channels, say, can carry values of different types.
+fn instantiate(id: &mut u64, typ: &Type, renamed: &mut HashMap<String, Type>) -> Type {
+    match typ {
+        Type::Channel(element) => Type::Channel(Arc::new(instantiate(id, element, renamed))),
+        Type::Function(param, body) => Type::Function(
+            Arc::new(instantiate(id, param, renamed)),
+            Arc::new(instantiate(id, body, renamed)),
+        ),
+        Type::Polymorphic(name) if is_declared_variable(name) => renamed
+            .entry(name.to_string())
+            .or_insert_with(|| fresh_type(id))
+            .clone(),
+        Type::Record(fields) => Type::Record(
+            fields
+                .iter()
+                .map(|field| (field.0.to_string(), instantiate(id, &field.1, renamed)))
+                .collect(),
+        ),
+        Type::Repeated(element) => Type::Repeated(Arc::new(instantiate(id, element, renamed))),
+        Type::Row(fields, row) => {
+            let row = match instantiate(id, &Type::Polymorphic(row.to_string()), renamed) {
+                Type::Polymorphic(row) => row,
+                _ => row.to_string(),
+            };
+            Type::Row(
+                fields
+                    .iter()
+                    .map(|field| (field.0.to_string(), instantiate(id, &field.1, renamed)))
+                    .collect(),
+                row,
+            )
+        }
+        Type::Tuple(elements) => Type::Tuple(
+            elements
+                .iter()
+                .map(|element| instantiate(id, element, renamed))
+                .collect(),
+        ),
+        _ => typ.clone(),
+    }
+}
+
+fn has_repeated(typ: &Type) -> bool {
+    match typ {
+        Type::Channel(element) => has_repeated(element),
+        Type::Function(param, body) => has_repeated(param) || has_repeated(body),
+        Type::Record(fields) | Type::Row(fields, _) => {
+            fields.iter().any(|field| has_repeated(&field.1))
+        }
+        Type::Repeated(_) => true,
+        Type::Tuple(elements) => elements.iter().any(has_repeated),
+        _ => false,
+    }
+}
+
+// The length of the tuple an argument gives in place of a repeated type in a
+// parameter, if it is known.
+fn repeated_length(param: &Type, arg: &Type) -> Option<usize> {
+    match (param, arg) {
+        (Type::Repeated(_), Type::Tuple(elements)) => Some(elements.len()),
+        (Type::Tuple(params), Type::Tuple(args)) if params.len() == args.len() => params
+            .iter()
+            .zip(args.iter())
+            .find_map(|(param, arg)| repeated_length(param, arg)),
+        _ => None,
+    }
+}
+
+// typ with each repeated type in it made a tuple of len elements.
+fn repeat(typ: &Type, len: usize) -> Type {
+    match typ {
+        Type::Channel(element) => Type::Channel(Arc::new(repeat(element, len))),
+        Type::Function(param, body) => {
+            Type::Function(Arc::new(repeat(param, len)), Arc::new(repeat(body, len)))
+        }
+        Type::Record(fields) => Type::Record(
+            fields
+                .iter()
+                .map(|field| (field.0.to_string(), repeat(&field.1, len)))
+                .collect(),
+        ),
+        Type::Repeated(element) => Type::Tuple(vec![repeat(element, len); len].into()),
+        Type::Row(fields, row) => Type::Row(
+            fields
+                .iter()
+                .map(|field| (field.0.to_string(), repeat(&field.1, len)))
+                .collect(),
+            row.to_string(),
+        ),
+        Type::Tuple(elements) => Type::Tuple(
+            elements
+                .iter()
+                .map(|element| repeat(element, len))
+                .collect(),
+        ),
+        _ => typ.clone(),
+    }
+}
+
+// The type of a record with the fields, each of which must have a different
+// name.
+fn record_type(
+    mut fields: Vec<(String, Type)>,
+    span: parser::Span,
+) -> Result<Type, InterpreterError> {
+    fields.sort_by(|x, y| x.0.cmp(&y.0));
+    if let Some(pair) = fields.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(InterpreterError::new(
+            Code::DuplicateField,
+            &format!(
+                "Type error: {} is given more than once in the record.",
+                pair[0].0
+            ),
+            span,
+        ));
+    }
+    Ok(Type::Record(fields.into()))
+}
+
+fn is_datatype(
+    name: &str,
+    ids: &HashMap<String, Type>,
+    datatypes: &HashMap<String, HashSet<String>>,
+) -> bool {
+    datatypes.contains_key(name)
+        || ids.values().any(|typ| match typ {
+            Type::Datatype(s) => s == name,
+            Type::Function(_, body) => matches!(&**body, Type::Datatype(s) if s == name),
+            _ => false,
+        })
+}
+
+fn annotation_type(
+    id: &mut u64,
+    ids: &HashMap<String, Type>,
+    datatypes: &HashMap<String, HashSet<String>>,
+    variables: &mut HashMap<String, Type>,
+    annotation: &parser::TypeAnnotation,
+    span: parser::Span,
+) -> Result<Type, InterpreterError> {
+    match annotation {
+        parser::TypeAnnotation::Function(param, body) => Ok(Type::Function(
+            Arc::new(annotation_type(id, ids, datatypes, variables, param, span)?),
+            Arc::new(annotation_type(id, ids, datatypes, variables, body, span)?),
+        )),
+        parser::TypeAnnotation::Name(name) => match name.as_str() {
+            "any" => Ok(Type::Any),
+            "boolean" => Ok(Type::Boolean),
+            "char" => Ok(Type::Char),
+            "integer" => Ok(Type::Integer),
+            "never" => Ok(Type::Never),
+            "string" => Ok(Type::String),
+            "unit" => Ok(Type::Unit),
+            _ => {
+                if is_datatype(name, ids, datatypes) {
+                    Ok(Type::Datatype(name.to_string()))
+                } else {
+                    let mut err = "Unknown type: ".to_string();
+                    err.push_str(name);
+                    err.push('.');
+                    Err(InterpreterError::new(Code::UnknownType, &err, span))
+                }
+            }
+        },
+        parser::TypeAnnotation::Record(fields) => {
+            let mut types = Vec::new();
+            for (name, field) in fields {
+                let typ = annotation_type(id, ids, datatypes, variables, field, span)?;
+                types.push((name.to_string(), typ));
+            }
+            record_type(types, span)
+        }
+        parser::TypeAnnotation::Tuple(elements) => {
+            let mut types = Vec::new();
+            for element in elements {
+                types.push(annotation_type(
+                    id, ids, datatypes, variables, element, span,
+                )?);
+            }
+            Ok(Type::Tuple(types.into()))
+        }
+        // Which member a value belongs to is found out at runtime, so each
+        // must be a type that values can be checked to have.
+        parser::TypeAnnotation::Union(members) => {
+            let mut types = Vec::new();
+            for member in members {
+                let typ = annotation_type(id, ids, datatypes, variables, member, span)?;
+                if matches!(typ, Type::Any | Type::Never)
+                    || !is_concrete(&typ)
+                    || !is_checkable(&typ)
+                {
+                    return Err(InterpreterError::new(
+                        Code::TypeMismatch,
+                        &format!("Type error: {} can't be a member of a union.", member),
+                        span,
+                    ));
+                }
+                types.push(typ);
+            }
+            Ok(union(types))
+        }
+        parser::TypeAnnotation::Variable(name) => {
+            if let Some(typ) = variables.get(name) {
+                Ok(typ.clone())
+            } else {
+                let typ = fresh_type(id);
+                variables.insert(name.to_string(), typ.clone());
+                Ok(typ)
+            }
+        }
+    }
+}
+
 fn build_param_constraints(
     id: &mut u64,
-    constraints: &mut Vec<(Type, Type, usize, usize)>,
     ids: &mut HashMap<String, Type>,
     ast: &parser::AST,
     insert_into_ids: bool,
 ) -> Result<TypedAST, InterpreterError> {
     match ast {
-        parser::AST::Identifier(s, _, _) => match ids.get(s) {
-            Some(typ) => {
-                let typ = typ.clone();
-                if insert_into_ids {
-                    ids.insert(s.clone(), typ.clone());
-                }
-                Ok(TypedAST::Identifier(typ, s.clone()))
-            }
-            None => {
-                let typ = fresh_type(id);
-                if insert_into_ids {
-                    ids.insert(s.clone(), typ.clone());
-                }
-                Ok(TypedAST::Identifier(typ, s.clone()))
+        // A parameter stands for whatever it is given, even when it has the
+        // name of something else in scope.
+        parser::AST::Identifier(s, span) => {
+            let typ = fresh_type(id);
+            if insert_into_ids {
+                ids.insert(s.clone(), typ.clone());
             }
-        },
-        parser::AST::Tuple(elements, _, _) => {
+            Ok(TypedAST::Identifier(typ, s.clone(), *span))
+        }
+        parser::AST::Tuple(elements, span) => {
             let mut types = Vec::new();
             let mut typed_elements = Vec::new();
             for element in elements {
-                let typed_element =
-                    build_param_constraints(id, constraints, ids, &element, insert_into_ids)?;
+                let typed_element = build_param_constraints(id, ids, element, insert_into_ids)?;
                 types.push(type_of(&typed_element));
                 typed_elements.push(typed_element);
             }
-            Ok(TypedAST::Tuple(Type::Tuple(types), typed_elements))
+            Ok(TypedAST::Tuple(
+                Type::Tuple(types.into()),
+                typed_elements,
+                *span,
+            ))
         }
-        parser::AST::Unit(_, _) => Ok(TypedAST::Unit),
-        parser::AST::BinaryOp(_, _, _, line, col)
-        | parser::AST::Boolean(_, line, col)
-        | parser::AST::Call(_, _, line, col)
-        | parser::AST::Datatype(_, _, line, col)
-        | parser::AST::Define(_, _, line, col)
-        | parser::AST::Function(_, _, _, line, col)
-        | parser::AST::If(_, _, line, col)
-        | parser::AST::Integer(_, line, col)
-        | parser::AST::Match(_, _, line, col)
-        | parser::AST::Program(_, line, col)
-        | parser::AST::UnaryOp(_, _, line, col) => Err(InterpreterError {
-            err: "Type error: lambda parameter must be identifier or tuple of identifiers."
-                .to_string(),
-            line: *line,
-            col: *col,
-        }),
+        parser::AST::Unit(span) => Ok(TypedAST::Unit(*span)),
+        parser::AST::Ascription(_, _, span)
+        | parser::AST::BinaryOp(_, _, _, span)
+        | parser::AST::Boolean(_, span)
+        | parser::AST::Char(_, span)
+        | parser::AST::Call(_, _, span)
+        | parser::AST::Datatype(_, _, span)
+        | parser::AST::Define(_, _, span)
+        | parser::AST::Field(_, _, span)
+        | parser::AST::For(_, _, _, span)
+        | parser::AST::Function(_, _, _, span)
+        | parser::AST::If(_, _, span)
+        | parser::AST::Index(_, _, span)
+        | parser::AST::Integer(_, span)
+        | parser::AST::Match(_, _, span)
+        | parser::AST::Program(_, span)
+        | parser::AST::Record(_, span)
+        | parser::AST::Slice(_, _, _, span)
+        | parser::AST::Spread(_, span)
+        | parser::AST::String(_, span)
+        | parser::AST::UnaryOp(_, _, span) => Err(InterpreterError::new(
+            Code::InvalidParameter,
+            "Type error: lambda parameter must be identifier or tuple of identifiers.",
+            *span,
+        )),
     }
 }
 
-fn build_constraints(
+// Variant parameters that name a type, such as integer or a datatype, even the
+// one being declared, have that type. Other names stand for any type.
+fn build_variant_constraints(
     id: &mut u64,
-    constraints: &mut Vec<(Type, Type, usize, usize)>,
-    mut ids: &mut HashMap<String, Type>,
-    datatypes: &mut HashMap<String, HashSet<String>>,
+    ids: &mut HashMap<String, Type>,
+    datatypes: &HashMap<String, HashSet<String>>,
     ast: &parser::AST,
 ) -> Result<TypedAST, InterpreterError> {
     match ast {
-        parser::AST::BinaryOp(op, lhs, rhs, line, col) => {
-            let typed_lhs = build_constraints(id, constraints, ids, datatypes, &lhs)?;
-            let typed_rhs = build_constraints(id, constraints, ids, datatypes, &rhs)?;
+        parser::AST::Identifier(name, span) => {
+            let annotation = parser::TypeAnnotation::Name(name.to_string());
+            let typ = annotation_type(id, ids, datatypes, &mut HashMap::new(), &annotation, *span)
+                .unwrap_or_else(|_| declared_variable(&fresh_type(id).to_string()));
+            Ok(TypedAST::Identifier(typ, name.to_string(), *span))
+        }
+        parser::AST::Tuple(elements, span) => {
+            let mut types = Vec::new();
+            let mut typed_elements = Vec::new();
+            for element in elements {
+                let typed_element = build_variant_constraints(id, ids, datatypes, element)?;
+                types.push(type_of(&typed_element));
+                typed_elements.push(typed_element);
+            }
+            Ok(TypedAST::Tuple(
+                Type::Tuple(types.into()),
+                typed_elements,
+                *span,
+            ))
+        }
+        _ => build_param_constraints(id, ids, ast, false),
+    }
+}
 
-            let typ = fresh_type(id);
-            match op {
-                parser::Operator::And | parser::Operator::Or => {
-                    constraints.push((Type::Boolean, type_of(&typed_lhs), *line, *col));
-                    constraints.push((Type::Boolean, type_of(&typed_rhs), *line, *col));
-                    constraints.push((typ.clone(), Type::Boolean, *line, *col));
-                }
-                parser::Operator::Divide
-                | parser::Operator::Mod
-                | parser::Operator::Multiply
-                | parser::Operator::Minus
-                | parser::Operator::Plus => {
-                    constraints.push((Type::Integer, type_of(&typed_lhs), *line, *col));
-                    constraints.push((Type::Integer, type_of(&typed_rhs), *line, *col));
-                    constraints.push((typ.clone(), Type::Integer, *line, *col));
-                }
-                parser::Operator::Greater
-                | parser::Operator::GreaterEqual
-                | parser::Operator::Less
-                | parser::Operator::LessEqual => {
-                    constraints.push((Type::Integer, type_of(&typed_lhs), *line, *col));
-                    constraints.push((Type::Integer, type_of(&typed_rhs), *line, *col));
-                    constraints.push((typ.clone(), Type::Boolean, *line, *col));
-                }
-                parser::Operator::Equal | parser::Operator::NotEqual => {
-                    constraints.push((type_of(&typed_lhs), type_of(&typed_rhs), *line, *col));
-                    constraints.push((typ.clone(), Type::Boolean, *line, *col));
+// The type a pattern for the value of a variant is checked against, where
+// the parts of the value without a declared type can be anything.
+fn declared_type(id: &mut u64, typ: &Type) -> Type {
+    match typ {
+        Type::Tuple(elements) => Type::Tuple(
+            elements
+                .iter()
+                .map(|element| declared_type(id, element))
+                .collect(),
+        ),
+        typ if is_concrete(typ) => typ.clone(),
+        _ => fresh_type(id),
+    }
+}
+
+// Checks that a pattern binds each of its names only once.
+fn unique_bindings(
+    pattern: TypedPattern,
+    span: parser::Span,
+) -> Result<TypedPattern, InterpreterError> {
+    let found = bindings(&pattern);
+    if let Some(pair) = found.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(InterpreterError::new(
+            Code::InvalidPattern,
+            &format!(
+                "Type error: {} is bound more than once in the pattern.",
+                pair[0].0
+            ),
+            span,
+        ));
+    }
+    Ok(pattern)
+}
+
+// Constrains a pattern to match values of type typ. The names of variants
+// are looked up in ids, and errors are reported at span, that of the match.
+fn build_pattern(
+    id: &mut u64,
+    constraints: &mut Constraints,
+    ids: &HashMap<String, Type>,
+    pattern: &parser::Pattern,
+    typ: &Type,
+    span: parser::Span,
+) -> Result<TypedPattern, InterpreterError> {
+    match pattern {
+        parser::Pattern::As(pattern, name, pattern_span) => {
+            let typed_pattern = build_pattern(id, constraints, ids, pattern, typ, span)?;
+            unique_bindings(
+                TypedPattern::As(
+                    typ.clone(),
+                    Box::new(typed_pattern),
+                    name.to_string(),
+                    *pattern_span,
+                ),
+                span,
+            )
+        }
+        // Capitalized names of variants without a value match the variant,
+        // as they would if written at the top of an arm.
+        parser::Pattern::Identifier(name, pattern_span)
+            if name.starts_with(|c: char| c.is_ascii_uppercase())
+                && matches!(ids.get(name), Some(Type::Datatype(_))) =>
+        {
+            let variant = parser::Pattern::Variant(name.to_string(), None, *pattern_span);
+            build_pattern(id, constraints, ids, &variant, typ, span)
+        }
+        parser::Pattern::Identifier(name, pattern_span) => Ok(TypedPattern::Identifier(
+            typ.clone(),
+            name.to_string(),
+            *pattern_span,
+        )),
+        parser::Pattern::Literal(literal, pattern_span) => {
+            let (typed_literal, literal_type) = match &**literal {
+                parser::AST::Boolean(b, span) => (TypedAST::Boolean(*b, *span), Type::Boolean),
+                parser::AST::Char(c, span) => (TypedAST::Char(*c, *span), Type::Char),
+                parser::AST::Integer(i, span) => (TypedAST::Integer(*i, *span), Type::Integer),
+                parser::AST::String(s, span) => {
+                    (TypedAST::String(s.to_string(), *span), Type::String)
                 }
                 _ => unreachable!(),
+            };
+            constraints.push((typ.clone(), literal_type, span));
+            Ok(TypedPattern::Literal(
+                Box::new(typed_literal),
+                *pattern_span,
+            ))
+        }
+        parser::Pattern::Or(alternatives, pattern_span) => {
+            let mut typed_alternatives = Vec::new();
+            for alternative in alternatives {
+                typed_alternatives.push(build_pattern(
+                    id,
+                    constraints,
+                    ids,
+                    alternative,
+                    typ,
+                    span,
+                )?);
             }
-
-            Ok(TypedAST::BinaryOp(
-                typ,
-                op.clone(),
-                Box::new(typed_lhs),
-                Box::new(typed_rhs),
-                *line,
-                *col,
+            let first = bindings(&typed_alternatives[0]);
+            for alternative in &typed_alternatives[1..] {
+                let other = bindings(alternative);
+                let missing = first
+                    .iter()
+                    .find(|binding| !other.iter().any(|found| found.0 == binding.0))
+                    .or_else(|| {
+                        other
+                            .iter()
+                            .find(|binding| !first.iter().any(|found| found.0 == binding.0))
+                    });
+                if let Some((name, _, _)) = missing {
+                    return Err(InterpreterError::new(
+                        Code::InvalidPattern,
+                        &format!(
+                            "Type error: {} is not bound by every alternative of the pattern.",
+                            name
+                        ),
+                        span,
+                    ));
+                }
+                for (found, binding) in first.iter().zip(other.iter()) {
+                    constraints.push((found.1.clone(), binding.1.clone(), span));
+                }
+            }
+            Ok(TypedPattern::Or(typed_alternatives, *pattern_span))
+        }
+        parser::Pattern::Tuple(elements, pattern_span) => {
+            let types: Vec<Type> = elements.iter().map(|_| fresh_type(id)).collect();
+            constraints.push((typ.clone(), Type::Tuple(types.clone().into()), span));
+            let mut typed_elements = Vec::new();
+            for (element, typ) in elements.iter().zip(types.iter()) {
+                typed_elements.push(build_pattern(id, constraints, ids, element, typ, span)?);
+            }
+            unique_bindings(TypedPattern::Tuple(typed_elements, *pattern_span), span)
+        }
+        parser::Pattern::Variant(name, param, pattern_span) => {
+            let found = solve(constraints, typ.clone());
+            // Members of a union are named by their type, and the pattern
+            // for their value matches the value itself.
+            if let Type::Union(members) = &found {
+                let member = match members.iter().find(|member| member.to_string() == *name) {
+                    Some(member) => member,
+                    None => {
+                        return Err(InterpreterError::new(
+                            Code::TypeMismatch,
+                            &format!("Type error: {} is not a member of {}.", name, found),
+                            span,
+                        )
+                        .with_note(
+                            "Every arm of a match on a union must name one of its members.",
+                        ));
+                    }
+                };
+                let typed_param = match param {
+                    Some(param) => Some(Box::new(build_pattern(
+                        id,
+                        constraints,
+                        ids,
+                        param,
+                        member,
+                        span,
+                    )?)),
+                    None => None,
+                };
+                return Ok(TypedPattern::Member(
+                    member.clone(),
+                    typed_param,
+                    *pattern_span,
+                ));
+            }
+            match found {
+                Type::Datatype(_) | Type::Polymorphic(_) => {}
+                _ => {
+                    return Err(InterpreterError::new(
+                        Code::ExpectedDatatype,
+                        "Match statement: expected datatype.",
+                        span,
+                    ));
+                }
+            }
+            let (variant_param, datatype) = match ids.get(name) {
+                Some(Type::Function(param, body)) if matches!(**body, Type::Datatype(_)) => {
+                    (Some(declared_type(id, param)), (**body).clone())
+                }
+                Some(datatype @ Type::Datatype(_)) => (None, datatype.clone()),
+                _ => {
+                    let mut err = "Unknown variant in match: ".to_string();
+                    err.push_str(name);
+                    err.push('.');
+                    return Err(InterpreterError::new(Code::UnknownVariant, &err, span));
+                }
+            };
+            if let Type::Datatype(_) = found {
+                if found != datatype {
+                    let mut err = "Type error: expected ".to_string();
+                    err.push_str(&found.to_string());
+                    err.push_str(" but found ");
+                    err.push_str(&datatype.to_string());
+                    err.push('.');
+                    return Err(
+                        InterpreterError::new(Code::TypeMismatch, &err, span).with_note(
+                            "Every arm of a match must be a variant of the same datatype.",
+                        ),
+                    );
+                }
+            }
+            constraints.push((typ.clone(), datatype, span));
+            let typed_param = match param {
+                Some(param) => {
+                    let variant_param = variant_param.unwrap_or_else(|| fresh_type(id));
+                    Some(Box::new(build_pattern(
+                        id,
+                        constraints,
+                        ids,
+                        param,
+                        &variant_param,
+                        span,
+                    )?))
+                }
+                None => None,
+            };
+            Ok(TypedPattern::Variant(
+                name.to_string(),
+                typed_param,
+                *pattern_span,
             ))
         }
-        parser::AST::Boolean(b, _, _) => Ok(TypedAST::Boolean(*b)),
-        parser::AST::Call(fun, arg, line, col) => {
-            let typed_fun = build_constraints(id, constraints, &mut ids, datatypes, &fun)?;
-            let typed_arg = build_constraints(id, constraints, &mut ids, datatypes, &arg)?;
+        parser::Pattern::Wildcard(pattern_span) => Ok(TypedPattern::Wildcard(*pattern_span)),
+    }
+}
+
+// Types a binary operation whose left operand has been typed already.
+fn binary_constraints(
+    id: &mut u64,
+    constraints: &mut Constraints,
+    ids: &mut HashMap<String, Type>,
+    datatypes: &mut HashMap<String, HashSet<String>>,
+    typed_lhs: TypedAST,
+    ast: &parser::AST,
+) -> Result<TypedAST, InterpreterError> {
+    let (op, rhs, span) = match ast {
+        parser::AST::BinaryOp(op, _, rhs, span) => (op, rhs, *span),
+        _ => unreachable!(),
+    };
+    let typed_rhs = build_constraints(id, constraints, ids, datatypes, rhs)?;
+
+    let typ = fresh_type(id);
+    match op {
+        parser::Operator::And | parser::Operator::Or => {
+            constraints.push((Type::Boolean, type_of(&typed_lhs), span));
+            constraints.push((Type::Boolean, type_of(&typed_rhs), span));
+            constraints.push((typ.clone(), Type::Boolean, span));
+        }
+        parser::Operator::Divide
+        | parser::Operator::Mod
+        | parser::Operator::Multiply
+        | parser::Operator::Minus
+        | parser::Operator::Plus => {
+            constraints.push((Type::Integer, type_of(&typed_lhs), span));
+            constraints.push((Type::Integer, type_of(&typed_rhs), span));
+            constraints.push((typ.clone(), Type::Integer, span));
+        }
+        parser::Operator::Greater
+        | parser::Operator::GreaterEqual
+        | parser::Operator::Less
+        | parser::Operator::LessEqual => {
+            let operand = ordered_type(
+                &solve(constraints, type_of(&typed_lhs)),
+                &solve(constraints, type_of(&typed_rhs)),
+            );
+            constraints.push((operand.clone(), type_of(&typed_lhs), span));
+            constraints.push((operand, type_of(&typed_rhs), span));
+            constraints.push((typ.clone(), Type::Boolean, span));
+        }
+        parser::Operator::Equal | parser::Operator::NotEqual => {
+            constraints.push((type_of(&typed_lhs), type_of(&typed_rhs), span));
+            constraints.push((typ.clone(), Type::Boolean, span));
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(TypedAST::BinaryOp(
+        typ,
+        op.clone(),
+        Box::new(typed_lhs),
+        Box::new(typed_rhs),
+        span,
+    ))
+}
+
+fn build_constraints(
+    id: &mut u64,
+    constraints: &mut Constraints,
+    ids: &mut HashMap<String, Type>,
+    datatypes: &mut HashMap<String, HashSet<String>>,
+    ast: &parser::AST,
+) -> Result<TypedAST, InterpreterError> {
+    match ast {
+        parser::AST::Ascription(expr, annotation, span) => {
+            let mut variables = HashMap::new();
+            let typ = annotation_type(id, ids, datatypes, &mut variables, annotation, *span)?;
+            let typed_expr = match (&typ, ascribe_branches(expr, annotation)) {
+                (Type::Union(_), Some(expr)) => {
+                    build_constraints(id, constraints, ids, datatypes, &expr)?
+                }
+                _ => build_constraints(id, constraints, ids, datatypes, expr)?,
+            };
+            if is_dynamic(&typ) || is_dynamic(&solve(constraints, type_of(&typed_expr))) {
+                let found = solve(constraints, type_of(&typed_expr));
+                cast(constraints, &found, &typ, *span)?;
+                Ok(TypedAST::Cast(typ, Box::new(typed_expr), *span))
+            } else {
+                // Never fits where other types are expected, but an
+                // expression that produces a value can't be made to fit it.
+                if let Type::Never = typ {
+                    let found = solve(constraints, type_of(&typed_expr));
+                    if !matches!(found, Type::Never | Type::Polymorphic(_)) {
+                        return Err(InterpreterError::new(
+                            Code::TypeMismatch,
+                            &format!("Type error: expected never but found {}.", found),
+                            *span,
+                        ));
+                    }
+                }
+                constraints.push((typ, type_of(&typed_expr), *span));
+                Ok(typed_expr)
+            }
+        }
+        // Chains of operators such as 1 + 2 + 3 nest to the left as deeply
+        // as they are long, so they are followed without recursing.
+        parser::AST::BinaryOp(_, _, _, _) => {
+            let mut chain = Vec::new();
+            let mut lhs = ast;
+            while let parser::AST::BinaryOp(_, inner, _, _) = lhs {
+                chain.push(lhs);
+                lhs = inner;
+            }
+            let mut typed = build_constraints(id, constraints, ids, datatypes, lhs)?;
+            for ast in chain.into_iter().rev() {
+                typed = binary_constraints(id, constraints, ids, datatypes, typed, ast)?;
+            }
+            Ok(typed)
+        }
+        parser::AST::Boolean(b, span) => Ok(TypedAST::Boolean(*b, *span)),
+        parser::AST::Char(c, span) => Ok(TypedAST::Char(*c, *span)),
+        parser::AST::Call(fun, arg, span) => {
+            let typed_fun = build_constraints(id, constraints, ids, datatypes, fun)?;
+            let typed_arg = build_constraints(id, constraints, ids, datatypes, arg)?;
+
+            // A name whose type is still being inferred, such as a parameter,
+            // must be a function taking the argument, and every call to it
+            // must agree on the types.
+            let typed_fun = match typed_fun {
+                // Functions taking tuples of any length, such as pmap, are
+                // typed for the length of the one they are given.
+                TypedAST::Identifier(Type::Function(param, body), name, fun_span)
+                    if has_repeated(&param) =>
+                {
+                    let arg = solve(constraints, type_of(&typed_arg));
+                    let len = match repeated_length(&param, &arg) {
+                        Some(len) => len,
+                        None => {
+                            return Err(InterpreterError::new(
+                                Code::ExpectedTuple,
+                                &format!("Type error: {} takes a tuple of known length.", name),
+                                *span,
+                            ))
+                        }
+                    };
+                    let fun_typ = repeat(&Type::Function(param, body), len);
+                    TypedAST::Identifier(fun_typ, name, fun_span)
+                }
+                TypedAST::Identifier(typ @ Type::Polymorphic(_), name, fun_span) => {
+                    let fun_typ =
+                        Type::Function(Arc::new(type_of(&typed_arg)), Arc::new(fresh_type(id)));
+                    constraints.push((typ, fun_typ.clone(), *span));
+                    TypedAST::Identifier(fun_typ, name, fun_span)
+                }
+                typed_fun => typed_fun,
+            };
 
             match &typed_fun {
-                TypedAST::Call(fun, _) => {
-                    if let TypedAST::Function(_, _, body) = &**fun {
-                        constraints.push((type_of(&body), type_of(&typed_arg), *line, *col));
+                TypedAST::Call(fun, _, _) => {
+                    if let TypedAST::Function(_, _, body, _) = &**fun {
+                        constraints.push((type_of(body), type_of(&typed_arg), *span));
                     }
                 }
-                TypedAST::Function(_, params, _) => {
-                    constraints.push((type_of(&params), type_of(&typed_arg), *line, *col));
+                TypedAST::Function(_, params, _, _) => {
+                    constraints.push((type_of(params), type_of(&typed_arg), *span));
+                }
+                TypedAST::Identifier(Type::Function(param, _), _, _) => {
+                    constraints.push(((**param).clone(), type_of(&typed_arg), *span));
                 }
-                TypedAST::Identifier(Type::Function(_, _), _) => {}
                 _ => {
-                    return Err(InterpreterError {
-                        err: "Type error: attempt to call non-lambda value.".to_string(),
-                        line: *line,
-                        col: *col,
-                    });
+                    return Err(InterpreterError::new(
+                        Code::NotCallable,
+                        "Type error: attempt to call non-lambda value.",
+                        *span,
+                    ));
                 }
             }
 
-            Ok(TypedAST::Call(Box::new(typed_fun), Box::new(typed_arg)))
+            Ok(TypedAST::Call(
+                Box::new(typed_fun),
+                Box::new(typed_arg),
+                *span,
+            ))
         }
-        parser::AST::Datatype(typ, variants, _, _) => {
-            let mut all_variants = HashSet::new();
+        parser::AST::Datatype(typ, variants, span) => {
+            // Added first, so that variants can refer to the datatype itself.
+            datatypes.insert(
+                typ.to_string(),
+                variants
+                    .iter()
+                    .map(|variant| variant.0.to_string())
+                    .collect(),
+            );
             let mut typed_variants = Vec::new();
             for variant in variants {
-                all_variants.insert(variant.0.to_string());
                 match &variant.1 {
                     Some(param) => {
                         // Type for constructor function
-                        let typed_param =
-                            build_param_constraints(id, constraints, &mut ids, &param, false)?;
+                        let typed_param = build_variant_constraints(id, ids, datatypes, param)?;
                         let typ = Type::Function(
-                            Box::new(type_of(&typed_param)),
-                            Box::new(Type::Datatype(typ.to_string())),
+                            Arc::new(type_of(&typed_param)),
+                            Arc::new(Type::Datatype(typ.to_string())),
                         );
                         ids.insert(variant.0.to_string(), typ.clone());
                         typed_variants.push((variant.0.to_string(), typ));
@@ -318,218 +1367,222 @@ fn build_constraints(
                     }
                 }
             }
-            datatypes.insert(typ.to_string(), all_variants);
             Ok(TypedAST::Datatype(
                 Type::Datatype(typ.to_string()),
                 typed_variants,
+                *span,
             ))
         }
-        parser::AST::Define(ident, value, line, col) => {
-            if let parser::AST::Identifier(ident, _, _) = &**ident {
-                let typed_value = build_constraints(id, constraints, ids, datatypes, &value)?;
+        parser::AST::Define(ident, value, span) => {
+            if let parser::AST::Identifier(ident, _) = &**ident {
+                let typed_value = build_constraints(id, constraints, ids, datatypes, value)?;
                 ids.insert(ident.to_string(), type_of(&typed_value));
                 Ok(TypedAST::Define(
                     type_of(&typed_value),
                     ident.clone(),
                     Box::new(typed_value),
+                    *span,
                 ))
             } else {
-                Err(InterpreterError {
-                    err: "Type error: expected identifier.".to_string(),
-                    line: *line,
-                    col: *col,
-                })
+                Err(InterpreterError::new(
+                    Code::ExpectedIdentifier,
+                    "Type error: expected identifier.",
+                    *span,
+                ))
             }
         }
-        parser::AST::Function(ident, param, body, line, col) => {
+        // A value whose type isn't known yet, such as a parameter, is given a
+        // row with the field, so that a function can take any record that
+        // has the fields it uses. Fields used later join the row.
+        parser::AST::Field(value, field, span) => {
+            let typed_value = build_constraints(id, constraints, ids, datatypes, value)?;
+            let found = solve(constraints, type_of(&typed_value));
+            let mut extend = |rest: Type| {
+                let typ = fresh_type(id);
+                let row = fresh_type(id).to_string();
+                let fields = Arc::new([(field.to_string(), typ.clone())]);
+                constraints.push((Type::Row(fields, row), rest, *span));
+                Some(typ)
+            };
+            let typ = match &found {
+                Type::Record(fields) => fields
+                    .iter()
+                    .find(|(name, _)| name == field)
+                    .map(|(_, typ)| typ.clone()),
+                Type::Row(fields, row) => match fields.iter().find(|(name, _)| name == field) {
+                    Some((_, typ)) => Some(typ.clone()),
+                    None => extend(Type::Polymorphic(row.to_string())),
+                },
+                Type::Polymorphic(_) => extend(found.clone()),
+                _ => None,
+            };
+            match typ {
+                Some(typ) => Ok(TypedAST::Field(
+                    typ,
+                    Box::new(typed_value),
+                    field.to_string(),
+                    *span,
+                )),
+                None => Err(InterpreterError::new(
+                    Code::UnknownField,
+                    &format!(
+                        "Type error: expected a record with field {} but found {}.",
+                        field, found
+                    ),
+                    *span,
+                )),
+            }
+        }
+        parser::AST::For(pattern, iter, body, span) => {
+            let typed_iter = build_constraints(id, constraints, ids, datatypes, iter)?;
+            let mut local_ids = ids.clone();
+            let typed_pattern = build_param_constraints(id, &mut local_ids, pattern, true)?;
+            // An iterator is a state, a function telling whether any elements
+            // remain and one returning the next element and the new state.
+            let state = Arc::new(fresh_type(id));
+            let next = Type::Tuple(Arc::new([type_of(&typed_pattern), (*state).clone()]));
+            let iterator = Type::Tuple(Arc::new([
+                (*state).clone(),
+                Type::Function(state.clone(), Arc::new(Type::Boolean)),
+                Type::Function(state, Arc::new(next)),
+            ]));
+            let typed_body = build_constraints(id, constraints, &mut local_ids, datatypes, body)?;
+            constraints.push((iterator, type_of(&typed_iter), *span));
+            Ok(TypedAST::For(
+                Box::new(typed_pattern),
+                Box::new(typed_iter),
+                Box::new(typed_body),
+                *span,
+            ))
+        }
+        parser::AST::Function(ident, param, body, span) => {
             let mut local_ids = ids.clone();
-            let typed_param =
-                build_param_constraints(id, constraints, &mut local_ids, &param, true)?;
+            let typed_param = build_param_constraints(id, &mut local_ids, param, true)?;
             let typed_body;
             if let Some(ident) = ident {
                 let typ = fresh_type(id);
                 ids.insert(
                     ident.to_string(),
-                    Type::Function(Box::new(type_of(&typed_param)), Box::new(typ.clone())),
+                    Type::Function(Arc::new(type_of(&typed_param)), Arc::new(typ.clone())),
                 );
                 local_ids.insert(
                     ident.to_string(),
-                    Type::Function(Box::new(type_of(&typed_param)), Box::new(typ.clone())),
+                    Type::Function(Arc::new(type_of(&typed_param)), Arc::new(typ.clone())),
                 );
-                typed_body = build_constraints(id, constraints, &mut local_ids, datatypes, &body)?;
-                constraints.push((typ, type_of(&typed_body), *line, *col));
+                typed_body = build_constraints(id, constraints, &mut local_ids, datatypes, body)?;
+                constraints.push((typ, type_of(&typed_body), *span));
+                let typ = generalize(constraints, ids, ident);
+                ids.insert(ident.to_string(), typ);
             } else {
-                typed_body = build_constraints(id, constraints, &mut local_ids, datatypes, &body)?;
+                typed_body = build_constraints(id, constraints, &mut local_ids, datatypes, body)?;
             }
 
             Ok(TypedAST::Function(
                 ident.clone(),
                 Box::new(typed_param),
                 Box::new(typed_body),
+                *span,
             ))
         }
-        parser::AST::Identifier(s, line, col) => match ids.get(s) {
-            Some(typ) => Ok(TypedAST::Identifier(typ.clone(), s.clone())),
+        parser::AST::Identifier(s, span) => match ids.get(s) {
+            Some(typ) if has_declared_variables(typ) => Ok(TypedAST::Identifier(
+                instantiate(id, typ, &mut HashMap::new()),
+                s.clone(),
+                *span,
+            )),
+            Some(typ) => Ok(TypedAST::Identifier(typ.clone(), s.clone(), *span)),
             None => {
                 let mut err = "Unknown identifier: ".to_string();
                 err.push_str(s);
                 err.push('.');
-                Err(InterpreterError {
-                    err,
-                    line: *line,
-                    col: *col,
-                })
+                Err(InterpreterError::new(Code::UnknownIdentifier, &err, *span))
             }
         },
-        parser::AST::If(conds, els, line, col) => {
-            let mut first = true;
-            let mut inferred_type = Type::Boolean;
+        parser::AST::If(conds, els, span) => {
+            let mut types = Vec::new();
             let mut typed_conds = Vec::new();
             for cond in conds {
                 let ifpart = build_constraints(id, constraints, ids, datatypes, &cond.0)?;
                 let thenpart = build_constraints(id, constraints, ids, datatypes, &cond.1)?;
-                constraints.push((Type::Boolean, type_of(&ifpart), *line, *col));
-                if first {
-                    first = false;
-                    inferred_type = type_of(&thenpart);
-                } else {
-                    constraints.push((inferred_type.clone(), type_of(&thenpart), *line, *col));
-                }
+                constraints.push((Type::Boolean, type_of(&ifpart), *span));
+                types.push(type_of(&thenpart));
 
                 typed_conds.push((ifpart, thenpart));
             }
-            let elsepart = build_constraints(id, constraints, ids, datatypes, &els)?;
-            constraints.push((inferred_type, type_of(&elsepart), *line, *col));
-            Ok(TypedAST::If(typed_conds, Box::new(elsepart)))
+            let elsepart = build_constraints(id, constraints, ids, datatypes, els)?;
+            types.push(type_of(&elsepart));
+            join(constraints, &types, *span);
+            Ok(TypedAST::If(typed_conds, Box::new(elsepart), *span))
         }
-        parser::AST::Integer(i, _, _) => Ok(TypedAST::Integer(*i)),
-        parser::AST::Match(cond, cases, line, col) => {
-            let typed_cond = build_constraints(id, constraints, ids, datatypes, &cond)?;
-            match type_of(&typed_cond) {
-                Type::Datatype(_) | Type::Polymorphic(_) => {}
-                _ => {
-                    return Err(InterpreterError {
-                        err: "Match statement: expected datatype.".to_string(),
-                        line: *line,
-                        col: *col,
-                    });
-                }
-            }
-
-            let mut first = true;
-            let mut inferred_type = Type::Unit;
+        parser::AST::Index(value, index, span) => {
+            let typed_value = build_constraints(id, constraints, ids, datatypes, value)?;
+            let typed_index = build_constraints(id, constraints, ids, datatypes, index)?;
+            constraints.push((Type::String, type_of(&typed_value), *span));
+            constraints.push((Type::Integer, type_of(&typed_index), *span));
+            Ok(TypedAST::Index(
+                Box::new(typed_value),
+                Box::new(typed_index),
+                *span,
+            ))
+        }
+        parser::AST::Integer(i, span) => Ok(TypedAST::Integer(*i, *span)),
+        parser::AST::String(s, span) => Ok(TypedAST::String(s.to_string(), *span)),
+        parser::AST::Match(cond, cases, span) => {
+            let typed_cond = build_constraints(id, constraints, ids, datatypes, cond)?;
+            let cond_type = type_of(&typed_cond);
+            let mut types = Vec::new();
             let mut typed_cases = Vec::new();
-            let mut present_variants = HashSet::new();
-            let mut datatype = Type::Unit;
             for case in cases {
+                let pattern = build_pattern(id, constraints, ids, &case.0, &cond_type, *span)?;
                 let mut local_ids = ids.clone();
-                let typed_param = match &case.1 {
-                    Some(param) => Some(build_param_constraints(
-                        id,
-                        constraints,
-                        &mut local_ids,
-                        &param,
-                        true,
-                    )?),
-                    None => None,
-                };
-
-                let typed_case =
-                    build_constraints(id, constraints, &mut local_ids, datatypes, &case.2)?;
-                if first {
-                    inferred_type = type_of(&typed_case);
-                } else {
-                    constraints.push((inferred_type.clone(), type_of(&typed_case), *line, *col));
-                }
-
-                let variant_type;
-                match ids.get(&case.0) {
-                    Some(typ) => {
-                        present_variants.insert(case.0.to_string());
-                        let typ = match typ {
-                            Type::Function(_, body) => body,
-                            _ => typ,
-                        };
-                        variant_type = typ.clone();
-                        if first {
-                            datatype = variant_type;
-                            if let Type::Polymorphic(_) = type_of(&typed_cond) {
-                                constraints.push((
-                                    type_of(&typed_cond),
-                                    datatype.clone(),
-                                    *line,
-                                    *col,
-                                ));
-                            }
-                        } else if variant_type != datatype {
-                            let mut err = "Type error: expected ".to_string();
-                            err.push_str(&datatype.to_string());
-                            err.push_str(" but found ");
-                            err.push_str(&variant_type.to_string());
-                            err.push('.');
-                            return Err(InterpreterError {
-                                err,
-                                line: *line,
-                                col: *col,
-                            });
-                        }
-                    }
-                    None => {
-                        let mut err = "Unknown variant in match: ".to_string();
-                        err.push_str(&case.0);
-                        err.push('.');
-
-                        return Err(InterpreterError {
-                            err,
-                            line: *line,
-                            col: *col,
-                        });
-                    }
-                }
-
-                typed_cases.push((case.0.to_string(), typed_param, typed_case));
-                first = false;
-            }
-
-            if let Some(all_variants) = datatypes.get(&datatype.to_string()) {
-                let mut missing: Vec<&String> =
-                    all_variants.difference(&present_variants).collect();
-                if !missing.is_empty() {
-                    missing.sort();
-                    let mut err = "Missing variants in match of ".to_string();
-                    err.push_str(&datatype.to_string());
-                    err.push(':');
-                    for variant in missing {
-                        err.push(' ');
-                        err.push_str(variant);
-                    }
-                    err.push('.');
-                    return Err(InterpreterError {
-                        err,
-                        line: *line,
-                        col: *col,
-                    });
+                for (name, typ, _) in bindings(&pattern) {
+                    local_ids.insert(name.to_string(), typ.clone());
                 }
+                let typed_case =
+                    build_constraints(id, constraints, &mut local_ids, datatypes, &case.1)?;
+                types.push(type_of(&typed_case));
+                typed_cases.push((pattern, typed_case));
             }
+            join(constraints, &types, *span);
 
-            Ok(TypedAST::Match(Box::new(typed_cond), datatype, typed_cases))
+            Ok(TypedAST::Match(
+                Box::new(typed_cond),
+                cond_type,
+                typed_cases,
+                *span,
+            ))
         }
-        parser::AST::Program(expressions, line, col) => {
+        parser::AST::Program(expressions, span) => {
             let mut typed_expressions = Vec::new();
             for expr in expressions {
-                let typed_expr = build_constraints(id, constraints, ids, datatypes, &expr)?;
+                let typed_expr = build_constraints(id, constraints, ids, datatypes, expr)?;
                 typed_expressions.push(typed_expr);
             }
             match typed_expressions.last() {
                 Some(expr) => {
                     let typ = fresh_type(id);
-                    constraints.push((typ, type_of(expr), *line, *col));
-                    Ok(TypedAST::Program(type_of(expr), typed_expressions))
+                    constraints.push((typ, type_of(expr), *span));
+                    Ok(TypedAST::Program(type_of(expr), typed_expressions, *span))
                 }
-                None => unreachable!(),
+                // A program of nothing but comments does nothing.
+                None => Ok(TypedAST::Program(Type::Unit, typed_expressions, *span)),
             }
         }
-        parser::AST::UnaryOp(op, ast, line, col) => {
+        parser::AST::Record(fields, span) => {
+            let mut types = Vec::new();
+            let mut typed_fields = Vec::new();
+            for (name, value) in fields {
+                let typed_value = build_constraints(id, constraints, ids, datatypes, value)?;
+                types.push((name.to_string(), type_of(&typed_value)));
+                typed_fields.push((name.to_string(), typed_value));
+            }
+            Ok(TypedAST::Record(
+                record_type(types, *span)?,
+                typed_fields,
+                *span,
+            ))
+        }
+        parser::AST::UnaryOp(op, ast, span) => {
             let typed = build_constraints(id, constraints, ids, datatypes, ast)?;
             let typ = fresh_type(id);
             let op_typ = match op {
@@ -538,23 +1591,66 @@ fn build_constraints(
                 _ => unreachable!(),
             };
 
-            constraints.push((op_typ.clone(), type_of(&typed), *line, *col));
+            constraints.push((op_typ.clone(), type_of(&typed), *span));
 
-            constraints.push((typ.clone(), op_typ, *line, *col));
+            constraints.push((typ.clone(), op_typ, *span));
 
-            Ok(TypedAST::UnaryOp(typ, op.clone(), Box::new(typed)))
+            Ok(TypedAST::UnaryOp(typ, op.clone(), Box::new(typed), *span))
         }
-        parser::AST::Tuple(elements, _, _) => {
+        parser::AST::Slice(value, start, end, span) => {
+            let typed_value = build_constraints(id, constraints, ids, datatypes, value)?;
+            let typed_start = build_constraints(id, constraints, ids, datatypes, start)?;
+            let typed_end = build_constraints(id, constraints, ids, datatypes, end)?;
+            constraints.push((Type::String, type_of(&typed_value), *span));
+            constraints.push((Type::Integer, type_of(&typed_start), *span));
+            constraints.push((Type::Integer, type_of(&typed_end), *span));
+            Ok(TypedAST::Slice(
+                Box::new(typed_value),
+                Box::new(typed_start),
+                Box::new(typed_end),
+                *span,
+            ))
+        }
+        parser::AST::Spread(_, span) => Err(InterpreterError::new(
+            Code::ExpectedTuple,
+            "Type error: values can only be spread into a tuple.",
+            *span,
+        )),
+        parser::AST::Tuple(elements, span) => {
             let mut types = Vec::new();
             let mut typed_elements = Vec::new();
             for element in elements {
-                let typed_element = build_constraints(id, constraints, ids, datatypes, &element)?;
+                if let parser::AST::Spread(expr, span) = element {
+                    // The elements of a spread tuple take its place, so its
+                    // size must already be known.
+                    let typed_expr = build_constraints(id, constraints, ids, datatypes, expr)?;
+                    let typ = solve(constraints, type_of(&typed_expr));
+                    match &typ {
+                        Type::Tuple(elements) => types.extend(elements.iter().cloned()),
+                        _ => {
+                            let mut err = "Type error: expected tuple but found ".to_string();
+                            err.push_str(&typ.to_string());
+                            err.push('.');
+                            return Err(InterpreterError::new(Code::ExpectedTuple, &err, *span)
+                                .with_note(
+                                    "A tuple can only be spread once its size is known, such as from an ascription.",
+                                ));
+                        }
+                    }
+                    typed_elements.push(TypedAST::Spread(typ, Box::new(typed_expr), *span));
+                    continue;
+                }
+                let typed_element = build_constraints(id, constraints, ids, datatypes, element)?;
                 types.push(type_of(&typed_element));
                 typed_elements.push(typed_element);
             }
-            Ok(TypedAST::Tuple(Type::Tuple(types), typed_elements))
+            Ok(TypedAST::Tuple(
+                Type::Tuple(types.into()),
+                typed_elements,
+                *span,
+            ))
         }
-        parser::AST::Unit(_, _) => Ok(TypedAST::Unit),
+        parser::AST::Unit(span) => Ok(TypedAST::Unit(*span)),
     }
 }
 
@@ -565,15 +1661,42 @@ fn substitute_in_type<S: ::std::hash::BuildHasher>(
     match typ {
         Type::Polymorphic(s) => {
             if let Some(subst) = bindings.get(s) {
+                // What a variable is bound to can itself contain variables
+                // bound later, as with a tuple pattern in a match.
+                let resolved = !matches!(subst, Type::Polymorphic(t) if t == s);
                 *typ = subst.clone();
+                if resolved {
+                    substitute_in_type(bindings, typ);
+                }
             }
         }
+        Type::Channel(element) => substitute_in_type(bindings, Arc::make_mut(element)),
         Type::Function(param, body) => {
-            substitute_in_type(bindings, param);
-            substitute_in_type(bindings, body);
+            substitute_in_type(bindings, Arc::make_mut(param));
+            substitute_in_type(bindings, Arc::make_mut(body));
+        }
+        Type::Record(fields) => {
+            Arc::make_mut(fields)
+                .iter_mut()
+                .for_each(|field| substitute_in_type(bindings, &mut field.1));
+        }
+        Type::Repeated(element) => substitute_in_type(bindings, Arc::make_mut(element)),
+        // The fields a row's variable stands for join those it already has.
+        Type::Row(fields, row) => {
+            Arc::make_mut(fields)
+                .iter_mut()
+                .for_each(|field| substitute_in_type(bindings, &mut field.1));
+            let mut rest = Type::Polymorphic(row.to_string());
+            substitute_in_type(bindings, &mut rest);
+            match rest {
+                Type::Polymorphic(name) => *row = name,
+                Type::Record(more) => *typ = Type::Record(merge_fields(fields, &more)),
+                Type::Row(more, name) => *typ = Type::Row(merge_fields(fields, &more), name),
+                _ => {}
+            }
         }
         Type::Tuple(elements) => {
-            elements
+            Arc::make_mut(elements)
                 .iter_mut()
                 .for_each(|element| substitute_in_type(bindings, element));
         }
@@ -581,61 +1704,130 @@ fn substitute_in_type<S: ::std::hash::BuildHasher>(
     }
 }
 
+// The fields of both, sorted by name.
+fn merge_fields(x: &[(String, Type)], y: &[(String, Type)]) -> Arc<[(String, Type)]> {
+    let mut fields: Vec<(String, Type)> = x.iter().chain(y.iter()).cloned().collect();
+    fields.sort_by(|x, y| x.0.cmp(&y.0));
+    fields.into()
+}
+
+fn substitute_pattern<S: ::std::hash::BuildHasher>(
+    bindings: &HashMap<String, Type, S>,
+    pattern: &mut TypedPattern,
+) {
+    match pattern {
+        TypedPattern::As(typ, pattern, _, _) => {
+            substitute_in_type(bindings, typ);
+            substitute_pattern(bindings, pattern);
+        }
+        TypedPattern::Identifier(typ, _, _) => substitute_in_type(bindings, typ),
+        TypedPattern::Literal(_, _) | TypedPattern::Wildcard(_) => {}
+        TypedPattern::Member(_, param, _) | TypedPattern::Variant(_, param, _) => {
+            if let Some(param) = param {
+                substitute_pattern(bindings, param);
+            }
+        }
+        TypedPattern::Or(patterns, _) | TypedPattern::Tuple(patterns, _) => {
+            for pattern in patterns {
+                substitute_pattern(bindings, pattern);
+            }
+        }
+    }
+}
+
 fn substitute<S: ::std::hash::BuildHasher>(
     bindings: &HashMap<String, Type, S>,
     ast: &mut TypedAST,
 ) {
     match ast {
-        TypedAST::BinaryOp(typ, _, lhs, rhs, _, _) => {
-            if let Type::Polymorphic(s) = typ {
-                if let Some(subst) = bindings.get(s) {
-                    *typ = subst.clone();
+        TypedAST::BinaryOp(_, _, _, _, _) => {
+            let mut ast = ast;
+            while let TypedAST::BinaryOp(typ, _, lhs, rhs, _) = ast {
+                if let Type::Polymorphic(s) = typ {
+                    if let Some(subst) = bindings.get(s) {
+                        *typ = subst.clone();
+                    }
                 }
+                substitute(bindings, rhs);
+                ast = lhs;
             }
-            substitute(bindings, lhs);
-            substitute(bindings, rhs);
+            substitute(bindings, ast);
         }
-        TypedAST::Call(fun, args) => {
+        TypedAST::Call(fun, args, _) => {
             substitute(bindings, fun);
             substitute(bindings, args);
         }
-        TypedAST::Define(_, _, value) => {
+        TypedAST::Cast(typ, ast, _) => {
+            substitute_in_type(bindings, typ);
+            substitute(bindings, ast);
+        }
+        TypedAST::Define(typ, _, value, _) => {
+            substitute_in_type(bindings, typ);
             substitute(bindings, value);
         }
-        TypedAST::Function(_, param, body) => {
+        TypedAST::Field(typ, value, _, _) => {
+            substitute_in_type(bindings, typ);
+            substitute(bindings, value);
+        }
+        TypedAST::For(pattern, iter, body, _) => {
+            substitute(bindings, pattern);
+            substitute(bindings, iter);
+            substitute(bindings, body);
+        }
+        TypedAST::Function(_, param, body, _) => {
             substitute(bindings, param);
             substitute(bindings, body);
         }
-        TypedAST::Identifier(typ, _) => {
+        TypedAST::Identifier(typ, _, _) => {
             substitute_in_type(bindings, typ);
         }
-        TypedAST::If(conds, els) => {
+        TypedAST::If(conds, els, _) => {
             for cond in conds {
                 substitute(bindings, &mut cond.0);
                 substitute(bindings, &mut cond.1);
             }
             substitute(bindings, els);
         }
-        TypedAST::Match(cond, datatype, cases) => {
+        TypedAST::Index(value, index, _) => {
+            substitute(bindings, value);
+            substitute(bindings, index);
+        }
+        TypedAST::Match(cond, datatype, cases, _) => {
             substitute(bindings, cond);
             substitute_in_type(bindings, datatype);
             for case in cases {
-                substitute(bindings, &mut case.2);
+                substitute_pattern(bindings, &mut case.0);
+                substitute(bindings, &mut case.1);
             }
         }
-        TypedAST::Program(typ, expressions) => {
+        TypedAST::Program(typ, expressions, _) => {
             substitute_in_type(bindings, typ);
             for expr in expressions {
                 substitute(bindings, expr);
             }
         }
-        TypedAST::Tuple(typ, elements) => {
+        TypedAST::Record(typ, fields, _) => {
+            substitute_in_type(bindings, typ);
+            for field in fields {
+                substitute(bindings, &mut field.1);
+            }
+        }
+        TypedAST::Slice(value, start, end, _) => {
+            substitute(bindings, value);
+            substitute(bindings, start);
+            substitute(bindings, end);
+        }
+        TypedAST::Spread(typ, ast, _) => {
+            substitute_in_type(bindings, typ);
+            substitute(bindings, ast);
+        }
+        TypedAST::Tuple(typ, elements, _) => {
             substitute_in_type(bindings, typ);
             for element in elements {
                 substitute(bindings, element);
             }
         }
-        TypedAST::UnaryOp(typ, _, ast) => {
+        TypedAST::UnaryOp(typ, _, ast, _) => {
             if let Type::Polymorphic(s) = typ {
                 if let Some(subst) = bindings.get(s) {
                     *typ = subst.clone();
@@ -643,41 +1835,411 @@ fn substitute<S: ::std::hash::BuildHasher>(
             }
             substitute(bindings, ast);
         }
-        _ => {}
+        _ => {}
+    }
+}
+
+fn find_infinite_type<S: ::std::hash::BuildHasher>(
+    x: &Type,
+    y: &Type,
+    bindings: &HashMap<String, Type, S>,
+) -> Option<(String, Type)> {
+    match (x, y) {
+        (Type::Polymorphic(s), Type::Polymorphic(t)) if s == t => None,
+        (Type::Polymorphic(s), t) | (t, Type::Polymorphic(s)) => {
+            if occurs(s, t, bindings) {
+                Some((s.to_string(), t.clone()))
+            } else {
+                None
+            }
+        }
+        (Type::Record(x_fields), Type::Record(y_fields)) => x_fields
+            .iter()
+            .zip(y_fields.iter())
+            .find_map(|(x, y)| find_infinite_type(&x.1, &y.1, bindings)),
+        (Type::Tuple(x_elements), Type::Tuple(y_elements)) => x_elements
+            .iter()
+            .zip(y_elements.iter())
+            .find_map(|(x, y)| find_infinite_type(x, y, bindings)),
+        (Type::Channel(x_element), Type::Channel(y_element))
+        | (Type::Repeated(x_element), Type::Repeated(y_element)) => {
+            find_infinite_type(x_element, y_element, bindings)
+        }
+        (Type::Function(x_param, x_body), Type::Function(y_param, y_body)) => {
+            find_infinite_type(x_param, y_param, bindings)
+                .or_else(|| find_infinite_type(x_body, y_body, bindings))
+        }
+        _ => None,
+    }
+}
+
+// Whether some part of a type is only known at runtime, as any and unions
+// are.
+fn is_dynamic(typ: &Type) -> bool {
+    match typ {
+        Type::Any | Type::Union(_) => true,
+        Type::Channel(element) | Type::Repeated(element) => is_dynamic(element),
+        Type::Function(param, body) => is_dynamic(param) || is_dynamic(body),
+        Type::Record(fields) | Type::Row(fields, _) => {
+            fields.iter().any(|field| is_dynamic(&field.1))
+        }
+        Type::Tuple(elements) => elements.iter().any(is_dynamic),
+        _ => false,
+    }
+}
+
+// Whether a value can be checked to have a type at runtime. Only whether a
+// value is a function or a channel is known, not which type of function or
+// channel it is.
+pub fn is_checkable(typ: &Type) -> bool {
+    match typ {
+        Type::Channel(_)
+        | Type::Function(_, _)
+        | Type::Polymorphic(_)
+        | Type::Repeated(_)
+        | Type::Row(_, _) => false,
+        Type::Record(fields) => fields.iter().all(|field| is_checkable(&field.1)),
+        Type::Tuple(elements) => elements.iter().all(is_checkable),
+        _ => true,
+    }
+}
+
+// The branches of an if or match ascribed a union are each ascribed it in
+// turn, so that they can have different types from the union.
+fn ascribe_branches(
+    expr: &parser::AST,
+    annotation: &parser::TypeAnnotation,
+) -> Option<parser::AST> {
+    let ascribe = |branch: &parser::AST| {
+        parser::AST::Ascription(
+            Box::new(branch.clone()),
+            annotation.clone(),
+            parser::span_of(branch),
+        )
+    };
+    match expr {
+        parser::AST::If(conds, els, span) => Some(parser::AST::If(
+            conds
+                .iter()
+                .map(|(cond, then)| (cond.clone(), ascribe(then)))
+                .collect(),
+            Box::new(ascribe(els)),
+            *span,
+        )),
+        parser::AST::Match(cond, cases, span) => Some(parser::AST::Match(
+            cond.clone(),
+            cases
+                .iter()
+                .map(|(pattern, body)| (pattern.clone(), ascribe(body)))
+                .collect(),
+            *span,
+        )),
+        _ => None,
+    }
+}
+
+// Ascribing a type to an expression whose type is known so far as found. Any
+// value can be given type any, and a value of type any can be given any type
+// that can be checked. A value can be given a union it is a member of, and a
+// value of a union can be given a member of it, or a union of some of its
+// members, which is checked. Other parts of the types must be the same.
+fn cast(
+    constraints: &mut Constraints,
+    found: &Type,
+    typ: &Type,
+    span: parser::Span,
+) -> Result<(), InterpreterError> {
+    let mismatch = || {
+        Err(InterpreterError::new(
+            Code::TypeMismatch,
+            &format!("Type error: expected {} but found {}.", typ, found),
+            span,
+        ))
+    };
+    let members = |typ: &Type| match typ {
+        Type::Union(members) => members.to_vec(),
+        typ => vec![typ.clone()],
+    };
+    match (found, typ) {
+        (_, Type::Any) => Ok(()),
+        (Type::Any, typ) if is_checkable(typ) => Ok(()),
+        (Type::Any, typ) => Err(InterpreterError::new(
+            Code::TypeMismatch,
+            &format!("Type error: any can't be checked to be {}.", typ),
+            span,
+        )),
+        (Type::Polymorphic(_), _) | (_, Type::Polymorphic(_)) => {
+            constraints.push((typ.clone(), found.clone(), span));
+            Ok(())
+        }
+        (Type::Union(_), _) | (_, Type::Union(_)) if is_concrete(found) => {
+            let (found, typ) = (members(found), members(typ));
+            if found.iter().all(|member| typ.contains(member))
+                || typ.iter().all(|member| found.contains(member))
+            {
+                Ok(())
+            } else {
+                mismatch()
+            }
+        }
+        // Parts of a member that aren't known yet are taken from the only
+        // member it could be.
+        (_, Type::Union(members)) => {
+            let mut candidates = members.iter().filter(|member| *member == found);
+            match (candidates.next(), candidates.next()) {
+                (Some(member), None) => {
+                    constraints.push((member.clone(), found.clone(), span));
+                    Ok(())
+                }
+                _ => mismatch(),
+            }
+        }
+        (Type::Record(found), Type::Record(fields))
+            if found.len() == fields.len()
+                && found.iter().zip(fields.iter()).all(|(x, y)| x.0 == y.0) =>
+        {
+            for (found, field) in found.iter().zip(fields.iter()) {
+                cast(constraints, &found.1, &field.1, span)?;
+            }
+            Ok(())
+        }
+        (Type::Tuple(found), Type::Tuple(elements)) if found.len() == elements.len() => {
+            for (found, typ) in found.iter().zip(elements.iter()) {
+                cast(constraints, found, typ, span)?;
+            }
+            Ok(())
+        }
+        _ => {
+            constraints.push((typ.clone(), found.clone(), span));
+            Ok(())
+        }
+    }
+}
+
+fn has_function(typ: &Type) -> bool {
+    match typ {
+        Type::Function(_, _) => true,
+        Type::Record(fields) | Type::Row(fields, _) => {
+            fields.iter().any(|field| has_function(&field.1))
+        }
+        Type::Tuple(elements) => elements.iter().any(has_function),
+        _ => false,
+    }
+}
+
+// Functions have no useful notion of equality, so comparing them is an error
+// once their types are known. Values whose types don't show that they hold
+// functions, such as datatypes with untyped parameters, are checked when they
+// are compared instead.
+fn check_comparisons(ast: &TypedAST) -> Result<(), InterpreterError> {
+    let mut pending = vec![ast];
+    while let Some(ast) = pending.pop() {
+        if let TypedAST::BinaryOp(_, parser::Operator::Equal, lhs, _, span)
+        | TypedAST::BinaryOp(_, parser::Operator::NotEqual, lhs, _, span) = ast
+        {
+            let typ = type_of(lhs);
+            if has_function(&typ) {
+                return Err(InterpreterError::new(
+                    Code::TypeMismatch,
+                    &format!(
+                        "Type error: functions can't be compared, but found {}.",
+                        typ
+                    ),
+                    *span,
+                ));
+            }
+        }
+        pending.extend(children(ast));
+    }
+    Ok(())
+}
+
+// The branches of an if or match must have the same type, except for those
+// that never produce a value, which are left out. Those are not constrained at
+// all, since a variable in another branch, such as a parameter, would
+// otherwise become never rather than the type the rest of the branches give
+// it.
+fn join(constraints: &mut Constraints, types: &[Type], span: parser::Span) {
+    let joined: Vec<&Type> = types
+        .iter()
+        .filter(|typ| !matches!(solve(constraints, (*typ).clone()), Type::Never))
+        .collect();
+    if let Some((first, rest)) = joined.split_first() {
+        for typ in rest {
+            constraints.push(((*first).clone(), (*typ).clone(), span));
+        }
+    }
+}
+
+// Integers, characters, strings and tuples of them can be ordered. The type
+// both operands must have is taken from whichever of them determines each
+// part of it, and anything else, including a type that neither determines, is
+// expected to be an integer.
+fn ordered_type(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Polymorphic(_), Type::Polymorphic(_)) => Type::Integer,
+        (Type::Polymorphic(_), typ) | (typ, Type::Polymorphic(_)) => ordered_type(typ, typ),
+        (Type::Char, _) => Type::Char,
+        (Type::String, _) => Type::String,
+        (Type::Tuple(lhs), Type::Tuple(rhs)) if lhs.len() == rhs.len() => Type::Tuple(
+            lhs.iter()
+                .zip(rhs.iter())
+                .map(|(lhs, rhs)| ordered_type(lhs, rhs))
+                .collect(),
+        ),
+        (Type::Tuple(elements), _) => Type::Tuple(
+            elements
+                .iter()
+                .map(|element| ordered_type(element, element))
+                .collect(),
+        ),
+        _ => Type::Integer,
+    }
+}
+
+// The constraints gathered so far, along with the bindings that solve them in
+// order. Those are kept up to date as constraints are added, so that finding a
+// type partway through inference only has to solve the ones added since.
+struct Constraints {
+    list: Vec<(Type, Type, parser::Span)>,
+    bindings: HashMap<String, Type>,
+    solved: usize,
+    error: Option<(Code, String, parser::Span)>,
+}
+
+impl Constraints {
+    fn new() -> Constraints {
+        Constraints {
+            list: Vec::new(),
+            bindings: HashMap::new(),
+            solved: 0,
+            error: None,
+        }
+    }
+
+    fn push(&mut self, constraint: (Type, Type, parser::Span)) {
+        self.list.push(constraint);
+    }
+
+    // Solves the constraints added since the last call, stopping at the
+    // first that can't be, whose error is kept for the full solution to
+    // report.
+    fn update(&mut self) -> Result<(), InterpreterError> {
+        while self.error.is_none() && self.solved < self.list.len() {
+            let (mut first, mut second, span) = self.list[self.solved].clone();
+            self.solved += 1;
+            substitute_in_type(&self.bindings, &mut first);
+            substitute_in_type(&self.bindings, &mut second);
+            if let Some((var, typ)) = find_infinite_type(&first, &second, &self.bindings) {
+                self.error = Some((
+                    Code::InfiniteType,
+                    format!("Type error: infinite type {} = {}.", var, typ),
+                    span,
+                ));
+            } else if !unify(
+                std::slice::from_ref(&first),
+                std::slice::from_ref(&second),
+                &mut self.bindings,
+            ) {
+                let err = format!("Type error: expected {} but found {}.", first, second);
+                self.error = Some((Code::TypeMismatch, err, span));
+            }
+        }
+        match &self.error {
+            Some((code, err, span)) => Err(InterpreterError::new(*code, err, *span)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Finds as much of a type as the constraints gathered so far determine,
+// leaving any errors for the full solution to report.
+fn solve(constraints: &mut Constraints, mut typ: Type) -> Type {
+    let _ = constraints.update();
+    substitute_in_type(&constraints.bindings, &mut typ);
+    typ
+}
+
+pub fn infer(
+    ast: &parser::AST,
+    ids: &mut HashMap<String, Type>,
+) -> Result<TypedAST, InterpreterError> {
+    infer_cached(ast, ids, &[])
+}
+
+// Datatypes declared at the top level of a program are known from the start,
+// so that the variants of each can refer to any of them, including those
+// declared later, as with a statement and an expression that contain each
+// other. Datatypes are told apart by name, so types that refer to themselves
+// need nothing further.
+fn declare_datatypes(ast: &parser::AST, datatypes: &mut HashMap<String, HashSet<String>>) {
+    let statements = match ast {
+        parser::AST::Program(statements, _) => &statements[..],
+        ast => std::slice::from_ref(ast),
+    };
+    for statement in statements {
+        if let parser::AST::Datatype(typ, variants, _) = statement {
+            datatypes.insert(
+                typ.to_string(),
+                variants
+                    .iter()
+                    .map(|variant| variant.0.to_string())
+                    .collect(),
+            );
+        }
     }
 }
 
-pub fn infer(
+// Like infer, but the statements of a program given in cached are taken from
+// there rather than inferred again. Those must be definitions with concrete
+// types.
+pub fn infer_cached(
     ast: &parser::AST,
-    mut ids: &mut HashMap<String, Type>,
+    ids: &mut HashMap<String, Type>,
+    cached: &[Option<TypedAST>],
 ) -> Result<TypedAST, InterpreterError> {
-    let mut id = 1;
-    let mut constraints = Vec::new();
+    let mut id = ids.values().map(next_type_id).max().unwrap_or(0).max(1);
+    let mut constraints = Constraints::new();
     let mut datatypes: HashMap<String, HashSet<String>> = HashMap::new();
+    declare_datatypes(ast, &mut datatypes);
 
-    let mut typed_ast =
-        build_constraints(&mut id, &mut constraints, &mut ids, &mut datatypes, &ast)?;
-    let mut bindings: HashMap<String, Type> = HashMap::new();
-    for mut constraint in constraints {
-        substitute_in_type(&bindings, &mut constraint.0);
-        substitute_in_type(&bindings, &mut constraint.1);
-        let typ_first = constraint.0.to_string();
-        let typ_second = constraint.1.to_string();
-        if !unify(&[constraint.0], &[constraint.1], &mut bindings) {
-            let mut err = "Type error: expected ".to_string();
-            err.push_str(&typ_first);
-            err.push_str(" but found ");
-            err.push_str(&typ_second);
-            err.push('.');
-
-            return Err(InterpreterError {
-                err,
-                line: constraint.2,
-                col: constraint.3,
-            });
+    let mut typed_ast = match ast {
+        parser::AST::Program(expressions, span) if !cached.is_empty() => {
+            let mut typed_expressions = Vec::new();
+            for (i, expr) in expressions.iter().enumerate() {
+                let typed_expr = match cached.get(i) {
+                    Some(Some(typed)) => {
+                        if let TypedAST::Define(_, name, _, _)
+                        | TypedAST::Function(Some(name), _, _, _) = typed
+                        {
+                            ids.insert(name.to_string(), type_of(typed));
+                        }
+                        typed.clone()
+                    }
+                    _ => build_constraints(&mut id, &mut constraints, ids, &mut datatypes, expr)?,
+                };
+                typed_expressions.push(typed_expr);
+            }
+            match typed_expressions.last() {
+                Some(expr) => {
+                    let typ = fresh_type(&mut id);
+                    constraints.push((typ, type_of(expr), *span));
+                    TypedAST::Program(type_of(expr), typed_expressions, *span)
+                }
+                None => TypedAST::Program(Type::Unit, typed_expressions, *span),
+            }
         }
-    }
+        _ => build_constraints(&mut id, &mut constraints, ids, &mut datatypes, ast)?,
+    };
+    constraints.update()?;
+    let bindings = constraints.bindings;
     substitute(&bindings, &mut typed_ast);
+    check_comparisons(&typed_ast)?;
+    // Variables solved here must not appear in the types of definitions, since
+    // later inputs can't solve them again.
+    for typ in ids.values_mut() {
+        substitute_in_type(&bindings, typ);
+    }
     Ok(typed_ast)
 }
 
@@ -718,8 +2280,8 @@ mod tests {
                     }
                     Err(err) => {
                         assert_eq!(err.err, $err);
-                        assert_eq!(err.line, $line);
-                        assert_eq!(err.col, $col);
+                        assert_eq!(err.span.line, $line);
+                        assert_eq!(err.span.col, $col);
                     }
                 },
                 Err(_) => {
@@ -729,6 +2291,84 @@ mod tests {
         }};
     }
 
+    #[test]
+    fn environment() {
+        let mut ids = HashMap::new();
+        for (input, value) in [
+            (
+                "def f := fn (x, y) -> x + y end",
+                "(integer, integer) -> integer",
+            ),
+            ("f", "(integer, integer) -> integer"),
+            ("(1, f)", "(integer, (integer, integer) -> integer)"),
+            ("type Shape := Circle (r) | Square (s) end", "Shape"),
+            (
+                "fn area (s) -> match s with Circle (r) -> r + 1 | Square (w) -> w end end",
+                "Shape -> integer",
+            ),
+            ("Circle (1)", "Shape"),
+            ("fn id (x) -> x end", "t1 -> t1"),
+            ("(id (1), id (\"a\"))", "(integer, string)"),
+        ] {
+            match parser::parse(input) {
+                Ok(ast) => match typeinfer::infer(&ast, &mut ids) {
+                    Ok(typed_ast) => {
+                        assert_eq!(type_of(&typed_ast).to_string(), value);
+                    }
+                    Err(_) => {
+                        unreachable!();
+                    }
+                },
+                Err(_) => {
+                    unreachable!();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn walks() {
+        let typed = match parser::parse("fn f (x) -> x + 1 end\n(f (2), \"a\")") {
+            Ok(ast) => match typeinfer::infer(&ast, &mut HashMap::new()) {
+                Ok(typed) => typed,
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        };
+        let mut types = Vec::new();
+        typeinfer::walk(&typed, &mut |ast| {
+            if let typeinfer::TypedAST::Identifier(typ, name, _) = ast {
+                types.push(format!("{}: {}", name, typ));
+            }
+            true
+        });
+        assert_eq!(types, ["x: integer", "x: integer", "f: integer -> integer"]);
+    }
+
+    #[test]
+    fn types_at_positions() {
+        let src = "fn double (x) -> 2 * x end
+def r := {a = double (1)}
+match (r.a, 'c') with (n, c) -> c end";
+        let typed = match parser::parse(src) {
+            Ok(ast) => match typeinfer::infer(&ast, &mut HashMap::new()) {
+                Ok(typed) => typed,
+                Err(_) => unreachable!(),
+            },
+            Err(_) => unreachable!(),
+        };
+        let type_at = |line, col| typeinfer::type_at(&typed, line, col).map(|t| t.to_string());
+        assert_eq!(type_at(1, 4).as_deref(), Some("integer -> integer"));
+        assert_eq!(type_at(1, 12).as_deref(), Some("integer"));
+        assert_eq!(type_at(2, 10).as_deref(), Some("{a: integer}"));
+        assert_eq!(type_at(2, 15).as_deref(), Some("integer -> integer"));
+        assert_eq!(type_at(3, 8).as_deref(), Some("{a: integer}"));
+        assert_eq!(type_at(3, 9).as_deref(), Some("integer"));
+        assert_eq!(type_at(3, 24).as_deref(), Some("integer"));
+        assert_eq!(type_at(3, 27).as_deref(), Some("char"));
+        assert_eq!(type_at(4, 1), None);
+    }
+
     #[test]
     fn inferences() {
         infer!("5", "integer");
@@ -737,6 +2377,8 @@ mod tests {
         infer!("true && false", "boolean");
         infer!("~false", "boolean");
         infer!("-1", "integer");
+        infer!("", "unit");
+        infer!("# Nothing but a comment.\n", "unit");
         inferfails!(
             "~1",
             "Type error: expected boolean but found integer.",
@@ -747,7 +2389,7 @@ mod tests {
             "2 + 5 + false",
             "Type error: expected integer but found boolean.",
             1,
-            7
+            1
         );
         infer!("1 + 1", "integer");
         infer!("1 - 1", "integer");
@@ -758,7 +2400,23 @@ mod tests {
         infer!("1 <= 1", "boolean");
         infer!("1 + 2 <= 1", "boolean");
         infer!("1 + 2 >= 1", "boolean");
+        infer!("\"a\" < \"b\"", "boolean");
+        infer!("(1, \"a\") >= (2, \"b\")", "boolean");
+        infer!("fn (x, y) -> x < y end", "(integer, integer) -> boolean");
+        infer!(
+            "fn (x, y) -> (x : string) < y end",
+            "(string, string) -> boolean"
+        );
+        infer!("fn s -> \"a\" < s end", "string -> boolean");
         infer!("1 + 2 == 3", "boolean");
+        infer!(
+            "for i in (0, fn i -> i < 3 end, fn i -> (i, i + 1) end) do i + 1 end",
+            "unit"
+        );
+        infer!(
+            "fn (iter) -> for (a, b) in iter do a && b end end",
+            "(t4, t4 -> boolean, t4 -> ((boolean, boolean), t4)) -> unit"
+        );
         infer!("1 == -1", "boolean");
         infer!("if true then 1 else 2 end", "integer");
         inferfails!(
@@ -888,6 +2546,25 @@ mod tests {
             ",
             "integer"
         );
+        infer!(
+            "type List := Cons (integer, List) | Nil end
+             fn sum (xs) -> match xs with Cons (x, rest) -> x + sum (rest) | Nil -> 0 end end",
+            "List -> integer"
+        );
+        infer!(
+            "type Expr := Num (integer) | Block (Stmt) end
+             type Stmt := Eval (Expr) | Seq (Stmt, Stmt) end
+             Block (Seq (Eval (Num (1)), Eval (Block (Eval (Num (2))))))",
+            "Expr"
+        );
+        inferfails!(
+            "type Expr := Num (integer) | Block (Stmt) end
+             type Stmt := Eval (Expr) | Seq (Stmt, Stmt) end
+             Block (Num (1))",
+            "Type error: expected Stmt but found Expr.",
+            3,
+            14
+        );
         inferfails!(
             "type E := A | B end
              match A with
@@ -945,6 +2622,52 @@ mod tests {
             ",
             "integer"
         );
+        infer!(
+            "type List := Nil | Cons (integer, List) end
+             Cons",
+            "(integer, List) -> List"
+        );
+        infer!(
+            "type List := Nil | Cons (integer, List) end
+             fn (list) ->
+                 match list with
+                     Nil -> Nil
+                     | Cons (head, tail) -> tail
+                 end
+             end",
+            "List -> List"
+        );
+        infer!(
+            "type Tree := Leaf | Node (Tree, integer, Tree) end
+             Node (Leaf, 1, Node (Leaf, 2, Leaf)) == Leaf",
+            "boolean"
+        );
+        // Each constructor call is only parsed once, so long lists written
+        // out as literals don't take exponential time.
+        let list = (0..20).rev().fold("Nil".to_string(), |list, n| {
+            format!("Cons ({}, {})", n, list)
+        });
+        infer!(
+            &format!("type List := Nil | Cons (integer, List) end\n{}", list),
+            "List"
+        );
+        inferfails!(
+            "type List := Nil | Cons (integer, List) end
+             Cons (true, Nil)",
+            "Type error: expected (integer, List) but found (boolean, List).",
+            2,
+            14
+        );
+        inferfails!(
+            "type List := Nil | Cons (integer, List) end
+             match Nil with
+                 Nil -> 0
+                 | Cons (head, tail) -> tail
+             end",
+            "Type error: expected integer but found List.",
+            2,
+            14
+        );
         inferfails!(
             "type E := A | B end
              match A with
@@ -956,15 +2679,365 @@ mod tests {
             2,
             14
         );
-        inferfails!(
+        infer!(
             "type E := A | B | C | D end
              match A with
                  A -> 0
              end
             ",
-            "Missing variants in match of E: B C D.",
+            "integer"
+        );
+        infer!("(1 : integer)", "integer");
+        infer!(
+            "def a := (1, true)
+             (..a, ..a, ())",
+            "(integer, boolean, integer, boolean, unit)"
+        );
+        infer!(
+            "fn p -> (..(p : (integer, string)), 0) end",
+            "(integer, string) -> (integer, string, integer)"
+        );
+        inferfails!(
+            "(..1, 2)",
+            "Type error: expected tuple but found integer.",
+            1,
+            2
+        );
+        inferfails!(
+            "fn p -> (..p, 0) end",
+            "Type error: expected tuple but found t1.",
+            1,
+            10
+        );
+        infer!("(\"s\" : string)", "string");
+        infer!("('s' : char)", "char");
+        infer!("fn c -> c < 'a' end", "char -> boolean");
+        infer!(
+            "fn (a, b) -> (a, 1) < (\"x\", b) end",
+            "(string, integer) -> boolean"
+        );
+        infer!("fn q -> (1, 'a') >= q end", "(integer, char) -> boolean");
+        infer!("fn (s, i) -> s[i] end", "(string, integer) -> char");
+        infer!("fn (s, i) -> s[i..i] end", "(string, integer) -> string");
+        inferfails!(
+            "\"abc\"[true]",
+            "Type error: expected integer but found boolean.",
+            1,
+            1
+        );
+        infer!("fn s -> s == \"\" end", "string -> boolean");
+        infer!(
+            "fn (x, y) -> (x : integer) == y end",
+            "(integer, integer) -> boolean"
+        );
+        infer!(
+            "fn x -> (x : (integer, boolean)) end",
+            "(integer, boolean) -> (integer, boolean)"
+        );
+        infer!("(fn x -> x end : integer -> integer)", "integer -> integer");
+        infer!(
+            "type E := A | B end
+             ((A, 1) : (E, integer))",
+            "(E, integer)"
+        );
+        infer!(
+            "fn (x, y) -> (x : 'a) == (y : 'b) end",
+            "(t2, t2) -> boolean"
+        );
+        inferfails!(
+            "(true : integer)",
+            "Type error: expected integer but found boolean.",
+            1,
+            1
+        );
+        inferfails!("(1 : widget)", "Unknown type: widget.", 1, 1);
+        inferfails!(
+            "fn f (x) -> f end",
+            "Type error: infinite type t2 = t1 -> t2.",
+            1,
+            1
+        );
+        inferfails!(
+            "fn f (x) -> (1, f) end",
+            "Type error: infinite type t2 = (integer, t1 -> t2).",
+            1,
+            1
+        );
+        inferfails!(
+            "fn g (x) -> g (g) + 1 end",
+            "Type error: infinite type t1 = t1 -> t2.",
+            1,
+            13
+        );
+        infer!("(1 : any)", "any");
+        infer!("((1 : any) : integer)", "integer");
+        infer!("(((1, 'a') : any) : (integer, any))", "(integer, any)");
+        infer!(
+            "((2, (true : any)) : (integer, boolean))",
+            "(integer, boolean)"
+        );
+        infer!("fn x -> (x : any) == (1 : any) end (2)", "boolean");
+        inferfails!(
+            "(1 : any) + 1",
+            "Type error: expected integer but found any.",
+            1,
+            1
+        );
+        inferfails!(
+            "((1 : any) : integer -> integer)",
+            "Type error: any can't be checked to be integer -> integer.",
+            1,
+            1
+        );
+        inferfails!(
+            "fn g (x) -> x + 1 end g ((1 : any))",
+            "Type error: expected integer but found any.",
+            1,
+            23
+        );
+        inferfails!(
+            "fn g (x) -> x + 1 end g (true)",
+            "Type error: expected integer but found boolean.",
+            1,
+            23
+        );
+        infer!("(1 : integer | boolean)", "boolean | integer");
+        infer!(
+            "(if true then 1 elsif false then 'a' else true end : integer | boolean | char)",
+            "boolean | char | integer"
+        );
+        infer!(
+            "((1 : integer | boolean) : boolean | integer | unit)",
+            "boolean | integer | unit"
+        );
+        infer!("((1 : integer | boolean) : integer) + 1", "integer");
+        infer!(
+            "fn x -> match (x : integer | string) with integer(n) -> n | string(s) -> 0 end end",
+            "integer | string -> integer"
+        );
+        infer!(
+            "type T := A (integer) | B (integer, string) | C (string, integer) end
+             fn f (t) -> match t with A (n) -> n | B (n, s) | C (s, n) -> n end end",
+            "T -> integer"
+        );
+        infer!(
+            "fn f (p) -> match p with (0, c) -> c | (_, 'e') -> 'f' | _ -> 'x' end end",
+            "(integer, char) -> char"
+        );
+        infer!(
+            "type List := Cons (integer, List) | Nil end
+             fn f (xs) -> match xs with Cons (x, Cons (y, _)) -> x + y | Cons (x, Nil) -> x | Nil -> 0 end end",
+            "List -> integer"
+        );
+        infer!(
+            "match (1, ('a', true)) with (n, (c, b) as p) as t -> (t, p, b) end",
+            "((integer, (char, boolean)), (char, boolean), boolean)"
+        );
+        infer!(
+            "match (1, ('a', true)) with (n, (c, b)) -> (b, c, n) end",
+            "(boolean, char, integer)"
+        );
+        infer!(
+            "fn p -> match p with (a, (b, c)) -> a + b + c end end",
+            "(integer, (integer, integer)) -> integer"
+        );
+        inferfails!(
+            "(if true then 1 else true end : integer | char)",
+            "Type error: expected char | integer but found boolean.",
+            1,
+            22
+        );
+        inferfails!(
+            "((1 : integer | boolean) : string)",
+            "Type error: expected string but found boolean | integer.",
+            1,
+            1
+        );
+        inferfails!(
+            "(1 : integer | 'a)",
+            "Type error: 'a can't be a member of a union.",
+            1,
+            1
+        );
+        inferfails!(
+            "match ('a' : char | unit) with char(c) -> 1 | integer(n) -> n end",
+            "Type error: integer is not a member of char | unit.",
+            1,
+            1
+        );
+        inferfails!(
+            "match (1, 2) with (a, b) -> a | Some (c) -> c end",
+            "Match statement: expected datatype.",
+            1,
+            1
+        );
+        inferfails!(
+            "type T := A (integer) | B (string) end
+             fn f (t) -> match t with A (x) | B (x) -> 0 end end",
+            "Type error: expected integer but found string.",
             2,
-            14
+            26
+        );
+        inferfails!(
+            "type T := A (integer) | B end
+             fn f (t) -> match t with A (x) | B -> x end end",
+            "Type error: x is not bound by every alternative of the pattern.",
+            2,
+            26
+        );
+        inferfails!(
+            "type List := Cons (integer, List) | Nil end
+             type Tree := Leaf | Node (Tree, integer, Tree) end
+             fn f (xs) -> match xs with Cons (x, Leaf) -> x | _ -> 0 end end",
+            "Type error: expected List but found Tree.",
+            3,
+            27
+        );
+        inferfails!(
+            "match 1 with 0 -> 0 | 'a' -> 1 | _ -> 2 end",
+            "Type error: expected integer but found char.",
+            1,
+            1
+        );
+        inferfails!(
+            "match (1, 2) with (a, b) as a -> a end",
+            "Type error: a is bound more than once in the pattern.",
+            1,
+            1
+        );
+        inferfails!(
+            "match (1, 2) with (a, a) -> a end",
+            "Type error: a is bound more than once in the pattern.",
+            1,
+            1
+        );
+        inferfails!(
+            "match (1, 2) with (a, (b, c)) -> a end",
+            "Type error: expected integer but found (t3, t4).",
+            1,
+            1
+        );
+        inferfails!(
+            "(1 : never)",
+            "Type error: expected never but found integer.",
+            1,
+            1
+        );
+        inferfails!(
+            "fn f (x) -> x end f == f",
+            "Type error: functions can't be compared, but found t5 -> t5.",
+            1,
+            19
+        );
+        infer!(
+            "fn first (x, y) -> x end fn (p) -> first (p) end",
+            "(t6, t7) -> t6"
+        );
+        infer!(
+            "fn swap (x, y) -> (y, x) end fn (p) -> swap (swap (p)) end",
+            "(t8, t9) -> (t8, t9)"
+        );
+        inferfails!(
+            "fn first (x, y) -> x end fn g (p) -> first (p) end g (5)",
+            "Type error: expected (t10, t11) but found integer.",
+            1,
+            52
+        );
+        infer!(
+            "fn id (x) -> x end (id (1), id (true), id ({x = 1}))",
+            "(integer, boolean, {x: integer})"
+        );
+        inferfails!(
+            "fn id (x) -> x end id (true) + 1",
+            "Type error: expected integer but found boolean.",
+            1,
+            20
+        );
+        inferfails!(
+            "fn id (x) -> x end id ({x = 1}) + 1",
+            "Type error: expected integer but found {x: integer}.",
+            1,
+            20
+        );
+        infer!("fn (f) -> f (1) end", "(integer -> t2) -> t2");
+        infer!("fn (f) -> f (1) + 1 end", "(integer -> integer) -> integer");
+        infer!("fn (f, x) -> f (f (x)) end", "(t4 -> t4, t4) -> t4");
+        inferfails!(
+            "fn (f) -> (f (1), f (true)) end",
+            "Type error: expected integer -> t2 but found boolean -> t3.",
+            1,
+            19
+        );
+        inferfails!(
+            "fn apply (f, x) -> f (x) end apply (fn (y) -> y + 1 end, true)",
+            "Type error: expected (t6 -> t7, t6) but found (integer -> integer, boolean).",
+            1,
+            30
+        );
+        infer!("{y = \"a\", x = 1}", "{x: integer, y: string}");
+        infer!("{x = 1, y = \"a\"}.y", "string");
+        infer!("{p = {x = true}}.p.x", "boolean");
+        infer!(
+            "fn (r) -> (r : {x: integer}).x end",
+            "{x: integer} -> integer"
+        );
+        infer!("{x = 1} == ({x = 2} : {x: integer})", "boolean");
+        inferfails!(
+            "{x = 1, y = 2, x = 3}",
+            "Type error: x is given more than once in the record.",
+            1,
+            1
+        );
+        inferfails!(
+            "{x = 1}.y",
+            "Type error: expected a record with field y but found {x: integer}.",
+            1,
+            1
+        );
+        // Functions take any record with the fields they use.
+        infer!("fn (r) -> 1 + r.x end", "{x: integer, ..t3} -> integer");
+        infer!(
+            "fn (r) -> (r.x, r.y, r.x) end",
+            "{x: t2, y: t4, ..t5} -> (t2, t4, t2)"
+        );
+        infer!(
+            "fn norm (p) -> p.x * p.x + p.y * p.y end
+             norm ({x = 3, y = 4}) + norm ({y = 1, x = 2, label = \"a\"})",
+            "integer"
+        );
+        infer!(
+            "def f := fn (r) -> r.x end
+             def g := fn (r) -> r.y end
+             fn c -> if c then f else g end end",
+            "boolean -> {x: t6, y: t6, ..t3t7} -> t6"
+        );
+        infer!(
+            "fn (r) -> (r.x, (r : {x: integer, y: string})) end",
+            "{x: integer, y: string} -> (integer, {x: integer, y: string})"
+        );
+        inferfails!(
+            "fn getx (r) -> r.x end getx ({y = 1})",
+            "Type error: expected {x: t7, ..t6} but found {y: integer}.",
+            1,
+            24
+        );
+        inferfails!(
+            "fn (r) -> (r : {y: integer}).x end",
+            "Type error: expected a record with field x but found {y: integer}.",
+            1,
+            11
+        );
+        inferfails!(
+            "{x = 1} == {x = true}",
+            "Type error: expected {x: integer} but found {x: boolean}.",
+            1,
+            1
+        );
+        inferfails!(
+            "fn (x) -> (x, 1) ~= (fn y -> y end, 1) end",
+            "Type error: functions can't be compared, but found (t2 -> t2, integer).",
+            1,
+            11
         );
     }
 }
@@ -2,12 +2,50 @@ use std::collections::HashMap;
 
 use crate::typeinfer::Type;
 
+pub fn occurs<S: ::std::hash::BuildHasher>(
+    var: &str,
+    x: &Type,
+    bindings: &HashMap<String, Type, S>,
+) -> bool {
+    match x {
+        Type::Polymorphic(s) => {
+            if s == var {
+                true
+            } else {
+                match bindings.get(s) {
+                    Some(Type::Polymorphic(t)) if t == s => false,
+                    Some(t) => occurs(var, t, bindings),
+                    None => false,
+                }
+            }
+        }
+        Type::Channel(element) => occurs(var, element, bindings),
+        Type::Function(param, body) => occurs(var, param, bindings) || occurs(var, body, bindings),
+        Type::Record(fields) => fields.iter().any(|field| occurs(var, &field.1, bindings)),
+        Type::Repeated(element) => occurs(var, element, bindings),
+        Type::Row(fields, row) => {
+            fields.iter().any(|field| occurs(var, &field.1, bindings))
+                || occurs(var, &Type::Polymorphic(row.to_string()), bindings)
+        }
+        Type::Tuple(elements) => elements
+            .iter()
+            .any(|element| occurs(var, element, bindings)),
+        _ => false,
+    }
+}
+
+// Types that are the same, or where one is never, which fits anywhere.
+fn fits(x: &Type, y: &Type) -> bool {
+    matches!(x, Type::Never) || matches!(y, Type::Never) || x == y
+}
+
 fn unify_variable<S: ::std::hash::BuildHasher>(
     var: &str,
     x: &Type,
     bindings: &mut HashMap<String, Type, S>,
 ) -> bool {
     match x {
+        Type::Polymorphic(s) if s == var => true,
         Type::Polymorphic(s) => match bindings.get(var) {
             Some(Type::Polymorphic(t)) => {
                 if s == t {
@@ -17,10 +55,8 @@ fn unify_variable<S: ::std::hash::BuildHasher>(
                 }
             }
             Some(t) => {
-                let s = s.to_string();
                 let t = t.clone();
-                bindings.insert(s, t);
-                true
+                unify_variable(s, &t, bindings)
             }
             None => match bindings.get(s) {
                 Some(token) => unify_variable(&s.to_string(), &token.clone(), bindings),
@@ -32,8 +68,16 @@ fn unify_variable<S: ::std::hash::BuildHasher>(
         },
         s => match bindings.get(var) {
             Some(Type::Polymorphic(t)) => unify_variable(&t.to_string(), s, bindings),
-            Some(t) => s == t,
+            // A variable bound earlier in the same unification, such as
+            // one that appears twice in a tuple, must agree with both.
+            Some(t) => {
+                let t = t.clone();
+                unify(std::slice::from_ref(s), &[t], bindings)
+            }
             None => {
+                if occurs(var, s, bindings) {
+                    return false;
+                }
                 bindings.insert(var.to_string(), s.clone());
                 true
             }
@@ -41,6 +85,64 @@ fn unify_variable<S: ::std::hash::BuildHasher>(
     }
 }
 
+// Unifies the fields of a row with those of a record, when t_row is None, or
+// of another row. Fields that only one side names must be among the others
+// that the row variable of the other side stands for, so a row unifies with
+// any record that has at least its fields.
+fn unify_rows<S: ::std::hash::BuildHasher>(
+    s_fields: &[(String, Type)],
+    s_row: &str,
+    t_fields: &[(String, Type)],
+    t_row: Option<&str>,
+    bindings: &mut HashMap<String, Type, S>,
+) -> bool {
+    let mut s_types = Vec::new();
+    let mut t_types = Vec::new();
+    let mut s_only = Vec::new();
+    for field in s_fields {
+        match t_fields.iter().find(|other| other.0 == field.0) {
+            Some(other) => {
+                s_types.push(field.1.clone());
+                t_types.push(other.1.clone());
+            }
+            None => s_only.push(field.clone()),
+        }
+    }
+    let t_only: Vec<(String, Type)> = t_fields
+        .iter()
+        .filter(|field| !s_fields.iter().any(|other| other.0 == field.0))
+        .cloned()
+        .collect();
+    if !unify(&s_types, &t_types, bindings) {
+        return false;
+    }
+    let var = |name: &str| Type::Polymorphic(name.to_string());
+    match t_row {
+        None => s_only.is_empty() && unify(&[var(s_row)], &[Type::Record(t_only.into())], bindings),
+        Some(t_row) if t_row == s_row => s_only.is_empty() && t_only.is_empty(),
+        Some(t_row) if s_only.is_empty() => unify(
+            &[var(s_row)],
+            &[Type::Row(t_only.into(), t_row.to_string())],
+            bindings,
+        ),
+        Some(t_row) if t_only.is_empty() => unify(
+            &[var(t_row)],
+            &[Type::Row(s_only.into(), s_row.to_string())],
+            bindings,
+        ),
+        // Both rows stand for the fields the other names, and for the same
+        // others besides, which a variable named after the two stands for.
+        Some(t_row) => {
+            let rest = format!("{}{}", s_row, t_row);
+            unify(
+                &[var(s_row)],
+                &[Type::Row(t_only.into(), rest.clone())],
+                bindings,
+            ) && unify(&[var(t_row)], &[Type::Row(s_only.into(), rest)], bindings)
+        }
+    }
+}
+
 pub fn unify<S: ::std::hash::BuildHasher>(
     x: &[Type],
     y: &[Type],
@@ -52,9 +154,12 @@ pub fn unify<S: ::std::hash::BuildHasher>(
 
     while matched {
         match x_iter.next() {
+            Some(Type::Never) => {
+                matched = y_iter.next().is_some();
+            }
             Some(Type::Polymorphic(s)) => match y_iter.next() {
                 Some(token) => {
-                    matched = unify_variable(s, &token, bindings);
+                    matched = unify_variable(s, token, bindings);
                 }
                 None => {
                     matched = false;
@@ -62,11 +167,99 @@ pub fn unify<S: ::std::hash::BuildHasher>(
             },
             Some(Type::Tuple(s_elements)) => match y_iter.next() {
                 Some(Type::Polymorphic(t)) => {
-                    matched = unify_variable(t, &Type::Tuple(s_elements.to_vec()), bindings);
+                    matched = unify_variable(t, &Type::Tuple(s_elements.clone()), bindings);
                 }
                 Some(Type::Tuple(t_elements)) => {
                     matched = unify(&s_elements[..], &t_elements[..], bindings);
                 }
+                Some(Type::Never) => {}
+                _ => {
+                    matched = false;
+                }
+            },
+            // Records only unify with records that have the same fields.
+            Some(Type::Record(s_fields)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(t, &Type::Record(s_fields.clone()), bindings);
+                }
+                Some(Type::Record(t_fields))
+                    if s_fields.len() == t_fields.len()
+                        && s_fields
+                            .iter()
+                            .zip(t_fields.iter())
+                            .all(|(s, t)| s.0 == t.0) =>
+                {
+                    let s_types: Vec<Type> = s_fields.iter().map(|s| s.1.clone()).collect();
+                    let t_types: Vec<Type> = t_fields.iter().map(|t| t.1.clone()).collect();
+                    matched = unify(&s_types, &t_types, bindings);
+                }
+                Some(Type::Row(t_fields, t_row)) => {
+                    matched = unify_rows(t_fields, t_row, s_fields, None, bindings);
+                }
+                Some(Type::Never) => {}
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::Row(s_fields, s_row)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(
+                        t,
+                        &Type::Row(s_fields.clone(), s_row.to_string()),
+                        bindings,
+                    );
+                }
+                Some(Type::Record(t_fields)) => {
+                    matched = unify_rows(s_fields, s_row, t_fields, None, bindings);
+                }
+                Some(Type::Row(t_fields, t_row)) => {
+                    matched = unify_rows(s_fields, s_row, t_fields, Some(t_row), bindings);
+                }
+                Some(Type::Never) => {}
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::Channel(s_element)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(t, &Type::Channel(s_element.clone()), bindings);
+                }
+                Some(Type::Channel(t_element)) => {
+                    matched = unify(&[(**s_element).clone()], &[(**t_element).clone()], bindings);
+                }
+                Some(Type::Never) => {}
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::Repeated(s_element)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(t, &Type::Repeated(s_element.clone()), bindings);
+                }
+                Some(Type::Repeated(t_element)) => {
+                    matched = unify(&[(**s_element).clone()], &[(**t_element).clone()], bindings);
+                }
+                Some(Type::Never) => {}
+                _ => {
+                    matched = false;
+                }
+            },
+            Some(Type::Function(s_param, s_body)) => match y_iter.next() {
+                Some(Type::Polymorphic(t)) => {
+                    matched = unify_variable(
+                        t,
+                        &Type::Function(s_param.clone(), s_body.clone()),
+                        bindings,
+                    );
+                }
+                Some(Type::Function(t_param, t_body)) => {
+                    matched = unify(
+                        &[(**s_param).clone(), (**s_body).clone()],
+                        &[(**t_param).clone(), (**t_body).clone()],
+                        bindings,
+                    );
+                }
+                Some(Type::Never) => {}
                 _ => {
                     matched = false;
                 }
@@ -76,7 +269,7 @@ pub fn unify<S: ::std::hash::BuildHasher>(
                     matched = unify_variable(t, s, bindings);
                 }
                 Some(t) => {
-                    matched = s == t;
+                    matched = fits(s, t);
                 }
                 None => {
                     matched = false;
@@ -95,6 +288,7 @@ pub fn unify<S: ::std::hash::BuildHasher>(
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::sync::Arc;
 
     use crate::unification::*;
 
@@ -127,5 +321,79 @@ mod tests {
 
         let mut bindings: HashMap<String, Type> = HashMap::new();
         assert!(!unify(&x, &y, &mut bindings));
+
+        let x = vec![Type::Polymorphic("'a".to_string())];
+        let y = vec![Type::Function(
+            Arc::new(Type::Integer),
+            Arc::new(Type::Polymorphic("'a".to_string())),
+        )];
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(!unify(&x, &y, &mut bindings));
+        assert_eq!(bindings.len(), 0);
+
+        let y = vec![Type::Tuple(Arc::new([
+            Type::Integer,
+            Type::Polymorphic("'a".to_string()),
+        ]))];
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(!unify(&x, &y, &mut bindings));
+        assert_eq!(bindings.len(), 0);
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(unify(&x, &x, &mut bindings));
+        assert_eq!(bindings.len(), 0);
+        assert!(unify(&x, &[Type::Integer], &mut bindings));
+        assert_eq!(bindings.get("'a"), Some(&Type::Integer));
+        // Never fits any type, without binding the variables in it.
+        let x = vec![
+            Type::Never,
+            Type::Integer,
+            Type::Polymorphic("'b".to_string()),
+        ];
+        let y = vec![y[0].clone(), Type::Never, Type::Never];
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(unify(&x, &y, &mut bindings));
+        assert!(unify(&y, &x, &mut bindings));
+        assert_eq!(bindings.get("'a"), None);
+
+        // A row unifies with records that have at least its fields, and its
+        // variable stands for the rest.
+        let field = |name: &str, typ: Type| (name.to_string(), typ);
+        let row = vec![Type::Row(
+            Arc::new([field("x", Type::Polymorphic("'a".to_string()))]),
+            "'r".to_string(),
+        )];
+        let record = vec![Type::Record(Arc::new([
+            field("x", Type::Integer),
+            field("y", Type::String),
+        ]))];
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(unify(&row, &record, &mut bindings));
+        assert_eq!(bindings.get("'a"), Some(&Type::Integer));
+        assert_eq!(
+            bindings.get("'r").map(|typ| typ.to_string()),
+            Some("{y: string}".to_string())
+        );
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(unify(&record, &row, &mut bindings));
+        let other = vec![Type::Row(
+            Arc::new([field("y", Type::Unit)]),
+            "'s".to_string(),
+        )];
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        assert!(unify(&row, &other, &mut bindings));
+        assert_eq!(
+            bindings.get("'r").map(|typ| typ.to_string()),
+            Some("{y: unit, ..'r's}".to_string())
+        );
+
+        let mut bindings: HashMap<String, Type> = HashMap::new();
+        let record = vec![Type::Record(Arc::new([field("y", Type::Integer)]))];
+        assert!(!unify(&row, &record, &mut bindings));
     }
 }
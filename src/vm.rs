@@ -1,56 +1,119 @@
+use crate::builtins;
+use crate::cache;
 use crate::codegen;
+use crate::diagnostics::Code;
+use crate::optimize;
+use crate::parser;
+use crate::profile::{self, Profile, Sampler};
 use crate::typeinfer;
 use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
 macro_rules! err {
-    ($vm:expr, $msg:expr) => {{
-        return Err(codegen::InterpreterError {
-            err: $msg.to_string(),
-            line: $vm.line,
-            col: $vm.col,
-        });
+    ($vm:expr, $code:expr, $msg:expr) => {{
+        return Err(codegen::InterpreterError::new($code, &$msg, $vm.span));
     }};
 }
 
+// Counts a value built on the heap, failing once the program has built more
+// than it may.
+macro_rules! allocate {
+    ($vm:expr) => {{
+        $vm.allocated += 1;
+        if let Some(max) = $vm.max_allocations {
+            if $vm.allocated - $vm.allocated_before > max {
+                err!(
+                    $vm,
+                    Code::AllocationLimit,
+                    format!("Allocation limit exceeded: more than {} values.", max)
+                )
+            }
+        }
+    }};
+}
+
+#[derive(Clone)]
 pub enum Opcode {
     Add,
     And,
     Arg(usize),
     Bconst(bool),
+    Cconst(char),
     Call,
+    // Fails unless the value on top of the stack has the type.
+    Check(typeinfer::Type),
+    Const(usize),
     Div,
     Dup,
+    // Replaces the tuple on top of the stack with one of its elements.
+    Element(usize),
     Equal,
+    Exit,
     ExtVal,
-    Dconst(String, String, usize),
+    // Replaces the record on top of the stack with the value of one of its
+    // fields.
+    Field(String),
+    Dconst(String, String),
     Fconst(
         Option<String>,
-        usize,
+        Arc<Chunk>,
         HashMap<String, (usize, typeinfer::Type)>,
     ),
     GetEnv(String),
     Greater,
     GreaterEqual,
-    Iconst(i64),
+    Index,
+    // Replaces the value on top of the stack with whether it has the type.
+    IsType(typeinfer::Type),
     Jmp(i64),
     Jz(i64),
     Less,
     LessEqual,
+    // Collects the values of the named fields, in the order they were
+    // pushed, into a record.
+    MakeRecord(Vec<String>),
+    MakeTuple(usize),
     Mod,
     Mul,
+    NoMatch,
     Not,
     NotEqual,
     Or,
+    Peek(usize),
+    // Calls the function in the tuple argument on each element of the tuple
+    // beside it, on as many threads as there are processors.
+    Pmap,
     Pop,
+    // Waits for a value to be sent on the channel argument.
+    Receive,
+    // Switches to the stack of the coroutine that the function running now
+    // resumes.
+    Resume,
     Ret(usize),
-    Rot,
     SetEnv(String),
-    Srcpos(usize, usize),
+    Slice,
+    // Calls the function argument on a thread of its own, without waiting
+    // for it.
+    Spawn,
+    Spread,
+    Srcpos(parser::Span),
+    Store(usize),
     Sub,
     TypeEq(String),
     Uconst,
+    Unpack(usize),
+    // Switches back from a coroutine, with the value it yields or returns.
+    Yield,
 }
 
 impl fmt::Display for Opcode {
@@ -60,47 +123,112 @@ impl fmt::Display for Opcode {
             Opcode::And => write!(f, "and"),
             Opcode::Arg(n) => write!(f, "arg {}", n),
             Opcode::Bconst(b) => write!(f, "const {}", b),
+            Opcode::Cconst(c) => write!(f, "const {}", parser::escape_char(*c)),
             Opcode::Call => write!(f, "call"),
+            Opcode::Check(typ) => write!(f, "check {}", typ),
+            Opcode::Const(idx) => write!(f, "const #{}", idx),
             Opcode::Div => write!(f, "div"),
             Opcode::Dup => write!(f, "dup"),
+            Opcode::Element(n) => write!(f, "element {}", n),
             Opcode::Equal => write!(f, "eq"),
+            Opcode::Exit => write!(f, "exit"),
             Opcode::ExtVal => write!(f, "extval"),
-            Opcode::Dconst(_, ctor, count) => write!(f, "const {} {}", ctor, count),
-            Opcode::Fconst(id, ip, _) => {
+            Opcode::Field(name) => write!(f, "field {}", name),
+            Opcode::Dconst(_, ctor) => write!(f, "const {}", ctor),
+            Opcode::Fconst(id, chunk, _) => {
+                let len = chunk.code.len();
                 if let Some(id) = id {
-                    write!(f, "{} @{}", id, ip)
+                    write!(f, "{} ({} instructions)", id, len)
                 } else {
-                    write!(f, "lambda @{}", ip)
+                    write!(f, "lambda ({} instructions)", len)
                 }
             }
             Opcode::GetEnv(id) => write!(f, "getenv {}", id),
             Opcode::Greater => write!(f, "gt"),
             Opcode::GreaterEqual => write!(f, "ge"),
-            Opcode::Iconst(i) => write!(f, "const {}", i),
+            Opcode::Index => write!(f, "index"),
+            Opcode::IsType(typ) => write!(f, "istype {}", typ),
             Opcode::Jmp(ip) => write!(f, "jmp {}", ip),
             Opcode::Jz(ip) => write!(f, "jz {}", ip),
             Opcode::Less => write!(f, "lt"),
             Opcode::LessEqual => write!(f, "le"),
+            Opcode::MakeRecord(names) => write!(f, "record {}", names.join(" ")),
+            Opcode::MakeTuple(n) => write!(f, "tuple {}", n),
             Opcode::Mod => write!(f, "mod"),
             Opcode::Mul => write!(f, "mul"),
+            Opcode::NoMatch => write!(f, "nomatch"),
             Opcode::Not => write!(f, "not"),
             Opcode::NotEqual => write!(f, "neq"),
             Opcode::Or => write!(f, "or"),
+            Opcode::Peek(n) => write!(f, "peek {}", n),
+            Opcode::Pmap => write!(f, "pmap"),
             Opcode::Pop => write!(f, "pop"),
+            Opcode::Receive => write!(f, "receive"),
+            Opcode::Resume => write!(f, "resume"),
             Opcode::Ret(n) => write!(f, "ret {}", n),
-            Opcode::Rot => write!(f, "rot"),
             Opcode::SetEnv(id) => write!(f, "setenv {}", id),
-            Opcode::Srcpos(line, col) => write!(f, "srcpos {} {}", line, col),
+            Opcode::Slice => write!(f, "slice"),
+            Opcode::Spawn => write!(f, "spawn"),
+            Opcode::Spread => write!(f, "spread"),
+            Opcode::Srcpos(span) => write!(f, "srcpos {} {}", span.line, span.col),
+            Opcode::Store(n) => write!(f, "store {}", n),
             Opcode::Sub => write!(f, "sub"),
             Opcode::TypeEq(typ) => write!(f, "typeq {}", typ),
             Opcode::Uconst => write!(f, "const"),
+            Opcode::Unpack(n) => write!(f, "unpack {}", n),
+            Opcode::Yield => write!(f, "yield"),
+        }
+    }
+}
+
+// The code for a function, ending with its only Ret, or for the top level of
+// a program. Closures share the chunk of their function, so its code is freed
+// along with the last closure that can run it. Jumps are relative, so a chunk
+// can be moved or copied as it is.
+#[derive(Clone, Default)]
+pub struct Chunk {
+    pub code: Vec<Opcode>,
+    // The type of a function and where it was defined, which closures are
+    // displayed with. The top level of a program has neither.
+    pub signature: Option<(typeinfer::Type, parser::Span)>,
+}
+
+impl Chunk {
+    pub fn new(code: Vec<Opcode>) -> Chunk {
+        Chunk {
+            code,
+            signature: None,
         }
     }
 }
 
+// Chunks are only equal to themselves, so that functions are equal when they
+// were created by the same code with the same environment.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Chunk) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Chunk({} instructions)", self.code.len())
+    }
+}
+
+// Disassembles the chunk, one numbered instruction per line.
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, op) in self.code.iter().enumerate() {
+            writeln!(f, "{:4} {}", i, op)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Environment {
-    pub fun: Option<(String, usize)>,
+    pub fun: Option<(String, Arc<Chunk>)>,
     pub values: HashMap<String, Value>,
     pub types: HashMap<String, typeinfer::Type>,
 }
@@ -113,14 +241,200 @@ impl Environment {
             types: HashMap::new(),
         }
     }
+
+    // The names bound here, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(|name| name.as_str())
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new()
+    }
+}
+
+// Values sent on a channel are received in the order they were sent, from
+// whichever thread receives first. Channels are only equal to themselves.
+pub struct Channel {
+    sender: mpsc::Sender<Value>,
+    receiver: Mutex<mpsc::Receiver<Value>>,
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        let (sender, receiver) = mpsc::channel();
+        Channel {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    // Sending never fails, since the channel holds its own receiver.
+    pub fn send(&self, value: Value) {
+        let _ = self.sender.send(value);
+    }
+
+    // The next value sent, if one has been.
+    pub fn try_receive(&self) -> Option<Value> {
+        self.receiver.lock().ok()?.try_recv().ok()
+    }
+
+    // Waits for the next value to be sent, for at most the timeout.
+    pub fn receive_timeout(&self, timeout: Duration) -> Option<Value> {
+        self.receiver.lock().ok()?.recv_timeout(timeout).ok()
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel::new()
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Channel) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Channel")
+    }
+}
+
+// Where code that isn't running now is up to, and the frames it had
+// entered, when profiling.
+struct Context {
+    chunk: Arc<Chunk>,
+    ip: usize,
+    stack: Vec<Value>,
+    callstack: Vec<(Arc<Chunk>, Arc<Environment>, usize, usize)>,
+    frames: Vec<String>,
+}
+
+enum Task {
+    // The function to call, and the function it yields with.
+    Start(Value, Value),
+    Suspended(Context),
+    Running,
+    Finished,
+}
+
+// The name that the functions that resume and yield from a coroutine find it
+// by in their environments, which no program can refer to.
+const COROUTINE: &str = "<coroutine>";
+
+// A function that runs a step at a time on the virtual machine that resumes
+// it, with a stack of its own, until it yields a value or returns one.
+// Coroutines are only equal to themselves.
+pub struct Coroutine {
+    task: Mutex<Task>,
+}
+
+impl Coroutine {
+    // The function that resumes a new coroutine, which calls fun with the
+    // function that yields from it. The code of both is shared by every
+    // coroutine, and they are named resume and yield in profiles.
+    pub fn start(fun: Value, resume: &Arc<Chunk>, yielder: &Arc<Chunk>) -> Value {
+        let coroutine = Arc::new(Coroutine {
+            task: Mutex::new(Task::Running),
+        });
+        let closure = |name: &str, chunk: &Arc<Chunk>, handle: CoroutineRef| {
+            let mut env = Environment::new();
+            env.fun = Some((name.to_string(), Arc::clone(chunk)));
+            env.values
+                .insert(COROUTINE.to_string(), Value::Coroutine(handle));
+            Value::Function(Arc::clone(chunk), Arc::new(env))
+        };
+        let resume = closure(
+            "resume",
+            resume,
+            CoroutineRef::Strong(Arc::clone(&coroutine)),
+        );
+        let yielder = closure(
+            "yield",
+            yielder,
+            CoroutineRef::Weak(Arc::downgrade(&coroutine)),
+        );
+        coroutine.set(Task::Start(fun, yielder));
+        resume
+    }
+
+    fn take(&self) -> Task {
+        match self.task.lock() {
+            Ok(mut task) => std::mem::replace(&mut *task, Task::Running),
+            Err(_) => Task::Finished,
+        }
+    }
+
+    fn set(&self, to: Task) {
+        if let Ok(mut task) = self.task.lock() {
+            *task = to;
+        }
+    }
+}
+
+// How the functions that resume and yield from a coroutine refer to it. The
+// coroutine holds on to the function it yields with until it starts, so that
+// one only refers to it weakly, or the two would keep each other alive.
+#[derive(Clone)]
+pub enum CoroutineRef {
+    Strong(Arc<Coroutine>),
+    Weak(Weak<Coroutine>),
+}
+
+impl CoroutineRef {
+    fn get(&self) -> Option<Arc<Coroutine>> {
+        match self {
+            CoroutineRef::Strong(coroutine) => Some(Arc::clone(coroutine)),
+            CoroutineRef::Weak(coroutine) => coroutine.upgrade(),
+        }
+    }
+}
+
+impl PartialEq for CoroutineRef {
+    fn eq(&self, other: &CoroutineRef) -> bool {
+        let ptr = |handle: &CoroutineRef| match handle {
+            CoroutineRef::Strong(coroutine) => Arc::as_ptr(coroutine),
+            CoroutineRef::Weak(coroutine) => coroutine.as_ptr(),
+        };
+        std::ptr::eq(ptr(self), ptr(other))
+    }
+}
+
+impl fmt::Debug for CoroutineRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Coroutine")
+    }
+}
+
+// The coroutine that the function running in env resumes or yields from, if
+// it is still around.
+fn coroutine_of(env: &Environment) -> Option<Arc<Coroutine>> {
+    match env.values.get(COROUTINE) {
+        Some(Value::Coroutine(handle)) => handle.get(),
+        _ => unreachable!(),
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Boolean(bool),
-    Datatype(String, String, Box<Value>),
-    Function(usize, Environment),
+    Channel(Arc<Channel>),
+    Char(char),
+    Coroutine(CoroutineRef),
+    // The value a variant holds is shared, so passing a list or tree along
+    // doesn't copy it.
+    Datatype(String, String, Arc<Value>),
+    // Environments are shared between closures and the calls made to them.
+    Function(Arc<Chunk>, Arc<Environment>),
     Integer(i64),
+    Native(String),
+    // Fields are sorted by name, as they are in the record's type.
+    Record(Vec<(String, Value)>),
+    String(String),
     Tuple(Vec<Value>),
     Unit,
 }
@@ -129,6 +443,9 @@ impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::Boolean(b) => write!(f, "{}", b),
+            Value::Channel(_) => write!(f, "(channel)"),
+            Value::Coroutine(_) => write!(f, "(coroutine)"),
+            Value::Char(c) => write!(f, "{}", parser::escape_char(*c)),
             Value::Datatype(n, _, v) => {
                 if let Value::Unit = v.borrow() {
                     write!(f, "{}", n)
@@ -136,8 +453,26 @@ impl fmt::Display for Value {
                     write!(f, "{} {}", n, v)
                 }
             }
-            Value::Function(ip, _) => write!(f, "(lambda @{})", ip),
+            Value::Function(chunk, _) => match &chunk.signature {
+                Some((typ, span)) if *span != parser::Span::unknown() => {
+                    write!(f, "fn {} @ {}:{}", typ, span.line, span.col)
+                }
+                Some((typ, _)) => write!(f, "fn {}", typ),
+                None => write!(f, "(lambda)"),
+            },
             Value::Integer(v) => write!(f, "{}", v),
+            Value::Native(name) => write!(f, "(native {})", name),
+            Value::Record(fields) => {
+                write!(f, "{{")?;
+                for i in 0..fields.len() {
+                    write!(f, "{} = {}", fields[i].0, fields[i].1)?;
+                    if i + 1 != fields.len() {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Value::String(s) => write!(f, "{}", parser::escape(s)),
             Value::Tuple(elements) => {
                 write!(f, "(")?;
                 for i in 0..elements.len() {
@@ -153,28 +488,372 @@ impl fmt::Display for Value {
     }
 }
 
+// Integers are ordered numerically, characters by code point, strings by their
+// characters and tuples by their first differing element. Other values are
+// never ordered.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Integer(x), Value::Integer(y)) => x.partial_cmp(y),
+            (Value::Char(x), Value::Char(y)) => x.partial_cmp(y),
+            (Value::String(x), Value::String(y)) => x.partial_cmp(y),
+            (Value::Tuple(x), Value::Tuple(y)) => x.partial_cmp(y),
+            _ => None,
+        }
+    }
+}
+
+// Whether a value has a type, where any part of the type that is any matches
+// every value, and a union matches values of any of its members. Function
+// types can't be checked.
+fn has_type(value: &Value, typ: &typeinfer::Type) -> bool {
+    match (value, typ) {
+        (_, typeinfer::Type::Any) => true,
+        (Value::Boolean(_), typeinfer::Type::Boolean)
+        | (Value::Char(_), typeinfer::Type::Char)
+        | (Value::Integer(_), typeinfer::Type::Integer)
+        | (Value::String(_), typeinfer::Type::String)
+        | (Value::Unit, typeinfer::Type::Unit) => true,
+        (Value::Datatype(name, _, _), typeinfer::Type::Datatype(typ)) => name == typ,
+        (value, typeinfer::Type::Union(members)) => {
+            members.iter().any(|member| has_type(value, member))
+        }
+        (Value::Record(fields), typeinfer::Type::Record(types)) => {
+            fields.len() == types.len()
+                && fields
+                    .iter()
+                    .zip(types.iter())
+                    .all(|(field, typ)| field.0 == typ.0 && has_type(&field.1, &typ.1))
+        }
+        (Value::Tuple(elements), typeinfer::Type::Tuple(types)) => {
+            elements.len() == types.len()
+                && elements
+                    .iter()
+                    .zip(types.iter())
+                    .all(|(element, typ)| has_type(element, typ))
+        }
+        _ => false,
+    }
+}
+
+// The type of a value, as far as it can be told from the value alone.
+fn type_name(value: &Value) -> String {
+    match value {
+        Value::Boolean(_) => "boolean".to_string(),
+        Value::Channel(_) => "a channel".to_string(),
+        Value::Coroutine(_) => "a coroutine".to_string(),
+        Value::Char(_) => "char".to_string(),
+        Value::Datatype(name, _, _) => name.to_string(),
+        Value::Function(chunk, _) => match &chunk.signature {
+            Some((typ, _)) => typ.to_string(),
+            None => "a function".to_string(),
+        },
+        Value::Integer(_) => "integer".to_string(),
+        Value::Native(_) => "a function".to_string(),
+        Value::Record(fields) => {
+            let types: Vec<String> = fields
+                .iter()
+                .map(|field| format!("{}: {}", field.0, type_name(&field.1)))
+                .collect();
+            format!("{{{}}}", types.join(", "))
+        }
+        Value::String(_) => "string".to_string(),
+        Value::Tuple(elements) => {
+            let types: Vec<String> = elements.iter().map(type_name).collect();
+            format!("({})", types.join(", "))
+        }
+        Value::Unit => "unit".to_string(),
+    }
+}
+
+// Whether a value is or contains a function. The type checker rejects
+// comparing these, but a datatype whose constructor takes any type can still
+// hide one.
+fn holds_function(value: &Value) -> bool {
+    match value {
+        Value::Function(_, _) | Value::Native(_) => true,
+        Value::Datatype(_, _, value) => holds_function(value),
+        Value::Record(fields) => fields.iter().any(|field| holds_function(&field.1)),
+        Value::Tuple(elements) => elements.iter().any(holds_function),
+        _ => false,
+    }
+}
+
+pub type Output = Arc<Mutex<dyn io::Write + Send>>;
+
+// Functions provided by the host. Arguments that are tuples are passed as
+// separate values. They are shared with the virtual machines that pmap runs
+// functions on.
+pub type NativeFn = Arc<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
+
+pub type NativeFuture = Pin<Box<dyn Future<Output = Result<Value, String>> + Send>>;
+
+pub type AsyncNativeFn = Arc<dyn Fn(&[Value]) -> NativeFuture + Send + Sync>;
+
+#[derive(Clone)]
+pub enum Native {
+    Blocking(NativeFn),
+    // Calls suspend the virtual machine until the future completes, which
+    // requires evaluating with run_async.
+    Async(AsyncNativeFn),
+}
+
+// There is nowhere for output to go on wasm32 unless the embedder provides
+// it.
+#[cfg(not(target_arch = "wasm32"))]
+fn default_output(out: impl io::Write + Send + 'static) -> Output {
+    Arc::new(Mutex::new(out))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_output(_: impl io::Write + Send + 'static) -> Output {
+    Arc::new(Mutex::new(io::sink()))
+}
+
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    env: Environment,
+    generated: usize,
+    stack: usize,
+}
+
+// Figures for monitoring a long lived virtual machine. Values are reference
+// counted rather than garbage collected, so there are no collections to count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Statistics {
+    // Bindings in the top level environment, including native functions.
+    pub env_entries: usize,
+    // Instructions and constants generated so far.
+    pub instructions: usize,
+    pub constants: usize,
+    // Instructions executed so far.
+    pub executed: usize,
+    // Values on the stack now, and the most there have ever been.
+    pub stack: usize,
+    pub max_stack: usize,
+    // Values built on the heap so far: strings, tuples, datatype values and
+    // closures, whether made by instructions or returned by native functions.
+    pub allocated: usize,
+}
+
+// Wall clock time spent in each phase of evaluation, added up over every
+// program evaluated while it is being measured. Parsing happens before the
+// virtual machine sees a program, so callers add it themselves.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timings {
+    pub parse: Duration,
+    pub typecheck: Duration,
+    pub codegen: Duration,
+    pub run: Duration,
+}
+
+pub const DEFAULT_MAX_DEPTH: usize = 150;
+
+const TIME_CHECK_INTERVAL: usize = 1024;
+
+// How long receive waits for a value between checks of the time limit and of
+// whether anything is left running to send one.
+const RECEIVE_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct VirtualMachine {
-    pub instructions: Vec<Opcode>,
+    // The code running now and where in it. Calls save the caller's chunk on
+    // the callstack, along with where to return to in it.
+    pub chunk: Arc<Chunk>,
     pub ip: usize,
+    // Literals loaded by Const instructions. Integer literals are shared
+    // between every instruction that uses them.
+    pub constants: Vec<Value>,
+    integers: HashMap<i64, usize>,
     pub stack: Vec<Value>,
-    pub callstack: Vec<(usize, Environment, usize, usize)>,
+    pub callstack: Vec<(Arc<Chunk>, Arc<Environment>, usize, usize)>,
+    // Where the code that resumed each coroutine running now is up to, the
+    // innermost last, to go back to when it yields or finishes.
+    resumers: Vec<(Context, Arc<Coroutine>)>,
+    generated: usize,
+    executed: usize,
+    max_stack: usize,
+    allocated: usize,
+    // What allocated was when the program running now started, since the
+    // limit applies to each program separately.
+    allocated_before: usize,
+
+    // The status a program asked to exit with, if it called exit.
+    pub exit_status: Option<i32>,
 
     pub env: Environment,
 
-    pub line: usize,
-    pub col: usize,
+    pub span: parser::Span,
+
+    pub dump_ast: bool,
+
+    // How deeply expressions may be nested, so that compiling a program
+    // can't overflow the stack. The default is safe on a main thread with an
+    // 8 MB stack, even in debug builds.
+    pub max_depth: usize,
+
+    // How many values built on the heap, as counted by the statistics, each
+    // program may allocate before it is stopped, or None for no limit.
+    pub max_allocations: Option<usize>,
+
+    // How many instructions each program may execute, and how long it may
+    // run for, before it is stopped, or None for no limit.
+    pub max_instructions: Option<usize>,
+    pub max_time: Option<Duration>,
+    // Where the program running now started, to measure those limits from.
+    executed_before: usize,
+    deadline: Option<Instant>,
+
+    // Spawned functions still running, shared with the virtual machines they
+    // run on, so that receiving on a channel that nothing is left to send on
+    // fails rather than waiting forever.
+    tasks: Arc<AtomicUsize>,
+
+    // Optimization passes run on each program as it is compiled, unless
+    // coverage is being collected.
+    pub passes: Vec<Box<dyn optimize::Pass>>,
+
+    // Compiled top level function definitions, so that evaluating them again
+    // unchanged reuses their code.
+    pub cache: HashMap<u64, cache::Entry>,
+
+    // Execution counts for each tagged source position, when collecting
+    // coverage.
+    pub coverage: Option<HashMap<parser::Span, usize>>,
+    // Instructions executed in each stack of functions, when profiling.
+    pub profile: Option<Profile>,
+    // Samples of the function and instruction running now, taken
+    // periodically while sampling.
+    pub sampler: Option<Sampler>,
+    // Time spent in each phase, when measuring it. The clock is only read
+    // while this is set.
+    pub timings: Option<Timings>,
+
+    pub natives: HashMap<String, (usize, typeinfer::Type, Native)>,
+    // Other values provided by the host, such as variants of registered
+    // datatypes without parameters.
+    pub values: HashMap<String, (typeinfer::Type, Value)>,
+
+    // Where output goes, the process stdout and stderr by default. These are
+    // shared so that native functions can hold on to them.
+    pub stdout: Output,
+    pub stderr: Output,
 }
 
 impl VirtualMachine {
-    #[allow(clippy::cognitive_complexity)]
     pub fn run(&mut self) -> Result<(), codegen::InterpreterError> {
-        while self.ip < self.instructions.len() {
-            match &self.instructions[self.ip] {
+        let executed = self.execute();
+        self.flush_profile();
+        if executed.is_err() {
+            self.unwind_coroutines();
+        }
+        match executed? {
+            Some((name, _)) => Err(codegen::InterpreterError::new(
+                Code::AsyncCall,
+                "Asynchronous functions can only be called with eval_async.",
+                self.span,
+            )
+            .with_note(&format!("{} is asynchronous.", name))),
+            None => Ok(()),
+        }
+    }
+
+    // Runs until the program finishes, waiting for asynchronous native
+    // functions as they are called.
+    pub async fn run_async(&mut self) -> Result<(), codegen::InterpreterError> {
+        loop {
+            let executed = self.execute();
+            self.flush_profile();
+            if executed.is_err() {
+                self.unwind_coroutines();
+            }
+            match executed? {
+                Some((name, future)) => {
+                    let result = future.await;
+                    self.push_result(&name, result)?;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn flush_profile(&mut self) {
+        if let Some(profile) = &mut self.profile {
+            profile.flush(self.executed);
+        }
+    }
+
+    fn push_result(
+        &mut self,
+        name: &str,
+        result: Result<Value, String>,
+    ) -> Result<(), codegen::InterpreterError> {
+        match result {
+            Ok(value) => {
+                if let Value::Channel(_)
+                | Value::Datatype(..)
+                | Value::Function(..)
+                | Value::Record(_)
+                | Value::String(_)
+                | Value::Tuple(_) = value
+                {
+                    allocate!(self);
+                }
+                self.stack.push(value);
+                Ok(())
+            }
+            Err(msg) => Err(
+                codegen::InterpreterError::new(Code::NativeError, &msg, self.span)
+                    .with_note(&format!("Raised by native function {}.", name)),
+            ),
+        }
+    }
+
+    // Runs until the program finishes or calls an asynchronous native
+    // function, in which case the call's future is returned and execution
+    // resumes after it.
+    #[allow(clippy::cognitive_complexity)]
+    fn execute(&mut self) -> Result<Option<(String, NativeFuture)>, codegen::InterpreterError> {
+        while self.ip < self.chunk.code.len() {
+            self.executed += 1;
+            self.max_stack = self.max_stack.max(self.stack.len());
+            if let Some(max) = self.max_instructions {
+                if self.executed - self.executed_before > max {
+                    err!(
+                        self,
+                        Code::EvaluationLimit,
+                        format!("Evaluation exceeded limit: more than {} instructions.", max)
+                    )
+                }
+            }
+            // The clock is only read once every so many instructions.
+            if let (Some(deadline), Some(max)) = (self.deadline, self.max_time) {
+                if self.executed.is_multiple_of(TIME_CHECK_INTERVAL) && Instant::now() > deadline {
+                    err!(
+                        self,
+                        Code::EvaluationLimit,
+                        format!(
+                            "Evaluation exceeded limit: more than {} ms.",
+                            max.as_millis()
+                        )
+                    )
+                }
+            }
+            if let Some(sampler) = &mut self.sampler {
+                if sampler.due() {
+                    let function = match self.callstack.last() {
+                        Some((_, env, _, _)) => profile::frame_name(&self.chunk, env),
+                        None => profile::TOP_LEVEL.to_string(),
+                    };
+                    sampler.record(function, self.ip);
+                }
+            }
+            match &self.chunk.code[self.ip] {
                 Opcode::Add => match self.stack.pop() {
                     Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Integer(x + y));
-                        }
+                        Some(Value::Integer(y)) => match x.checked_add(y) {
+                            Some(z) => self.stack.push(Value::Integer(z)),
+                            None => err!(self, Code::IntegerOverflow, "Integer overflow."),
+                        },
                         _ => unreachable!(),
                     },
                     _ => unreachable!(),
@@ -197,23 +876,62 @@ impl VirtualMachine {
                 Opcode::Bconst(b) => {
                     self.stack.push(Value::Boolean(*b));
                 }
+                Opcode::Cconst(c) => {
+                    self.stack.push(Value::Char(*c));
+                }
                 Opcode::Call => match self.stack.pop() {
-                    Some(Value::Function(ip, env)) => {
-                        let return_ip = self.ip;
-                        self.ip = ip;
+                    Some(Value::Function(chunk, env)) => {
+                        if let Some(profile) = &mut self.profile {
+                            profile.enter(profile::frame_name(&chunk, &env), self.executed);
+                        }
+                        let caller = std::mem::replace(&mut self.chunk, chunk);
                         self.callstack
-                            .push((ip, env, self.stack.len() - 1, return_ip));
+                            .push((caller, env, self.stack.len() - 1, self.ip));
+                        self.ip = 0;
                         continue;
                     }
+                    Some(Value::Native(name)) => match self.natives.get(&name) {
+                        Some((arity, _, native)) => {
+                            // The call is counted as the native function's
+                            // only instruction.
+                            if let Some(profile) = &mut self.profile {
+                                profile.enter(name.to_string(), self.executed - 1);
+                                profile.leave(self.executed);
+                            }
+                            let args = match self.stack.pop() {
+                                Some(Value::Tuple(elements)) if *arity > 1 => elements,
+                                Some(value) => vec![value],
+                                None => unreachable!(),
+                            };
+                            match native {
+                                Native::Blocking(fun) => {
+                                    let result = fun(&args);
+                                    self.push_result(&name, result)?;
+                                }
+                                Native::Async(fun) => {
+                                    let future = fun(&args);
+                                    self.ip += 1;
+                                    return Ok(Some((name, future)));
+                                }
+                            }
+                        }
+                        None => unreachable!(),
+                    },
                     _ => unreachable!(),
                 },
+                Opcode::Const(idx) => {
+                    self.stack.push(self.constants[*idx].clone());
+                }
                 Opcode::Div => match self.stack.pop() {
                     Some(Value::Integer(x)) => match self.stack.pop() {
                         Some(Value::Integer(y)) => {
                             if y == 0 {
-                                err!(self, "Division by zero.")
+                                err!(self, Code::DivisionByZero, "Division by zero.")
+                            }
+                            match x.checked_div(y) {
+                                Some(z) => self.stack.push(Value::Integer(z)),
+                                None => err!(self, Code::IntegerOverflow, "Integer overflow."),
                             }
-                            self.stack.push(Value::Integer(x / y));
                         }
                         _ => unreachable!(),
                     },
@@ -226,69 +944,62 @@ impl VirtualMachine {
                     }
                     _ => unreachable!(),
                 },
+                // Stops the program by failing with the Exit code, so that
+                // nothing after it runs and evaluation unwinds as for any
+                // other error.
+                Opcode::Exit => match self.stack.pop() {
+                    Some(Value::Integer(status)) => match i32::try_from(status) {
+                        Ok(status) if (0..=255).contains(&status) => {
+                            self.exit_status = Some(status);
+                            err!(self, Code::Exit, format!("Exited with status {}.", status))
+                        }
+                        _ => err!(
+                            self,
+                            Code::NativeError,
+                            format!("exit: status {} is not from 0 to 255.", status)
+                        ),
+                    },
+                    _ => unreachable!(),
+                },
                 Opcode::ExtVal => match self.stack.pop() {
                     Some(Value::Datatype(_, _, v)) => {
-                        if let Value::Tuple(elements) = *v {
-                            for element in elements {
-                                self.stack.push(element);
-                            }
-                        } else {
-                            self.stack.push(*v);
-                        }
+                        self.stack
+                            .push(Arc::try_unwrap(v).unwrap_or_else(|v| Value::clone(&v)));
                     }
                     _ => unreachable!(),
                 },
                 Opcode::Equal => match self.stack.pop() {
                     Some(x) => match self.stack.pop() {
                         Some(y) => {
+                            if holds_function(&x) || holds_function(&y) {
+                                err!(self, Code::CompareFunctions, "Functions can't be compared.")
+                            }
                             self.stack.push(Value::Boolean(x == y));
                         }
                         _ => unreachable!(),
                     },
                     _ => unreachable!(),
                 },
-                Opcode::Dconst(typ, ctor, count) => {
-                    if *count == 0 {
-                        unreachable!();
-                    } else if *count == 1 {
-                        match self.stack.pop() {
-                            Some(value) => {
-                                self.stack.push(Value::Datatype(
-                                    typ.to_string(),
-                                    ctor.to_string(),
-                                    Box::new(value),
-                                ));
-                            }
-                            _ => unreachable!(),
-                        }
-                    } else {
-                        let mut elements = Vec::new();
-                        for _ in 0..*count {
-                            match self.stack.pop() {
-                                Some(value) => {
-                                    elements.push(value);
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                        elements.reverse();
+                Opcode::Dconst(typ, ctor) => match self.stack.pop() {
+                    Some(value) => {
+                        allocate!(self);
                         self.stack.push(Value::Datatype(
                             typ.to_string(),
                             ctor.to_string(),
-                            Box::new(Value::Tuple(elements)),
+                            Arc::new(value),
                         ));
                     }
-                }
-                Opcode::Fconst(id, ip, upvalues) => {
-                    let len = self.callstack.len();
-                    let mut env = if len > 0 {
-                        self.callstack[len - 1].1.clone()
-                    } else {
-                        self.env.clone()
+                    _ => unreachable!(),
+                },
+                Opcode::Fconst(id, chunk, upvalues) => {
+                    let mut env = match self.callstack.last() {
+                        Some((_, env, _, _)) => Environment::clone(env),
+                        None => self.env.clone(),
                     };
-                    if let Some((ident, ip)) = &env.fun {
-                        env.values
-                            .insert(ident.to_string(), Value::Function(*ip, env.clone()));
+                    if let Some((ident, chunk)) = &env.fun {
+                        allocate!(self);
+                        let fun = Value::Function(Arc::clone(chunk), Arc::new(env.clone()));
+                        env.values.insert(ident.to_string(), fun);
                     }
                     for upvalue in upvalues {
                         if let Some((_, _, sp, _)) = self.callstack.last() {
@@ -300,53 +1011,57 @@ impl VirtualMachine {
                         }
                     }
                     if let Some(id) = id {
-                        env.fun = Some((id.clone(), *ip));
+                        env.fun = Some((id.clone(), Arc::clone(chunk)));
                     }
-                    self.stack.push(Value::Function(*ip, env));
+                    allocate!(self);
+                    self.stack
+                        .push(Value::Function(Arc::clone(chunk), Arc::new(env)));
                 }
                 Opcode::GetEnv(id) => {
-                    let len = self.callstack.len();
-                    let env = if len > 0 {
-                        &self.callstack[len - 1].1
-                    } else {
-                        &self.env
-                    };
-                    match env.values.get(id) {
-                        Some(x) => {
-                            self.stack.push(x.clone());
-                        }
-                        None => {
-                            if let Some((ident, ip)) = &env.fun {
-                                if id == ident {
-                                    self.stack.push(Value::Function(*ip, env.clone()));
+                    let value = match self.callstack.last() {
+                        Some((_, env, _, _)) => match env.values.get(id) {
+                            Some(x) => x.clone(),
+                            None => match &env.fun {
+                                Some((ident, chunk)) if id == ident => {
+                                    Value::Function(Arc::clone(chunk), Arc::clone(env))
                                 }
-                            } else {
-                                unreachable!()
-                            }
-                        }
-                    }
+                                _ => unreachable!(),
+                            },
+                        },
+                        None => match self.env.values.get(id) {
+                            Some(x) => x.clone(),
+                            None => unreachable!(),
+                        },
+                    };
+                    self.stack.push(value);
                 }
-                Opcode::Greater => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x > y));
-                        }
-                        _ => unreachable!(),
-                    },
+                Opcode::Greater => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(x), Some(y)) => self.stack.push(Value::Boolean(x > y)),
                     _ => unreachable!(),
                 },
-                Opcode::GreaterEqual => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x >= y));
+                Opcode::GreaterEqual => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(x), Some(y)) => self.stack.push(Value::Boolean(x >= y)),
+                    _ => unreachable!(),
+                },
+                // Strings are indexed by character rather than byte, counting
+                // from zero.
+                Opcode::Index => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(Value::Integer(i)), Some(Value::String(s))) => {
+                        match usize::try_from(i).ok().and_then(|i| s.chars().nth(i)) {
+                            Some(c) => self.stack.push(Value::Char(c)),
+                            None => err!(
+                                self,
+                                Code::IndexOutOfRange,
+                                format!(
+                                    "Index out of range: {} for length {}.",
+                                    i,
+                                    s.chars().count()
+                                )
+                            ),
                         }
-                        _ => unreachable!(),
-                    },
+                    }
                     _ => unreachable!(),
                 },
-                Opcode::Iconst(i) => {
-                    self.stack.push(Value::Integer(*i));
-                }
                 Opcode::Jmp(offset) => {
                     self.ip = (self.ip as i64 + offset) as usize;
                     continue;
@@ -360,31 +1075,70 @@ impl VirtualMachine {
                     }
                     _ => unreachable!(),
                 },
-                Opcode::Less => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x < y));
-                        }
-                        _ => unreachable!(),
-                    },
+                Opcode::Less => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(x), Some(y)) => self.stack.push(Value::Boolean(x < y)),
                     _ => unreachable!(),
                 },
-                Opcode::LessEqual => match self.stack.pop() {
-                    Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Boolean(x <= y));
+                Opcode::MakeRecord(names) => {
+                    let values = self.stack.split_off(self.stack.len() - names.len());
+                    let mut fields: Vec<(String, Value)> =
+                        names.iter().cloned().zip(values).collect();
+                    fields.sort_by(|x, y| x.0.cmp(&y.0));
+                    allocate!(self);
+                    self.stack.push(Value::Record(fields));
+                }
+                Opcode::MakeTuple(n) => {
+                    let elements = self.stack.split_off(self.stack.len() - n);
+                    allocate!(self);
+                    self.stack.push(Value::Tuple(elements));
+                }
+                // Like Index, by character, copying the slice from the string
+                // at once.
+                Opcode::Slice => match (self.stack.pop(), self.stack.pop(), self.stack.pop()) {
+                    (
+                        Some(Value::Integer(end)),
+                        Some(Value::Integer(start)),
+                        Some(Value::String(s)),
+                    ) => {
+                        let len = s.chars().count();
+                        match (usize::try_from(start), usize::try_from(end)) {
+                            (Ok(lower), Ok(upper)) if lower <= upper && upper <= len => {
+                                let slice = s.chars().skip(lower).take(upper - lower).collect();
+                                allocate!(self);
+                                self.stack.push(Value::String(slice));
+                            }
+                            _ => err!(
+                                self,
+                                Code::IndexOutOfRange,
+                                format!(
+                                    "Slice out of range: {}..{} for length {}.",
+                                    start, end, len
+                                )
+                            ),
                         }
-                        _ => unreachable!(),
-                    },
+                    }
+                    _ => unreachable!(),
+                },
+                // Moves the elements of a tuple onto the stack, first on the
+                // bottom, for a MakeTuple to collect.
+                Opcode::Spread => match self.stack.pop() {
+                    Some(Value::Tuple(elements)) => self.stack.extend(elements),
+                    _ => unreachable!(),
+                },
+                Opcode::LessEqual => match (self.stack.pop(), self.stack.pop()) {
+                    (Some(x), Some(y)) => self.stack.push(Value::Boolean(x <= y)),
                     _ => unreachable!(),
                 },
                 Opcode::Mod => match self.stack.pop() {
                     Some(Value::Integer(x)) => match self.stack.pop() {
                         Some(Value::Integer(y)) => {
                             if y == 0 {
-                                err!(self, "Division by zero.")
+                                err!(self, Code::DivisionByZero, "Division by zero.")
+                            }
+                            match x.checked_rem(y) {
+                                Some(z) => self.stack.push(Value::Integer(z)),
+                                None => err!(self, Code::IntegerOverflow, "Integer overflow."),
                             }
-                            self.stack.push(Value::Integer(x % y));
                         }
                         _ => unreachable!(),
                     },
@@ -392,16 +1146,40 @@ impl VirtualMachine {
                 },
                 Opcode::Mul => match self.stack.pop() {
                     Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Integer(x * y));
-                        }
+                        Some(Value::Integer(y)) => match x.checked_mul(y) {
+                            Some(z) => self.stack.push(Value::Integer(z)),
+                            None => err!(self, Code::IntegerOverflow, "Integer overflow."),
+                        },
                         _ => unreachable!(),
                     },
                     _ => unreachable!(),
                 },
+                // Matches that don't handle every variant fail when none of
+                // their arms apply.
+                Opcode::NoMatch => match self.stack.pop() {
+                    Some(Value::Datatype(_, variant, _)) => {
+                        err!(
+                            self,
+                            Code::NoMatch,
+                            format!("No match arm for variant: {}.", variant)
+                        )
+                    }
+                    // Matches on unions only name types.
+                    Some(value) => {
+                        err!(
+                            self,
+                            Code::NoMatch,
+                            format!("No match arm for type: {}.", type_name(&value))
+                        )
+                    }
+                    None => unreachable!(),
+                },
                 Opcode::NotEqual => match self.stack.pop() {
                     Some(x) => match self.stack.pop() {
                         Some(y) => {
+                            if holds_function(&x) || holds_function(&y) {
+                                err!(self, Code::CompareFunctions, "Functions can't be compared.")
+                            }
                             self.stack.push(Value::Boolean(x != y));
                         }
                         _ => unreachable!(),
@@ -423,30 +1201,125 @@ impl VirtualMachine {
                     },
                     _ => unreachable!(),
                 },
+                // Copies the value n below the top of the stack, and Store
+                // replaces it with the value on top.
+                Opcode::Peek(n) => {
+                    let value = self.stack[self.stack.len() - 1 - n].clone();
+                    self.stack.push(value);
+                }
+                // The argument is left for Ret to remove.
+                Opcode::Pmap => {
+                    let mapped = match self.callstack.last() {
+                        Some((_, _, sp, _)) => match &self.stack[*sp] {
+                            Value::Tuple(args) => match &args[..] {
+                                [fun, Value::Tuple(elements)] => self.pmap(fun, elements),
+                                _ => unreachable!(),
+                            },
+                            _ => unreachable!(),
+                        },
+                        None => unreachable!(),
+                    };
+                    let mut results = Vec::new();
+                    for (result, executed, allocated, exit_status) in mapped {
+                        self.executed += executed;
+                        self.allocated += allocated;
+                        if exit_status.is_some() {
+                            self.exit_status = exit_status;
+                        }
+                        results.push(result?);
+                    }
+                    allocate!(self);
+                    self.stack.push(Value::Tuple(results));
+                }
                 Opcode::Pop => match self.stack.pop() {
                     Some(_) => {}
                     _ => unreachable!(),
                 },
+                // The argument is left for Ret to remove.
+                Opcode::Receive => {
+                    let channel = match self.callstack.last() {
+                        Some((_, _, sp, _)) => match &self.stack[*sp] {
+                            Value::Channel(channel) => Arc::clone(channel),
+                            _ => unreachable!(),
+                        },
+                        None => unreachable!(),
+                    };
+                    let value = self.receive(&channel)?;
+                    self.stack.push(value);
+                }
+                // The argument is left for Ret to remove.
+                Opcode::Spawn => {
+                    let fun = match self.callstack.last() {
+                        Some((_, _, sp, _)) => self.stack[*sp].clone(),
+                        None => unreachable!(),
+                    };
+                    self.spawn(fun)?;
+                    self.stack.push(Value::Unit);
+                }
+                // Runs the coroutine on its own stack, from where it last
+                // yielded, until it yields or finishes, which pushes the
+                // result of the call to resume onto this stack.
+                Opcode::Resume => {
+                    let coroutine = match self
+                        .callstack
+                        .last()
+                        .and_then(|(_, env, _, _)| coroutine_of(env))
+                    {
+                        Some(coroutine) => coroutine,
+                        None => unreachable!(),
+                    };
+                    let mut context = match coroutine.take() {
+                        Task::Start(fun, yielder) => Context {
+                            chunk: Arc::new(Chunk::new(vec![Opcode::Call, Opcode::Yield])),
+                            ip: 0,
+                            stack: vec![yielder, fun],
+                            callstack: Vec::new(),
+                            frames: Vec::new(),
+                        },
+                        Task::Suspended(context) => context,
+                        Task::Running => err!(
+                            self,
+                            Code::NativeError,
+                            "resume: the coroutine is already running."
+                        ),
+                        Task::Finished => {
+                            coroutine.set(Task::Finished);
+                            err!(
+                                self,
+                                Code::NativeError,
+                                "resume: the coroutine has finished."
+                            )
+                        }
+                    };
+                    if let Some(profile) = &mut self.profile {
+                        profile.resume(std::mem::take(&mut context.frames), self.executed);
+                    }
+                    self.ip += 1;
+                    let resumer = self.switch(context);
+                    self.resumers.push((resumer, coroutine));
+                    continue;
+                }
+                // The n arguments end at sp, just below the return value.
+                // Constructors consume their own arguments and return with
+                // n of zero.
                 Opcode::Ret(n) => match self.callstack.pop() {
-                    Some((_, _, sp, ip)) => {
-                        self.stack.drain(sp..sp + n);
+                    Some((chunk, _, sp, ip)) => {
+                        if let Some(profile) = &mut self.profile {
+                            profile.leave(self.executed);
+                        }
+                        if *n > 0 {
+                            self.stack.drain(sp + 1 - n..sp + 1);
+                        }
+                        self.chunk = chunk;
                         self.ip = ip;
                     }
                     None => unreachable!(),
                 },
-                Opcode::Rot => {
-                    if self.stack.len() < 3 {
-                        unreachable!();
-                    }
-                    if let Some(a) = self.stack.pop() {
-                        self.stack.insert(self.stack.len() - 2, a);
-                    }
-                }
                 Opcode::SetEnv(id) => match self.stack.pop() {
                     Some(x) => {
                         let len = self.callstack.len();
                         let values = if len > 0 {
-                            &mut self.callstack[len - 1].1.values
+                            &mut Arc::make_mut(&mut self.callstack[len - 1].1).values
                         } else {
                             &mut self.env.values
                         };
@@ -454,43 +1327,637 @@ impl VirtualMachine {
                     }
                     _ => unreachable!(),
                 },
-                Opcode::Srcpos(line, col) => {
-                    self.line = *line;
-                    self.col = *col;
+                Opcode::Store(n) => match self.stack.pop() {
+                    Some(value) => {
+                        let len = self.stack.len();
+                        self.stack[len - 1 - n] = value;
+                    }
+                    _ => unreachable!(),
+                },
+                Opcode::Srcpos(span) => {
+                    self.span = *span;
+                    if let Some(hits) = &mut self.coverage {
+                        *hits.entry(*span).or_insert(0) += 1;
+                    }
                 }
                 Opcode::Sub => match self.stack.pop() {
                     Some(Value::Integer(x)) => match self.stack.pop() {
-                        Some(Value::Integer(y)) => {
-                            self.stack.push(Value::Integer(x - y));
-                        }
+                        Some(Value::Integer(y)) => match x.checked_sub(y) {
+                            Some(z) => self.stack.push(Value::Integer(z)),
+                            None => err!(self, Code::IntegerOverflow, "Integer overflow."),
+                        },
                         _ => unreachable!(),
                     },
                     _ => unreachable!(),
                 },
+                Opcode::Check(typ) => match self.stack.last() {
+                    Some(value) if has_type(value, typ) => {}
+                    Some(value) => err!(
+                        self,
+                        Code::FailedCheck,
+                        format!(
+                            "Type error: expected {} but found {}.",
+                            typ,
+                            type_name(value)
+                        )
+                    ),
+                    None => unreachable!(),
+                },
+                Opcode::Element(n) => match self.stack.pop() {
+                    Some(Value::Tuple(mut elements)) if *n < elements.len() => {
+                        self.stack.push(elements.swap_remove(*n));
+                    }
+                    _ => unreachable!(),
+                },
+                Opcode::Field(name) => match self.stack.pop() {
+                    Some(Value::Record(mut fields)) => {
+                        match fields.binary_search_by(|field| field.0.as_str().cmp(name)) {
+                            Ok(i) => self.stack.push(fields.swap_remove(i).1),
+                            Err(_) => unreachable!(),
+                        }
+                    }
+                    _ => unreachable!(),
+                },
+                Opcode::IsType(typ) => match self.stack.pop() {
+                    Some(value) => self.stack.push(Value::Boolean(has_type(&value, typ))),
+                    None => unreachable!(),
+                },
+                // Values inside of a variant may not be datatypes at all.
                 Opcode::TypeEq(typ) => match self.stack.pop() {
                     Some(Value::Datatype(_, variant, _)) => {
                         self.stack.push(Value::Boolean(variant == *typ));
                     }
-                    _ => unreachable!(),
+                    Some(_) => self.stack.push(Value::Boolean(false)),
+                    None => unreachable!(),
                 },
                 Opcode::Uconst => {
                     self.stack.push(Value::Unit);
                 }
+                // Replaces a tuple argument with its elements, the first on
+                // top, so that parameters can be addressed by offset.
+                Opcode::Unpack(n) => match self.stack.pop() {
+                    Some(Value::Tuple(elements)) if elements.len() == *n => {
+                        self.stack.extend(elements.into_iter().rev());
+                        let top = self.stack.len() - 1;
+                        match self.callstack.last_mut() {
+                            Some((_, _, sp, _)) => *sp = top,
+                            None => unreachable!(),
+                        }
+                    }
+                    _ => unreachable!(),
+                },
+                // In a call to yield, suspends the coroutine with the value
+                // it was given, to return unit when resumed. At the end of
+                // the coroutine's own code, finishes it with the value its
+                // function returned.
+                Opcode::Yield => {
+                    let (value, suspended) = match self.callstack.last() {
+                        Some((_, env, sp, _)) => {
+                            match (self.resumers.last(), coroutine_of(env)) {
+                                (Some((_, running)), Some(coroutine))
+                                    if Arc::ptr_eq(running, &coroutine) => {}
+                                _ => err!(
+                                    self,
+                                    Code::NativeError,
+                                    "yield: called outside of its coroutine."
+                                ),
+                            }
+                            (self.stack[*sp].clone(), true)
+                        }
+                        None => match self.stack.pop() {
+                            Some(value) => (value, false),
+                            None => unreachable!(),
+                        },
+                    };
+                    let (resumer, coroutine) = match self.resumers.pop() {
+                        Some(resumer) => resumer,
+                        None => unreachable!(),
+                    };
+                    let frames = match &mut self.profile {
+                        Some(profile) => profile.suspend(self.callstack.len(), self.executed),
+                        None => Vec::new(),
+                    };
+                    if suspended {
+                        self.stack.push(Value::Unit);
+                        self.ip += 1;
+                        let mut context = self.switch(resumer);
+                        context.frames = frames;
+                        coroutine.set(Task::Suspended(context));
+                    } else {
+                        self.switch(resumer);
+                        coroutine.set(Task::Finished);
+                    }
+                    allocate!(self);
+                    self.stack
+                        .push(Value::Tuple(vec![Value::Boolean(suspended), value]));
+                    continue;
+                }
             }
             self.ip += 1;
         }
+        self.max_stack = self.max_stack.max(self.stack.len());
+        Ok(None)
+    }
+
+    // Runs from context instead, returning where the code running now is up
+    // to.
+    fn switch(&mut self, context: Context) -> Context {
+        Context {
+            chunk: std::mem::replace(&mut self.chunk, context.chunk),
+            ip: std::mem::replace(&mut self.ip, context.ip),
+            stack: std::mem::replace(&mut self.stack, context.stack),
+            callstack: std::mem::replace(&mut self.callstack, context.callstack),
+            frames: Vec::new(),
+        }
+    }
+
+    // When a program fails in a coroutine, the coroutines running are
+    // abandoned, and the stack of the code that resumed the outermost of
+    // them is restored.
+    fn unwind_coroutines(&mut self) {
+        while let Some((resumer, coroutine)) = self.resumers.pop() {
+            coroutine.set(Task::Finished);
+            self.switch(resumer);
+        }
+    }
+
+    // Calls fun on each element, spreading them over as many threads as there
+    // are processors, each with a virtual machine of its own. Each result is
+    // returned along with the instructions executed and values allocated for
+    // it, to be added to this virtual machine's counts, and the status the
+    // function exited with, if it called exit.
+    #[allow(clippy::type_complexity)]
+    fn pmap(
+        &self,
+        fun: &Value,
+        elements: &[Value],
+    ) -> Vec<(
+        Result<Value, codegen::InterpreterError>,
+        usize,
+        usize,
+        Option<i32>,
+    )> {
+        let threads = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(elements.len());
+        let call = |worker: &mut VirtualMachine, element: &Value| {
+            let (executed, allocated) = (worker.executed, worker.allocated);
+            worker.stack.clear();
+            worker.stack.push(element.clone());
+            worker.stack.push(fun.clone());
+            worker.chunk = Arc::new(Chunk::new(vec![Opcode::Call]));
+            worker.ip = 0;
+            let result = worker.run().map(|_| match worker.stack.pop() {
+                Some(value) => value,
+                None => unreachable!(),
+            });
+            (
+                result,
+                worker.executed - executed,
+                worker.allocated - allocated,
+                worker.exit_status.take(),
+            )
+        };
+        // There is no need for threads for one element, or where there is
+        // only one processor or threads aren't available, as on wasm32.
+        if threads <= 1 {
+            let mut worker = self.worker();
+            return elements
+                .iter()
+                .map(|element| call(&mut worker, element))
+                .collect();
+        }
+        let mut mapped: Vec<Option<_>> = elements.iter().map(|_| None).collect();
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|i| {
+                    let mut worker = self.worker();
+                    let call = &call;
+                    scope.spawn(move || {
+                        elements
+                            .iter()
+                            .enumerate()
+                            .skip(i)
+                            .step_by(threads)
+                            .map(|(j, element)| (j, call(&mut worker, element)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            for handle in handles {
+                match handle.join() {
+                    Ok(results) => {
+                        for (j, result) in results {
+                            mapped[j] = Some(result);
+                        }
+                    }
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            }
+        });
+        mapped
+            .into_iter()
+            .map(|result| match result {
+                Some(result) => result,
+                None => unreachable!(),
+            })
+            .collect()
+    }
+
+    // Calls fun on a thread of its own with a virtual machine of its own. No
+    // one waits for it, so an error it fails with, including exiting, only
+    // stops it, and is written to stderr.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn(&self, fun: Value) -> Result<(), codegen::InterpreterError> {
+        let mut worker = self.worker();
+        self.tasks.fetch_add(1, AtomicOrdering::SeqCst);
+        thread::spawn(move || {
+            worker.stack.push(Value::Unit);
+            worker.stack.push(fun);
+            worker.chunk = Arc::new(Chunk::new(vec![Opcode::Call]));
+            if let Err(err) = worker.run() {
+                if let Ok(mut out) = worker.stderr.lock() {
+                    let _ = writeln!(
+                        out,
+                        "spawn: {} ({}:{})",
+                        err.err, err.span.line, err.span.col
+                    );
+                }
+            }
+            worker.tasks.fetch_sub(1, AtomicOrdering::SeqCst);
+        });
         Ok(())
     }
 
-    pub fn new() -> VirtualMachine {
+    #[cfg(target_arch = "wasm32")]
+    fn spawn(&self, _: Value) -> Result<(), codegen::InterpreterError> {
+        err!(self, Code::NativeError, "spawn: threads are not available.")
+    }
+
+    // Waits for a value on the channel, checking the time limit as it does.
+    // Only spawned functions can send while this one waits, so once none are
+    // running, and nothing has been sent, nothing ever will be.
+    fn receive(&self, channel: &Channel) -> Result<Value, codegen::InterpreterError> {
+        loop {
+            let running = self.tasks.load(AtomicOrdering::SeqCst);
+            if let Some(value) = channel.try_receive() {
+                return Ok(value);
+            }
+            if running == 0 {
+                err!(
+                    self,
+                    Code::NativeError,
+                    "receive: nothing was sent, and nothing is left running to send a value."
+                )
+            }
+            if let (Some(deadline), Some(max)) = (self.deadline, self.max_time) {
+                if Instant::now() > deadline {
+                    err!(
+                        self,
+                        Code::EvaluationLimit,
+                        format!(
+                            "Evaluation exceeded limit: more than {} ms.",
+                            max.as_millis()
+                        )
+                    )
+                }
+            }
+            if let Some(value) = channel.receive_timeout(RECEIVE_CHECK_INTERVAL) {
+                return Ok(value);
+            }
+        }
+    }
+
+    // A virtual machine to run this one's functions on another thread, with
+    // its constants, native functions, output and limits, and counting from
+    // where this one's counts are so that the limits are shared. Functions
+    // carry their own environments, so it needs no bindings.
+    fn worker(&self) -> VirtualMachine {
         VirtualMachine {
-            instructions: Vec::new(),
+            chunk: Arc::new(Chunk::default()),
             ip: 0,
+            constants: self.constants.clone(),
+            integers: HashMap::new(),
             stack: Vec::new(),
             callstack: Vec::new(),
+            resumers: Vec::new(),
+            generated: 0,
+            executed: self.executed,
+            max_stack: 0,
+            allocated: self.allocated,
+            allocated_before: self.allocated_before,
+            exit_status: None,
             env: Environment::new(),
-            line: usize::max_value(),
-            col: usize::max_value(),
+            span: self.span,
+            dump_ast: false,
+            max_depth: self.max_depth,
+            max_allocations: self.max_allocations,
+            max_instructions: self.max_instructions,
+            max_time: self.max_time,
+            executed_before: self.executed_before,
+            deadline: self.deadline,
+            tasks: Arc::clone(&self.tasks),
+            passes: Vec::new(),
+            cache: HashMap::new(),
+            coverage: None,
+            profile: None,
+            sampler: None,
+            timings: None,
+            natives: self.natives.clone(),
+            values: HashMap::new(),
+            stdout: Arc::clone(&self.stdout),
+            stderr: Arc::clone(&self.stderr),
+        }
+    }
+
+    pub fn new() -> VirtualMachine {
+        let mut vm = VirtualMachine {
+            chunk: Arc::new(Chunk::default()),
+            ip: 0,
+            constants: Vec::new(),
+            integers: HashMap::new(),
+            stack: Vec::new(),
+            callstack: Vec::new(),
+            resumers: Vec::new(),
+            generated: 0,
+            executed: 0,
+            max_stack: 0,
+            allocated: 0,
+            allocated_before: 0,
+            exit_status: None,
+            env: Environment::new(),
+            span: parser::Span::unknown(),
+            dump_ast: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_allocations: None,
+            max_instructions: None,
+            max_time: None,
+            executed_before: 0,
+            deadline: None,
+            tasks: Arc::new(AtomicUsize::new(0)),
+            passes: optimize::pipeline(optimize::Level::O1),
+            cache: HashMap::new(),
+            coverage: None,
+            profile: None,
+            sampler: None,
+            timings: None,
+            natives: HashMap::new(),
+            values: HashMap::new(),
+            stdout: default_output(io::stdout()),
+            stderr: default_output(io::stderr()),
+        };
+        builtins::register(&mut vm);
+        builtins::register_files(&mut vm);
+        vm
+    }
+
+    // Functions that read and write files are available unless this is
+    // turned off, for instance to run programs that can't be trusted.
+    pub fn set_file_access(&mut self, enabled: bool) {
+        for name in builtins::FILE_FUNCTIONS {
+            self.unregister(name);
+        }
+        if enabled {
+            builtins::register_files(self);
+        }
+    }
+
+    pub fn file_access(&self) -> bool {
+        builtins::FILE_FUNCTIONS
+            .iter()
+            .all(|name| self.natives.contains_key(*name))
+    }
+
+    // Removes a native function, along with its binding unless a program
+    // has since replaced it.
+    pub fn unregister(&mut self, name: &str) {
+        if self.natives.remove(name).is_some() {
+            if let Some(Value::Native(native)) = self.env.values.get(name) {
+                if native == name {
+                    self.env.values.remove(name);
+                    self.env.types.remove(name);
+                }
+            }
+        }
+    }
+
+    // Makes a host function available to programs under the given name. The
+    // type must be a function type, and is used to check calls.
+    pub fn register_fn<F>(&mut self, name: &str, typ: typeinfer::Type, fun: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.register(name, typ, Native::Blocking(Arc::new(fun)));
+    }
+
+    // Like register_fn, for host functions that return futures.
+    pub fn register_async_fn<F, T>(&mut self, name: &str, typ: typeinfer::Type, fun: F)
+    where
+        F: Fn(&[Value]) -> T + Send + Sync + 'static,
+        T: Future<Output = Result<Value, String>> + Send + 'static,
+    {
+        self.register(
+            name,
+            typ,
+            Native::Async(Arc::new(move |args| Box::pin(fun(args)))),
+        );
+    }
+
+    fn register(&mut self, name: &str, typ: typeinfer::Type, native: Native) {
+        let arity = match &typ {
+            typeinfer::Type::Function(param, _) => match &**param {
+                typeinfer::Type::Tuple(elements) => elements.len(),
+                _ => 1,
+            },
+            _ => panic!("register_fn: {} is not a function type", typ),
+        };
+        self.env
+            .values
+            .insert(name.to_string(), Value::Native(name.to_string()));
+        self.env.types.insert(name.to_string(), typ.clone());
+        self.natives.insert(name.to_string(), (arity, typ, native));
+    }
+
+    // Declares a datatype for host functions to use, so that programs can
+    // match on it. Variants with a parameter of the given type are built by
+    // calling them, as with datatypes declared in programs.
+    pub fn register_datatype(&mut self, name: &str, variants: &[(&str, Option<typeinfer::Type>)]) {
+        let typ = typeinfer::Type::Datatype(name.to_string());
+        for (variant, param) in variants {
+            let datatype = name.to_string();
+            let ctor = variant.to_string();
+            match param {
+                Some(param) => {
+                    let fun =
+                        typeinfer::Type::Function(Arc::new(param.clone()), Arc::new(typ.clone()));
+                    self.register_fn(variant, fun, move |args| {
+                        let value = match args {
+                            [value] => value.clone(),
+                            _ => Value::Tuple(args.to_vec()),
+                        };
+                        Ok(Value::Datatype(
+                            datatype.clone(),
+                            ctor.clone(),
+                            Arc::new(value),
+                        ))
+                    });
+                }
+                None => {
+                    let value = Value::Datatype(datatype, ctor.clone(), Arc::new(Value::Unit));
+                    self.env.values.insert(ctor.clone(), value.clone());
+                    self.env.types.insert(ctor.clone(), typ.clone());
+                    self.values.insert(ctor, (typ.clone(), value));
+                }
+            }
+        }
+    }
+
+    // Adds a string to the constant pool, returning its index. Unlike
+    // integers, strings are not shared.
+    pub fn string(&mut self, s: &str) -> usize {
+        self.constants.push(Value::String(s.to_string()));
+        self.constants.len() - 1
+    }
+
+    // Returns the index of an integer in the constant pool, adding it if
+    // necessary.
+    pub fn integer(&mut self, i: i64) -> usize {
+        let constants = &mut self.constants;
+        *self.integers.entry(i).or_insert_with(|| {
+            constants.push(Value::Integer(i));
+            constants.len() - 1
+        })
+    }
+
+    // Discards all definitions and generated code, keeping options, output
+    // handles and registered native functions.
+    pub fn reset(&mut self) {
+        self.chunk = Arc::new(Chunk::default());
+        self.constants.clear();
+        self.integers.clear();
+        self.ip = 0;
+        self.stack.clear();
+        self.callstack.clear();
+        self.generated = 0;
+        self.executed = 0;
+        self.max_stack = 0;
+        self.allocated = 0;
+        self.allocated_before = 0;
+        self.executed_before = 0;
+        self.deadline = None;
+        self.exit_status = None;
+        self.cache.clear();
+        self.env = Environment::new();
+        self.span = parser::Span::unknown();
+        for (name, (_, typ, _)) in &self.natives {
+            self.env
+                .values
+                .insert(name.clone(), Value::Native(name.clone()));
+            self.env.types.insert(name.clone(), typ.clone());
+        }
+        for (name, (typ, value)) in &self.values {
+            self.env.values.insert(name.clone(), value.clone());
+            self.env.types.insert(name.clone(), typ.clone());
+        }
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        Statistics {
+            env_entries: self.env.values.len(),
+            instructions: self.generated,
+            constants: self.constants.len(),
+            executed: self.executed,
+            stack: self.stack.len(),
+            max_stack: self.max_stack,
+            allocated: self.allocated,
+        }
+    }
+
+    // Starts timing a phase, if timings are being measured.
+    pub fn start_timing(&self) -> Option<Instant> {
+        self.timings.map(|_| Instant::now())
+    }
+
+    // Adds the time since start to a phase.
+    pub fn record_timing(
+        &mut self,
+        start: Option<Instant>,
+        phase: impl FnOnce(&mut Timings) -> &mut Duration,
+    ) {
+        if let (Some(timings), Some(start)) = (&mut self.timings, start) {
+            *phase(timings) += start.elapsed();
         }
     }
+
+    // Makes a chunk the program to run next, from its start.
+    pub fn start(&mut self, chunk: Arc<Chunk>) {
+        self.chunk = chunk;
+        self.ip = 0;
+        self.allocated_before = self.allocated;
+        self.executed_before = self.executed;
+        self.deadline = self.max_time.map(|max| Instant::now() + max);
+        if let Some(profile) = &mut self.profile {
+            profile.start(self.executed);
+        }
+        if let Some(sampler) = &self.sampler {
+            sampler.skip();
+        }
+    }
+
+    // Wraps generated code in a chunk, counting its instructions.
+    pub fn new_chunk(&mut self, code: Vec<Opcode>) -> Arc<Chunk> {
+        self.generated += code.len();
+        Arc::new(Chunk::new(code))
+    }
+
+    // Like new_chunk, for the code of a function of type typ defined at span.
+    pub fn new_function(
+        &mut self,
+        code: Vec<Opcode>,
+        typ: typeinfer::Type,
+        span: parser::Span,
+    ) -> Arc<Chunk> {
+        self.generated += code.len();
+        Arc::new(Chunk {
+            code,
+            signature: Some((typ, span)),
+        })
+    }
+
+    // Records the current bindings so that they can be restored later, for
+    // instance to roll back an evaluation that failed partway through.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            env: self.env.clone(),
+            generated: self.generated,
+            stack: self.stack.len(),
+        }
+    }
+
+    // Returns to the state of a snapshot, discarding the definitions made
+    // since it was taken. Constants are kept, since closures made in the
+    // meantime can outlive it, for instance by being sent on a channel.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.env = snapshot.env;
+        self.chunk = Arc::new(Chunk::default());
+        self.generated = snapshot.generated;
+        self.ip = 0;
+        self.stack.truncate(snapshot.stack);
+        self.callstack.clear();
+    }
+
+    // Runs f in a child environment that is discarded afterwards, along with
+    // any code generated while running it.
+    pub fn isolated<T>(&mut self, f: impl FnOnce(&mut VirtualMachine) -> T) -> T {
+        let snapshot = self.snapshot();
+        let result = f(self);
+        self.restore(snapshot);
+        result
+    }
+}
+
+impl Default for VirtualMachine {
+    fn default() -> VirtualMachine {
+        VirtualMachine::new()
+    }
 }
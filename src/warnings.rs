@@ -0,0 +1,1045 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::diagnostics::Code;
+use crate::parser;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Category {
+    Unused,
+    Shadowing,
+    NonExhaustive,
+    Unreachable,
+    Discarded,
+}
+
+pub const CATEGORIES: [Category; 5] = [
+    Category::Unused,
+    Category::Shadowing,
+    Category::NonExhaustive,
+    Category::Unreachable,
+    Category::Discarded,
+];
+
+impl Category {
+    // The name used in -W flags.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Unused => "unused",
+            Category::Shadowing => "shadowing",
+            Category::NonExhaustive => "non-exhaustive",
+            Category::Unreachable => "unreachable",
+            Category::Discarded => "discarded",
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            Category::Unused => Code::UnusedBinding,
+            Category::Shadowing => Code::Shadowing,
+            Category::NonExhaustive => Code::NonExhaustiveMatch,
+            Category::Unreachable => Code::UnreachableArm,
+            Category::Discarded => Code::DiscardedValue,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+// How each category of warning is treated. Denied warnings are reported as
+// errors, and stop a program from running.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    levels: HashMap<Category, Level>,
+}
+
+impl Settings {
+    pub fn level(&self, category: Category) -> Level {
+        self.levels[&category]
+    }
+
+    pub fn set(&mut self, category: Category, level: Level) {
+        self.levels.insert(category, level);
+    }
+
+    // Applies a flag as given after -W: a category or all to warn about it,
+    // no- followed by one to allow it, error= followed by one to deny it, or
+    // error alone to deny everything that would be warned about.
+    pub fn apply(&mut self, flag: &str) -> Result<(), String> {
+        if flag == "error" {
+            for level in self.levels.values_mut() {
+                if *level == Level::Warn {
+                    *level = Level::Deny;
+                }
+            }
+            return Ok(());
+        }
+        let (name, level) = if let Some(name) = flag.strip_prefix("no-") {
+            (name, Level::Allow)
+        } else if let Some(name) = flag.strip_prefix("error=") {
+            (name, Level::Deny)
+        } else {
+            (flag, Level::Warn)
+        };
+        let mut found = false;
+        for category in CATEGORIES {
+            if name == "all" || name == category.name() {
+                self.set(category, level);
+                found = true;
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(format!("Unknown warning: {}.", name))
+        }
+    }
+}
+
+// Shadowing is common and usually deliberate, so it is only reported on
+// request, while matches must handle every variant unless allowed otherwise.
+impl Default for Settings {
+    fn default() -> Settings {
+        let mut levels = HashMap::new();
+        levels.insert(Category::Unused, Level::Warn);
+        levels.insert(Category::Shadowing, Level::Allow);
+        levels.insert(Category::NonExhaustive, Level::Deny);
+        levels.insert(Category::Unreachable, Level::Warn);
+        levels.insert(Category::Discarded, Level::Warn);
+        Settings { levels }
+    }
+}
+
+#[derive(Debug)]
+pub struct Warning {
+    pub category: Category,
+    pub code: Code,
+    pub msg: String,
+    pub span: parser::Span,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Warning: {}", self.msg)
+    }
+}
+
+fn warn(warnings: &mut Vec<Warning>, category: Category, msg: String, span: parser::Span) {
+    warnings.push(Warning {
+        category,
+        code: category.code(),
+        msg,
+        span,
+    });
+}
+
+struct Binding {
+    name: String,
+    kind: &'static str,
+    span: parser::Span,
+    used: bool,
+}
+
+// Bindings are tracked within functions, along with the datatypes declared
+// so far and which datatype each variant belongs to.
+struct State {
+    scope: Vec<Binding>,
+    datatypes: HashMap<String, Vec<String>>,
+    // The datatype of each variant, and if it has a value, how many elements
+    // the value has when it is a tuple, or one when it isn't.
+    variants: HashMap<String, (String, Option<usize>)>,
+    warnings: Vec<Warning>,
+}
+
+fn bind(state: &mut State, binding: Binding) {
+    if !binding.name.starts_with('_') && state.scope.iter().any(|b| b.name == binding.name) {
+        let mut msg = "Shadowing ".to_string();
+        msg.push_str(binding.kind);
+        msg.push_str(": ");
+        msg.push_str(&binding.name);
+        msg.push('.');
+        warn(&mut state.warnings, Category::Shadowing, msg, binding.span);
+    }
+    state.scope.push(binding);
+}
+
+fn bind_params(ast: &parser::AST, kind: &'static str, state: &mut State) {
+    match ast {
+        parser::AST::Identifier(name, span) => {
+            bind(
+                state,
+                Binding {
+                    name: name.to_string(),
+                    kind,
+                    span: *span,
+                    used: false,
+                },
+            );
+        }
+        parser::AST::Tuple(elements, _) => {
+            for element in elements {
+                bind_params(element, kind, state);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn close_scope(mark: usize, state: &mut State) {
+    for binding in state.scope.drain(mark..) {
+        if !binding.used && !binding.name.starts_with('_') {
+            let mut msg = "Unused ".to_string();
+            msg.push_str(binding.kind);
+            msg.push_str(": ");
+            msg.push_str(&binding.name);
+            msg.push('.');
+            warn(&mut state.warnings, Category::Unused, msg, binding.span);
+        }
+    }
+}
+
+// The names a pattern binds. Every alternative binds the same names, so only
+// those of the first are reported.
+fn bind_pattern(pattern: &parser::Pattern, state: &mut State) {
+    match pattern {
+        parser::Pattern::As(pattern, name, span) => {
+            bind_pattern(pattern, state);
+            bind(
+                state,
+                Binding {
+                    name: name.to_string(),
+                    kind: "variable",
+                    span: *span,
+                    used: false,
+                },
+            );
+        }
+        parser::Pattern::Identifier(name, _) if names_variant(name, state) => {}
+        parser::Pattern::Identifier(name, span) => {
+            bind(
+                state,
+                Binding {
+                    name: name.to_string(),
+                    kind: "variable",
+                    span: *span,
+                    used: false,
+                },
+            );
+        }
+        parser::Pattern::Literal(_, _) | parser::Pattern::Wildcard(_) => {}
+        parser::Pattern::Or(alternatives, _) => bind_pattern(&alternatives[0], state),
+        parser::Pattern::Tuple(elements, _) => {
+            for element in elements {
+                bind_pattern(element, state);
+            }
+        }
+        parser::Pattern::Variant(_, param, _) => {
+            if let Some(param) = param {
+                bind_pattern(param, state);
+            }
+        }
+    }
+}
+
+fn alternatives(pattern: &parser::Pattern) -> &[parser::Pattern] {
+    match pattern {
+        parser::Pattern::Or(alternatives, _) => alternatives,
+        pattern => std::slice::from_ref(pattern),
+    }
+}
+
+// Whether a name inside a pattern matches a variant without a value, which
+// type checking decides the same way, rather than being bound.
+fn names_variant(name: &str, state: &State) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        && matches!(state.variants.get(name), Some((_, None)))
+}
+
+// What a pattern matches, as far as telling which values no arm handles.
+// Variants, tuples and literals are constructors of the values they match,
+// with patterns for the parts of those values.
+#[derive(Clone)]
+enum Space {
+    Any,
+    Constructor(Constructor, Vec<Space>),
+}
+
+#[derive(Clone, PartialEq)]
+enum Constructor {
+    Literal(String),
+    Tuple(usize),
+    Variant(String),
+}
+
+impl fmt::Display for Space {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Space::Any => write!(f, "_"),
+            Space::Constructor(Constructor::Literal(literal), _) => write!(f, "{}", literal),
+            Space::Constructor(Constructor::Tuple(_), elements) => {
+                let elements: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+                write!(f, "({})", elements.join(", "))
+            }
+            Space::Constructor(Constructor::Variant(name), param) => match param.first() {
+                Some(param @ Space::Constructor(Constructor::Tuple(_), _)) => {
+                    write!(f, "{} {}", name, param)
+                }
+                Some(param) => write!(f, "{} ({})", name, param),
+                None => write!(f, "{}", name),
+            },
+        }
+    }
+}
+
+// How many parts a constructor has. Variants have their value, if they have
+// one, and those of datatypes from earlier input are taken to have one.
+fn arity(constructor: &Constructor, state: &State) -> usize {
+    match constructor {
+        Constructor::Literal(_) => 0,
+        Constructor::Tuple(n) => *n,
+        Constructor::Variant(name) => state
+            .variants
+            .get(name)
+            .map_or(1, |(_, value)| usize::from(value.is_some())),
+    }
+}
+
+fn space(pattern: &parser::Pattern, state: &State) -> Space {
+    match pattern {
+        parser::Pattern::As(pattern, _, _) => space(pattern, state),
+        parser::Pattern::Identifier(name, _) if names_variant(name, state) => {
+            Space::Constructor(Constructor::Variant(name.to_string()), Vec::new())
+        }
+        parser::Pattern::Identifier(_, _) | parser::Pattern::Wildcard(_) => Space::Any,
+        parser::Pattern::Literal(literal, _) => {
+            let literal = match &**literal {
+                parser::AST::Boolean(b, _) => b.to_string(),
+                parser::AST::Char(c, _) => parser::escape_char(*c),
+                parser::AST::Integer(i, _) => i.to_string(),
+                parser::AST::String(s, _) => parser::escape(s),
+                _ => unreachable!(),
+            };
+            Space::Constructor(Constructor::Literal(literal), Vec::new())
+        }
+        // Alternatives are only found at the top of an arm.
+        parser::Pattern::Or(_, _) => unreachable!(),
+        parser::Pattern::Tuple(elements, _) => Space::Constructor(
+            Constructor::Tuple(elements.len()),
+            elements
+                .iter()
+                .map(|element| space(element, state))
+                .collect(),
+        ),
+        parser::Pattern::Variant(name, param, _) => {
+            let constructor = Constructor::Variant(name.to_string());
+            let param = match (arity(&constructor, state), param) {
+                (0, _) => Vec::new(),
+                (_, Some(param)) => vec![space(param, state)],
+                (_, None) => vec![Space::Any],
+            };
+            Space::Constructor(constructor, param)
+        }
+    }
+}
+
+// The constructors at the start of the rows, and every constructor the
+// values there can have, when those are known: the variants of a datatype,
+// both booleans, or a tuple. Other values, such as integers, have too many to
+// list, and variants of datatypes from earlier input aren't known.
+fn constructors(
+    rows: &[Vec<Space>],
+    state: &State,
+) -> (Vec<Constructor>, Option<Vec<Constructor>>) {
+    let mut found: Vec<Constructor> = Vec::new();
+    for row in rows {
+        if let Space::Constructor(constructor, _) = &row[0] {
+            if !found.contains(constructor) {
+                found.push(constructor.clone());
+            }
+        }
+    }
+    let all = match found.first() {
+        Some(Constructor::Tuple(n)) => Some(vec![Constructor::Tuple(*n)]),
+        Some(Constructor::Literal(literal)) if literal == "true" || literal == "false" => Some(
+            ["false", "true"]
+                .iter()
+                .map(|b| Constructor::Literal(b.to_string()))
+                .collect(),
+        ),
+        Some(Constructor::Variant(name)) => state
+            .variants
+            .get(name)
+            .and_then(|(datatype, _)| state.datatypes.get(datatype))
+            .map(|variants| {
+                let mut variants = variants.clone();
+                variants.sort();
+                variants.into_iter().map(Constructor::Variant).collect()
+            }),
+        _ => None,
+    };
+    (found, all)
+}
+
+// The rows that can match a value built by constructor, with the patterns
+// for its parts in place of the first.
+fn specialize(rows: &[Vec<Space>], constructor: &Constructor, arity: usize) -> Vec<Vec<Space>> {
+    rows.iter()
+        .filter_map(|row| match &row[0] {
+            Space::Any => Some(
+                std::iter::repeat_n(Space::Any, arity)
+                    .chain(row[1..].iter().cloned())
+                    .collect(),
+            ),
+            Space::Constructor(found, parts) if found == constructor => {
+                Some(parts.iter().chain(row[1..].iter()).cloned().collect())
+            }
+            Space::Constructor(_, _) => None,
+        })
+        .collect()
+}
+
+// The rows that match any value at all in their first place, without it.
+fn default(rows: &[Vec<Space>]) -> Vec<Vec<Space>> {
+    rows.iter()
+        .filter(|row| matches!(row[0], Space::Any))
+        .map(|row| row[1..].to_vec())
+        .collect()
+}
+
+// Whether some values matched by row are matched by none of rows.
+fn useful(rows: &[Vec<Space>], row: &[Space], state: &State) -> bool {
+    let (first, rest) = match row.split_first() {
+        Some(split) => split,
+        None => return rows.is_empty(),
+    };
+    match first {
+        Space::Constructor(constructor, parts) => {
+            let row: Vec<Space> = parts.iter().chain(rest.iter()).cloned().collect();
+            useful(&specialize(rows, constructor, parts.len()), &row, state)
+        }
+        Space::Any => match constructors(rows, state) {
+            (found, Some(all)) if all.iter().all(|c| found.contains(c)) => {
+                all.iter().any(|constructor| {
+                    let arity = arity(constructor, state);
+                    let row: Vec<Space> = std::iter::repeat_n(Space::Any, arity)
+                        .chain(rest.iter().cloned())
+                        .collect();
+                    useful(&specialize(rows, constructor, arity), &row, state)
+                })
+            }
+            _ => useful(&default(rows), rest, state),
+        },
+    }
+}
+
+// Values of width parts that none of rows match, if there are any. Where
+// the constructors aren't known, those that are found are taken to be all of
+// them, so that only what is certainly missing is reported.
+fn missing(rows: &[Vec<Space>], width: usize, state: &State) -> Option<Vec<Space>> {
+    if width == 0 {
+        return if rows.is_empty() {
+            Some(Vec::new())
+        } else {
+            None
+        };
+    }
+    let (found, all) = constructors(rows, state);
+    let complete = match &all {
+        Some(all) => all.iter().all(|c| found.contains(c)),
+        None => found.iter().any(|c| matches!(c, Constructor::Variant(_))),
+    };
+    if complete {
+        for constructor in all.as_ref().unwrap_or(&found) {
+            let arity = arity(constructor, state);
+            let specialized = specialize(rows, constructor, arity);
+            if let Some(mut values) = missing(&specialized, arity + width - 1, state) {
+                let rest = values.split_off(arity);
+                let mut value = vec![Space::Constructor(constructor.clone(), values)];
+                value.extend(rest);
+                return Some(value);
+            }
+        }
+        return None;
+    }
+    let rest = missing(&default(rows), width - 1, state)?;
+    let first = all
+        .and_then(|all| all.into_iter().find(|c| !found.contains(c)))
+        .map_or(Space::Any, |constructor| example(constructor, state));
+    let mut value = vec![first];
+    value.extend(rest);
+    Some(value)
+}
+
+// A value built by constructor from any values, which are the elements of the
+// tuple for variants whose value is one.
+fn example(constructor: Constructor, state: &State) -> Space {
+    let parts = match &constructor {
+        Constructor::Variant(name) => match state.variants.get(name) {
+            Some((_, Some(n))) if *n > 1 => vec![Space::Constructor(
+                Constructor::Tuple(*n),
+                vec![Space::Any; *n],
+            )],
+            _ => vec![Space::Any; arity(&constructor, state)],
+        },
+        _ => vec![Space::Any; arity(&constructor, state)],
+    };
+    Space::Constructor(constructor, parts)
+}
+
+// Whether a value has no constructors but those of tuples, so that it stands
+// for values that only literals were given for.
+fn unlisted(value: &Space) -> bool {
+    match value {
+        Space::Any => true,
+        Space::Constructor(Constructor::Tuple(_), parts) => parts.iter().all(unlisted),
+        Space::Constructor(_, _) => false,
+    }
+}
+
+// Alternatives of arms that only match values earlier ones already do never
+// run, and values that no arm matches would fail at runtime. Missing variants
+// of a datatype are listed, while other values are reported by an example.
+fn check_arms(cases: &[(parser::Pattern, parser::AST)], span: parser::Span, state: &mut State) {
+    let mut rows: Vec<Vec<Space>> = Vec::new();
+    for case in cases {
+        let alternatives = alternatives(&case.0);
+        let mut unreachable = Vec::new();
+        for alternative in alternatives {
+            let row = vec![space(alternative, state)];
+            if !useful(&rows, &row, state) {
+                unreachable.push(alternative);
+            }
+            rows.push(row);
+        }
+        let name = |mut pattern: &parser::Pattern| {
+            while let parser::Pattern::As(inner, _, _) = pattern {
+                pattern = inner;
+            }
+            match pattern {
+                parser::Pattern::Variant(name, _, _) => {
+                    format!("Unreachable match arm: {}.", name)
+                }
+                _ => "Unreachable match arm.".to_string(),
+            }
+        };
+        // Only the alternatives are reported if others in the arm still run.
+        if unreachable.len() == alternatives.len() {
+            let msg = if alternatives.len() == 1 {
+                name(&alternatives[0])
+            } else {
+                "Unreachable match arm.".to_string()
+            };
+            warn(
+                &mut state.warnings,
+                Category::Unreachable,
+                msg,
+                parser::span_of(&case.1),
+            );
+        } else {
+            for alternative in unreachable {
+                let msg = name(alternative);
+                let span = parser::pattern_span(alternative);
+                warn(&mut state.warnings, Category::Unreachable, msg, span);
+            }
+        }
+    }
+    let value = match missing(&rows, 1, state) {
+        Some(mut value) => value.remove(0),
+        None => return,
+    };
+    let msg = match &value {
+        Space::Constructor(Constructor::Variant(name), _) => {
+            let (found, all) = constructors(&rows, state);
+            let absent: Vec<String> = all
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|constructor| !found.contains(constructor))
+                .filter_map(|constructor| match constructor {
+                    Constructor::Variant(name) => Some(name),
+                    _ => None,
+                })
+                .collect();
+            match state.variants.get(name) {
+                Some((datatype, _)) if absent.contains(name) => format!(
+                    "Missing variants in match of {}: {}.",
+                    datatype,
+                    absent.join(" ")
+                ),
+                _ => format!("Missing pattern in match: {}.", value),
+            }
+        }
+        value if unlisted(value) => "Missing arm in match for other values.".to_string(),
+        value => format!("Missing pattern in match: {}.", value),
+    };
+    warn(&mut state.warnings, Category::NonExhaustive, msg, span);
+}
+
+// Whether evaluating ast does nothing but produce a value. Calls and
+// definitions may have effects, so anything containing them is left alone, as
+// is unit, which programs discard on purpose.
+fn is_pure(ast: &parser::AST) -> bool {
+    match ast {
+        parser::AST::Ascription(expr, _, _)
+        | parser::AST::Field(expr, _, _)
+        | parser::AST::Spread(expr, _)
+        | parser::AST::UnaryOp(_, expr, _) => is_pure(expr),
+        parser::AST::BinaryOp(_, _, _, _) => {
+            let (first, links) = parser::chain(ast);
+            is_pure(first) && links.iter().all(|(_, rhs, _)| is_pure(rhs))
+        }
+        parser::AST::Index(lhs, rhs, _) => is_pure(lhs) && is_pure(rhs),
+        parser::AST::Slice(value, start, end, _) => {
+            is_pure(value) && is_pure(start) && is_pure(end)
+        }
+        parser::AST::Record(fields, _) => fields.iter().all(|field| is_pure(&field.1)),
+        parser::AST::Tuple(elements, _) => elements.iter().all(is_pure),
+        parser::AST::Function(None, _, _, _)
+        | parser::AST::Boolean(_, _)
+        | parser::AST::Char(_, _)
+        | parser::AST::Identifier(_, _)
+        | parser::AST::Integer(_, _)
+        | parser::AST::String(_, _) => true,
+        _ => false,
+    }
+}
+
+fn find_warnings(ast: &parser::AST, depth: usize, state: &mut State) {
+    match ast {
+        parser::AST::Ascription(expr, _, _) => {
+            find_warnings(expr, depth, state);
+        }
+        parser::AST::BinaryOp(_, _, _, _) => {
+            let (first, links) = parser::chain(ast);
+            find_warnings(first, depth, state);
+            for (_, rhs, _) in links {
+                find_warnings(rhs, depth, state);
+            }
+        }
+        parser::AST::Call(fun, arg, _) => {
+            find_warnings(fun, depth, state);
+            find_warnings(arg, depth, state);
+        }
+        parser::AST::Datatype(name, variants, _) => {
+            let names: Vec<String> = variants
+                .iter()
+                .map(|variant| variant.0.to_string())
+                .collect();
+            for variant in variants {
+                let value = variant.1.as_ref().map(|value| match value {
+                    parser::AST::Tuple(elements, _) => elements.len(),
+                    _ => 1,
+                });
+                state
+                    .variants
+                    .insert(variant.0.to_string(), (name.to_string(), value));
+            }
+            state.datatypes.insert(name.to_string(), names);
+        }
+        parser::AST::Define(id, value, span) => {
+            find_warnings(value, depth, state);
+            // Top level definitions may be used by later input, so only
+            // definitions inside of functions are tracked.
+            if let parser::AST::Identifier(name, _) = &**id {
+                if depth > 0 {
+                    bind(
+                        state,
+                        Binding {
+                            name: name.to_string(),
+                            kind: "definition",
+                            span: *span,
+                            used: false,
+                        },
+                    );
+                }
+            }
+        }
+        parser::AST::Field(value, _, _) => {
+            find_warnings(value, depth, state);
+        }
+        parser::AST::For(pattern, iter, body, _) => {
+            find_warnings(iter, depth, state);
+            let mark = state.scope.len();
+            bind_params(pattern, "variable", state);
+            find_warnings(body, depth, state);
+            close_scope(mark, state);
+        }
+        parser::AST::Function(name, param, body, span) => {
+            let mark = state.scope.len();
+            if let Some(name) = name {
+                bind(
+                    state,
+                    Binding {
+                        name: name.to_string(),
+                        kind: "function",
+                        span: *span,
+                        used: true,
+                    },
+                );
+            }
+            bind_params(param, "parameter", state);
+            find_warnings(body, depth + 1, state);
+            close_scope(mark, state);
+        }
+        parser::AST::Identifier(name, _) => {
+            if let Some(binding) = state.scope.iter_mut().rev().find(|b| b.name == *name) {
+                binding.used = true;
+            }
+        }
+        parser::AST::If(conds, els, _) => {
+            for cond in conds {
+                find_warnings(&cond.0, depth, state);
+                find_warnings(&cond.1, depth, state);
+            }
+            find_warnings(els, depth, state);
+        }
+        parser::AST::Index(value, index, _) => {
+            find_warnings(value, depth, state);
+            find_warnings(index, depth, state);
+        }
+        parser::AST::Match(cond, cases, span) => {
+            find_warnings(cond, depth, state);
+            check_arms(cases, *span, state);
+            for case in cases {
+                let mark = state.scope.len();
+                bind_pattern(&case.0, state);
+                find_warnings(&case.1, depth, state);
+                close_scope(mark, state);
+            }
+        }
+        parser::AST::Program(expressions, _) => {
+            for (i, expression) in expressions.iter().enumerate() {
+                // Only the value of the last expression is kept.
+                if i + 1 < expressions.len() && is_pure(expression) {
+                    warn(
+                        &mut state.warnings,
+                        Category::Discarded,
+                        "Discarded value.".to_string(),
+                        parser::span_of(expression),
+                    );
+                }
+                find_warnings(expression, depth, state);
+            }
+        }
+        parser::AST::Record(fields, _) => {
+            for field in fields {
+                find_warnings(&field.1, depth, state);
+            }
+        }
+        parser::AST::Slice(value, start, end, _) => {
+            find_warnings(value, depth, state);
+            find_warnings(start, depth, state);
+            find_warnings(end, depth, state);
+        }
+        parser::AST::Spread(ast, _) => {
+            find_warnings(ast, depth, state);
+        }
+        parser::AST::Tuple(elements, _) => {
+            for element in elements {
+                find_warnings(element, depth, state);
+            }
+        }
+        parser::AST::UnaryOp(_, ast, _) => {
+            find_warnings(ast, depth, state);
+        }
+        parser::AST::Boolean(_, _)
+        | parser::AST::Char(_, _)
+        | parser::AST::Integer(_, _)
+        | parser::AST::String(_, _)
+        | parser::AST::Unit(_) => {}
+    }
+}
+
+// Finds the warnings in a program that the settings don't allow, in order of
+// position.
+pub fn check(ast: &parser::AST, settings: &Settings) -> Vec<Warning> {
+    let mut state = State {
+        scope: Vec::new(),
+        datatypes: HashMap::new(),
+        variants: HashMap::new(),
+        warnings: Vec::new(),
+    };
+    find_warnings(ast, 0, &mut state);
+    let mut warnings: Vec<Warning> = state
+        .warnings
+        .into_iter()
+        .filter(|warning| settings.level(warning.category) != Level::Allow)
+        .collect();
+    warnings.sort_by_key(|warning| (warning.span.line, warning.span.col));
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser;
+    use crate::warnings;
+    use crate::warnings::{Category, Level, Settings};
+
+    macro_rules! warns {
+        ($input:expr, $($msg:expr, $line:expr, $col:expr),*) => {{
+            let mut settings = Settings::default();
+            settings.set(Category::Shadowing, Level::Warn);
+            match parser::parse($input) {
+                Ok(ast) => {
+                    let found = warnings::check(&ast, &settings);
+                    let expected: Vec<(&str, usize, usize)> = vec![$(($msg, $line, $col)),*];
+                    assert_eq!(found.len(), expected.len());
+                    for (warning, expected) in found.iter().zip(expected.iter()) {
+                        assert_eq!(warning.msg, expected.0);
+                        assert_eq!(warning.span.line, expected.1);
+                        assert_eq!(warning.span.col, expected.2);
+                    }
+                }
+                Err(_) => {
+                    assert!(false);
+                }
+            }
+        }};
+    }
+
+    #[test]
+    fn unused() {
+        warns!("fn x -> x + 1 end",);
+        warns!("def x := 1",);
+        warns!("fn x -> 1 end", "Unused parameter: x.", 1, 4);
+        warns!("fn _x -> 1 end",);
+        warns!("fn (x, y) -> y end", "Unused parameter: x.", 1, 5);
+        warns!(
+            "fn (x, y) -> 1 end",
+            "Unused parameter: x.",
+            1,
+            5,
+            "Unused parameter: y.",
+            1,
+            8
+        );
+        warns!("fn x -> fn y -> x + y end end",);
+        warns!("fn x -> fn y -> y end end", "Unused parameter: x.", 1, 4);
+        warns!(
+            "fn x ->
+                 def t := 2
+                 x
+             end",
+            "Unused definition: t.",
+            2,
+            18
+        );
+        warns!(
+            "fn x ->
+                 def t := 2
+                 def t := t + 1
+                 x + t
+             end",
+            "Shadowing definition: t.",
+            3,
+            18
+        );
+        warns!(
+            "fn fact (n, acc) ->
+                 if n == 0 then
+                    acc
+                 else
+                    fact(n - 1, n*acc)
+                 end
+             end",
+        );
+        warns!(
+            "type Pair := Cons (a, b) | Null end
+             fn len (pair) ->
+                 match pair with
+                    Null -> 0
+                    | Cons (a, b) -> 1 + len (b)
+                 end
+             end",
+            "Unused variable: a.",
+            5,
+            29
+        );
+        warns!(
+            "type Pair := Cons (a, b) | Null end
+             fn len (pair) ->
+                 match pair with
+                    Null -> 0
+                    | Cons (_a, b) -> 1 + len (b)
+                 end
+             end",
+        );
+    }
+
+    #[test]
+    fn shadowing() {
+        warns!(
+            "fn x -> fn x -> x end end",
+            "Unused parameter: x.",
+            1,
+            4,
+            "Shadowing parameter: x.",
+            1,
+            12
+        );
+        warns!("fn x -> fn _x -> x end end",);
+        warns!(
+            "fn x ->
+                 def x := 1
+                 x
+             end",
+            "Unused parameter: x.",
+            1,
+            4,
+            "Shadowing definition: x.",
+            2,
+            18
+        );
+    }
+
+    #[test]
+    fn matches() {
+        warns!(
+            "type List := Cons (integer, List) | Nil end
+             match Nil with Cons (x, Cons (y, _)) -> x + y | Nil -> 0 end",
+            "Missing pattern in match: Cons (_, Nil).",
+            2,
+            14
+        );
+        warns!(
+            "type List := Cons (integer, List) | Nil end
+             match Cons (1, Nil) with Cons (_, Nil) as l -> l | Nil -> Nil end",
+            "Missing pattern in match: Cons (_, Cons (_, _)).",
+            2,
+            14
+        );
+        warns!(
+            "type List := Cons (integer, List) | Nil end
+             match Nil with Cons (x, Nil) -> x | Cons (_, Cons (_, _)) -> 0 end",
+            "Missing variants in match of List: Nil.",
+            2,
+            14
+        );
+        warns!(
+            "type List := Cons (integer, List) | Nil end
+             match Nil with Cons (_, Nil) | Nil -> 0 | Cons (_, _) -> 1 | Cons (x, Nil) -> x end",
+            "Unreachable match arm: Cons.",
+            2,
+            92
+        );
+        warns!(
+            "match (true, 1) with (true, _) -> 1 | (false, 0) -> 2 end",
+            "Missing pattern in match: (false, _).",
+            1,
+            1
+        );
+        warns!(
+            "match 3 with 1 -> 0 | (2) -> 1 end",
+            "Missing arm in match for other values.",
+            1,
+            1
+        );
+        warns!("match 3 == 4 with true -> 0 | false -> 1 end",);
+        warns!(
+            "match (1, 2) with (1, x) -> x | (x, y) -> x + y | _ -> 0 end",
+            "Unreachable match arm.",
+            1,
+            56
+        );
+        warns!(
+            "type E := A | B | C | D end
+             match A with
+                 A -> 0
+             end",
+            "Missing variants in match of E: B C D.",
+            2,
+            14
+        );
+        warns!(
+            "type E := A | B end
+             match A with
+                 A -> 0
+                 | B -> 1
+                 | A -> 2
+             end",
+            "Unreachable match arm: A.",
+            5,
+            25
+        );
+        warns!(
+            "type E := A | B | C end
+             match A with
+                 A | B -> 0
+                 | C | A -> 1
+             end",
+            "Unreachable match arm: A.",
+            4,
+            24
+        );
+        warns!(
+            "match (1, 2) with
+                 (a, b) -> a + b
+                 | (c, d) -> c + d
+             end",
+            "Unreachable match arm.",
+            3,
+            30
+        );
+    }
+
+    #[test]
+    fn discarded() {
+        warns!(
+            "fn f (x) ->
+                 x + 1
+                 x * 2
+             end",
+            "Discarded value.",
+            2,
+            18
+        );
+        warns!(
+            "1
+             (\"a\", fn x -> x end)
+             print (2)
+             ()
+             def y := 3
+             y",
+            "Discarded value.",
+            1,
+            1,
+            "Discarded value.",
+            2,
+            14
+        );
+        warns!("fn f (x) -> x end f (1) fn g (x) -> f (x) end g",);
+    }
+
+    #[test]
+    fn settings() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.level(Category::Unused), Level::Warn);
+        assert_eq!(settings.level(Category::Shadowing), Level::Allow);
+        assert_eq!(settings.level(Category::NonExhaustive), Level::Deny);
+        assert_eq!(settings.level(Category::Discarded), Level::Warn);
+        assert_eq!(settings.apply("shadowing"), Ok(()));
+        assert_eq!(settings.level(Category::Shadowing), Level::Warn);
+        assert_eq!(settings.apply("no-unused"), Ok(()));
+        assert_eq!(settings.level(Category::Unused), Level::Allow);
+        assert_eq!(settings.apply("error=unreachable"), Ok(()));
+        assert_eq!(settings.level(Category::Unreachable), Level::Deny);
+        assert_eq!(settings.apply("error"), Ok(()));
+        assert_eq!(settings.level(Category::Shadowing), Level::Deny);
+        assert_eq!(settings.level(Category::Unused), Level::Allow);
+        assert_eq!(settings.apply("no-all"), Ok(()));
+        assert_eq!(settings.level(Category::NonExhaustive), Level::Allow);
+        assert_eq!(
+            settings.apply("bogus"),
+            Err("Unknown warning: bogus.".to_string())
+        );
+
+        match parser::parse("fn x -> 1 end") {
+            Ok(ast) => assert!(warnings::check(&ast, &settings).is_empty()),
+            Err(_) => unreachable!(),
+        }
+    }
+}